@@ -0,0 +1,50 @@
+use std::{fs, iter::repeat_with, path::PathBuf};
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use git_rs::{database::Database, staging::stage_files, workspace::Workspace};
+
+const FILE_COUNT: usize = 2000;
+const FILE_SIZE: usize = 16 * 1024;
+
+/// Creates a workspace with FILE_COUNT files spread over a few directories
+fn setup() -> PathBuf {
+    let name: String = repeat_with(fastrand::alphanumeric).take(6).collect();
+    let root = std::env::temp_dir().join(format!("git_rs_bench_{}", name));
+
+    for i in 0..FILE_COUNT {
+        let dir = root.join(format!("dir_{}", i % 20));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = repeat_with(|| fastrand::u8(..)).take(FILE_SIZE).collect();
+        fs::write(dir.join(format!("file_{}", i)), data).unwrap();
+    }
+    root
+}
+
+fn bench_add(c: &mut Criterion) {
+    let root = setup();
+    let workspace = Workspace::new(root.clone());
+    let files = workspace.list_files(None).unwrap();
+
+    let mut group = c.benchmark_group("stage_files");
+    group.sample_size(10);
+    for jobs in [1, 2, 4, 8].iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(jobs), jobs, |b, &jobs| {
+            b.iter_batched(
+                || {
+                    // Use a fresh database every time so every object is actually written
+                    let objects = root.join("objects");
+                    let _ = fs::remove_dir_all(&objects);
+                    Database::new(objects)
+                },
+                |db| stage_files(&workspace, &db, &files, jobs).unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+criterion_group!(benches, bench_add);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+//! `git_rs receive-pack` under the name git clients run with `--receive-pack=git_rs-receive-pack`
+
+use std::{env, io, path::PathBuf};
+
+use anyhow::{Context, Result};
+use git_rs::remote::server;
+
+fn main() -> Result<()> {
+    let directory = env::args_os()
+        .nth(1)
+        .map(PathBuf::from)
+        .context("usage: git_rs-receive-pack <directory>")?;
+    let mut output = io::BufWriter::new(io::stdout());
+    server::receive_pack(
+        &directory,
+        server::ServeOptions::default(),
+        &mut io::stdin(),
+        &mut output,
+    )
+}
@@ -0,0 +1,22 @@
+//! `git_rs upload-pack` under the name git clients run with `--upload-pack=git_rs-upload-pack`
+
+use std::{env, io, path::PathBuf};
+
+use anyhow::{Context, Result};
+use git_rs::remote::server;
+
+fn main() -> Result<()> {
+    let directory = env::args_os()
+        .nth(1)
+        .map(PathBuf::from)
+        .context("usage: git_rs-upload-pack <directory>")?;
+    let version = server::protocol_version(env::var("GIT_PROTOCOL").ok().as_deref());
+    let mut output = io::BufWriter::new(io::stdout());
+    server::upload_pack(
+        &directory,
+        version,
+        server::ServeOptions::default(),
+        &mut io::stdin(),
+        &mut output,
+    )
+}
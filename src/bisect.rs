@@ -0,0 +1,562 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{self, Display, Formatter},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    database::{commit::Commit, tree::TreeItem, Database},
+    index::Index,
+    migration,
+    refs::HEAD,
+    rev_list,
+    revision::Revision,
+    staging,
+    workspace::Workspace,
+    Author, ObjectId, Refs,
+};
+
+/// Holds the branch or commit HEAD was on when the bisection started
+pub const BISECT_START: &str = "BISECT_START";
+/// Every command of the bisection, in a format `bisect replay` can read back
+pub const BISECT_LOG: &str = "BISECT_LOG";
+const BISECT_REFS: &str = "refs/bisect/";
+const BAD_REF: &str = "refs/bisect/bad";
+
+/// Exit code of a `bisect run` script meaning the commit can't be tested
+const SKIP_CODE: i32 = 125;
+
+/// How a commit was marked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Term {
+    Good,
+    Bad,
+    Skip,
+}
+
+impl Display for Term {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match self {
+            Term::Good => "good",
+            Term::Bad => "bad",
+            Term::Skip => "skip",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Term {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "good" | "old" => Ok(Term::Good),
+            "bad" | "new" => Ok(Term::Bad),
+            "skip" => Ok(Term::Skip),
+            _ => bail!("unknown bisect term: {}", s),
+        }
+    }
+}
+
+/// Binary search of the commit that introduced a bug. Commits marked bad and good are kept
+/// as refs under `refs/bisect`, and every step checks out the commit splitting the remaining
+/// candidates in two halves as even as possible.
+pub struct Bisect<'a> {
+    git_path: PathBuf,
+    database: &'a Database,
+    workspace: &'a Workspace,
+    index: &'a mut Index,
+    refs: &'a Refs,
+    /// Where the steps and the result are reported, nothing is shown by default
+    out: Box<dyn Write + 'a>,
+}
+
+impl<'a> Bisect<'a> {
+    pub fn new(
+        git_path: &Path,
+        database: &'a Database,
+        workspace: &'a Workspace,
+        index: &'a mut Index,
+        refs: &'a Refs,
+    ) -> Self {
+        Self {
+            git_path: git_path.to_path_buf(),
+            database,
+            workspace,
+            index,
+            refs,
+            out: Box::new(io::sink()),
+        }
+    }
+
+    /// Reports the steps and the result to `out`
+    pub fn with_output(mut self, out: Box<dyn Write + 'a>) -> Self {
+        self.out = out;
+        self
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.git_path.join(BISECT_START).is_file()
+    }
+
+    /// Starts a bisection, optionally marking a bad commit and some good ones right away
+    pub fn start(&mut self, bad: Option<&str>, good: &[String]) -> Result<()> {
+        let head_id = self
+            .refs
+            .read_head()
+            .context("Cannot bisect without a HEAD")?;
+        if self.in_progress() {
+            self.reset(None)?;
+        }
+        self.ensure_clean(&head_id)?;
+
+        let head_name = self
+            .refs
+            .read_symref(HEAD)
+            .map(|name| name.trim_start_matches("refs/heads/").to_string())
+            .unwrap_or(head_id);
+        fs::write(self.git_path.join(BISECT_START), format!("{}\n", head_name))?;
+        fs::write(self.git_path.join(BISECT_LOG), "")?;
+
+        let mut args: Vec<&str> = bad.into_iter().collect();
+        args.extend(good.iter().map(String::as_str));
+        let command = format!("git bisect start {}", args.join(" "));
+        self.log(command.trim_end())?;
+
+        if let Some(bad) = bad {
+            self.mark_commit(Term::Bad, bad)?;
+        }
+        for good in good {
+            self.mark_commit(Term::Good, good)?;
+        }
+        self.next().map(|_| ())
+    }
+
+    /// Marks commits, HEAD when none is given, then checks out the next one to test.
+    /// Returns the first bad commit once it's found.
+    pub fn mark(&mut self, term: Term, revisions: &[String]) -> Result<Option<ObjectId>> {
+        if !self.in_progress() {
+            bail!("You need to start by \"git bisect start\"");
+        }
+        if term == Term::Bad && revisions.len() > 1 {
+            bail!("'git bisect bad' can take only one argument.");
+        }
+        let head = [HEAD.to_string()];
+        let revisions = if revisions.is_empty() {
+            &head
+        } else {
+            revisions
+        };
+        let mut commit_ids = vec![];
+        for revision in revisions {
+            commit_ids.push(self.mark_commit(term, revision)?);
+        }
+        self.log(&format!("git bisect {} {}", term, commit_ids.join(" ")))?;
+        self.next()
+    }
+
+    /// Ends the bisection and checks out the branch it was started from, or `commit`
+    pub fn reset(&mut self, commit: Option<&str>) -> Result<()> {
+        if !self.in_progress() {
+            writeln!(self.out, "We are not bisecting.")?;
+            return Ok(());
+        }
+        let start = self.read_state(BISECT_START)?;
+        let target = commit.unwrap_or(&start);
+        let commit_id = Revision::parse(target)?.resolve(self.refs, self.database)?;
+        self.checkout(&commit_id)?;
+
+        let identity = Author::from_env();
+        let message = format!("checkout: moving from {} to {}", self.head_label(), target);
+        let branch = format!("refs/heads/{}", target);
+        if self.refs.read_ref(&branch).is_some() {
+            self.refs
+                .update_symref(HEAD, &branch, &identity, &message)?;
+        } else {
+            self.refs.update_ref(HEAD, commit_id, &identity, &message)?;
+        }
+
+        for (name, _) in self.refs.list_refs(BISECT_REFS)? {
+            self.refs.delete_ref(&name)?;
+        }
+        for name in [BISECT_START, BISECT_LOG] {
+            fs::remove_file(self.git_path.join(name))?;
+        }
+        Ok(())
+    }
+
+    /// The commands of the bisection so far
+    pub fn read_log(&self) -> Result<String> {
+        if !self.in_progress() {
+            bail!("We are not bisecting.");
+        }
+        Ok(fs::read_to_string(self.git_path.join(BISECT_LOG))?)
+    }
+
+    /// Starts over and runs the commands of a log written by `bisect log`
+    pub fn replay(&mut self, log_path: &Path) -> Result<()> {
+        let log = fs::read_to_string(log_path)
+            .with_context(|| format!("cannot read {} for replaying", log_path.display()))?;
+        if self.in_progress() {
+            self.reset(None)?;
+        }
+        for line in log.lines() {
+            let words: Vec<String> = match line.strip_prefix("git bisect ") {
+                Some(command) => command.split_whitespace().map(String::from).collect(),
+                None => continue,
+            };
+            match words.split_first() {
+                Some((command, args)) if command == "start" => {
+                    let (bad, good) = match args.split_first() {
+                        Some((bad, good)) => (Some(bad.as_str()), good),
+                        None => (None, args),
+                    };
+                    self.start(bad, good)?;
+                }
+                Some((term, args)) => {
+                    self.mark(term.parse()?, args)?;
+                }
+                None => bail!("?? what are you talking about?"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the command and its arguments on every commit to test until the first bad commit
+    /// is found. Exit code 0 means good, 125 means the commit can't be tested, and any other
+    /// code below 128 means bad.
+    pub fn run(&mut self, argv: &[String]) -> Result<ObjectId> {
+        if !self.in_progress() {
+            bail!("You need to start by \"git bisect start\"");
+        }
+        if self.refs.read_ref(BAD_REF).is_none() || self.good_commits()?.is_empty() {
+            bail!("bisect run failed: mark a good and a bad commit first");
+        }
+        let command = sq_quote_argv(argv);
+        loop {
+            writeln!(self.out, "running {}", command)?;
+            self.out.flush()?;
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(&command)
+                .status()
+                .with_context(|| format!("Failed to run {}", command))?;
+            let term = match status.code() {
+                Some(0) => Term::Good,
+                Some(SKIP_CODE) => Term::Skip,
+                Some(code) if code < 128 => Term::Bad,
+                _ => bail!(
+                    "bisect run failed: exit code {:?} from '{}'",
+                    status.code(),
+                    command
+                ),
+            };
+            if let Some(first_bad) = self.mark(term, &[])? {
+                writeln!(self.out, "bisect found first bad commit")?;
+                return Ok(first_bad);
+            }
+        }
+    }
+
+    /// Records the mark of a commit, the command itself is logged by the caller
+    fn mark_commit(&mut self, term: Term, revision: &str) -> Result<ObjectId> {
+        let commit_id = Revision::parse(revision)?.resolve(self.refs, self.database)?;
+        let name = match term {
+            Term::Bad => BAD_REF.to_string(),
+            _ => format!("{}{}-{}", BISECT_REFS, term, commit_id),
+        };
+        let identity = Author::from_env();
+        self.refs
+            .update_ref(&name, commit_id.clone(), &identity, "bisect")?;
+
+        let subject = self.subject(&commit_id)?;
+        self.log(&format!("# {}: [{}] {}", term, commit_id, subject))?;
+        Ok(commit_id)
+    }
+
+    /// Checks out the best commit left to test, or reports the first bad commit when there is
+    /// none left
+    fn next(&mut self) -> Result<Option<ObjectId>> {
+        let bad = self.refs.read_ref(BAD_REF);
+        let good = self.good_commits()?;
+        let bad = match (bad, good.is_empty()) {
+            (Some(bad), false) => bad,
+            (None, true) => {
+                writeln!(self.out, "status: waiting for both good and bad commits")?;
+                return Ok(None);
+            }
+            (Some(_), true) => {
+                writeln!(
+                    self.out,
+                    "status: waiting for good commit(s), bad commit known"
+                )?;
+                return Ok(None);
+            }
+            (None, false) => {
+                writeln!(
+                    self.out,
+                    "status: waiting for bad commit, {} good commit(s) known",
+                    good.len()
+                )?;
+                return Ok(None);
+            }
+        };
+
+        let candidates = self.candidates()?;
+        if candidates.is_empty() {
+            bail!("Some good revs are not ancestors of the bad rev.\ngit bisect cannot work properly in this case.");
+        }
+        let testable = self.testable()?;
+        if testable.is_empty() {
+            let skipped: Vec<&ObjectId> = candidates
+                .iter()
+                .filter(|commit_id| **commit_id != bad)
+                .collect();
+            if skipped.is_empty() {
+                writeln!(self.out, "{} is the first bad commit", bad)?;
+                let commit = self.load_commit(&bad)?;
+                writeln!(
+                    self.out,
+                    "Author: {} <{}>",
+                    commit.author().name,
+                    commit.author().email
+                )?;
+                writeln!(self.out)?;
+                for line in commit.message().lines() {
+                    writeln!(self.out, "    {}", line)?;
+                }
+                return Ok(Some(bad));
+            }
+            writeln!(self.out, "There are only 'skip'ped commits left to test.")?;
+            writeln!(self.out, "The first bad commit could be any of:")?;
+            for commit_id in skipped.into_iter().chain(std::iter::once(&bad)) {
+                writeln!(self.out, "{}", commit_id)?;
+            }
+            bail!("We cannot bisect more!");
+        }
+
+        let best = best_commit(self.database, &candidates, &testable)?;
+        // Whatever the answer, about half of the candidates are left after this step
+        let left = candidates.len() / 2;
+        let steps = (usize::BITS - left.leading_zeros()).saturating_sub(1);
+        writeln!(
+            self.out,
+            "Bisecting: {} revision{} left to test after this (roughly {} step{})",
+            left,
+            if left == 1 { "" } else { "s" },
+            steps,
+            if steps == 1 { "" } else { "s" }
+        )?;
+        writeln!(self.out, "[{}] {}", best, self.subject(&best)?)?;
+
+        self.checkout(&best)?;
+        let identity = Author::from_env();
+        let message = format!("checkout: moving from {} to {}", self.head_label(), best);
+        self.refs.update_ref(HEAD, best, &identity, &message)?;
+        Ok(None)
+    }
+
+    fn good_commits(&self) -> Result<Vec<ObjectId>> {
+        let prefix = format!("{}{}-", BISECT_REFS, Term::Good);
+        Ok(self
+            .refs
+            .list_refs(&prefix)?
+            .into_iter()
+            .map(|(_, commit_id)| commit_id)
+            .collect())
+    }
+
+    /// The commits that may have introduced the bug: reachable from the bad commit but not
+    /// from a good one, the bad commit included
+    fn candidates(&self) -> Result<Vec<ObjectId>> {
+        let bad = self.refs.read_ref(BAD_REF).context("No bad commit")?;
+        rev_list::commits_between(self.database, &self.good_commits()?, &bad)
+    }
+
+    /// The candidates that still need to be tested
+    fn testable(&self) -> Result<Vec<ObjectId>> {
+        let prefix = format!("{}{}-", BISECT_REFS, Term::Skip);
+        let skipped: HashSet<ObjectId> = self
+            .refs
+            .list_refs(&prefix)?
+            .into_iter()
+            .map(|(_, commit_id)| commit_id)
+            .collect();
+        let bad = self.refs.read_ref(BAD_REF);
+        Ok(self
+            .candidates()?
+            .into_iter()
+            .filter(|commit_id| !skipped.contains(commit_id) && Some(commit_id) != bad.as_ref())
+            .collect())
+    }
+
+    fn ensure_clean(&mut self, head_id: &str) -> Result<()> {
+        self.index.load()?;
+        if self.index.tree_items() != self.tree_items(head_id)? {
+            bail!("cannot bisect: Your index contains uncommitted changes.");
+        }
+        for entry in self.index.entries() {
+            if staging::is_modified(self.workspace, self.index, entry.path())? {
+                bail!("cannot bisect: You have unstaged changes.");
+            }
+        }
+        Ok(())
+    }
+
+    fn checkout(&mut self, commit_id: &str) -> Result<()> {
+        let items = self.tree_items(commit_id)?;
+        self.index.load_for_update()?;
+        migration::checkout_entries(self.database, self.workspace, self.index, &items)?;
+        self.index.write_updates()
+    }
+
+    fn tree_items(&self, commit_id: &str) -> Result<BTreeMap<String, TreeItem>> {
+        self.database
+            .load_tree_entries(&self.database.load_tree_id(commit_id)?)
+    }
+
+    /// The branch HEAD is on or the commit it's detached at, for reflog messages
+    fn head_label(&self) -> String {
+        match self.refs.read_symref(HEAD) {
+            Some(name) => name.trim_start_matches("refs/heads/").to_string(),
+            None => self.refs.read_head().unwrap_or_default(),
+        }
+    }
+
+    fn load_commit(&self, commit_id: &str) -> Result<Commit> {
+        let (_, data) = self.database.load(commit_id)?;
+        Commit::parse(&data)
+    }
+
+    fn subject(&self, commit_id: &str) -> Result<String> {
+        let commit = self.load_commit(commit_id)?;
+        Ok(commit
+            .message()
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    fn log(&self, line: &str) -> Result<()> {
+        let mut log = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.git_path.join(BISECT_LOG))?;
+        writeln!(log, "{}", line)?;
+        Ok(())
+    }
+
+    fn read_state(&self, name: &str) -> Result<String> {
+        Ok(fs::read_to_string(self.git_path.join(name))
+            .with_context(|| format!("Failed to read bisect state: {}", name))?
+            .trim()
+            .to_string())
+    }
+}
+
+/// Picks the testable commit whose number of candidate ancestors is the closest to half of
+/// the candidates. Counting ancestors instead of positions in the history keeps the halves
+/// even when merges bring several branches in.
+pub fn best_commit(
+    database: &Database,
+    candidates: &[ObjectId],
+    testable: &[ObjectId],
+) -> Result<ObjectId> {
+    let candidate_set: HashSet<&ObjectId> = candidates.iter().collect();
+    let mut weights: HashMap<&ObjectId, usize> = HashMap::new();
+    for commit_id in testable {
+        let weight = rev_list::ancestors(database, commit_id)?
+            .iter()
+            .filter(|ancestor| candidate_set.contains(ancestor))
+            .count();
+        weights.insert(commit_id, weight);
+    }
+    testable
+        .iter()
+        .max_by_key(|commit_id| {
+            let weight = weights[commit_id];
+            weight.min(candidates.len() - weight)
+        })
+        .cloned()
+        .context("No commit left to test")
+}
+
+/// Quotes every argument for the shell like git's `sq_quote_argv`, so the command runs with
+/// the arguments it was given
+fn sq_quote_argv(argv: &[String]) -> String {
+    argv.iter()
+        .map(|arg| format!("'{}'", arg.replace('\'', "'\\''").replace('!', "'\\!'")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::tree::Tree;
+    use crate::test_utils;
+
+    #[test]
+    fn pick_the_middle_commit() -> Result<()> {
+        let dir = test_utils::temp_path("bisect");
+        let database = Database::new(dir.clone());
+        let tree_id = database.store(&Tree::new())?;
+        let author = test_utils::test_author();
+        let commit = |parents: Vec<&ObjectId>, message: &str| {
+            let parents = parents.into_iter().cloned().collect();
+            let commit = Commit::new(parents, tree_id.clone(), author.clone(), message.into());
+            database.store(&commit).unwrap()
+        };
+
+        //   a - b - c - d - e - f - m
+        //        \                 /
+        //         x - y - z ------
+        let a = commit(vec![], "a");
+        let b = commit(vec![&a], "b");
+        let c = commit(vec![&b], "c");
+        let d = commit(vec![&c], "d");
+        let e = commit(vec![&d], "e");
+        let f = commit(vec![&e], "f");
+        let x = commit(vec![&b], "x");
+        let y = commit(vec![&x], "y");
+        let z = commit(vec![&y], "z");
+        let m = commit(vec![&f, &z], "m");
+
+        let candidates = rev_list::commits_between(&database, std::slice::from_ref(&a), &m)?;
+        assert_eq!(candidates.len(), 9);
+        let testable: Vec<ObjectId> = candidates.iter().filter(|id| **id != m).cloned().collect();
+        // e and z have 4 of the 9 candidates as ancestors, f has the other 5 ones
+        let best = best_commit(&database, &candidates, &testable)?;
+        assert!([&e, &f, &z].contains(&&best));
+
+        // The merge brings x and y in, so y is a better split than c
+        let testable = vec![b, c, y.clone()];
+        assert_eq!(best_commit(&database, &candidates, &testable)?, y);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn quote_run_arguments() -> Result<()> {
+        let argv: Vec<String> = ["sh", "-c", "test \"$0\" = 'a b!'", "a b!"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let command = sq_quote_argv(&argv);
+        assert_eq!(
+            command,
+            r#"'sh' '-c' 'test "$0" = '\''a b'\!''\''' 'a b'\!''"#
+        );
+        let status = Command::new("sh").arg("-c").arg(&command).status()?;
+        assert!(status.success());
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    database::{commit::Commit, Database},
+    diff::{
+        lines,
+        patch::tree_changes,
+        rename::{self, Detection},
+        DiffOptions, Edit,
+    },
+    ObjectId,
+};
+
+/// Moved lines are only followed when the block has at least this many alphanumeric
+/// characters, so that moving braces or blank lines around doesn't hide their author
+const MOVE_SCORE: usize = 20;
+
+/// Where a line of the blamed file was introduced
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlameLine {
+    pub commit_id: ObjectId,
+    /// Path of the file in that commit, it differs from the blamed path when it was renamed
+    pub path: String,
+    /// Number of the line in the file of that commit, starting at 1
+    pub original_line: usize,
+    /// Number of the line in the blamed file, starting at 1
+    pub final_line: usize,
+    /// The line with its newline, which is added when the file doesn't end with one
+    pub content: Vec<u8>,
+}
+
+/// Lines still looking for their origin, as their index in the blamed file and in the
+/// suspected version of the file
+type Suspects = Vec<(usize, usize)>;
+
+/// Attributes the lines of a file to the commits that introduced them by walking the
+/// history and passing every line that is unchanged in a parent on to that parent
+pub struct Blame<'a> {
+    database: &'a Database,
+    options: DiffOptions,
+    /// Follow lines moved within the file, like `-M`
+    find_moves: bool,
+    commits: HashMap<ObjectId, Commit>,
+}
+
+impl<'a> Blame<'a> {
+    pub fn new(database: &'a Database, options: DiffOptions, find_moves: bool) -> Self {
+        Self {
+            database,
+            options,
+            find_moves,
+            commits: HashMap::new(),
+        }
+    }
+
+    /// The commits seen during the walk, loaded once
+    pub fn commit(&mut self, commit_id: &str) -> Result<&Commit> {
+        if !self.commits.contains_key(commit_id) {
+            let (_, data) = self.database.load(commit_id)?;
+            self.commits
+                .insert(commit_id.to_string(), Commit::parse(&data)?);
+        }
+        Ok(&self.commits[commit_id])
+    }
+
+    /// Blames the lines of `path` at `commit_id`, only the lines in the `-L` range when given
+    pub fn blame(
+        &mut self,
+        commit_id: &str,
+        path: &str,
+        range: Option<&str>,
+    ) -> Result<Vec<BlameLine>> {
+        let data = self
+            .file_at(commit_id, path)?
+            .with_context(|| format!("no such path {} in {}", path, commit_id))?;
+        let final_lines = lines(&data);
+        let (start, end) = match range {
+            Some(range) => parse_range(range, final_lines.len())?,
+            None => (1, final_lines.len()),
+        };
+        if end > final_lines.len() {
+            bail!("file {} has only {} lines", path, final_lines.len());
+        }
+
+        let mut blamed: BTreeMap<usize, BlameLine> = BTreeMap::new();
+        // The same commit can be queued several times when it's reached from different
+        // children, its suspects are then all handled the first time it's popped
+        let mut pending: HashMap<(ObjectId, String), Suspects> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        let suspects = (start - 1..end).map(|i| (i, i)).collect();
+        self.enqueue(&mut pending, &mut queue, commit_id, path, suspects)?;
+
+        while let Some((_, commit_id, path)) = queue.pop() {
+            let suspects = match pending.remove(&(commit_id.clone(), path.clone())) {
+                Some(suspects) => suspects,
+                None => continue,
+            };
+            let data = self.file_at(&commit_id, &path)?.unwrap_or_default();
+            // The history of a shallow repository ends at its shallow commits
+            let parents = if self.database.is_shallow(&commit_id) {
+                vec![]
+            } else {
+                self.commit(&commit_id)?.parents().to_vec()
+            };
+
+            let mut remaining = suspects;
+            for parent in parents {
+                if remaining.is_empty() {
+                    break;
+                }
+                let parent_path = match self.path_in_parent(&commit_id, &parent, &path)? {
+                    Some(parent_path) => parent_path,
+                    None => continue,
+                };
+                let parent_data = self.file_at(&parent, &parent_path)?.unwrap_or_default();
+                let (passed, kept) = self.pass_to_parent(&parent_data, &data, remaining);
+                remaining = kept;
+                self.enqueue(&mut pending, &mut queue, &parent, &parent_path, passed)?;
+            }
+
+            for (final_index, index) in remaining {
+                let mut content = final_lines[final_index].to_vec();
+                if !content.ends_with(b"\n") {
+                    content.push(b'\n');
+                }
+                blamed.insert(
+                    final_index,
+                    BlameLine {
+                        commit_id: commit_id.clone(),
+                        path: path.clone(),
+                        original_line: index + 1,
+                        final_line: final_index + 1,
+                        content,
+                    },
+                );
+            }
+        }
+        Ok(blamed.into_values().collect())
+    }
+
+    fn enqueue(
+        &mut self,
+        pending: &mut HashMap<(ObjectId, String), Suspects>,
+        queue: &mut BinaryHeap<(i64, ObjectId, String)>,
+        commit_id: &str,
+        path: &str,
+        suspects: Suspects,
+    ) -> Result<()> {
+        if suspects.is_empty() {
+            return Ok(());
+        }
+        // Newer commits first so that a commit is usually reached by all its children
+        // before it's handled
+        let time = self.commit(commit_id)?.committer().time.timestamp();
+        pending
+            .entry((commit_id.to_string(), path.to_string()))
+            .or_default()
+            .extend(suspects);
+        queue.push((time, commit_id.to_string(), path.to_string()));
+        Ok(())
+    }
+
+    /// Content of the file at `path` in a commit, `None` when it doesn't exist
+    fn file_at(&mut self, commit_id: &str, path: &str) -> Result<Option<Vec<u8>>> {
+        let tree_id = self.commit(commit_id)?.tree_id().clone();
+        match self.database.load_tree_entries(&tree_id)?.get(path) {
+            Some(item) => Ok(Some(self.database.load(&item.object_id)?.1)),
+            None => Ok(None),
+        }
+    }
+
+    /// Path of the file in a parent, following a rename of the whole file
+    fn path_in_parent(
+        &mut self,
+        commit_id: &str,
+        parent: &str,
+        path: &str,
+    ) -> Result<Option<String>> {
+        let tree_id = self.commit(commit_id)?.tree_id().clone();
+        let parent_tree_id = self.commit(parent)?.tree_id().clone();
+        let items = self.database.load_tree_entries(&tree_id)?;
+        let parent_items = self.database.load_tree_entries(&parent_tree_id)?;
+        if parent_items.contains_key(path) {
+            return Ok(Some(path.to_string()));
+        }
+
+        // Only the files added and removed matter to find where the file came from
+        let changes = tree_changes(&parent_items, &items)
+            .into_iter()
+            .filter(|change| change.old.is_none() || change.new.is_none())
+            .collect();
+        let changes = rename::detect(self.database, changes, &Detection::renames())?;
+        Ok(changes
+            .into_iter()
+            .find(|change| change.path == path)
+            .and_then(|change| change.origin)
+            .map(|origin| origin.path))
+    }
+
+    /// Splits the suspects between the lines found unchanged in the parent, with their index
+    /// in the parent, and the lines introduced by the commit
+    fn pass_to_parent(
+        &self,
+        parent_data: &[u8],
+        data: &[u8],
+        suspects: Suspects,
+    ) -> (Suspects, Suspects) {
+        let (a, b) = (lines(parent_data), lines(data));
+        let mut in_parent: HashMap<usize, usize> = HashMap::new();
+        if parent_data == data {
+            in_parent.extend((0..b.len()).map(|j| (j, j)));
+        } else {
+            for edit in self.options.diff_lines(&a, &b) {
+                if let Edit::Equal(i, j) = edit {
+                    in_parent.insert(j, i);
+                }
+            }
+        }
+        if self.find_moves {
+            self.find_moved_lines(&a, &b, &suspects, &mut in_parent);
+        }
+
+        let (passed, kept): (Suspects, Suspects) = suspects
+            .into_iter()
+            .partition(|(_, index)| in_parent.contains_key(index));
+        let passed = passed
+            .into_iter()
+            .map(|(final_index, index)| (final_index, in_parent[&index]))
+            .collect();
+        (passed, kept)
+    }
+
+    /// Looks for the blocks of suspect lines that the diff didn't match anywhere in the
+    /// parent, they were moved there when they are long enough
+    fn find_moved_lines(
+        &self,
+        a: &[&[u8]],
+        b: &[&[u8]],
+        suspects: &Suspects,
+        in_parent: &mut HashMap<usize, usize>,
+    ) {
+        let a: Vec<Vec<u8>> = a.iter().map(|line| self.options.normalize(line)).collect();
+        let b: Vec<Vec<u8>> = b.iter().map(|line| self.options.normalize(line)).collect();
+        let mut unmatched: Vec<usize> = suspects
+            .iter()
+            .map(|(_, index)| *index)
+            .filter(|index| !in_parent.contains_key(index))
+            .collect();
+        unmatched.sort_unstable();
+
+        let mut k = 0;
+        while k < unmatched.len() {
+            let start = unmatched[k];
+            // The block can only extend over consecutive unmatched lines
+            let run = unmatched[k..]
+                .iter()
+                .enumerate()
+                .take_while(|(n, index)| **index == start + n)
+                .count();
+            let best = (0..a.len())
+                .map(|i| {
+                    let len = (0..run.min(a.len() - i))
+                        .take_while(|n| a[i + n] == b[start + n])
+                        .count();
+                    (len, i)
+                })
+                .max_by_key(|(len, i)| (*len, std::cmp::Reverse(*i)));
+
+            match best {
+                Some((len, i))
+                    if len > 0 && alphanumerics(&b[start..start + len]) >= MOVE_SCORE =>
+                {
+                    in_parent.extend((0..len).map(|n| (start + n, i + n)));
+                    k += len;
+                }
+                _ => k += 1,
+            }
+        }
+    }
+}
+
+fn alphanumerics(lines: &[Vec<u8>]) -> usize {
+    lines
+        .iter()
+        .flatten()
+        .filter(|byte| byte.is_ascii_alphanumeric())
+        .count()
+}
+
+/// Parses the `start,end` argument of `-L`, where end can also be `+count` or be left out to
+/// go to the end of the file
+fn parse_range(range: &str, line_count: usize) -> Result<(usize, usize)> {
+    let (start, end) = range.split_once(',').unwrap_or((range, ""));
+    let start: usize = start
+        .parse()
+        .with_context(|| format!("invalid -L range: {}", range))?;
+    let end = match end {
+        "" => line_count,
+        end => match end.strip_prefix('+') {
+            Some(count) => start + count.parse::<usize>()?.max(1) - 1,
+            None => end
+                .parse()
+                .with_context(|| format!("invalid -L range: {}", range))?,
+        },
+    };
+    if start == 0 || end < start {
+        bail!("invalid -L range: {}", range);
+    }
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{blob::Blob, tree::TreeItem};
+    use crate::test_utils;
+
+    /// Stores a commit with a single file, used to build histories in tests
+    fn commit_file(
+        database: &Database,
+        parents: &[ObjectId],
+        path: &str,
+        content: &str,
+    ) -> ObjectId {
+        let blob_id = database.store(&Blob::new(content.into())).unwrap();
+        let mut items = BTreeMap::new();
+        items.insert(
+            path.to_string(),
+            TreeItem {
+                mode: 0o100644,
+                object_id: blob_id,
+            },
+        );
+        let tree_id = database.store_tree(&items).unwrap();
+        let author = test_utils::test_author();
+        let commit = Commit::new(parents.to_vec(), tree_id, author, content.into());
+        database.store(&commit).unwrap()
+    }
+
+    #[test]
+    fn blame_lines() -> Result<()> {
+        let dir = test_utils::temp_path("blame");
+        let database = Database::new(dir.clone());
+
+        let moved = "let moved_function_body = compute();\n";
+        let a = commit_file(&database, &[], "a.rs", &format!("one\ntwo\n{}", moved));
+        let b = commit_file(
+            &database,
+            std::slice::from_ref(&a),
+            "a.rs",
+            &format!("one\nthree\n{}", moved),
+        );
+        let c = commit_file(
+            &database,
+            std::slice::from_ref(&b),
+            "b.rs",
+            &format!("{}one\nthree\n", moved),
+        );
+
+        let origins = |blame: Vec<BlameLine>| -> Vec<(ObjectId, String, usize)> {
+            blame
+                .into_iter()
+                .map(|line| (line.commit_id, line.path, line.original_line))
+                .collect()
+        };
+        let mut blame = Blame::new(&database, DiffOptions::default(), false);
+        assert_eq!(
+            origins(blame.blame(&c, "b.rs", None)?),
+            vec![
+                (c.clone(), "b.rs".into(), 1),
+                (a.clone(), "a.rs".into(), 1),
+                (b.clone(), "a.rs".into(), 2),
+            ]
+        );
+
+        let mut blame = Blame::new(&database, DiffOptions::default(), true);
+        assert_eq!(
+            origins(blame.blame(&c, "b.rs", Some("1,2"))?),
+            vec![(a.clone(), "a.rs".into(), 3), (a, "a.rs".into(), 1)]
+        );
+        let d = commit_file(&database, &[], "c.rs", "first\nno newline at the end");
+        let lines = blame.blame(&d, "c.rs", None)?;
+        let content: Vec<&[u8]> = lines.iter().map(|line| &line.content[..]).collect();
+        assert_eq!(content, vec![&b"first\n"[..], b"no newline at the end\n"]);
+        assert_eq!(parse_range("2,+3", 10)?, (2, 4));
+        assert_eq!(parse_range("4", 10)?, (4, 10));
+        assert!(parse_range("5,2", 10).is_err());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+use std::{fs, io::Write, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::lockfile::Lockfile;
+
+/// A line of the config file, kept as is so saving doesn't lose comments or formatting
+#[derive(Debug, Clone)]
+enum Line {
+    /// `[section]` or `[section "subsection"]`, with the section lowercased
+    Section {
+        name: String,
+        raw: String,
+    },
+    Variable {
+        section: String,
+        key: String,
+        value: String,
+        raw: String,
+    },
+    Other(String),
+}
+
+impl Line {
+    fn raw(&self) -> &str {
+        match self {
+            Line::Section { raw, .. } | Line::Variable { raw, .. } | Line::Other(raw) => raw,
+        }
+    }
+}
+
+/// The `config` file of a repository in git's ini-like format. Variables are named like
+/// `section.key` or `section.subsection.key`, sections and keys are case insensitive.
+pub struct Config {
+    path: PathBuf,
+    lines: Vec<Line>,
+}
+
+/// Splits `section.subsection.key` in the lowercased section with its subsection and the
+/// lowercased key
+fn split_name(name: &str) -> Result<(String, String)> {
+    let (section, key) = name
+        .rsplit_once('.')
+        .with_context(|| format!("key does not contain a section: {}", name))?;
+    if section.is_empty() || key.is_empty() {
+        bail!("invalid key: {}", name);
+    }
+    let section = match section.split_once('.') {
+        Some((section, subsection)) => format!("{}.{}", section.to_lowercase(), subsection),
+        None => section.to_lowercase(),
+    };
+    Ok((section, key.to_lowercase()))
+}
+
+/// Parses a `[section]` header into the normalized section name
+fn parse_section(line: &str) -> Result<String> {
+    let inner = line
+        .trim()
+        .strip_prefix('[')
+        .and_then(|rest| rest.split(']').next())
+        .with_context(|| format!("bad config line: {}", line))?;
+    match inner.split_once(' ') {
+        Some((section, subsection)) => {
+            let subsection = subsection
+                .trim()
+                .strip_prefix('"')
+                .and_then(|rest| rest.strip_suffix('"'))
+                .with_context(|| format!("bad config line: {}", line))?
+                .replace("\\\"", "\"")
+                .replace("\\\\", "\\");
+            Ok(format!("{}.{}", section.to_lowercase(), subsection))
+        }
+        // The deprecated `[section.subsection]` syntax
+        None => Ok(match inner.split_once('.') {
+            Some((section, subsection)) => format!("{}.{}", section.to_lowercase(), subsection),
+            None => inner.to_lowercase(),
+        }),
+    }
+}
+
+/// Parses a value, removing quotes, comments and escapes
+fn parse_value(value: &str) -> Result<String> {
+    let mut result = String::new();
+    let mut in_quotes = false;
+    let mut chars = value.trim().chars();
+    // Whitespace is only kept when something follows it
+    let mut pending_space = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '#' | ';' if !in_quotes => break,
+            '\\' => {
+                let escaped = match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('b') => '\x08',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    other => bail!("bad config value escape: {:?}", other),
+                };
+                result.push_str(&pending_space);
+                pending_space.clear();
+                result.push(escaped);
+            }
+            c if c.is_whitespace() && !in_quotes => pending_space.push(c),
+            c => {
+                result.push_str(&pending_space);
+                pending_space.clear();
+                result.push(c);
+            }
+        }
+    }
+    if in_quotes {
+        bail!("bad config value: {}", value);
+    }
+    Ok(result)
+}
+
+/// Formats a value so that it's read back as is
+fn format_value(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    let needs_quotes = value.starts_with(' ') || value.ends_with(' ') || value.contains(['#', ';']);
+    if needs_quotes {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
+
+impl Config {
+    /// Reads the config file at `path`, a missing file is an empty config
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err).context("Failed to read config"),
+        };
+
+        let mut lines = vec![];
+        let mut section = None;
+        for raw in content.lines() {
+            let trimmed = raw.trim();
+            if trimmed.is_empty() || trimmed.starts_with(['#', ';']) {
+                lines.push(Line::Other(raw.to_string()));
+            } else if trimmed.starts_with('[') {
+                let name = parse_section(trimmed)?;
+                section = Some(name.clone());
+                lines.push(Line::Section {
+                    name,
+                    raw: raw.to_string(),
+                });
+            } else {
+                let section = section
+                    .clone()
+                    .with_context(|| format!("variable outside of a section: {}", raw))?;
+                let (key, value) = match trimmed.split_once('=') {
+                    Some((key, value)) => (key.trim(), parse_value(value)?),
+                    // A key alone is a true boolean
+                    None => (trimmed, "true".to_string()),
+                };
+                lines.push(Line::Variable {
+                    section,
+                    key: key.to_lowercase(),
+                    value,
+                    raw: raw.to_string(),
+                });
+            }
+        }
+        Ok(Self { path, lines })
+    }
+
+    /// Returns the last value of a variable
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.get_all(name).pop()
+    }
+
+    /// Returns every value of a multi-valued variable, in the order they appear
+    pub fn get_all(&self, name: &str) -> Vec<String> {
+        let (section, key) = match split_name(name) {
+            Ok(parts) => parts,
+            Err(_) => return vec![],
+        };
+        self.variables()
+            .filter(|(s, k, _)| **s == section && **k == key)
+            .map(|(_, _, value)| value.clone())
+            .collect()
+    }
+
+    /// Reads a boolean like git does: true, yes, on and 1 or false, no, off and 0
+    pub fn get_bool(&self, name: &str) -> Result<Option<bool>> {
+        match self.get(name).map(|value| value.to_lowercase()).as_deref() {
+            None => Ok(None),
+            Some("true") | Some("yes") | Some("on") | Some("1") => Ok(Some(true)),
+            Some("false") | Some("no") | Some("off") | Some("0") | Some("") => Ok(Some(false)),
+            Some(value) => bail!("bad boolean config value '{}' for '{}'", value, name),
+        }
+    }
+
+    /// Every variable as `(name, value)`, like `config --list`
+    pub fn list(&self) -> Vec<(String, String)> {
+        self.variables()
+            .map(|(section, key, value)| (format!("{}.{}", section, key), value.clone()))
+            .collect()
+    }
+
+    /// Names of the subsections of a section, like the names of the remotes
+    pub fn subsections(&self, section: &str) -> Vec<String> {
+        let prefix = format!("{}.", section.to_lowercase());
+        let mut names: Vec<String> = self
+            .lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Section { name, .. } => name.strip_prefix(&prefix).map(String::from),
+                _ => None,
+            })
+            .collect();
+        names.dedup();
+        names
+    }
+
+    /// Sets a variable, replacing its last value or adding it at the end of its section
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let (section, key) = split_name(name)?;
+        let last = self.lines.iter().rposition(|line| {
+            matches!(line, Line::Variable { section: s, key: k, .. } if *s == section && *k == key)
+        });
+        match last {
+            Some(position) => {
+                self.lines[position] = variable_line(&section, &key, value);
+                Ok(())
+            }
+            None => self.add(name, value),
+        }
+    }
+
+    /// Adds a value to a variable, keeping the existing ones
+    pub fn add(&mut self, name: &str, value: &str) -> Result<()> {
+        let (section, key) = split_name(name)?;
+        let line = variable_line(&section, &key, value);
+        let section_end = self.lines.iter().rposition(|line| match line {
+            Line::Section { name, .. } | Line::Variable { section: name, .. } => *name == section,
+            Line::Other(_) => false,
+        });
+        match section_end {
+            Some(position) => self.lines.insert(position + 1, line),
+            None => {
+                let raw = match section.split_once('.') {
+                    Some((name, subsection)) => format!(
+                        "[{} \"{}\"]",
+                        name,
+                        subsection.replace('\\', "\\\\").replace('"', "\\\"")
+                    ),
+                    None => format!("[{}]", section),
+                };
+                self.lines.push(Line::Section {
+                    name: section.clone(),
+                    raw,
+                });
+                self.lines.push(line);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes every value of a variable, returns false if it wasn't set
+    pub fn unset(&mut self, name: &str) -> Result<bool> {
+        let (section, key) = split_name(name)?;
+        let count = self.lines.len();
+        self.lines.retain(|line| {
+            !matches!(line, Line::Variable { section: s, key: k, .. } if *s == section && *k == key)
+        });
+        Ok(self.lines.len() != count)
+    }
+
+    /// Removes a whole section like `remote.origin` with its variables
+    pub fn remove_section(&mut self, section: &str) -> Result<bool> {
+        let section = match section.split_once('.') {
+            Some((name, subsection)) => format!("{}.{}", name.to_lowercase(), subsection),
+            None => section.to_lowercase(),
+        };
+        let count = self.lines.len();
+        self.lines.retain(|line| match line {
+            Line::Section { name, .. } | Line::Variable { section: name, .. } => *name != section,
+            Line::Other(_) => true,
+        });
+        Ok(self.lines.len() != count)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut lockfile = Lockfile::new(&self.path);
+        lockfile
+            .hold_for_update()
+            .with_context(|| format!("Could not acquire lock on file: {}", self.path.display()))?;
+        for line in &self.lines {
+            writeln!(lockfile, "{}", line.raw())?;
+        }
+        lockfile.commit()
+    }
+
+    fn variables(&self) -> impl Iterator<Item = (&String, &String, &String)> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Variable {
+                section,
+                key,
+                value,
+                ..
+            } => Some((section, key, value)),
+            _ => None,
+        })
+    }
+}
+
+fn variable_line(section: &str, key: &str, value: &str) -> Line {
+    Line::Variable {
+        section: section.to_string(),
+        key: key.to_string(),
+        value: value.to_string(),
+        raw: format!("\t{} = {}", key, format_value(value)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn read_and_write_config() -> Result<()> {
+        let path = test_utils::temp_path("config");
+        fs::write(
+            &path,
+            "# comment\n[core]\n\tbare = false\n\tFileMode\n[remote \"origin\"]\n\turl = \"/tmp/a b\" ; comment\n\tfetch = +refs/heads/*:refs/remotes/origin/*\n",
+        )?;
+
+        let mut config = Config::load(path.clone())?;
+        assert_eq!(config.get_bool("core.bare")?, Some(false));
+        assert_eq!(config.get_bool("core.filemode")?, Some(true));
+        assert_eq!(config.get("remote.origin.url").as_deref(), Some("/tmp/a b"));
+        assert_eq!(config.get("remote.ORIGIN.url"), None);
+        assert_eq!(config.subsections("remote"), vec!["origin"]);
+
+        config.set("diff.algorithm", "patience")?;
+        config.add("remote.origin.fetch", "+refs/tags/*:refs/tags/*")?;
+        config.set("core.bare", "true")?;
+        config.save()?;
+
+        let config = Config::load(path.clone())?;
+        assert_eq!(config.get("diff.algorithm").as_deref(), Some("patience"));
+        assert_eq!(config.get_all("remote.origin.fetch").len(), 2);
+        assert_eq!(config.get_bool("core.bare")?, Some(true));
+        assert!(fs::read_to_string(&path)?.starts_with("# comment\n[core]\n\tbare = true\n"));
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -1,26 +1,85 @@
+use anyhow::{Context, Result};
+
 use crate::{database::Object, Author, ObjectId};
 
 pub struct Commit {
-    parent: Option<ObjectId>,
+    parents: Vec<ObjectId>,
     tree_id: ObjectId,
     author: Author,
+    committer: Author,
     message: String,
 }
 
 impl Commit {
-    pub fn new(
-        parent: Option<ObjectId>,
-        tree_id: ObjectId,
-        author: Author,
-        message: String,
-    ) -> Self {
+    pub fn new(parents: Vec<ObjectId>, tree_id: ObjectId, author: Author, message: String) -> Self {
         Self {
-            parent,
+            parents,
             tree_id,
+            committer: author.clone(),
             author,
             message,
         }
     }
+
+    /// Sets a committer different from the author, like when a commit is replayed
+    pub fn with_committer(mut self, committer: Author) -> Self {
+        self.committer = committer;
+        self
+    }
+
+    /// Parses the content of a commit object, the committer defaults to the author
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let data = std::str::from_utf8(data)?;
+        let (headers, message) = data.split_once("\n\n").unwrap_or((data, ""));
+
+        let mut parents = vec![];
+        let mut tree_id = None;
+        let mut author: Option<Author> = None;
+        let mut committer = None;
+        for line in headers.lines() {
+            match line.split_once(' ') {
+                Some(("tree", value)) => tree_id = Some(value.to_string()),
+                Some(("parent", value)) => parents.push(value.to_string()),
+                Some(("author", value)) => author = Some(value.parse()?),
+                Some(("committer", value)) => committer = Some(value.parse()?),
+                _ => (),
+            }
+        }
+
+        let author = author.context("Commit is missing its author")?;
+        Ok(Self {
+            parents,
+            tree_id: tree_id.context("Commit is missing its tree")?,
+            committer: committer.unwrap_or_else(|| author.clone()),
+            author,
+            message: message.to_string(),
+        })
+    }
+
+    /// Returns the first parent of the commit
+    pub fn parent(&self) -> Option<&ObjectId> {
+        self.parents.first()
+    }
+
+    pub fn parents(&self) -> &[ObjectId] {
+        &self.parents
+    }
+
+    pub fn tree_id(&self) -> &ObjectId {
+        &self.tree_id
+    }
+
+    pub fn author(&self) -> &Author {
+        &self.author
+    }
+
+    pub fn committer(&self) -> &Author {
+        &self.committer
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
 }
 
 impl Object for Commit {
@@ -30,11 +89,11 @@ impl Object for Commit {
 
     fn serialize_data(&self) -> Vec<u8> {
         let mut lines = vec![format!("tree {}", self.tree_id)];
-        if let Some(parent_id) = &self.parent {
+        for parent_id in &self.parents {
             lines.push(format!("parent {}", parent_id));
         }
         lines.push(format!("author {}", self.author));
-        lines.push(format!("committer {}", self.author));
+        lines.push(format!("committer {}", self.committer));
         lines.push("".into());
         lines.push(self.message.clone());
 
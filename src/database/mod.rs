@@ -1,22 +1,37 @@
 pub mod blob;
 pub mod commit;
+pub mod pack;
+pub mod tag;
 pub mod tree;
 
 use std::{
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet},
     fs::{self, File},
-    io::Write,
+    io::{ErrorKind, Read, Write},
     iter::repeat_with,
     path::PathBuf,
+    sync::{Mutex, RwLock},
 };
 
-use anyhow::Result;
-use flate2::{write::ZlibEncoder, Compression};
+use anyhow::{bail, Context, Result};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
 
-use crate::{hash, ObjectId};
+use crate::{hash, lockfile::Lockfile, remote::promisor, ObjectId};
+
+use tree::TreeItem;
 
 pub const MODE_REGULAR: i32 = 0o100644;
-pub const MODE_EXECUTABLE: i32 = 0o100644;
-pub const MODE_DIRECTORY: i32 = 0o100644;
+pub const MODE_EXECUTABLE: i32 = 0o100755;
+pub const MODE_DIRECTORY: i32 = 0o40000;
+
+/// Lists the shallow commits, it's in the git directory next to the objects
+const SHALLOW: &str = "shallow";
+
+thread_local! {
+    /// Set while the thread fetches missing objects, the fetch itself can't start another one
+    static IS_FETCHING: Cell<bool> = const { Cell::new(false) };
+}
 
 pub trait Object {
     fn serialize_type(&self) -> &str;
@@ -40,11 +55,27 @@ pub trait Object {
 
 pub struct Database {
     path: PathBuf,
+    /// Commits of a shallow repository whose parents are missing
+    shallow: RwLock<BTreeSet<ObjectId>>,
+    /// Held while missing objects are fetched so threads don't fetch them twice
+    fetch_lock: Mutex<()>,
+    /// Objects that only live in memory, like the workspace files a diff compares
+    pretended: RwLock<BTreeMap<ObjectId, (String, Vec<u8>)>>,
 }
 
 impl Database {
     pub fn new(path: PathBuf) -> Self {
-        Self { path }
+        let shallow = path
+            .parent()
+            .and_then(|git_path| fs::read_to_string(git_path.join(SHALLOW)).ok())
+            .map(|content| content.lines().map(String::from).collect())
+            .unwrap_or_default();
+        Self {
+            path,
+            shallow: RwLock::new(shallow),
+            fetch_lock: Mutex::new(()),
+            pretended: RwLock::new(BTreeMap::new()),
+        }
     }
 
     pub fn store<O>(&self, object: &O) -> Result<ObjectId>
@@ -58,10 +89,250 @@ impl Database {
         Ok(object_id)
     }
 
+    /// Computes the id of an object and keeps it in memory instead of writing it, `load` finds
+    /// it until the database is dropped
+    pub fn pretend<O>(&self, object: &O) -> ObjectId
+    where
+        O: Object,
+    {
+        let object_id = object.object_id();
+        let mut pretended = self
+            .pretended
+            .write()
+            .expect("Failed to write pretended objects");
+        pretended
+            .entry(object_id.clone())
+            .or_insert_with(|| (object.serialize_type().to_string(), object.serialize_data()));
+        object_id
+    }
+
+    /// Stores an object read from another repository, given its type and content
+    pub fn store_raw(&self, kind: &str, data: &[u8]) -> Result<ObjectId> {
+        let mut content = format!("{} {}\0", kind, data.len()).into_bytes();
+        content.extend_from_slice(data);
+        let object_id = hash(&content);
+        self.write(&object_id, content)?;
+        Ok(object_id)
+    }
+
+    /// Stores the trees needed to hold the given entries and returns the id of the root tree
+    pub fn store_tree(&self, items: &BTreeMap<String, TreeItem>) -> Result<ObjectId> {
+        let entries: Vec<tree::Entry> = items
+            .iter()
+            .map(|(path, item)| {
+                tree::Entry::new(path.into(), item.object_id.clone(), item.mode as i32)
+            })
+            .collect();
+        let tree = tree::build(&entries);
+        Ok(tree.traverse(&|tree| self.store(tree).expect("Failed while saving tree")))
+    }
+
+    /// Reads the object with the given id and returns its type and content. A partial clone
+    /// fetches the object from its promisor remote when it's missing.
+    pub fn load(&self, object_id: &str) -> Result<(String, Vec<u8>)> {
+        if let Some(object) = self
+            .pretended
+            .read()
+            .expect("Failed to read pretended objects")
+            .get(object_id)
+        {
+            return Ok(object.clone());
+        }
+        let object_path = self.object_path(object_id);
+        let not_found = || format!("Object {} not found", object_id);
+        let compressed = match fs::read(&object_path) {
+            Ok(compressed) => compressed,
+            Err(err)
+                if err.kind() == ErrorKind::NotFound
+                    && self
+                        .fetch_missing(&[object_id.to_string()])
+                        .with_context(not_found)? =>
+            {
+                fs::read(&object_path).with_context(not_found)?
+            }
+            Err(err) => return Err(err).with_context(not_found),
+        };
+
+        let mut content = vec![];
+        ZlibDecoder::new(&compressed[..]).read_to_end(&mut content)?;
+
+        let header_end = content
+            .iter()
+            .position(|b| *b == 0)
+            .with_context(|| format!("Invalid object header for {}", object_id))?;
+        let header = std::str::from_utf8(&content[..header_end])?;
+        let kind = header
+            .split(' ')
+            .next()
+            .with_context(|| format!("Invalid object header for {}", object_id))?;
+
+        Ok((kind.to_string(), content[header_end + 1..].to_vec()))
+    }
+
+    /// Follows annotated tags until reaching an object that isn't a tag
+    pub fn peel(&self, object_id: &str) -> Result<ObjectId> {
+        let mut object_id = object_id.to_string();
+        loop {
+            let (kind, data) = self.load(&object_id)?;
+            if kind != "tag" {
+                return Ok(object_id);
+            }
+            object_id = tag::Tag::parse(&data)?.object;
+        }
+    }
+
+    /// Recursively lists the blobs of a tree keyed by their path relative to the tree
+    pub fn load_tree_entries(&self, tree_id: &str) -> Result<BTreeMap<String, TreeItem>> {
+        let mut entries = BTreeMap::new();
+        self.collect_tree_entries(tree_id, "", &mut entries)?;
+        Ok(entries)
+    }
+
+    fn collect_tree_entries(
+        &self,
+        tree_id: &str,
+        prefix: &str,
+        entries: &mut BTreeMap<String, TreeItem>,
+    ) -> Result<()> {
+        let (kind, data) = self.load(tree_id)?;
+        if kind != "tree" {
+            bail!("Object {} is a {}, not a tree", tree_id, kind);
+        }
+        for (name, item) in tree::parse(&data)? {
+            let path = format!("{}{}", prefix, name);
+            if item.mode == MODE_DIRECTORY as u32 {
+                self.collect_tree_entries(&item.object_id, &format!("{}/", path), entries)?;
+            } else {
+                entries.insert(path, item);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the tree of the given commit or tag, or the object itself if it's a tree
+    pub fn load_tree_id(&self, object_id: &str) -> Result<ObjectId> {
+        let object_id = self.peel(object_id)?;
+        let (kind, data) = self.load(&object_id)?;
+        match kind.as_str() {
+            "tree" => Ok(object_id),
+            "commit" => Ok(commit::Commit::parse(&data)?.tree_id().clone()),
+            _ => bail!("Object {} is a {}, not a tree-ish", object_id, kind),
+        }
+    }
+
+    /// Finds every object whose id starts with the given prefix
+    pub fn prefix_match(&self, prefix: &str) -> Result<Vec<ObjectId>> {
+        if prefix.len() < 4 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(vec![]);
+        }
+        let dir = self.path.join(&prefix[..2]);
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut object_ids = vec![];
+        for file in fs::read_dir(dir)? {
+            let name = file?.file_name().to_string_lossy().to_string();
+            let object_id = format!("{}{}", &prefix[..2], name);
+            if object_id.len() == 40 && object_id.starts_with(prefix) {
+                object_ids.push(object_id);
+            }
+        }
+        object_ids.sort();
+        Ok(object_ids)
+    }
+
+    /// The commits whose history is cut in a shallow repository, it's empty otherwise
+    pub fn shallow(&self) -> Vec<ObjectId> {
+        let shallow = self.shallow.read().expect("Failed to read shallow commits");
+        shallow.iter().cloned().collect()
+    }
+
+    /// A shallow commit is a root of the history even if it has parents
+    pub fn is_shallow(&self, commit_id: &str) -> bool {
+        let shallow = self.shallow.read().expect("Failed to read shallow commits");
+        shallow.contains(commit_id)
+    }
+
+    /// Records that the parents of `added` are missing and those of `removed` were fetched.
+    /// The `shallow` file is removed once the whole history is there.
+    pub fn update_shallow(&self, added: &[ObjectId], removed: &[ObjectId]) -> Result<()> {
+        let mut shallow = self
+            .shallow
+            .write()
+            .expect("Failed to write shallow commits");
+        let old = shallow.clone();
+        shallow.extend(added.iter().cloned());
+        for commit_id in removed {
+            shallow.remove(commit_id);
+        }
+        if *shallow == old {
+            return Ok(());
+        }
+
+        let path = self
+            .path
+            .parent()
+            .context("The objects have no git directory")?
+            .join(SHALLOW);
+        if shallow.is_empty() {
+            fs::remove_file(path)?;
+            return Ok(());
+        }
+        let mut lockfile = Lockfile::new(&path);
+        lockfile
+            .hold_for_update()
+            .with_context(|| format!("Could not acquire lock on file: {}", path.display()))?;
+        for commit_id in shallow.iter() {
+            writeln!(lockfile, "{}", commit_id)?;
+        }
+        lockfile.commit()
+    }
+
+    /// Fetches the objects a partial clone is missing in a single request, rather than one at
+    /// a time as they're loaded
+    pub fn prefetch(&self, object_ids: &[ObjectId]) -> Result<()> {
+        if object_ids.iter().any(|object_id| !self.exists(object_id)) {
+            self.fetch_missing(object_ids)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `false` when the objects can't be fetched, the repository isn't a partial
+    /// clone or this thread is already fetching
+    fn fetch_missing(&self, object_ids: &[ObjectId]) -> Result<bool> {
+        let git_path = match self.path.parent() {
+            Some(git_path) if !IS_FETCHING.with(Cell::get) => git_path,
+            _ => return Ok(false),
+        };
+        let _lock = self.fetch_lock.lock().expect("Failed to lock the fetch");
+        // Another thread may have fetched them in the meantime
+        let missing: Vec<ObjectId> = object_ids
+            .iter()
+            .filter(|object_id| !self.exists(object_id))
+            .cloned()
+            .collect();
+        if missing.is_empty() {
+            return Ok(true);
+        }
+        IS_FETCHING.with(|is_fetching| is_fetching.set(true));
+        let fetched = promisor::fetch_missing(git_path, self, &missing);
+        IS_FETCHING.with(|is_fetching| is_fetching.set(false));
+        fetched
+    }
+
+    pub fn exists(&self, object_id: &str) -> bool {
+        self.object_path(object_id).exists()
+    }
+
+    fn object_path(&self, object_id: &str) -> PathBuf {
+        self.path.join(&object_id[..2]).join(&object_id[2..])
+    }
+
     /// Writes the given object to the file system
     // TODO should this use the lockfile?
     pub fn write(&self, object_id: &str, content: Vec<u8>) -> Result<()> {
-        let object_path = self.path.join(&object_id[..2]).join(&object_id[2..]);
+        let object_path = self.object_path(object_id);
         if object_path.exists() {
             return Ok(());
         }
@@ -72,7 +343,7 @@ impl Database {
         encoder.write_all(&content)?;
         let compressed = &encoder.finish()?;
 
-        fs::create_dir_all(&dirname)?;
+        fs::create_dir_all(dirname)?;
         File::create(&temp_path)?.write_all(compressed)?;
 
         log::trace!("Writing {}", object_path.display());
@@ -87,3 +358,26 @@ fn generate_temp_name() -> String {
     let s: String = repeat_with(fastrand::alphanumeric).take(6).collect();
     format!("tmp_obj_#{}", s)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils;
+    use blob::Blob;
+
+    #[test]
+    fn pretend_objects() -> Result<()> {
+        let dir = test_utils::temp_path("database");
+        let database = Database::new(dir.clone());
+
+        let blob = Blob::new(b"only in memory".to_vec());
+        let object_id = database.pretend(&blob);
+        assert_eq!(object_id, blob.object_id());
+        assert_eq!(
+            database.load(&object_id)?,
+            ("blob".to_string(), b"only in memory".to_vec())
+        );
+        assert!(!dir.exists());
+        Ok(())
+    }
+}
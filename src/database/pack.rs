@@ -0,0 +1,421 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, Read, Write},
+};
+
+use anyhow::{bail, Context, Result};
+use flate2::{bufread::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use super::Database;
+use crate::{
+    utils::{deserialize_hash, serialize_hash},
+    HashWriter, ObjectId,
+};
+
+const SIGNATURE: &[u8] = b"PACK";
+const VERSION: u32 = 2;
+
+const COMMIT: u8 = 1;
+const TREE: u8 = 2;
+const BLOB: u8 = 3;
+const TAG: u8 = 4;
+const OFS_DELTA: u8 = 6;
+const REF_DELTA: u8 = 7;
+
+fn type_code(kind: &str) -> Result<u8> {
+    Ok(match kind {
+        "commit" => COMMIT,
+        "tree" => TREE,
+        "blob" => BLOB,
+        "tag" => TAG,
+        _ => bail!("Unknown object type {}", kind),
+    })
+}
+
+fn type_name(code: u8) -> Result<&'static str> {
+    Ok(match code {
+        COMMIT => "commit",
+        TREE => "tree",
+        BLOB => "blob",
+        TAG => "tag",
+        _ => bail!("Unknown pack object type {}", code),
+    })
+}
+
+/// Writes the given objects as a pack, every object is stored whole without deltas
+pub fn write_pack(database: &Database, objects: &[ObjectId], out: &mut dyn Write) -> Result<()> {
+    let mut hasher = HashWriter::new();
+    let mut header = SIGNATURE.to_vec();
+    header.extend_from_slice(&VERSION.to_be_bytes());
+    header.extend_from_slice(&(objects.len() as u32).to_be_bytes());
+    hasher.write(&header);
+    out.write_all(&header)?;
+
+    for object_id in objects {
+        let (kind, data) = database.load(object_id)?;
+        let mut entry = object_header(type_code(&kind)?, data.len());
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&data)?;
+        entry.extend_from_slice(&encoder.finish()?);
+        hasher.write(&entry);
+        out.write_all(&entry)?;
+    }
+    out.write_all(&serialize_hash(&hasher.finish())?)?;
+    Ok(())
+}
+
+/// The type and the size of an object, the size is split in 7 bits groups after the first 4
+fn object_header(code: u8, size: usize) -> Vec<u8> {
+    let mut header = vec![(code << 4) | (size & 0x0f) as u8];
+    let mut size = size >> 4;
+    while size > 0 {
+        *header.last_mut().expect("header is never empty") |= 0x80;
+        header.push((size & 0x7f) as u8);
+        size >>= 7;
+    }
+    header
+}
+
+/// Where the base of a delta is
+enum Base {
+    /// The object at this position in the pack
+    Offset(u64),
+    /// Any object, in the pack or already in the database for thin packs
+    Id(ObjectId),
+}
+
+/// Reads a pack and stores its objects in the database, resolving the deltas. Returns the
+/// ids of the stored objects.
+pub fn read_pack(database: &Database, input: &mut dyn Read) -> Result<Vec<ObjectId>> {
+    let mut reader = PackReader::new(input);
+    let mut header = [0; 12];
+    reader
+        .read_exact(&mut header)
+        .context("Truncated pack header")?;
+    if &header[..4] != SIGNATURE {
+        bail!("Invalid pack signature");
+    }
+    let version = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    if version != 2 && version != 3 {
+        bail!("Unsupported pack version {}", version);
+    }
+    let count = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+
+    let mut stored = vec![];
+    let mut by_offset: HashMap<u64, ObjectId> = HashMap::new();
+    let mut deltas = vec![];
+    for _ in 0..count {
+        let offset = reader.offset;
+        let (code, size) = read_object_header(&mut reader)?;
+        let base = match code {
+            OFS_DELTA => Some(Base::Offset(
+                offset
+                    .checked_sub(read_base_offset(&mut reader)?)
+                    .context("Delta base offset outside of the pack")?,
+            )),
+            REF_DELTA => {
+                let mut base_id = [0; 20];
+                reader.read_exact(&mut base_id)?;
+                Some(Base::Id(deserialize_hash(&base_id)))
+            }
+            _ => None,
+        };
+        // The sizes come from the sender, nothing is allocated or inflated past them
+        let mut data = vec![];
+        ZlibDecoder::new(&mut reader)
+            .take(size as u64 + 1)
+            .read_to_end(&mut data)?;
+        if data.len() != size {
+            bail!("Pack object at offset {} has the wrong size", offset);
+        }
+        match base {
+            Some(base) => deltas.push((offset, base, data)),
+            None => {
+                let object_id = database.store_raw(type_name(code)?, &data)?;
+                by_offset.insert(offset, object_id.clone());
+                stored.push(object_id);
+            }
+        }
+    }
+    reader.check_trailer()?;
+
+    // A delta can come before its base or be the base of another delta, so they are resolved
+    // until none is left
+    while !deltas.is_empty() {
+        let remaining_before = deltas.len();
+        let mut remaining = vec![];
+        for (offset, base, delta) in deltas {
+            let base_id = match &base {
+                Base::Offset(base_offset) => by_offset.get(base_offset).cloned(),
+                Base::Id(base_id) => Some(base_id.clone()).filter(|id| database.exists(id)),
+            };
+            match base_id {
+                Some(base_id) => {
+                    let (kind, base_data) = database.load(&base_id)?;
+                    let object_id = database.store_raw(&kind, &apply_delta(&base_data, &delta)?)?;
+                    by_offset.insert(offset, object_id.clone());
+                    stored.push(object_id);
+                }
+                None => remaining.push((offset, base, delta)),
+            }
+        }
+        if remaining.len() == remaining_before {
+            bail!("Pack has {} deltas without a base", remaining.len());
+        }
+        deltas = remaining;
+    }
+    Ok(stored)
+}
+
+fn read_byte(reader: &mut dyn Read) -> Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte).context("Truncated pack")?;
+    Ok(byte[0])
+}
+
+fn read_object_header(reader: &mut dyn Read) -> Result<(u8, usize)> {
+    let mut byte = read_byte(reader)?;
+    let code = (byte >> 4) & 0x07;
+    let mut size = (byte & 0x0f) as usize;
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        if shift >= usize::BITS {
+            bail!("Pack object size is too large");
+        }
+        byte = read_byte(reader)?;
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+    }
+    Ok((code, size))
+}
+
+/// The distance back to the base of an offset delta, every continuation adds one so that
+/// no two encodings give the same number
+fn read_base_offset(reader: &mut dyn Read) -> Result<u64> {
+    let mut byte = read_byte(reader)?;
+    let mut offset = (byte & 0x7f) as u64;
+    while byte & 0x80 != 0 {
+        byte = read_byte(reader)?;
+        let next = offset
+            .checked_add(1)
+            .filter(|next| next.leading_zeros() >= 7)
+            .context("Delta base offset is too large")?;
+        offset = (next << 7) | (byte & 0x7f) as u64;
+    }
+    Ok(offset)
+}
+
+/// Rebuilds an object from its base and a delta made of instructions copying ranges of the
+/// base and inserting new data
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut i = 0;
+    let base_size = read_delta_size(delta, &mut i)?;
+    if base_size != base.len() {
+        bail!("Delta base has the wrong size");
+    }
+    let size = read_delta_size(delta, &mut i)?;
+
+    let mut result = vec![];
+    while i < delta.len() {
+        let instruction = delta[i];
+        i += 1;
+        if instruction & 0x80 != 0 {
+            let mut values = [0usize; 2];
+            for bit in 0..7 {
+                if instruction & (1 << bit) != 0 {
+                    let byte = *delta.get(i).context("Truncated delta")? as usize;
+                    let (value, shift) = if bit < 4 { (0, bit) } else { (1, bit - 4) };
+                    values[value] |= byte << (8 * shift);
+                    i += 1;
+                }
+            }
+            let [offset, len] = values;
+            let len = if len == 0 { 0x10000 } else { len };
+            let copied = base
+                .get(offset..offset + len)
+                .context("Delta copies outside of its base")?;
+            result.extend_from_slice(copied);
+            if result.len() > size {
+                bail!("Delta result has the wrong size");
+            }
+        } else if instruction != 0 {
+            let inserted = delta
+                .get(i..i + instruction as usize)
+                .context("Truncated delta")?;
+            result.extend_from_slice(inserted);
+            i += instruction as usize;
+        } else {
+            bail!("Invalid delta instruction");
+        }
+    }
+    if result.len() != size {
+        bail!("Delta result has the wrong size");
+    }
+    Ok(result)
+}
+
+fn read_delta_size(delta: &[u8], i: &mut usize) -> Result<usize> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        if shift >= usize::BITS {
+            bail!("Delta size is too large");
+        }
+        let byte = *delta.get(*i).context("Truncated delta")?;
+        *i += 1;
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+/// Buffers the pack and hashes what is consumed, the zlib streams of the objects aren't
+/// delimited so the decoder must only consume what it uses
+struct PackReader<'a> {
+    input: &'a mut dyn Read,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    /// Number of bytes consumed since the start of the pack
+    offset: u64,
+    hasher: HashWriter,
+}
+
+impl<'a> PackReader<'a> {
+    fn new(input: &'a mut dyn Read) -> Self {
+        Self {
+            input,
+            buffer: vec![0; 8192],
+            start: 0,
+            end: 0,
+            offset: 0,
+            hasher: HashWriter::new(),
+        }
+    }
+
+    /// Compares the checksum at the end of the pack with the hash of what was read
+    fn check_trailer(&mut self) -> Result<()> {
+        let checksum = self.hasher.finish();
+        let mut trailer = vec![];
+        while trailer.len() < 20 {
+            if self.start == self.end {
+                self.end = self.input.read(&mut self.buffer)?;
+                self.start = 0;
+                if self.end == 0 {
+                    bail!("Truncated pack checksum");
+                }
+            }
+            let len = (20 - trailer.len()).min(self.end - self.start);
+            trailer.extend_from_slice(&self.buffer[self.start..self.start + len]);
+            self.start += len;
+        }
+        if deserialize_hash(&trailer) != checksum {
+            bail!("Pack checksum mismatch");
+        }
+        Ok(())
+    }
+}
+
+impl Read for PackReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl BufRead for PackReader<'_> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.start == self.end {
+            self.end = self.input.read(&mut self.buffer)?;
+            self.start = 0;
+        }
+        Ok(&self.buffer[self.start..self.end])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.hasher
+            .write(&self.buffer[self.start..self.start + amt]);
+        self.start += amt;
+        self.offset += amt as u64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::blob::Blob;
+    use crate::test_utils;
+
+    #[test]
+    fn pack_round_trip() -> Result<()> {
+        let dir = test_utils::temp_path("pack");
+        let source = Database::new(dir.join("source"));
+        let target = Database::new(dir.join("target"));
+
+        let content = "hello world\n".repeat(4);
+        let base_id = source.store(&Blob::new(content.clone().into()))?;
+        let mut pack = vec![];
+        write_pack(&source, std::slice::from_ref(&base_id), &mut pack)?;
+
+        // Append an offset delta copying the base and inserting a line, then fix the count
+        // and the checksum
+        let base_size = content.len() as u8;
+        let delta = [
+            &[base_size, base_size + 4, 0x90, base_size, 4][..],
+            b"end\n",
+        ]
+        .concat();
+        pack.truncate(pack.len() - 20);
+        let delta_offset = pack.len() as u8;
+        pack.extend(object_header(OFS_DELTA, delta.len()));
+        pack.push(delta_offset - 12);
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&delta)?;
+        pack.extend(encoder.finish()?);
+        pack[11] = 2;
+        let mut hasher = HashWriter::new();
+        hasher.write(&pack);
+        pack.extend(serialize_hash(&hasher.finish())?);
+
+        let stored = read_pack(&target, &mut &pack[..])?;
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0], base_id);
+        let (kind, data) = target.load(&stored[1])?;
+        assert_eq!(kind, "blob");
+        assert_eq!(data, format!("{}end\n", content).into_bytes());
+
+        pack[20] ^= 1;
+        assert!(read_pack(&target, &mut &pack[..]).is_err());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_oversized_sizes() -> Result<()> {
+        let dir = test_utils::temp_path("pack");
+        let database = Database::new(dir.clone());
+        let header = |count: u8| [SIGNATURE, &[0, 0, 0, 2, 0, 0, 0, count]].concat();
+
+        // A size that never ends would overflow the shift
+        let pack = [header(1), vec![0xff; 32]].concat();
+        assert!(read_pack(&database, &mut &pack[..]).is_err());
+
+        // A huge declared size isn't allocated up front
+        let mut pack = [header(1), object_header(3, 1 << 40)].concat();
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"small")?;
+        pack.extend(encoder.finish()?);
+        assert!(read_pack(&database, &mut &pack[..]).is_err());
+
+        assert!(apply_delta(b"", &[0x80; 32]).is_err());
+        assert!(apply_delta(b"", &[0, 0xff, 0xff, 0xff, 0xff, 0xff, 0x0f]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::{Context, Result};
+
+use crate::{database::Object, Author, ObjectId};
+
+/// An annotated tag
+pub struct Tag {
+    pub object: ObjectId,
+    pub kind: String,
+    pub name: String,
+    pub tagger: Author,
+    pub message: String,
+}
+
+impl Tag {
+    pub fn new(
+        object: ObjectId,
+        kind: String,
+        name: String,
+        tagger: Author,
+        message: String,
+    ) -> Self {
+        Self {
+            object,
+            kind,
+            name,
+            tagger,
+            message,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let data = std::str::from_utf8(data)?;
+        let (headers, message) = data.split_once("\n\n").unwrap_or((data, ""));
+
+        let mut object = None;
+        let mut kind = None;
+        let mut name = None;
+        let mut tagger = None;
+        for line in headers.lines() {
+            match line.split_once(' ') {
+                Some(("object", value)) => object = Some(value.to_string()),
+                Some(("type", value)) => kind = Some(value.to_string()),
+                Some(("tag", value)) => name = Some(value.to_string()),
+                Some(("tagger", value)) => tagger = Some(value.parse()?),
+                _ => (),
+            }
+        }
+
+        Ok(Self {
+            object: object.context("Tag is missing its object")?,
+            kind: kind.context("Tag is missing its type")?,
+            name: name.context("Tag is missing its name")?,
+            tagger: tagger.context("Tag is missing its tagger")?,
+            message: message.to_string(),
+        })
+    }
+}
+
+impl Object for Tag {
+    fn serialize_type(&self) -> &str {
+        "tag"
+    }
+
+    fn serialize_data(&self) -> Vec<u8> {
+        let mut lines = vec![
+            format!("object {}", self.object),
+            format!("type {}", self.kind),
+            format!("tag {}", self.name),
+            format!("tagger {}", self.tagger),
+        ];
+        lines.push("".into());
+        lines.push(self.message.clone());
+
+        let mut data = lines.join("\n");
+        if !data.ends_with('\n') {
+            data.push('\n');
+        }
+        data.as_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let tagger = Author {
+            time: Utc.timestamp_opt(1_600_000_000, 0).unwrap().into(),
+            ..test_utils::test_author()
+        };
+        let tag = Tag::new(
+            "a".repeat(40),
+            "commit".into(),
+            "v1.0".into(),
+            tagger,
+            "Release 1.0".into(),
+        );
+
+        let data = tag.serialize_data();
+        assert_eq!(
+            std::str::from_utf8(&data)?,
+            format!(
+                "object {}\ntype commit\ntag v1.0\ntagger A U Thor <author@example.com> 1600000000 +0000\n\nRelease 1.0\n",
+                "a".repeat(40)
+            )
+        );
+
+        let parsed = Tag::parse(&data)?;
+        assert_eq!(parsed.object, tag.object);
+        assert_eq!(parsed.name, "v1.0");
+        assert_eq!(parsed.tagger.time, tag.tagger.time);
+        assert_eq!(parsed.message, "Release 1.0\n");
+        Ok(())
+    }
+}
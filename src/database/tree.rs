@@ -1,18 +1,18 @@
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     ffi::{OsStr, OsString},
     path::PathBuf,
 };
 
-use is_executable::IsExecutable;
+use anyhow::{Context, Result};
 
 use crate::{
     database::{self, Object},
-    utils::serialize_hash,
+    utils::{deserialize_hash, serialize_hash},
     ObjectId,
 };
 
-use super::{MODE_DIRECTORY, MODE_EXECUTABLE, MODE_REGULAR};
+use super::MODE_DIRECTORY;
 
 pub enum TreeEntry {
     Tree(Tree),
@@ -23,7 +23,7 @@ impl TreeEntry {
     pub fn mode(&self) -> i32 {
         match self {
             TreeEntry::Tree(_) => MODE_DIRECTORY,
-            TreeEntry::Entry(entry) => entry.mode(),
+            TreeEntry::Entry(entry) => entry.mode,
         }
     }
 
@@ -48,7 +48,7 @@ impl std::fmt::Debug for TreeEntry {
 }
 
 pub struct Tree {
-    entries: HashMap<OsString, TreeEntry>,
+    entries: BTreeMap<OsString, TreeEntry>,
 }
 
 impl Object for Tree {
@@ -57,8 +57,18 @@ impl Object for Tree {
     }
 
     fn serialize_data(&self) -> Vec<u8> {
-        self.entries
-            .iter()
+        // git sorts subtrees as if their name ended with a slash
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_cached_key(|(path, entry)| {
+            let mut key = path.to_str().expect("Failed to convert to str").to_string();
+            if let TreeEntry::Tree(_) = entry {
+                key.push('/');
+            }
+            key
+        });
+
+        entries
+            .into_iter()
             .flat_map(|(path, entry)| {
                 let mut entry_vec = format!(
                     "{:o} {}\0",
@@ -85,7 +95,7 @@ impl Default for Tree {
 impl Tree {
     pub fn new() -> Self {
         Self {
-            entries: HashMap::new(),
+            entries: BTreeMap::new(),
         }
     }
 
@@ -141,18 +151,73 @@ pub fn build(entries: &[Entry]) -> Tree {
 pub struct Entry {
     pub path: PathBuf,
     pub object_id: ObjectId,
+    pub mode: i32,
 }
 
 impl Entry {
-    pub fn new(path: PathBuf, object_id: ObjectId) -> Self {
-        Self { path, object_id }
+    pub fn new(path: PathBuf, object_id: ObjectId, mode: i32) -> Self {
+        Self {
+            path,
+            object_id,
+            mode,
+        }
     }
+}
 
-    pub fn mode(&self) -> i32 {
-        if self.path.is_executable() {
-            MODE_EXECUTABLE
-        } else {
-            MODE_REGULAR
-        }
+/// An entry read back from a tree object
+#[derive(Clone, Debug, PartialEq)]
+pub struct TreeItem {
+    pub mode: u32,
+    pub object_id: ObjectId,
+}
+
+/// Parses the content of a tree object into its entries
+pub fn parse(data: &[u8]) -> Result<Vec<(String, TreeItem)>> {
+    let mut entries = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let space = i + data[i..]
+            .iter()
+            .position(|b| *b == b' ')
+            .context("Invalid tree entry mode")?;
+        let null = space
+            + data[space..]
+                .iter()
+                .position(|b| *b == 0)
+                .context("Invalid tree entry name")?;
+
+        let mode = u32::from_str_radix(std::str::from_utf8(&data[i..space])?, 8)?;
+        let name = std::str::from_utf8(&data[space + 1..null])?.to_string();
+        let object_id = deserialize_hash(
+            data.get(null + 1..null + 21)
+                .context("Invalid tree entry object id")?,
+        );
+        entries.push((name, TreeItem { mode, object_id }));
+        i = null + 21;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{MODE_EXECUTABLE, MODE_REGULAR};
+
+    #[test]
+    fn serialize_and_parse() -> Result<()> {
+        let entries = vec![
+            Entry::new("a.txt".into(), "a".repeat(40), MODE_REGULAR),
+            Entry::new("a/b.txt".into(), "b".repeat(40), MODE_EXECUTABLE),
+            Entry::new("a-c.txt".into(), "c".repeat(40), MODE_REGULAR),
+        ];
+        let tree = build(&entries);
+        let parsed = parse(&tree.serialize_data())?;
+
+        // The a subtree sorts as "a/" so it comes after "a-c.txt"
+        let names: Vec<_> = parsed.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["a-c.txt", "a.txt", "a"]);
+        assert_eq!(parsed[2].1.mode, MODE_DIRECTORY as u32);
+        assert_eq!(parsed[1].1.object_id, "a".repeat(40));
+        Ok(())
     }
 }
@@ -0,0 +1,113 @@
+use std::{collections::HashMap, hash::Hash};
+
+use super::{diff_trimmed, myers, Algorithm, Edit};
+
+/// Lines appearing more often than this in `a` are never used to split the sequences
+const MAX_CHAIN: usize = 64;
+
+/// Histogram diff, git's extension of patience diff. Instead of requiring unique lines, it
+/// splits the sequences around the longest common region containing the line that appears
+/// the least in `a`, then diffs both sides recursively. Falls back to Myers when the common
+/// lines are too frequent.
+pub struct Histogram;
+
+impl Algorithm for Histogram {
+    fn diff<T: Eq + Hash>(&self, a: &[T], b: &[T]) -> Vec<Edit> {
+        let mut edits = vec![];
+        diff_into(a, b, &mut edits);
+        edits
+    }
+}
+
+fn diff_into<T: Eq + Hash>(a: &[T], b: &[T], edits: &mut Vec<Edit>) {
+    diff_trimmed(a, b, edits, |a, b, edits| {
+        let (a_start, b_start, len) = match longest_rare_region(a, b) {
+            Some(region) => region,
+            None => {
+                edits.extend(myers::diff(a, b));
+                return;
+            }
+        };
+
+        diff_into(&a[..a_start], &b[..b_start], edits);
+        edits.extend((0..len).map(|k| Edit::Equal(a_start + k, b_start + k)));
+
+        let (a_end, b_end) = (a_start + len, b_start + len);
+        let rest = edits.len();
+        diff_into(&a[a_end..], &b[b_end..], edits);
+        for edit in &mut edits[rest..] {
+            *edit = edit.offset(a_end, b_end);
+        }
+    });
+}
+
+/// Finds the common region `(a_start, b_start, len)` whose rarest line has the fewest
+/// occurrences in `a`, the longest one on ties
+fn longest_rare_region<T: Eq + Hash>(a: &[T], b: &[T]) -> Option<(usize, usize, usize)> {
+    let mut positions: HashMap<&T, Vec<usize>> = HashMap::new();
+    for (i, line) in a.iter().enumerate() {
+        positions.entry(line).or_default().push(i);
+    }
+
+    // Best region as (rarest count, length, a_start, b_start)
+    let mut best: Option<(usize, usize, usize, usize)> = None;
+    for (j, line) in b.iter().enumerate() {
+        let occurrences = match positions.get(line) {
+            Some(occurrences) if occurrences.len() <= MAX_CHAIN => occurrences,
+            _ => continue,
+        };
+        for &i in occurrences {
+            let before = (1..=i.min(j)).take_while(|k| a[i - k] == b[j - k]).count();
+            let after = (1..(a.len() - i).min(b.len() - j))
+                .take_while(|k| a[i + k] == b[j + k])
+                .count();
+            let (a_start, b_start) = (i - before, j - before);
+            let len = before + 1 + after;
+            let count = a[a_start..a_start + len]
+                .iter()
+                .map(|line| positions[line].len())
+                .min()
+                .unwrap_or(usize::MAX);
+
+            let is_better = match best {
+                None => true,
+                Some((best_count, best_len, _, _)) => {
+                    count < best_count || (count == best_count && len > best_len)
+                }
+            };
+            if is_better {
+                best = Some((count, len, a_start, b_start));
+            }
+        }
+    }
+    best.map(|(_, len, a_start, b_start)| (a_start, b_start, len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram_splits_on_rare_lines() {
+        let a: Vec<char> = "xaybzc".chars().collect();
+        let b: Vec<char> = "aqbrcz".chars().collect();
+        let edits = Histogram.diff(&a, &b);
+
+        let result: Vec<char> = edits
+            .iter()
+            .filter_map(|edit| match edit {
+                Edit::Equal(_, j) | Edit::Insert(j) => Some(b[*j]),
+                Edit::Delete(_) => None,
+            })
+            .collect();
+        assert_eq!(result, b);
+        let equal: Vec<char> = edits
+            .iter()
+            .filter_map(|edit| match edit {
+                Edit::Equal(i, _) => Some(a[*i]),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(equal, vec!['a', 'b', 'c']);
+    }
+}
@@ -0,0 +1,109 @@
+use super::Edit;
+
+/// A group of changes with the unchanged lines around them
+#[derive(Debug, PartialEq)]
+pub struct Hunk {
+    /// Number of lines of `a` and `b` before the hunk
+    pub a_offset: usize,
+    pub b_offset: usize,
+    pub edits: Vec<Edit>,
+}
+
+impl Hunk {
+    /// The `@@ -a,b +c,d @@` line, an empty side starts at the line before the hunk
+    pub fn header(&self) -> String {
+        let a_len = self
+            .edits
+            .iter()
+            .filter(|edit| !matches!(edit, Edit::Insert(_)))
+            .count();
+        let b_len = self
+            .edits
+            .iter()
+            .filter(|edit| !matches!(edit, Edit::Delete(_)))
+            .count();
+        format!(
+            "@@ -{} +{} @@",
+            range(self.a_offset, a_len),
+            range(self.b_offset, b_len)
+        )
+    }
+}
+
+fn range(offset: usize, len: usize) -> String {
+    let start = if len == 0 { offset } else { offset + 1 };
+    if len == 1 {
+        start.to_string()
+    } else {
+        format!("{},{}", start, len)
+    }
+}
+
+/// Groups the edits in hunks with `context` unchanged lines around the changes.
+/// Changes separated by less than twice the context end up in the same hunk.
+pub fn hunks(edits: &[Edit], context: usize) -> Vec<Hunk> {
+    // Lines of each side consumed before every edit
+    let mut offsets = Vec::with_capacity(edits.len());
+    let (mut a, mut b) = (0, 0);
+    for edit in edits {
+        offsets.push((a, b));
+        match edit {
+            Edit::Equal(..) => {
+                a += 1;
+                b += 1;
+            }
+            Edit::Delete(_) => a += 1,
+            Edit::Insert(_) => b += 1,
+        }
+    }
+    let is_change = |i: usize| !matches!(edits[i], Edit::Equal(..));
+
+    let mut hunks = vec![];
+    let mut i = 0;
+    while i < edits.len() {
+        if !is_change(i) {
+            i += 1;
+            continue;
+        }
+        let start = i.saturating_sub(context);
+        let mut last_change = i;
+        while let Some(next) = (last_change + 1..edits.len()).find(|&k| is_change(k)) {
+            if next - last_change - 1 > 2 * context {
+                break;
+            }
+            last_change = next;
+        }
+        let end = (last_change + context + 1).min(edits.len());
+
+        let (a_offset, b_offset) = offsets[start];
+        hunks.push(Hunk {
+            a_offset,
+            b_offset,
+            edits: edits[start..end].to_vec(),
+        });
+        i = end;
+    }
+    hunks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::diff::diff;
+
+    #[test]
+    fn group_hunks() {
+        let a: Vec<_> = (1..=20).collect();
+        let mut b = a.clone();
+        b[1] = 100;
+        b.remove(17);
+
+        let hunks = hunks(&diff(&a, &b), 3);
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(hunks[0].header(), "@@ -1,5 +1,5 @@");
+        assert_eq!(hunks[1].header(), "@@ -15,6 +15,5 @@");
+
+        let added = super::hunks(&diff(&[], &[1, 2]), 3);
+        assert_eq!(added[0].header(), "@@ -0,0 +1,2 @@");
+    }
+}
@@ -0,0 +1,230 @@
+pub mod histogram;
+pub mod hunk;
+pub mod myers;
+pub mod patch;
+pub mod patience;
+pub mod rename;
+pub mod words;
+
+use std::{
+    fmt::{self, Display, Formatter},
+    hash::Hash,
+    str::FromStr,
+};
+
+use anyhow::bail;
+
+/// One step of the edit script turning `a` into `b`, with the indices of the lines involved
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edit {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+impl Edit {
+    /// Moves the indices of an edit computed on subslices starting at `a` and `b`
+    pub fn offset(self, a: usize, b: usize) -> Self {
+        match self {
+            Edit::Equal(i, j) => Edit::Equal(i + a, j + b),
+            Edit::Delete(i) => Edit::Delete(i + a),
+            Edit::Insert(j) => Edit::Insert(j + b),
+        }
+    }
+}
+
+/// Splits data in lines, keeping the line endings so the lines can be joined back as is
+pub fn lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|byte| *byte == b'\n').collect()
+}
+
+/// Computes the shortest edit script between two sequences of lines
+pub fn diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    myers::diff(a, b)
+}
+
+/// An algorithm computing an edit script between two sequences
+pub trait Algorithm {
+    fn diff<T: Eq + Hash>(&self, a: &[T], b: &[T]) -> Vec<Edit>;
+}
+
+/// Lengths of the common prefix and suffix of two sequences, they don't overlap
+fn common_ends<T: PartialEq>(a: &[T], b: &[T]) -> (usize, usize) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    (prefix, suffix)
+}
+
+/// Diffs `a` and `b` by trimming their common ends then letting `middle` diff what's left.
+/// Used by the algorithms that split the sequences recursively.
+fn diff_trimmed<T: PartialEq>(
+    a: &[T],
+    b: &[T],
+    edits: &mut Vec<Edit>,
+    middle: impl FnOnce(&[T], &[T], &mut Vec<Edit>),
+) {
+    let (prefix, suffix) = common_ends(a, b);
+    edits.extend((0..prefix).map(|i| Edit::Equal(i, i)));
+
+    let (a_end, b_end) = (a.len() - suffix, b.len() - suffix);
+    let (a_mid, b_mid) = (&a[prefix..a_end], &b[prefix..b_end]);
+    if a_mid.is_empty() {
+        edits.extend((prefix..b_end).map(Edit::Insert));
+    } else if b_mid.is_empty() {
+        edits.extend((prefix..a_end).map(Edit::Delete));
+    } else {
+        let start = edits.len();
+        middle(a_mid, b_mid, edits);
+        for edit in &mut edits[start..] {
+            *edit = edit.offset(prefix, prefix);
+        }
+    }
+
+    edits.extend((0..suffix).map(|i| Edit::Equal(a_end + i, b_end + i)));
+}
+
+/// The algorithms that can be picked with `--diff-algorithm` or `diff.algorithm`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DiffAlgorithm {
+    #[default]
+    Myers,
+    Patience,
+    Histogram,
+}
+
+impl DiffAlgorithm {
+    pub fn diff<T: Eq + Hash>(&self, a: &[T], b: &[T]) -> Vec<Edit> {
+        match self {
+            DiffAlgorithm::Myers => myers::Myers.diff(a, b),
+            DiffAlgorithm::Patience => patience::Patience.diff(a, b),
+            DiffAlgorithm::Histogram => histogram::Histogram.diff(a, b),
+        }
+    }
+}
+
+impl Display for DiffAlgorithm {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match self {
+            DiffAlgorithm::Myers => "myers",
+            DiffAlgorithm::Patience => "patience",
+            DiffAlgorithm::Histogram => "histogram",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for DiffAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "myers" | "default" | "minimal" => Ok(DiffAlgorithm::Myers),
+            "patience" => Ok(DiffAlgorithm::Patience),
+            "histogram" => Ok(DiffAlgorithm::Histogram),
+            _ => bail!("unknown diff algorithm: {}", s),
+        }
+    }
+}
+
+/// How `--word-diff` marks the changed words
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WordDiff {
+    /// `[-removed-]{+added+}`
+    Plain,
+    /// Removed words in red and added words in green
+    Color,
+}
+
+impl FromStr for WordDiff {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "plain" => Ok(WordDiff::Plain),
+            "color" => Ok(WordDiff::Color),
+            _ => bail!("bad --word-diff argument: {}", s),
+        }
+    }
+}
+
+/// Options changing how the lines of two files are compared and shown
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiffOptions {
+    pub algorithm: DiffAlgorithm,
+    pub ignore_all_space: bool,
+    pub ignore_space_change: bool,
+    pub ignore_blank_lines: bool,
+    pub word_diff: Option<WordDiff>,
+}
+
+impl DiffOptions {
+    /// Diffs the lines with the selected algorithm, comparing them without the whitespace
+    /// that is ignored
+    pub fn diff_lines(&self, a: &[&[u8]], b: &[&[u8]]) -> Vec<Edit> {
+        if !self.ignore_all_space && !self.ignore_space_change {
+            return self.algorithm.diff(a, b);
+        }
+        let a: Vec<Vec<u8>> = a.iter().map(|line| self.normalize(line)).collect();
+        let b: Vec<Vec<u8>> = b.iter().map(|line| self.normalize(line)).collect();
+        self.algorithm.diff(&a, &b)
+    }
+
+    /// The part of a line that is compared
+    pub(crate) fn normalize(&self, line: &[u8]) -> Vec<u8> {
+        if !self.ignore_all_space && !self.ignore_space_change {
+            return line.to_vec();
+        }
+        if self.ignore_all_space {
+            return line
+                .iter()
+                .filter(|byte| !byte.is_ascii_whitespace())
+                .copied()
+                .collect();
+        }
+        // Runs of whitespace compare equal to a single space, trailing whitespace is dropped
+        let mut normalized = vec![];
+        let mut in_space = false;
+        for byte in line {
+            if byte.is_ascii_whitespace() {
+                in_space = true;
+                continue;
+            }
+            if in_space {
+                normalized.push(b' ');
+                in_space = false;
+            }
+            normalized.push(*byte);
+        }
+        normalized
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ignore_whitespace() {
+        let options = DiffOptions {
+            ignore_space_change: true,
+            ..DiffOptions::default()
+        };
+        let a: Vec<&[u8]> = vec![b"a  b\n", b"  c\n", b"de\n"];
+        let b: Vec<&[u8]> = vec![b"a b \n", b" c\n", b"d e\n"];
+        let edits = options.diff_lines(&a, &b);
+        assert_eq!(&edits[..2], &[Edit::Equal(0, 0), Edit::Equal(1, 1)]);
+        assert_eq!(edits.len(), 4);
+
+        let options = DiffOptions {
+            ignore_all_space: true,
+            ..DiffOptions::default()
+        };
+        let edits = options.diff_lines(&a, &b);
+        assert!(edits.iter().all(|edit| matches!(edit, Edit::Equal(..))));
+    }
+}
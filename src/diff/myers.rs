@@ -0,0 +1,120 @@
+use std::hash::Hash;
+
+use super::{Algorithm, Edit};
+
+/// Myers' algorithm, the shortest edit script. This is what git uses by default.
+pub struct Myers;
+
+impl Algorithm for Myers {
+    fn diff<T: Eq + Hash>(&self, a: &[T], b: &[T]) -> Vec<Edit> {
+        diff(a, b)
+    }
+}
+
+/// Myers' O(ND) algorithm, the trace of every step is kept to walk back the shortest path
+pub fn diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    let trace = shortest_edit(a, b);
+    backtrack(a, b, &trace)
+}
+
+fn shortest_edit<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Vec<isize>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m) as usize;
+    // Diagonals go from -max to max, they are offset by max to be used as indices
+    let offset = max as isize;
+    let mut v = vec![0isize; 2 * max + 2];
+    let mut trace = vec![];
+
+    for d in 0..=max as isize {
+        trace.push(v.clone());
+        let mut k = -d;
+        while k <= d {
+            let index = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+                v[index + 1]
+            } else {
+                v[index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index] = x;
+            if x >= n && y >= m {
+                return trace;
+            }
+            k += 2;
+        }
+    }
+    trace
+}
+
+fn backtrack<T: PartialEq>(a: &[T], b: &[T], trace: &[Vec<isize>]) -> Vec<Edit> {
+    let (mut x, mut y) = (a.len() as isize, b.len() as isize);
+    let offset = (a.len() + b.len()) as isize;
+    let mut edits = vec![];
+
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let previous_k =
+            if k == -d || (k != d && v[(k - 1 + offset) as usize] < v[(k + 1 + offset) as usize]) {
+                k + 1
+            } else {
+                k - 1
+            };
+        let previous_x = v[(previous_k + offset) as usize];
+        let previous_y = previous_x - previous_k;
+
+        while x > previous_x && y > previous_y {
+            edits.push(Edit::Equal((x - 1) as usize, (y - 1) as usize));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == previous_x {
+                edits.push(Edit::Insert((y - 1) as usize));
+            } else {
+                edits.push(Edit::Delete((x - 1) as usize));
+            }
+        }
+        x = previous_x;
+        y = previous_y;
+    }
+
+    edits.reverse();
+    edits
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shortest_edit_script() {
+        let a: Vec<char> = "ABCABBA".chars().collect();
+        let b: Vec<char> = "CBABAC".chars().collect();
+        let edits = diff(&a, &b);
+
+        let changes = edits
+            .iter()
+            .filter(|edit| !matches!(edit, Edit::Equal(..)))
+            .count();
+        assert_eq!(changes, 5);
+
+        // Replaying the script on `a` gives back `b`
+        let result: Vec<char> = edits
+            .iter()
+            .filter_map(|edit| match edit {
+                Edit::Equal(_, j) | Edit::Insert(j) => Some(b[*j]),
+                Edit::Delete(_) => None,
+            })
+            .collect();
+        assert_eq!(result, b);
+
+        assert!(diff::<char>(&[], &[]).is_empty());
+        assert_eq!(diff(&[], &['a']), vec![Edit::Insert(0)]);
+        assert_eq!(diff(&['a'], &[]), vec![Edit::Delete(0)]);
+    }
+}
@@ -0,0 +1,295 @@
+use std::{collections::BTreeMap, io::Write};
+
+use anyhow::Result;
+
+use super::{hunk, lines, words, DiffOptions, Edit};
+use crate::database::{tree::TreeItem, Database};
+
+const NULL_PATH: &str = "/dev/null";
+const CONTEXT: usize = 3;
+
+/// A path that differs between two trees, `None` when it's missing from a side
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileChange {
+    pub path: String,
+    pub old: Option<TreeItem>,
+    pub new: Option<TreeItem>,
+    /// Set when the file was renamed or copied from another path, `old` is then its content
+    pub origin: Option<Origin>,
+}
+
+/// The path a file was renamed or copied from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    pub path: String,
+    pub item: TreeItem,
+    pub is_copy: bool,
+    /// Percentage of similarity between the two files
+    pub similarity: usize,
+}
+
+impl FileChange {
+    /// The letter used by `--name-status` for this change
+    pub fn status(&self) -> char {
+        match (&self.origin, &self.old, &self.new) {
+            (Some(origin), _, _) if origin.is_copy => 'C',
+            (Some(_), _, _) => 'R',
+            (None, None, _) => 'A',
+            (None, _, None) => 'D',
+            _ => 'M',
+        }
+    }
+
+    pub fn old_path(&self) -> &str {
+        match &self.origin {
+            Some(origin) => &origin.path,
+            None => &self.path,
+        }
+    }
+}
+
+/// Lists the paths that were added, removed or modified from `a` to `b`
+pub fn tree_changes(
+    a: &BTreeMap<String, TreeItem>,
+    b: &BTreeMap<String, TreeItem>,
+) -> Vec<FileChange> {
+    let mut paths: Vec<&String> = a.keys().chain(b.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    paths
+        .into_iter()
+        .filter(|path| a.get(*path) != b.get(*path))
+        .map(|path| FileChange {
+            path: path.clone(),
+            old: a.get(path).cloned(),
+            new: b.get(path).cloned(),
+            origin: None,
+        })
+        .collect()
+}
+
+fn load_blob(database: &Database, item: Option<&TreeItem>) -> Result<Vec<u8>> {
+    match item {
+        Some(item) => Ok(database.load(&item.object_id)?.1),
+        None => Ok(vec![]),
+    }
+}
+
+/// Git considers a file binary when a NUL byte shows up in its first 8000 bytes
+pub(crate) fn is_binary(data: &[u8]) -> bool {
+    data.iter().take(8000).any(|byte| *byte == 0)
+}
+
+/// Writes the change in the unified format of `diff --git`. Files whose only changes are
+/// ignored by the options are left out.
+pub fn write_patch(
+    out: &mut dyn Write,
+    database: &Database,
+    change: &FileChange,
+    options: &DiffOptions,
+) -> Result<()> {
+    let same_content = change.old.as_ref().map(|item| &item.object_id)
+        == change.new.as_ref().map(|item| &item.object_id);
+    let old_data = load_blob(database, change.old.as_ref())?;
+    let new_data = load_blob(database, change.new.as_ref())?;
+    let binary = is_binary(&old_data) || is_binary(&new_data);
+    let (a, b) = (lines(&old_data), lines(&new_data));
+    let hunks = if same_content || binary {
+        vec![]
+    } else {
+        let is_blank = |line: &[u8]| line.iter().all(u8::is_ascii_whitespace);
+        hunk::hunks(&options.diff_lines(&a, &b), CONTEXT)
+            .into_iter()
+            .filter(|hunk| {
+                !options.ignore_blank_lines
+                    || hunk.edits.iter().any(|edit| match *edit {
+                        Edit::Equal(..) => false,
+                        Edit::Delete(i) => !is_blank(a[i]),
+                        Edit::Insert(i) => !is_blank(b[i]),
+                    })
+            })
+            .collect()
+    };
+    let only_content = change.origin.is_none()
+        && matches!((&change.old, &change.new), (Some(old), Some(new)) if old.mode == new.mode);
+    if only_content && !binary && hunks.is_empty() {
+        return Ok(());
+    }
+
+    let (old_path, path) = (change.old_path(), &change.path);
+    writeln!(out, "diff --git a/{} b/{}", old_path, path)?;
+    if let Some(origin) = &change.origin {
+        let kind = if origin.is_copy { "copy" } else { "rename" };
+        writeln!(out, "similarity index {}%", origin.similarity)?;
+        writeln!(out, "{} from {}", kind, origin.path)?;
+        writeln!(out, "{} to {}", kind, path)?;
+    }
+
+    let old_id = change
+        .old
+        .as_ref()
+        .map_or("0000000", |item| &item.object_id[..7]);
+    let new_id = change
+        .new
+        .as_ref()
+        .map_or("0000000", |item| &item.object_id[..7]);
+    match (&change.old, &change.new) {
+        (None, Some(new)) => {
+            writeln!(out, "new file mode {:06o}", new.mode)?;
+            writeln!(out, "index {}..{}", old_id, new_id)?;
+        }
+        (Some(old), None) => {
+            writeln!(out, "deleted file mode {:06o}", old.mode)?;
+            writeln!(out, "index {}..{}", old_id, new_id)?;
+        }
+        (Some(old), Some(new)) if old.mode != new.mode => {
+            writeln!(out, "old mode {:06o}", old.mode)?;
+            writeln!(out, "new mode {:06o}", new.mode)?;
+            if !same_content {
+                writeln!(out, "index {}..{}", old_id, new_id)?;
+            }
+        }
+        (Some(old), Some(_)) if !same_content => {
+            writeln!(out, "index {}..{} {:06o}", old_id, new_id, old.mode)?
+        }
+        (Some(_), Some(_)) => (),
+        (None, None) => return Ok(()),
+    }
+    if same_content {
+        return Ok(());
+    }
+
+    let old_name = match change.old {
+        Some(_) => format!("a/{}", old_path),
+        None => NULL_PATH.to_string(),
+    };
+    let new_name = match change.new {
+        Some(_) => format!("b/{}", path),
+        None => NULL_PATH.to_string(),
+    };
+    if binary {
+        writeln!(out, "Binary files {} and {} differ", old_name, new_name)?;
+        return Ok(());
+    }
+
+    writeln!(out, "--- {}", old_name)?;
+    writeln!(out, "+++ {}", new_name)?;
+    for hunk in &hunks {
+        writeln!(out, "{}", hunk.header())?;
+        if let Some(mode) = options.word_diff {
+            words::write_hunk(out, &a, &b, hunk, mode, options.algorithm)?;
+            continue;
+        }
+        for edit in &hunk.edits {
+            let (sign, line) = match *edit {
+                Edit::Equal(i, _) => (' ', a[i]),
+                Edit::Delete(i) => ('-', a[i]),
+                Edit::Insert(i) => ('+', b[i]),
+            };
+            write!(out, "{}", sign)?;
+            out.write_all(line)?;
+            if !line.ends_with(b"\n") {
+                writeln!(out, "\n\\ No newline at end of file")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the status letter and the paths of every change like `diff --name-status`
+pub fn write_name_status(out: &mut dyn Write, changes: &[FileChange]) -> Result<()> {
+    for change in changes {
+        match &change.origin {
+            Some(origin) => writeln!(
+                out,
+                "{}{:03}\t{}\t{}",
+                change.status(),
+                origin.similarity,
+                origin.path,
+                change.path
+            )?,
+            None => writeln!(out, "{}\t{}", change.status(), change.path)?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes the number of changed lines of every file like `diff --stat`
+pub fn write_stat(
+    out: &mut dyn Write,
+    database: &Database,
+    changes: &[FileChange],
+    options: &DiffOptions,
+) -> Result<()> {
+    let mut stats = vec![];
+    for change in changes {
+        let old_data = load_blob(database, change.old.as_ref())?;
+        let new_data = load_blob(database, change.new.as_ref())?;
+        let stat = if is_binary(&old_data) || is_binary(&new_data) {
+            None
+        } else {
+            let edits = options.diff_lines(&lines(&old_data), &lines(&new_data));
+            let insertions = edits
+                .iter()
+                .filter(|edit| matches!(edit, Edit::Insert(_)))
+                .count();
+            let deletions = edits
+                .iter()
+                .filter(|edit| matches!(edit, Edit::Delete(_)))
+                .count();
+            Some((insertions, deletions))
+        };
+        let path = match &change.origin {
+            Some(origin) => format!("{} => {}", origin.path, change.path),
+            None => change.path.clone(),
+        };
+        stats.push((path, stat));
+    }
+
+    let path_width = stats.iter().map(|(path, _)| path.len()).max().unwrap_or(0);
+    let count_width = stats
+        .iter()
+        .map(|(_, stat)| stat.map_or(3, |(i, d)| (i + d).to_string().len()))
+        .max()
+        .unwrap_or(0);
+    let (mut total_insertions, mut total_deletions) = (0, 0);
+    for (path, stat) in &stats {
+        match stat {
+            Some((insertions, deletions)) => {
+                writeln!(
+                    out,
+                    " {:path_width$} | {:>count_width$} {}{}",
+                    path,
+                    insertions + deletions,
+                    "+".repeat(*insertions),
+                    "-".repeat(*deletions),
+                    path_width = path_width,
+                    count_width = count_width
+                )?;
+                total_insertions += insertions;
+                total_deletions += deletions;
+            }
+            None => writeln!(
+                out,
+                " {:path_width$} | {:>count_width$}",
+                path,
+                "Bin",
+                path_width = path_width,
+                count_width = count_width
+            )?,
+        }
+    }
+
+    let plural = |count: usize, word: &str| {
+        format!("{} {}{}", count, word, if count == 1 { "" } else { "s" })
+    };
+    write!(out, " {} changed", plural(stats.len(), "file"))?;
+    if total_insertions > 0 || total_deletions == 0 {
+        write!(out, ", {}(+)", plural(total_insertions, "insertion"))?;
+    }
+    if total_deletions > 0 || total_insertions == 0 {
+        write!(out, ", {}(-)", plural(total_deletions, "deletion"))?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
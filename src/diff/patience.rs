@@ -0,0 +1,122 @@
+use std::{collections::HashMap, hash::Hash};
+
+use super::{diff_trimmed, myers, Algorithm, Edit};
+
+/// Patience diff matches the lines that appear exactly once on each side first, keeping the
+/// longest run of them that is in the same order on both sides, then diffs the gaps between
+/// them recursively. Unique lines are rarely braces or blank lines, so the hunks follow the
+/// structure of the code better than Myers'. Falls back to Myers when no line is unique.
+pub struct Patience;
+
+impl Algorithm for Patience {
+    fn diff<T: Eq + Hash>(&self, a: &[T], b: &[T]) -> Vec<Edit> {
+        let mut edits = vec![];
+        diff_into(a, b, &mut edits);
+        edits
+    }
+}
+
+fn diff_into<T: Eq + Hash>(a: &[T], b: &[T], edits: &mut Vec<Edit>) {
+    diff_trimmed(a, b, edits, |a, b, edits| {
+        let anchors = unique_common_lines(a, b);
+        if anchors.is_empty() {
+            edits.extend(myers::diff(a, b));
+            return;
+        }
+
+        let (mut a_start, mut b_start) = (0, 0);
+        for (i, j) in anchors
+            .into_iter()
+            .chain(std::iter::once((a.len(), b.len())))
+        {
+            let start = edits.len();
+            diff_into(&a[a_start..i], &b[b_start..j], edits);
+            for edit in &mut edits[start..] {
+                *edit = edit.offset(a_start, b_start);
+            }
+            if i < a.len() {
+                edits.push(Edit::Equal(i, j));
+            }
+            a_start = i + 1;
+            b_start = j + 1;
+        }
+    });
+}
+
+/// Pairs of indices of the lines unique to both sides, in the longest sequence where they
+/// appear in the same order
+fn unique_common_lines<T: Eq + Hash>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    // Occurrences in a, occurrences in b, last index in a, last index in b
+    let mut counts: HashMap<&T, (usize, usize, usize, usize)> = HashMap::new();
+    for (i, line) in a.iter().enumerate() {
+        let entry = counts.entry(line).or_default();
+        entry.0 += 1;
+        entry.2 = i;
+    }
+    for (j, line) in b.iter().enumerate() {
+        if let Some(entry) = counts.get_mut(line) {
+            entry.1 += 1;
+            entry.3 = j;
+        }
+    }
+    let mut pairs: Vec<(usize, usize)> = counts
+        .values()
+        .filter(|(a_count, b_count, _, _)| *a_count == 1 && *b_count == 1)
+        .map(|(_, _, i, j)| (*i, *j))
+        .collect();
+    pairs.sort_unstable();
+
+    // Longest increasing subsequence of the indices in b with patience sorting:
+    // `tops` holds the last pair of each pile, `previous` links a pair to the pile on its left
+    let mut tops: Vec<usize> = vec![];
+    let mut previous: Vec<Option<usize>> = vec![None; pairs.len()];
+    for (k, (_, j)) in pairs.iter().enumerate() {
+        let pile = tops.partition_point(|&top| pairs[top].1 < *j);
+        previous[k] = pile.checked_sub(1).map(|left| tops[left]);
+        if pile == tops.len() {
+            tops.push(k);
+        } else {
+            tops[pile] = k;
+        }
+    }
+
+    let mut sequence = vec![];
+    let mut current = tops.last().copied();
+    while let Some(k) = current {
+        sequence.push(pairs[k]);
+        current = previous[k];
+    }
+    sequence.reverse();
+    sequence
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn patience_keeps_unique_lines_together() {
+        let a = vec!["fn a() {", "  one", "}", "", "fn b() {", "  two", "}"];
+        let b = vec![
+            "fn a() {", "  one", "}", "", "fn c() {", "  three", "}", "", "fn b() {", "  two", "}",
+        ];
+        let edits = Patience.diff(&a, &b);
+        let inserted: Vec<&str> = edits
+            .iter()
+            .filter_map(|edit| match edit {
+                Edit::Insert(j) => Some(b[*j]),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(inserted, vec!["fn c() {", "  three", "}", ""]);
+
+        let result: Vec<&str> = edits
+            .iter()
+            .filter_map(|edit| match edit {
+                Edit::Equal(_, j) | Edit::Insert(j) => Some(b[*j]),
+                Edit::Delete(_) => None,
+            })
+            .collect();
+        assert_eq!(result, b);
+    }
+}
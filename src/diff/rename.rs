@@ -0,0 +1,284 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{bail, Result};
+
+use super::{
+    lines,
+    patch::{FileChange, Origin},
+};
+use crate::database::Database;
+
+/// Minimum similarity of a rename or a copy when no score is given
+pub const DEFAULT_SCORE: usize = 50;
+
+/// Which kinds of origins to look for and how similar files must be, in percents
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Detection {
+    pub rename_score: Option<usize>,
+    pub copy_score: Option<usize>,
+}
+
+impl Detection {
+    /// Renames only, like merges do
+    pub fn renames() -> Self {
+        Self {
+            rename_score: Some(DEFAULT_SCORE),
+            copy_score: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rename_score.is_some() || self.copy_score.is_some()
+    }
+}
+
+/// Parses the score of `-M<n>` or `-C<n>`. Like git, `90%` is 90% but digits alone are the
+/// decimals of a fraction so `9` and `90` are also 90%.
+pub fn parse_score(score: &str) -> Result<usize> {
+    if score.is_empty() {
+        return Ok(DEFAULT_SCORE);
+    }
+    let value = match score.strip_suffix('%') {
+        Some(percent) => percent.parse::<usize>().ok(),
+        None if score.chars().all(|c| c.is_ascii_digit()) => {
+            let digits = format!("{:0<2}", score);
+            digits[..2].parse::<usize>().ok()
+        }
+        None => None,
+    };
+    match value {
+        Some(value) if value <= 100 => Ok(value),
+        _ => bail!("invalid similarity score: {}", score),
+    }
+}
+
+/// Percentage of the content of the larger file that is also found in the other one,
+/// compared line by line
+pub fn similarity(a: &[u8], b: &[u8]) -> usize {
+    let size = a.len().max(b.len());
+    if size == 0 {
+        return 100;
+    }
+    let mut counts: HashMap<&[u8], usize> = HashMap::new();
+    for line in lines(a) {
+        *counts.entry(line).or_default() += 1;
+    }
+    let mut shared = 0;
+    for line in lines(b) {
+        if let Some(count) = counts.get_mut(line).filter(|count| **count > 0) {
+            *count -= 1;
+            shared += line.len();
+        }
+    }
+    shared * 100 / size
+}
+
+/// Pairs added files with removed or modified ones they were renamed or copied from.
+/// Identical files are paired first, then the most similar ones above the score.
+/// Renamed files become a single change from the source path.
+pub fn detect(
+    database: &Database,
+    changes: Vec<FileChange>,
+    detection: &Detection,
+) -> Result<Vec<FileChange>> {
+    if !detection.is_enabled() {
+        return Ok(changes);
+    }
+    let rename_score = detection.rename_score.or(detection.copy_score);
+
+    let added: Vec<usize> = (0..changes.len())
+        .filter(|&i| changes[i].old.is_none())
+        .collect();
+    // Deleted files can be renamed, modified ones can only be copied
+    let sources: Vec<usize> = (0..changes.len())
+        .filter(|&i| {
+            let change = &changes[i];
+            change.old.is_some() && (change.new.is_none() || detection.copy_score.is_some())
+        })
+        .collect();
+    if added.is_empty() || sources.is_empty() {
+        return Ok(changes);
+    }
+
+    // Every candidate pair with its score, identical files score above everything else
+    let mut candidates = vec![];
+    let mut contents = HashMap::new();
+    for &source in &sources {
+        let old = changes[source]
+            .old
+            .as_ref()
+            .expect("Source without content");
+        let min_score = if changes[source].new.is_none() {
+            rename_score.unwrap_or(100)
+        } else {
+            detection.copy_score.unwrap_or(100)
+        };
+        for &target in &added {
+            let new = changes[target]
+                .new
+                .as_ref()
+                .expect("Added file without content");
+            let score = if old.object_id == new.object_id {
+                101
+            } else {
+                if !contents.contains_key(&old.object_id) {
+                    contents.insert(old.object_id.clone(), database.load(&old.object_id)?.1);
+                }
+                if !contents.contains_key(&new.object_id) {
+                    contents.insert(new.object_id.clone(), database.load(&new.object_id)?.1);
+                }
+                let (a, b) = (&contents[&old.object_id], &contents[&new.object_id]);
+                // Files of very different sizes can't be similar enough
+                if a.len().min(b.len()) * 100 < a.len().max(b.len()) * min_score {
+                    continue;
+                }
+                similarity(a, b)
+            };
+            if score >= min_score {
+                let same_name =
+                    file_name(&changes[source].path) == file_name(&changes[target].path);
+                candidates.push((score, same_name, source, target));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| {
+        (b.0, b.1)
+            .cmp(&(a.0, a.1))
+            .then((a.2, a.3).cmp(&(b.2, b.3)))
+    });
+
+    let mut origins: HashMap<usize, Origin> = HashMap::new();
+    let mut renamed = BTreeSet::new();
+    for (score, _, source, target) in candidates {
+        if origins.contains_key(&target) {
+            continue;
+        }
+        let is_deleted = changes[source].new.is_none();
+        let is_copy = !is_deleted || renamed.contains(&source);
+        if is_copy && detection.copy_score.is_none() {
+            continue;
+        }
+        if !is_copy {
+            renamed.insert(source);
+        }
+        origins.insert(
+            target,
+            Origin {
+                path: changes[source].path.clone(),
+                item: changes[source].old.clone().expect("Source without content"),
+                is_copy,
+                similarity: score.min(100),
+            },
+        );
+    }
+
+    let mut result: Vec<FileChange> = changes
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !renamed.contains(i))
+        .map(|(i, mut change)| {
+            if let Some(origin) = origins.remove(&i) {
+                change.old = Some(origin.item.clone());
+                change.origin = Some(origin);
+            }
+            change
+        })
+        .collect();
+    result.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(result)
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::database::{blob::Blob, tree::TreeItem, MODE_REGULAR};
+    use crate::diff::patch::tree_changes;
+    use crate::test_utils;
+
+    #[test]
+    fn similarity_scores() -> Result<()> {
+        assert_eq!(parse_score("")?, 50);
+        assert_eq!(parse_score("90%")?, 90);
+        assert_eq!(parse_score("9")?, 90);
+        assert_eq!(parse_score("75")?, 75);
+        assert!(parse_score("x").is_err());
+
+        assert_eq!(similarity(b"a\nb\n", b"a\nb\n"), 100);
+        assert_eq!(similarity(b"a\nb\nc\nd\n", b"a\nb\nc\nx\n"), 75);
+        assert_eq!(similarity(b"a\n", b"b\n"), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn detect_renames_and_copies() -> Result<()> {
+        let dir = test_utils::temp_path("rename");
+        let database = Database::new(dir.clone());
+        let tree = |items: &[(&str, &str)]| -> BTreeMap<String, TreeItem> {
+            items
+                .iter()
+                .map(|(path, data)| {
+                    let object_id = database.store(&Blob::new(data.as_bytes().to_vec()));
+                    let item = TreeItem {
+                        mode: MODE_REGULAR as u32,
+                        object_id: object_id.unwrap(),
+                    };
+                    (path.to_string(), item)
+                })
+                .collect()
+        };
+
+        let a = tree(&[
+            ("same.txt", "1\n2\n3\n"),
+            ("edited.txt", "a\nb\nc\nd\n"),
+            ("base.txt", "x\ny\nz\nw\n"),
+        ]);
+        let b = tree(&[
+            ("moved.txt", "1\n2\n3\n"),
+            ("renamed.txt", "a\nb\nc\nD\n"),
+            ("base.txt", "x\ny\nz\nW\n"),
+            ("copy.txt", "x\ny\nz\nw\n"),
+        ]);
+        let describe = |changes: Vec<FileChange>| -> Vec<String> {
+            changes
+                .iter()
+                .map(|change| match &change.origin {
+                    Some(origin) => format!(
+                        "{}{} {} {}",
+                        if origin.is_copy { "C" } else { "R" },
+                        origin.similarity,
+                        origin.path,
+                        change.path
+                    ),
+                    None => format!("{} {}", change.status(), change.path),
+                })
+                .collect()
+        };
+
+        let renames = detect(&database, tree_changes(&a, &b), &Detection::renames())?;
+        assert_eq!(
+            describe(renames),
+            vec![
+                "M base.txt",
+                "A copy.txt",
+                "R100 same.txt moved.txt",
+                "R75 edited.txt renamed.txt"
+            ]
+        );
+
+        let copies = Detection {
+            rename_score: Some(DEFAULT_SCORE),
+            copy_score: Some(DEFAULT_SCORE),
+        };
+        let copies = detect(&database, tree_changes(&a, &b), &copies)?;
+        assert_eq!(describe(copies)[1], "C100 base.txt copy.txt");
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::io::Write;
+
+use anyhow::Result;
+
+use super::{hunk::Hunk, DiffAlgorithm, Edit, WordDiff};
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[m";
+
+/// A word with the whitespace following it
+struct Word<'a> {
+    text: &'a [u8],
+    space: &'a [u8],
+}
+
+/// Splits a text in words separated by whitespace, returns the leading whitespace apart
+fn split_words(text: &[u8]) -> (&[u8], Vec<Word<'_>>) {
+    let is_space = |byte: &u8| byte.is_ascii_whitespace();
+    let start = text
+        .iter()
+        .position(|byte| !is_space(byte))
+        .unwrap_or(text.len());
+    let mut words = vec![];
+    let mut i = start;
+    while i < text.len() {
+        let word_end = text[i..]
+            .iter()
+            .position(is_space)
+            .map_or(text.len(), |n| i + n);
+        let space_end = text[word_end..]
+            .iter()
+            .position(|byte| !is_space(byte))
+            .map_or(text.len(), |n| word_end + n);
+        words.push(Word {
+            text: &text[i..word_end],
+            space: &text[word_end..space_end],
+        });
+        i = space_end;
+    }
+    (&text[..start], words)
+}
+
+/// Writes a run of removed or added words, keeping the whitespace between them
+fn write_run(out: &mut dyn Write, words: &[&Word], mode: WordDiff, added: bool) -> Result<()> {
+    let (open, close) = match (mode, added) {
+        (WordDiff::Plain, false) => ("[-", "-]"),
+        (WordDiff::Plain, true) => ("{+", "+}"),
+        (WordDiff::Color, false) => (RED, RESET),
+        (WordDiff::Color, true) => (GREEN, RESET),
+    };
+    write!(out, "{}", open)?;
+    for (k, word) in words.iter().enumerate() {
+        out.write_all(word.text)?;
+        if k + 1 < words.len() {
+            out.write_all(word.space)?;
+        }
+    }
+    write!(out, "{}", close)?;
+    Ok(())
+}
+
+/// Writes a hunk as the new version of its lines with the changed words marked, like
+/// `diff --word-diff`. Words are compared without the whitespace around them.
+pub fn write_hunk(
+    out: &mut dyn Write,
+    a: &[&[u8]],
+    b: &[&[u8]],
+    hunk: &Hunk,
+    mode: WordDiff,
+    algorithm: DiffAlgorithm,
+) -> Result<()> {
+    let mut old_text = vec![];
+    let mut new_text = vec![];
+    for edit in &hunk.edits {
+        match *edit {
+            Edit::Equal(i, j) => {
+                old_text.extend_from_slice(a[i]);
+                new_text.extend_from_slice(b[j]);
+            }
+            Edit::Delete(i) => old_text.extend_from_slice(a[i]),
+            Edit::Insert(j) => new_text.extend_from_slice(b[j]),
+        }
+    }
+    let (_, old_words) = split_words(&old_text);
+    let (indent, new_words) = split_words(&new_text);
+    let old_texts: Vec<&[u8]> = old_words.iter().map(|word| word.text).collect();
+    let new_texts: Vec<&[u8]> = new_words.iter().map(|word| word.text).collect();
+
+    out.write_all(indent)?;
+    let (mut removed, mut added) = (vec![], vec![]);
+    let edits = algorithm.diff(&old_texts, &new_texts);
+    for (k, edit) in edits.iter().enumerate() {
+        match *edit {
+            Edit::Delete(i) => removed.push(&old_words[i]),
+            Edit::Insert(j) => added.push(&new_words[j]),
+            Edit::Equal(_, j) => {
+                out.write_all(new_words[j].text)?;
+                out.write_all(new_words[j].space)?;
+            }
+        }
+
+        let run_ends = edits
+            .get(k + 1)
+            .is_none_or(|next| matches!(next, Edit::Equal(..)));
+        if run_ends && (!removed.is_empty() || !added.is_empty()) {
+            if !removed.is_empty() {
+                write_run(out, &removed, mode, false)?;
+            }
+            if !added.is_empty() {
+                write_run(out, &added, mode, true)?;
+            }
+            let space = added
+                .last()
+                .or_else(|| removed.last())
+                .map(|word| word.space);
+            out.write_all(space.unwrap_or_default())?;
+            removed.clear();
+            added.clear();
+        }
+    }
+    if !new_text.ends_with(b"\n") {
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::diff::{diff, hunk::hunks, lines};
+
+    #[test]
+    fn mark_changed_words() -> Result<()> {
+        let old = b"let x = 1;\nprintln!(x);\n";
+        let new = b"let y = 1;\nprintln!(x);\nreturn y;\n";
+        let (a, b) = (lines(old), lines(new));
+        let hunk = &hunks(&diff(&a, &b), 3)[0];
+
+        let mut out = vec![];
+        write_hunk(
+            &mut out,
+            &a,
+            &b,
+            hunk,
+            WordDiff::Plain,
+            DiffAlgorithm::Myers,
+        )?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "let [-x-]{+y+} = 1;\nprintln!(x);\n{+return y;+}\n"
+        );
+        Ok(())
+    }
+}
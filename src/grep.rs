@@ -0,0 +1,258 @@
+use std::{collections::BTreeMap, io::Write, path::Path};
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use regex::bytes::{Regex, RegexBuilder};
+
+use crate::{
+    database::Database,
+    diff::{lines, patch::is_binary},
+    workspace::Workspace,
+    ObjectId,
+};
+
+/// How the pattern is read, like the `-G`, `-E`, `-F` and `-P` flags
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PatternType {
+    /// POSIX basic regex where `\(`, `\)`, `\{`, `\}`, `\|`, `\+` and `\?` are the operators
+    #[default]
+    Basic,
+    Extended,
+    Fixed,
+    Perl,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GrepOptions {
+    pub pattern_type: PatternType,
+    pub ignore_case: bool,
+    /// Only match whole words
+    pub word_regexp: bool,
+    pub line_number: bool,
+    /// Only show the names of the matching files
+    pub files_with_matches: bool,
+    /// Show the number of matching lines of every file
+    pub count: bool,
+    pub before_context: usize,
+    pub after_context: usize,
+}
+
+/// Where the content of a searched file is read from
+#[derive(Debug, Clone)]
+pub enum Content {
+    /// A file of the workspace
+    Workspace(String),
+    /// A blob of the index or of a tree
+    Blob(ObjectId),
+}
+
+/// A file to search, with the name it's shown with like `HEAD:src/main.rs`
+#[derive(Debug, Clone)]
+pub struct GrepFile {
+    pub name: String,
+    pub content: Content,
+}
+
+/// Searches files for the lines matching a pattern, like `git grep`
+pub struct Grep<'a> {
+    database: &'a Database,
+    workspace: &'a Workspace,
+    regex: Regex,
+    options: GrepOptions,
+}
+
+impl<'a> Grep<'a> {
+    pub fn new(
+        database: &'a Database,
+        workspace: &'a Workspace,
+        pattern: &str,
+        options: GrepOptions,
+    ) -> Result<Self> {
+        let pattern = match options.pattern_type {
+            PatternType::Basic => basic_to_extended(pattern),
+            PatternType::Extended | PatternType::Perl => pattern.to_string(),
+            PatternType::Fixed => regex::escape(pattern),
+        };
+        let pattern = if options.word_regexp {
+            format!(r"\b(?:{})\b", pattern)
+        } else {
+            pattern
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(options.ignore_case)
+            .build()
+            .with_context(|| format!("invalid pattern: {}", pattern))?;
+        Ok(Self {
+            database,
+            workspace,
+            regex,
+            options,
+        })
+    }
+
+    /// Searches the files with `jobs` threads and writes the results in the order of `files`.
+    /// Returns whether anything matched.
+    pub fn run(&self, out: &mut dyn Write, files: &[GrepFile], jobs: usize) -> Result<bool> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .context("Failed to build grep thread pool")?;
+        let results: Vec<Vec<u8>> = pool.install(|| {
+            files
+                .par_iter()
+                .map(|file| self.search(file))
+                .collect::<Result<_>>()
+        })?;
+
+        let has_context = self.options.before_context > 0 || self.options.after_context > 0;
+        let mut found = false;
+        for result in results.iter().filter(|result| !result.is_empty()) {
+            if found && has_context && !self.options.files_with_matches && !self.options.count {
+                writeln!(out, "--")?;
+            }
+            out.write_all(result)?;
+            found = true;
+        }
+        Ok(found)
+    }
+
+    /// Searches a single file, the output is empty when nothing matched
+    fn search(&self, file: &GrepFile) -> Result<Vec<u8>> {
+        let data = match &file.content {
+            Content::Workspace(path) => self.workspace.read_file(Path::new(path))?,
+            Content::Blob(object_id) => self.database.load(object_id)?.1,
+        };
+        let mut out = vec![];
+        if is_binary(&data) {
+            if self.regex.is_match(&data) {
+                writeln!(out, "Binary file {} matches", file.name)?;
+            }
+            return Ok(out);
+        }
+
+        let lines = lines(&data);
+        let matches: Vec<usize> = (0..lines.len())
+            .filter(|&i| self.regex.is_match(lines[i]))
+            .collect();
+        if matches.is_empty() {
+            return Ok(out);
+        }
+        if self.options.files_with_matches {
+            writeln!(out, "{}", file.name)?;
+            return Ok(out);
+        }
+        if self.options.count {
+            writeln!(out, "{}:{}", file.name, matches.len())?;
+            return Ok(out);
+        }
+
+        // Line indices to show, with whether they matched
+        let mut shown: BTreeMap<usize, bool> = BTreeMap::new();
+        for &i in &matches {
+            let start = i.saturating_sub(self.options.before_context);
+            let end = (i + self.options.after_context).min(lines.len() - 1);
+            for j in start..=end {
+                *shown.entry(j).or_default() |= j == i;
+            }
+        }
+
+        let has_context = self.options.before_context > 0 || self.options.after_context > 0;
+        let mut previous = None;
+        for (i, is_match) in shown {
+            if has_context && previous.is_some_and(|previous| previous + 1 != i) {
+                writeln!(out, "--")?;
+            }
+            let separator = if is_match { ':' } else { '-' };
+            write!(out, "{}{}", file.name, separator)?;
+            if self.options.line_number {
+                write!(out, "{}{}", i + 1, separator)?;
+            }
+            out.write_all(lines[i])?;
+            if !lines[i].ends_with(b"\n") {
+                writeln!(out)?;
+            }
+            previous = Some(i);
+        }
+        Ok(out)
+    }
+}
+
+/// Rewrites a POSIX basic regex with the extended syntax: the escaped operators lose their
+/// backslash and the bare ones become literal characters
+fn basic_to_extended(pattern: &str) -> String {
+    let mut result = String::new();
+    let mut chars = pattern.chars();
+    let mut in_brackets = false;
+    while let Some(c) = chars.next() {
+        if in_brackets {
+            result.push(c);
+            in_brackets = c != ']';
+            continue;
+        }
+        match c {
+            '\\' => match chars.next() {
+                Some(c @ ('(' | ')' | '{' | '}' | '|' | '+' | '?')) => result.push(c),
+                Some(c) => {
+                    result.push('\\');
+                    result.push(c);
+                }
+                None => result.push_str(r"\\"),
+            },
+            '(' | ')' | '{' | '}' | '|' | '+' | '?' => {
+                result.push('\\');
+                result.push(c);
+            }
+            '[' => {
+                in_brackets = true;
+                result.push(c);
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn grep_lines() -> Result<()> {
+        assert_eq!(basic_to_extended(r"a\(b\|c\)+[(]"), r"a(b|c)\+[(]");
+
+        let dir = test_utils::temp_path("grep");
+        let workspace = Workspace::new(dir.clone());
+        let database = Database::new(dir.join("objects"));
+        workspace.write_file(
+            Path::new("a.txt"),
+            b"one\nfoo two\nthree\nfour\nfoobar\n",
+            false,
+        )?;
+        let files = vec![GrepFile {
+            name: "a.txt".into(),
+            content: Content::Workspace("a.txt".into()),
+        }];
+
+        let options = GrepOptions {
+            word_regexp: true,
+            line_number: true,
+            ..GrepOptions::default()
+        };
+        let mut out = vec![];
+        let grep = Grep::new(&database, &workspace, "FOO", options.clone())?;
+        assert!(!grep.run(&mut out, &files, 1)?);
+
+        let options = GrepOptions {
+            ignore_case: true,
+            after_context: 1,
+            ..options
+        };
+        let grep = Grep::new(&database, &workspace, "FOO", options)?;
+        assert!(grep.run(&mut out, &files, 1)?);
+        assert_eq!(String::from_utf8(out)?, "a.txt:2:foo two\na.txt-3-three\n");
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    database::{commit::Commit, tree::TreeItem, Database},
+    diff::{
+        patch::{tree_changes, FileChange},
+        rename::{self, Detection},
+    },
+    pathspec::Pathspec,
+    rev_list, ObjectId,
+};
+
+/// A commit shown by `log`
+pub struct LogEntry {
+    pub commit_id: ObjectId,
+    pub commit: Commit,
+    /// What the commit changed compared to its first parent, limited to the paths of the log.
+    /// It's only filled when the log is limited to paths or asked for the changes.
+    pub changes: Vec<FileChange>,
+}
+
+/// Walks the history from newest to oldest committer date, like `git log`. A log limited to
+/// paths only shows the commits changing them compared to their first parent, there's no
+/// history simplification of merges.
+pub struct Log<'a> {
+    database: &'a Database,
+    pathspec: Pathspec,
+    /// The path `--follow` tracks, it becomes the old name whenever the file was renamed
+    follow: Option<String>,
+    with_changes: bool,
+}
+
+impl<'a> Log<'a> {
+    pub fn new(database: &'a Database, pathspec: Pathspec) -> Self {
+        Self {
+            database,
+            pathspec,
+            follow: None,
+            with_changes: false,
+        }
+    }
+
+    /// Limits the log to a single file and keeps following it across renames
+    pub fn follow(database: &'a Database, path: &str) -> Result<Self> {
+        let path = path.trim_end_matches('/');
+        if path.is_empty() {
+            bail!("--follow requires exactly one pathspec");
+        }
+        Ok(Self {
+            follow: Some(path.to_string()),
+            ..Self::new(database, Pathspec::default())
+        })
+    }
+
+    /// Also computes the changes of the commits when the log isn't limited to paths
+    pub fn with_changes(mut self) -> Self {
+        self.with_changes = true;
+        self
+    }
+
+    pub fn walk(&mut self, tips: &[ObjectId]) -> Result<Vec<LogEntry>> {
+        let mut queue = Queue::default();
+        for commit_id in tips {
+            queue.push(self.database, commit_id)?;
+        }
+
+        let mut entries = vec![];
+        while let Some((commit_id, commit)) = queue.pop() {
+            for parent in rev_list::parents(self.database, &commit_id)? {
+                queue.push(self.database, &parent)?;
+            }
+
+            let is_limited = self.follow.is_some() || !self.pathspec.is_empty();
+            let changes = if is_limited || self.with_changes {
+                self.changes(&commit_id, &commit)?
+            } else {
+                vec![]
+            };
+            if is_limited && changes.is_empty() {
+                continue;
+            }
+            entries.push(LogEntry {
+                commit_id,
+                commit,
+                changes,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn changes(&mut self, commit_id: &str, commit: &Commit) -> Result<Vec<FileChange>> {
+        let new = self.database.load_tree_entries(commit.tree_id())?;
+        let old = match rev_list::parents(self.database, commit_id)?.first() {
+            Some(parent) => self
+                .database
+                .load_tree_entries(&self.database.load_tree_id(parent)?)?,
+            None => BTreeMap::new(),
+        };
+
+        let path = match &self.follow {
+            Some(path) => path.clone(),
+            None => {
+                let limit = |items: BTreeMap<String, TreeItem>| -> BTreeMap<String, TreeItem> {
+                    items
+                        .into_iter()
+                        .filter(|(path, _)| self.pathspec.matches(path))
+                        .collect()
+                };
+                return Ok(tree_changes(&limit(old), &limit(new)));
+            }
+        };
+
+        let changes = tree_changes(&old, &new);
+        if !changes.iter().any(|change| change.path == path) {
+            return Ok(vec![]);
+        }
+        let is_added = changes
+            .iter()
+            .any(|change| change.path == path && change.old.is_none());
+        // Only look for the origin of the file when it appeared, renames are rare
+        let changes = if is_added {
+            rename::detect(self.database, changes, &Detection::renames())?
+        } else {
+            changes
+        };
+        let change = changes
+            .into_iter()
+            .find(|change| change.path == path)
+            .expect("Rename detection lost a change");
+        if let Some(origin) = &change.origin {
+            self.follow = Some(origin.path.clone());
+        }
+        Ok(vec![change])
+    }
+}
+
+/// Commits waiting to be shown, the newest committer date first. Like git, commits with the
+/// same date come out in the order they were found, so children come before their parents.
+#[derive(Default)]
+struct Queue {
+    heap: BinaryHeap<(i64, Reverse<usize>, ObjectId)>,
+    commits: HashMap<ObjectId, Commit>,
+    seen: HashSet<ObjectId>,
+}
+
+impl Queue {
+    fn push(&mut self, database: &Database, commit_id: &str) -> Result<()> {
+        if !self.seen.insert(commit_id.to_string()) {
+            return Ok(());
+        }
+        let (_, data) = database.load(commit_id)?;
+        let commit = Commit::parse(&data)?;
+        let time = commit.committer().time.timestamp();
+        self.heap
+            .push((time, Reverse(self.seen.len()), commit_id.to_string()));
+        self.commits.insert(commit_id.to_string(), commit);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<(ObjectId, Commit)> {
+        let (_, _, commit_id) = self.heap.pop()?;
+        let commit = self.commits.remove(&commit_id)?;
+        Some((commit_id, commit))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{database::blob::Blob, test_utils};
+
+    #[test]
+    fn follow_renames() -> Result<()> {
+        let dir = test_utils::temp_path("history");
+        let database = Database::new(dir.clone());
+        let author = test_utils::test_author();
+        let commit = |parents: &[&ObjectId], files: &[(&str, &str)], message: &str| {
+            let items: BTreeMap<String, TreeItem> = files
+                .iter()
+                .map(|(path, content)| {
+                    let object_id = database.store(&Blob::new(content.as_bytes().to_vec()));
+                    let item = TreeItem {
+                        mode: 0o100644,
+                        object_id: object_id.unwrap(),
+                    };
+                    (path.to_string(), item)
+                })
+                .collect();
+            let tree_id = database.store_tree(&items).unwrap();
+            let parents = parents.iter().map(|parent| parent.to_string()).collect();
+            let commit = Commit::new(parents, tree_id, author.clone(), message.into());
+            database.store(&commit).unwrap()
+        };
+        let content = "one\ntwo\nthree\nfour\nfive\n";
+
+        let a = commit(&[], &[("a.txt", content)], "add a");
+        let b = commit(&[&a], &[("a.txt", content), ("b", "b")], "add b");
+        let c = commit(&[&b], &[("c.txt", content), ("b", "b")], "rename a");
+        let d = commit(&[&c], &[("c.txt", "changed"), ("b", "b")], "change c");
+        let commit_ids = |entries: Vec<LogEntry>| -> Vec<ObjectId> {
+            entries.into_iter().map(|entry| entry.commit_id).collect()
+        };
+
+        let mut log = Log::new(&database, Pathspec::default());
+        assert_eq!(
+            commit_ids(log.walk(std::slice::from_ref(&d))?),
+            vec![d.clone(), c.clone(), b, a.clone()]
+        );
+        let mut log = Log::new(&database, Pathspec::parse(&["c.txt"], "")?);
+        assert_eq!(
+            commit_ids(log.walk(std::slice::from_ref(&d))?),
+            vec![d.clone(), c.clone()]
+        );
+
+        let mut log = Log::follow(&database, "c.txt")?;
+        let entries = log.walk(std::slice::from_ref(&d))?;
+        let statuses: Vec<char> = entries
+            .iter()
+            .map(|entry| entry.changes[0].status())
+            .collect();
+        assert_eq!(statuses, vec!['M', 'R', 'A']);
+        assert_eq!(entries[2].changes[0].path, "a.txt");
+        assert_eq!(commit_ids(entries), vec![d, c, a]);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -11,9 +11,10 @@ use std::{
 use anyhow::{bail, Context, Result};
 
 use crate::{
-    database::{MODE_EXECUTABLE, MODE_REGULAR},
+    database::{tree::TreeItem, MODE_EXECUTABLE, MODE_REGULAR},
     lockfile::Lockfile,
-    utils::deserialize_hash,
+    pathspec::Pathspec,
+    utils::{deserialize_hash, serialize_hash},
     HashWriter, Metadata, ObjectId,
 };
 
@@ -24,11 +25,23 @@ const HEADER_SIZE: usize = 12;
 const CHECKSUM_SIZE: usize = 20;
 const SIGNATURE: &str = "DIRC";
 const ENTRY_MIN_SIZE: usize = 64;
+const EMPTY_BLOB_ID: &str = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+const ENTRY_STAGE_SHIFT: u16 = 12;
+const ENTRY_STAGE_MASK: u16 = 0x3000;
+
+/// Conflict stages of an unmerged path, stage 0 is used for merged entries
+pub const STAGE_BASE: u16 = 1;
+pub const STAGE_OURS: u16 = 2;
+pub const STAGE_THEIRS: u16 = 3;
 
 pub struct Index {
-    entries: BTreeMap<String, Entry>,
+    path: PathBuf,
+    /// Entries are keyed by path and stage so the sides of a conflict sort together
+    entries: BTreeMap<(String, u16), Entry>,
     lockfile: Lockfile,
     changed: bool,
+    /// Modification time of the index file when it was loaded
+    mtime: Option<SystemTime>,
 }
 
 impl Index {
@@ -36,19 +49,187 @@ impl Index {
         Self {
             entries: BTreeMap::new(),
             lockfile: Lockfile::new(&path),
+            path,
             changed: false,
+            mtime: None,
         }
     }
 
     pub fn add(&mut self, path: String, object_id: ObjectId, metadata: &Metadata) -> Result<()> {
         let entry = Entry::new(path, object_id, metadata)?;
+        // Staging a path resolves its conflict
+        for stage in STAGE_BASE..=STAGE_THEIRS {
+            self.entries.remove(&(entry.path.clone(), stage));
+        }
+        self.insert_entry(entry);
+        self.changed = true;
+        Ok(())
+    }
+
+    /// Returns the merged entries as tree items, ready to be stored as a tree
+    pub fn tree_items(&self) -> BTreeMap<String, TreeItem> {
+        self.entries
+            .values()
+            .filter(|entry| entry.stage() == 0)
+            .map(|entry| {
+                let item = TreeItem {
+                    mode: entry.mode,
+                    object_id: entry.oid.clone(),
+                };
+                (entry.path.clone(), item)
+            })
+            .collect()
+    }
+
+    /// Records one side of a conflict, replacing the merged entry of the path
+    pub fn add_conflict(&mut self, path: String, stage: u16, item: &TreeItem) -> Result<()> {
+        if !(STAGE_BASE..=STAGE_THEIRS).contains(&stage) {
+            bail!("Invalid conflict stage: {}", stage);
+        }
+        let metadata = Metadata {
+            is_executable: item.mode == MODE_EXECUTABLE as u32,
+            ..Metadata::default()
+        };
+        let mut entry = Entry::new(path, item.object_id.clone(), &metadata)?;
+        entry.flags |= stage << ENTRY_STAGE_SHIFT;
+        self.entries.remove(&(entry.path.clone(), 0));
         self.insert_entry(entry);
         self.changed = true;
         Ok(())
     }
 
     fn insert_entry(&mut self, entry: Entry) {
-        self.entries.insert(entry.path.clone(), entry);
+        self.entries
+            .insert((entry.path.clone(), entry.stage()), entry);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.values()
+    }
+
+    /// Returns the merged entry at `path`
+    pub fn entry(&self, path: &str) -> Option<&Entry> {
+        self.entries.get(&(path.to_string(), 0))
+    }
+
+    /// Returns one side of the conflict at `path`
+    pub fn conflict_entry(&self, path: &str, stage: u16) -> Option<&Entry> {
+        self.entries.get(&(path.to_string(), stage))
+    }
+
+    /// Checks if the path, or any path under it, has unresolved conflicts
+    pub fn is_conflicted(&self, path: &str) -> bool {
+        self.entries_under(path).any(|entry| entry.stage() > 0)
+    }
+
+    pub fn has_conflicts(&self) -> bool {
+        self.entries.values().any(|entry| entry.stage() > 0)
+    }
+
+    /// Adds an entry read from a tree, it has no stat information until it is refreshed
+    pub fn add_tree_item(&mut self, path: String, item: &TreeItem) -> Result<()> {
+        let metadata = Metadata {
+            is_executable: item.mode == MODE_EXECUTABLE as u32,
+            ..Metadata::default()
+        };
+        self.add(path, item.object_id.clone(), &metadata)
+    }
+
+    /// Replaces the stat information of the merged entry at `path` with the metadata of its
+    /// file, which was found unchanged, so the next stat check doesn't hash it again
+    pub fn refresh(&mut self, path: &str, metadata: &Metadata) -> Result<()> {
+        let entry = match self.entries.get_mut(&(path.to_string(), 0)) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        *entry = Entry::new(path.to_string(), entry.oid.clone(), metadata)?;
+        self.changed = true;
+        Ok(())
+    }
+
+    /// Removes the entries at `path`, or every entry under it if it's a directory
+    pub fn remove(&mut self, path: &str) -> Vec<Entry> {
+        let removed_paths: Vec<(String, u16)> = self
+            .entries_under(path)
+            .map(|entry| (entry.path.clone(), entry.stage()))
+            .collect();
+
+        if !removed_paths.is_empty() {
+            self.changed = true;
+        }
+        removed_paths
+            .iter()
+            .filter_map(|entry_path| self.entries.remove(entry_path))
+            .collect()
+    }
+
+    /// Returns the entries at `path` or every entry under it if it's a directory
+    pub fn entries_under<'a>(&'a self, path: &str) -> impl Iterator<Item = &'a Entry> + 'a {
+        let path = path.trim_end_matches('/').to_string();
+        let prefix = format!("{}/", path);
+        self.entries
+            .values()
+            .filter(move |entry| entry.path == path || entry.path.starts_with(&prefix))
+    }
+
+    /// Checks if the path is tracked, either as a file or as a directory containing files
+    pub fn is_tracked(&self, path: &str) -> bool {
+        self.entries_under(path).next().is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.changed = true;
+    }
+
+    /// Replaces every entry with the content of a tree
+    pub fn reset_to(&mut self, items: &BTreeMap<String, TreeItem>) -> Result<()> {
+        self.entries.clear();
+        self.changed = true;
+        for (path, item) in items {
+            self.add_tree_item(path.clone(), item)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the entries matching the pathspec with the ones from a tree
+    pub fn reset_paths(
+        &mut self,
+        pathspec: &Pathspec,
+        items: &BTreeMap<String, TreeItem>,
+    ) -> Result<()> {
+        self.entries.retain(|(path, _), _| !pathspec.matches(path));
+        self.changed = true;
+        for (path, item) in items.iter().filter(|(path, _)| pathspec.matches(path)) {
+            self.add_tree_item(path.clone(), item)?;
+        }
+        Ok(())
+    }
+
+    /// Checks if the file at `path` can be considered unchanged by only looking at its metadata.
+    /// Entries modified in the same second the index was written are racily clean: the file
+    /// could have changed again without its stat information changing, so they are never clean.
+    pub fn is_stat_clean(&self, path: &str, metadata: &Metadata) -> bool {
+        let entry = match self.entry(path) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        // A smudged entry needs its content to be checked
+        if entry.size == 0 && entry.oid != EMPTY_BLOB_ID {
+            return false;
+        }
+
+        if let Some(index_mtime) = self.mtime {
+            if entry.is_racy(index_mtime) {
+                return false;
+            }
+        }
+
+        match Entry::new(path.into(), entry.oid.clone(), metadata) {
+            Ok(stat) => entry.stat_match(&stat),
+            Err(_) => false,
+        }
     }
 
     pub fn write_updates(&mut self) -> Result<()> {
@@ -64,7 +245,13 @@ impl Index {
         header_bytes.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
         writer.write(&header_bytes)?;
 
-        for entry in self.entries.values() {
+        // The index file will be at least this recent so any entry modified after this point
+        // would look clean to the next reader even if it changes again in the same second
+        let now = SystemTime::now();
+        for entry in self.entries.values_mut() {
+            if entry.is_racy(now) {
+                entry.smudge();
+            }
             writer.write(&entry.serialize()?)?;
         }
 
@@ -78,11 +265,29 @@ impl Index {
 
     pub fn load_for_update(&mut self) -> Result<()> {
         self.lockfile.hold_for_update()?;
+        self.load()
+    }
 
+    /// Reads the index and takes its lock unless another process holds it. Returns whether the
+    /// lock was taken, commands that only read the index then write back refreshed entries
+    pub fn load_for_refresh(&mut self) -> Result<bool> {
+        let locked = self.lockfile.hold_for_update().is_ok();
+        self.load()?;
+        Ok(locked)
+    }
+
+    /// Reads the index without holding its lock, changes can't be written back
+    pub fn load(&mut self) -> Result<()> {
         self.entries = Default::default();
         self.changed = false;
+        self.mtime = None;
+
+        if !self.path.exists() {
+            return Ok(());
+        }
 
-        let file = OpenOptions::new().read(true).open("foo.txt")?;
+        let file = OpenOptions::new().read(true).open(&self.path)?;
+        self.mtime = Some(file.metadata()?.modified()?);
         let mut reader = ChecksumBuf::new(file);
 
         let data = reader.read(HEADER_SIZE)?;
@@ -104,7 +309,7 @@ impl Index {
 
         for _ in 0..count {
             let mut bytes = reader.read(ENTRY_MIN_SIZE)?;
-            while bytes.last().context("load_for_update")? != &b'\0' {
+            while bytes.last().context("load")? != &b'\0' {
                 bytes.extend_from_slice(&reader.read(ENTRY_BLOCK_SIZE)?);
             }
             self.insert_entry(Entry::deserialize(bytes)?);
@@ -117,7 +322,7 @@ impl Index {
 }
 
 #[derive(Debug, Clone)]
-struct Entry {
+pub struct Entry {
     ctime: u32,
     ctime_nsec: u32,
     mtime: u32,
@@ -134,26 +339,40 @@ struct Entry {
 }
 
 impl Entry {
-    // TODO add a #[cfg(unix)] version
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn object_id(&self) -> &ObjectId {
+        &self.oid
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    pub fn stage(&self) -> u16 {
+        (self.flags & ENTRY_STAGE_MASK) >> ENTRY_STAGE_SHIFT
+    }
+
     pub fn new(path: String, object_id: ObjectId, metadata: &Metadata) -> Result<Self> {
         let created = metadata.created.duration_since(SystemTime::UNIX_EPOCH)?;
         let modified = metadata.modified.duration_since(SystemTime::UNIX_EPOCH)?;
 
         Ok(Self {
             ctime: created.as_secs() as u32,
-            ctime_nsec: created.as_nanos() as u32,
+            ctime_nsec: created.subsec_nanos(),
             mtime: modified.as_secs() as u32,
-            mtime_nsec: modified.as_nanos() as u32,
+            mtime_nsec: modified.subsec_nanos(),
             dev: metadata.device_id,
-            // ino, uid, gid are set to 0 on windows because they don't have an equivalent
-            ino: 0,
+            ino: metadata.inode,
             mode: if metadata.is_executable {
                 MODE_EXECUTABLE as u32
             } else {
                 MODE_REGULAR as u32
             },
-            uid: 0,
-            gid: 0,
+            uid: metadata.uid,
+            gid: metadata.gid,
             size: metadata.len,
             oid: object_id,
             flags: min(path.len(), ENTRY_MAX_PATH_SIZE) as u16,
@@ -161,6 +380,33 @@ impl Entry {
         })
     }
 
+    /// Compares the stat information of both entries
+    fn stat_match(&self, other: &Entry) -> bool {
+        self.mode == other.mode
+            && self.size == other.size
+            && self.mtime == other.mtime
+            && self.mtime_nsec == other.mtime_nsec
+            && self.ctime == other.ctime
+            && self.ctime_nsec == other.ctime_nsec
+            && self.ino == other.ino
+            && self.dev == other.dev
+            && self.uid == other.uid
+            && self.gid == other.gid
+    }
+
+    /// Timestamps are only compared to the second like git does when it isn't built with USE_NSEC
+    fn is_racy(&self, index_mtime: SystemTime) -> bool {
+        let index_mtime = index_mtime
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |mtime| mtime.as_secs());
+        self.mtime as u64 >= index_mtime
+    }
+
+    /// Clearing the size forces the next stat check to look at the content of the file
+    fn smudge(&mut self) {
+        self.size = 0;
+    }
+
     fn serialize(&self) -> Result<Vec<u8>> {
         let mut bytes = vec![];
 
@@ -278,7 +524,7 @@ where
 
     fn write_checksum(&mut self) -> Result<()> {
         let checksum = self.hash_writer.finish();
-        self.buf.write_all(checksum.as_bytes())?;
+        self.buf.write_all(&serialize_hash(&checksum)?)?;
         Ok(())
     }
 
@@ -303,7 +549,10 @@ where
 
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
     use super::*;
+    use crate::test_utils;
 
     #[test]
     fn entry_serialization() -> Result<()> {
@@ -318,9 +567,7 @@ mod test {
                 created: SystemTime::now(),
                 modified: SystemTime::now(),
                 accessed: SystemTime::now(),
-                len: 0,
-                is_executable: false,
-                device_id: 0,
+                ..Metadata::default()
             },
         )?;
 
@@ -331,4 +578,139 @@ mod test {
 
         Ok(())
     }
+
+    fn metadata(modified: SystemTime, len: u32) -> Metadata {
+        Metadata {
+            created: modified,
+            modified,
+            accessed: modified,
+            len,
+            inode: 42,
+            ..Metadata::default()
+        }
+    }
+
+    fn temp_index() -> Index {
+        let dir = test_utils::temp_path("index");
+        std::fs::create_dir_all(&dir).unwrap();
+        Index::new(dir.join("index"))
+    }
+
+    #[test]
+    fn stat_clean() -> Result<()> {
+        let old = SystemTime::now() - Duration::from_secs(60);
+        let mut index = temp_index();
+        index.load_for_update()?;
+        index.add("file".into(), "a".repeat(40), &metadata(old, 5))?;
+        index.mtime = Some(SystemTime::now());
+
+        assert!(index.is_stat_clean("file", &metadata(old, 5)));
+        assert!(!index.is_stat_clean("file", &metadata(old, 6)));
+        assert!(!index.is_stat_clean("file", &metadata(SystemTime::now(), 5)));
+        assert!(!index.is_stat_clean("other", &metadata(old, 5)));
+
+        // Written in the same second as the file was modified
+        index.mtime = Some(old);
+        assert!(!index.is_stat_clean("file", &metadata(old, 5)));
+
+        index.lockfile.rollback()?;
+        std::fs::remove_dir_all(index.path.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn reset_paths() -> Result<()> {
+        let old = SystemTime::now() - Duration::from_secs(60);
+        let mut index = temp_index();
+        index.load_for_update()?;
+        index.add("a".into(), "a".repeat(40), &metadata(old, 5))?;
+        index.add("dir/b".into(), "b".repeat(40), &metadata(old, 5))?;
+        index.add("dir/c".into(), "c".repeat(40), &metadata(old, 5))?;
+
+        let mut items = BTreeMap::new();
+        let item = |c: &str| TreeItem {
+            mode: MODE_REGULAR as u32,
+            object_id: c.repeat(40),
+        };
+        items.insert("a".to_string(), item("1"));
+        items.insert("dir/b".to_string(), item("2"));
+        index.reset_paths(&Pathspec::parse(&["dir/"], "")?, &items)?;
+
+        let paths: Vec<_> = index.entries().map(|entry| entry.path()).collect();
+        assert_eq!(paths, vec!["a", "dir/b"]);
+        assert_eq!(index.entry("a").unwrap().object_id(), &"a".repeat(40));
+        assert_eq!(index.entry("dir/b").unwrap().object_id(), &"2".repeat(40));
+
+        assert!(index.is_tracked("dir"));
+        assert!(!index.is_tracked("di"));
+        let removed: Vec<_> = index
+            .remove("dir")
+            .iter()
+            .map(|entry| entry.path().to_string())
+            .collect();
+        assert_eq!(removed, vec!["dir/b"]);
+        assert!(!index.is_tracked("dir"));
+
+        index.lockfile.rollback()?;
+        std::fs::remove_dir_all(index.path.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn racy_entries_are_smudged_on_write() -> Result<()> {
+        let old = SystemTime::now() - Duration::from_secs(60);
+        let future = SystemTime::now() + Duration::from_secs(60);
+        let mut index = temp_index();
+        index.load_for_update()?;
+        index.add("old".into(), "a".repeat(40), &metadata(old, 5))?;
+        index.add("racy".into(), "b".repeat(40), &metadata(future, 5))?;
+        index.write_updates()?;
+
+        index.load_for_update()?;
+        assert_eq!(index.entry("old").unwrap().size, 5);
+        assert_eq!(index.entry("racy").unwrap().size, 0);
+        assert!(index.is_stat_clean("old", &metadata(old, 5)));
+        assert!(!index.is_stat_clean("racy", &metadata(future, 5)));
+        index.lockfile.rollback()?;
+
+        std::fs::remove_dir_all(index.path.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn conflict_stages() -> Result<()> {
+        let old = SystemTime::now() - Duration::from_secs(60);
+        let mut index = temp_index();
+        index.load_for_update()?;
+        index.add("file".into(), "a".repeat(40), &metadata(old, 5))?;
+
+        let item = |c: &str| TreeItem {
+            mode: MODE_REGULAR as u32,
+            object_id: c.repeat(40),
+        };
+        index.add_conflict("file".into(), STAGE_OURS, &item("2"))?;
+        index.add_conflict("file".into(), STAGE_THEIRS, &item("3"))?;
+        assert!(index.entry("file").is_none());
+        assert!(index.is_conflicted("file"));
+        index.write_updates()?;
+
+        index.load_for_update()?;
+        let stages: Vec<_> = index.entries().map(|entry| entry.stage()).collect();
+        assert_eq!(stages, vec![STAGE_OURS, STAGE_THEIRS]);
+        assert_eq!(
+            index
+                .conflict_entry("file", STAGE_THEIRS)
+                .unwrap()
+                .object_id(),
+            &"3".repeat(40)
+        );
+
+        index.add("file".into(), "b".repeat(40), &metadata(old, 5))?;
+        assert!(!index.has_conflicts());
+        assert_eq!(index.entries().count(), 1);
+        index.lockfile.rollback()?;
+
+        std::fs::remove_dir_all(index.path.parent().unwrap())?;
+        Ok(())
+    }
 }
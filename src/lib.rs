@@ -1,32 +1,53 @@
 #![allow(clippy::expect_fun_call)]
 
+pub mod bisect;
+pub mod blame;
+pub mod config;
 pub mod database;
+pub mod diff;
+pub mod grep;
+pub mod history;
 pub mod index;
 pub mod lockfile;
+pub mod merge;
+pub mod migration;
+pub mod pathspec;
+pub mod pull;
+pub mod reflog;
+pub mod refs;
+pub mod remote;
+pub mod rev_list;
+pub mod revision;
+pub mod sequencer;
+pub mod staging;
+pub mod stash;
+#[cfg(test)]
+mod test_utils;
 pub mod utils;
 pub mod workspace;
 
 use std::{
     fmt::{self, Display, Formatter},
-    io::Write,
-    path::PathBuf,
+    str::FromStr,
     time::SystemTime,
 };
 
-use anyhow::{Context, Result};
-use chrono::{DateTime, Utc};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
 use crypto::{digest::Digest, sha1::Sha1};
 
-use lockfile::Lockfile;
+pub use refs::Refs;
 
 type ObjectId = String;
 
 pub const GIT_FOLDER: &str = "git"; // TODO reset to .git
 
+#[derive(Clone)]
 pub struct Author {
     pub name: String,
     pub email: String,
-    pub time: DateTime<Utc>,
+    /// Keeps the timezone of the identity, rewriting a commit must not change it
+    pub time: DateTime<FixedOffset>,
 }
 
 impl Display for Author {
@@ -37,45 +58,66 @@ impl Display for Author {
             self.name,
             self.email,
             self.time.timestamp(),
-            self.time.timezone()
+            self.time.format("%z")
         )
     }
 }
 
-pub struct Refs {
-    path: PathBuf,
-}
-
-impl Refs {
-    pub fn new(path: PathBuf) -> Self {
-        Self { path }
+impl FromStr for Author {
+    type Err = anyhow::Error;
+
+    /// Parses the `name <email> timestamp timezone` format used by commits and reflogs
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, rest) = s
+            .split_once(" <")
+            .with_context(|| format!("Invalid identity: {}", s))?;
+        let (email, rest) = rest
+            .split_once("> ")
+            .with_context(|| format!("Invalid identity: {}", s))?;
+        let mut fields = rest.split(' ');
+        let timestamp = fields
+            .next()
+            .with_context(|| format!("Invalid identity: {}", s))?
+            .parse()?;
+        let offset = match fields.next() {
+            Some(timezone) => parse_timezone(timezone)?,
+            None => FixedOffset::east_opt(0).context("Invalid timezone")?,
+        };
+
+        Ok(Self {
+            name: name.into(),
+            email: email.into(),
+            time: offset
+                .timestamp_opt(timestamp, 0)
+                .single()
+                .context("Invalid timestamp")?,
+        })
     }
+}
 
-    pub fn head_path(&self) -> PathBuf {
-        self.path.join("HEAD")
-    }
-
-    pub fn update_head(&self, object_id: ObjectId) -> Result<()> {
-        let mut lockfile = Lockfile::new(&self.head_path());
-
-        lockfile.hold_for_update().with_context(|| {
-            format!(
-                "Could not acquire lock on file: {}",
-                self.head_path().display()
-            )
-        })?;
-
-        lockfile.write_all(&object_id.as_bytes())?;
-        lockfile.write_all(b"\n")?;
-        lockfile.commit()?;
-        Ok(())
+/// Parses a timezone like `-0500` into its offset from UTC
+fn parse_timezone(timezone: &str) -> Result<FixedOffset> {
+    let invalid = || format!("Invalid timezone: {}", timezone);
+    let (sign, digits) = match timezone.split_at_checked(1) {
+        Some(("+", digits)) => (1, digits),
+        Some(("-", digits)) => (-1, digits),
+        _ => bail!(invalid()),
+    };
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        bail!(invalid());
     }
+    let hours: i32 = digits[..2].parse()?;
+    let minutes: i32 = digits[2..].parse()?;
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).with_context(invalid)
+}
 
-    pub fn read_head(&self) -> Option<ObjectId> {
-        if self.head_path().exists() {
-            std::fs::read_to_string(self.head_path()).ok()
-        } else {
-            None
+impl Author {
+    /// Reads the identity from GIT_AUTHOR_NAME and GIT_AUTHOR_EMAIL
+    pub fn from_env() -> Self {
+        Self {
+            name: std::env::var("GIT_AUTHOR_NAME").expect("GIT_AUTHOR_NAME is undefined"),
+            email: std::env::var("GIT_AUTHOR_EMAIL").expect("GIT_AUTHOR_EMAIL is undefined"),
+            time: Utc::now().into(),
         }
     }
 }
@@ -121,4 +163,50 @@ pub struct Metadata {
     pub len: u32,
     pub is_executable: bool,
     pub device_id: u32,
+    pub inode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Self {
+            created: SystemTime::UNIX_EPOCH,
+            modified: SystemTime::UNIX_EPOCH,
+            accessed: SystemTime::UNIX_EPOCH,
+            len: 0,
+            is_executable: false,
+            device_id: 0,
+            inode: 0,
+            uid: 0,
+            gid: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn author_keeps_timezone() -> Result<()> {
+        let line = "A U Thor <author@example.com> 1600000000 -0530";
+        let author: Author = line.parse()?;
+        assert_eq!(author.time.timestamp(), 1_600_000_000);
+        assert_eq!(
+            author.time.offset().local_minus_utc(),
+            -(5 * 3600 + 30 * 60)
+        );
+        assert_eq!(author.to_string(), line);
+
+        let author: Author = "A U Thor <author@example.com> 1600000000 +0200".parse()?;
+        assert_eq!(
+            author.to_string(),
+            "A U Thor <author@example.com> 1600000000 +0200"
+        );
+        assert!("A U Thor <author@example.com> 1600000000 0200"
+            .parse::<Author>()
+            .is_err());
+        Ok(())
+    }
 }
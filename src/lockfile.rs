@@ -16,17 +16,18 @@ impl Lockfile {
     pub fn new(path: &Path) -> Self {
         Self {
             file_path: path.to_path_buf(),
-            lock_path: path.with_extension("lock"),
+            lock_path: lock_path(path),
             lock: None,
         }
     }
 
+    /// Takes the lock, failing if another process holds it
     pub fn hold_for_update(&mut self) -> Result<()> {
         if self.lock.is_none() {
             let file = OpenOptions::new()
                 .read(true)
                 .write(true)
-                .create(true)
+                .create_new(true)
                 .open(&self.lock_path)?;
 
             self.lock = Some(file);
@@ -58,11 +59,27 @@ impl Lockfile {
     }
 }
 
+impl Drop for Lockfile {
+    /// Releases the lock if it wasn't committed or rolled back, like when a command fails
+    fn drop(&mut self) {
+        if self.lock.is_some() {
+            let _ = std::fs::remove_file(&self.lock_path);
+        }
+    }
+}
+
+/// Appends .lock to the full file name, `with_extension` would replace anything after a dot
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock_path = path.as_os_str().to_os_string();
+    lock_path.push(".lock");
+    PathBuf::from(lock_path)
+}
+
 impl Write for Lockfile {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         match self.raise_on_stale_lock() {
             Ok(_) => (),
-            Err(err) => return Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
+            Err(err) => return Err(std::io::Error::other(err)),
         }
 
         if let Some(mut file) = self.lock.as_ref() {
@@ -87,11 +104,11 @@ impl Write for Lockfile {
     }
 }
 
-impl<'a> Write for &'a Lockfile {
+impl Write for &Lockfile {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         match self.raise_on_stale_lock() {
             Ok(_) => (),
-            Err(err) => return Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
+            Err(err) => return Err(std::io::Error::other(err)),
         }
 
         if let Some(mut file) = self.lock.as_ref() {
@@ -129,7 +146,7 @@ impl Read for Lockfile {
     }
 }
 
-impl<'a> Read for &'a Lockfile {
+impl Read for &Lockfile {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         if let Some(mut file) = self.lock.as_ref() {
             file.read(buf)
@@ -1,20 +1,49 @@
 #![allow(clippy::expect_fun_call)]
 
 use std::{
+    collections::{BTreeMap, BTreeSet},
     fs,
-    io::{Read, Write},
-    path::PathBuf,
+    io::{BufWriter, Read, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
 };
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use chrono::Utc;
-use clap::Clap;
+use clap::{AppSettings, Clap};
 use dotenv::dotenv;
 use flate2::read::ZlibDecoder;
 
 use git_rs::{
-    database::{blob::Blob, commit::Commit, tree::Entry, Database},
-    index::Index,
+    bisect::{Bisect, Term},
+    blame::Blame,
+    config::Config,
+    database::{commit::Commit, tag::Tag, tree::TreeItem, Database, MODE_EXECUTABLE},
+    diff::{
+        patch,
+        rename::{self, Detection},
+        DiffAlgorithm, DiffOptions,
+    },
+    grep::{Content, Grep, GrepFile, GrepOptions, PatternType},
+    history::Log,
+    index::{Index, STAGE_BASE, STAGE_OURS, STAGE_THEIRS},
+    merge::diff3,
+    migration,
+    pathspec::{wildmatch, Match, Pathspec},
+    pull::{self, FastForward, Merge, Outcome},
+    refs::{HEAD, MERGE_HEAD, ORIG_HEAD},
+    remote::{
+        self,
+        daemon::{self, DaemonOptions},
+        refspec::Refspec,
+        server, FetchOptions, Remote, UpdateStatus,
+    },
+    rev_list::{self, Deepen, Filter, INFINITE_DEPTH},
+    revision::{ReflogSelector, Revision},
+    sequencer::{Action, Command, Options, Sequencer, MERGE_MSG},
+    staging,
+    stash::{self, Stash},
+    utils::{parse_date, version_cmp},
     workspace::Workspace,
     Author, Refs, GIT_FOLDER,
 };
@@ -27,6 +56,9 @@ enum Commands {
         /// Where to create the repository.
         #[clap(name = "directory", parse(from_os_str))]
         path: Option<PathBuf>,
+        /// Create a repository without a workspace, to push to
+        #[clap(long)]
+        bare: bool,
     },
     Commit {
         #[clap(short, long)]
@@ -34,9 +66,13 @@ enum Commands {
     },
     /// Add file to the index
     Add {
-        /// Files to add
+        /// Pathspecs of the files to add
         #[clap()]
         paths: Vec<String>,
+        /// Number of files to hash and store in parallel, defaults to GIT_JOBS, add.jobs,
+        /// core.jobs or one per cpu
+        #[clap(short, long)]
+        jobs: Option<usize>,
     },
     Read {
         #[clap(name = "file", parse(from_os_str))]
@@ -47,6 +83,575 @@ enum Commands {
         object_id: String,
     },
     Clear,
+    /// Manage reflog information
+    ///
+    /// Every command moving a ref logs it: commit, reset, tag, cherry-pick, revert, rebase,
+    /// stash, bisect, pull, clone, fetch and push. There are no checkout, branch or merge
+    /// commands yet, so HEAD only moves through these.
+    Reflog {
+        #[clap(subcommand)]
+        command: Option<ReflogCommand>,
+    },
+    /// Move loose refs to the packed-refs file
+    PackRefs {
+        /// Pack every ref instead of only tags
+        #[clap(long)]
+        all: bool,
+        /// Keep the loose refs after packing them
+        #[clap(long)]
+        no_prune: bool,
+    },
+    /// Create, list or delete tags
+    Tag {
+        /// Name of the tag and the object it points to, or the patterns to list
+        #[clap()]
+        names: Vec<String>,
+        /// Create an annotated tag
+        #[clap(short, long)]
+        annotate: bool,
+        /// Message of an annotated tag, implies -a
+        #[clap(short, long)]
+        message: Option<String>,
+        /// Replace an existing tag
+        #[clap(short, long)]
+        force: bool,
+        /// Delete the given tags
+        #[clap(short, long)]
+        delete: bool,
+        /// List tags matching the given patterns
+        #[clap(short, long)]
+        list: bool,
+        /// Sort by `refname` or `version:refname`, prefix with - to reverse
+        #[clap(long)]
+        sort: Option<String>,
+    },
+    /// Reset HEAD to a revision, or reset paths in the index
+    Reset {
+        /// Only move HEAD
+        #[clap(long)]
+        soft: bool,
+        /// Move HEAD and reset the index, this is the default
+        #[clap(long)]
+        mixed: bool,
+        /// Move HEAD and reset both the index and the workspace
+        #[clap(long)]
+        hard: bool,
+        /// The revision to reset to, defaults to HEAD, followed by paths to reset in the index
+        #[clap()]
+        args: Vec<String>,
+        /// Paths to reset in the index
+        #[clap(last = true)]
+        paths: Vec<String>,
+    },
+    /// Remove files from the workspace and the index
+    Rm {
+        #[clap(required = true)]
+        paths: Vec<String>,
+        /// Only remove the files from the index
+        #[clap(long)]
+        cached: bool,
+        /// Allow removing directories
+        #[clap(short)]
+        recursive: bool,
+        /// Skip the checks for unstaged or staged modifications
+        #[clap(short, long)]
+        force: bool,
+    },
+    /// Move or rename files and directories in the workspace and the index
+    Mv {
+        /// Sources followed by the destination
+        #[clap(required = true, min_values = 2)]
+        paths: Vec<String>,
+        /// Overwrite the destination if it exists
+        #[clap(short, long)]
+        force: bool,
+    },
+    /// Restore files in the workspace or the index from the index or a commit
+    Restore {
+        #[clap(required = true)]
+        paths: Vec<String>,
+        /// Restore from this revision instead of the index, or HEAD with --staged
+        #[clap(short, long)]
+        source: Option<String>,
+        /// Restore the index
+        #[clap(short = 'S', long)]
+        staged: bool,
+        /// Restore the workspace, this is the default without --staged
+        #[clap(short = 'W', long)]
+        worktree: bool,
+        /// Write our side of unmerged paths
+        #[clap(long)]
+        ours: bool,
+        /// Write their side of unmerged paths
+        #[clap(long)]
+        theirs: bool,
+        /// Recreate the conflicted merge of unmerged paths
+        #[clap(short, long)]
+        merge: bool,
+    },
+    /// Apply the changes introduced by existing commits
+    CherryPick {
+        commits: Vec<String>,
+        /// Append a line with the id of the original commit to the message
+        #[clap(short = 'x')]
+        record_origin: bool,
+        /// Commit the resolved conflict and apply the remaining commits
+        #[clap(long = "continue")]
+        resume: bool,
+        /// Skip the current commit and apply the remaining ones
+        #[clap(long)]
+        skip: bool,
+        /// Go back to the state before the cherry-pick started
+        #[clap(long)]
+        abort: bool,
+        /// Forget about the cherry-pick in progress
+        #[clap(long)]
+        quit: bool,
+    },
+    /// Create commits reverting the changes introduced by existing commits
+    Revert {
+        commits: Vec<String>,
+        /// Commit the resolved conflict and revert the remaining commits
+        #[clap(long = "continue")]
+        resume: bool,
+        /// Skip the current commit and revert the remaining ones
+        #[clap(long)]
+        skip: bool,
+        /// Go back to the state before the revert started
+        #[clap(long)]
+        abort: bool,
+        /// Forget about the revert in progress
+        #[clap(long)]
+        quit: bool,
+    },
+    /// Replay the commits of the current branch on top of another base
+    Rebase {
+        /// Commits reachable from upstream are not replayed
+        upstream: Option<String>,
+        /// Replay the commits on top of this commit instead of upstream
+        #[clap(long)]
+        onto: Option<String>,
+        /// Edit the todo list before replaying the commits
+        #[clap(short, long)]
+        interactive: bool,
+        /// Commit the resolved conflict and replay the remaining commits
+        #[clap(long = "continue")]
+        resume: bool,
+        /// Skip the current commit and replay the remaining ones
+        #[clap(long)]
+        skip: bool,
+        /// Go back to the branch as it was before the rebase started
+        #[clap(long)]
+        abort: bool,
+        /// Forget about the rebase in progress
+        #[clap(long)]
+        quit: bool,
+    },
+    /// Show the changes between the workspace, the index or commits
+    Diff {
+        /// With no commit the workspace is compared to the index, with one commit to the
+        /// workspace or to the index with --cached, with two commits to each other
+        #[clap()]
+        commits: Vec<String>,
+        /// Compare the index to a commit, HEAD by default
+        #[clap(long, alias = "staged")]
+        cached: bool,
+        /// Detect renames, with an optional minimum similarity like -M=90%
+        #[clap(short = 'M', long, require_equals = true)]
+        find_renames: Option<Option<String>>,
+        /// Also detect copies from modified files, implies -M
+        #[clap(short = 'C', long, require_equals = true)]
+        find_copies: Option<Option<String>>,
+        /// Show the number of changed lines of every file instead of a patch
+        #[clap(long)]
+        stat: bool,
+        /// Show the status and the path of every file instead of a patch
+        #[clap(long)]
+        name_status: bool,
+        /// Diff with the patience algorithm
+        #[clap(long)]
+        patience: bool,
+        /// Diff with the histogram algorithm
+        #[clap(long)]
+        histogram: bool,
+        /// Diff with myers, patience or histogram, defaults to the diff.algorithm config
+        #[clap(long)]
+        diff_algorithm: Option<String>,
+        /// Ignore whitespace when comparing lines
+        #[clap(short = 'w', long)]
+        ignore_all_space: bool,
+        /// Ignore changes in the amount of whitespace
+        #[clap(short = 'b', long)]
+        ignore_space_change: bool,
+        /// Ignore changes whose lines are all blank
+        #[clap(long)]
+        ignore_blank_lines: bool,
+        /// Mark the changed words instead of lines, plain by default or color
+        #[clap(long, require_equals = true)]
+        word_diff: Option<Option<String>>,
+        /// Only compare the files matching these pathspecs
+        #[clap(last = true)]
+        paths: Vec<String>,
+    },
+    /// Show the commits reachable from the given revisions, newest first
+    Log {
+        /// Defaults to HEAD
+        #[clap()]
+        revisions: Vec<String>,
+        /// Only show the commits changing the files matching these pathspecs
+        #[clap(last = true)]
+        paths: Vec<String>,
+        /// Keep showing the history of a single file before it was renamed
+        #[clap(long)]
+        follow: bool,
+        /// Show every commit on a single line
+        #[clap(long)]
+        oneline: bool,
+        /// Show the status and the path of the files every commit changed
+        #[clap(long)]
+        name_status: bool,
+    },
+    /// Show the staged, unstaged and untracked files, staged renames are detected
+    Status {
+        /// Only show the files matching these pathspecs
+        #[clap()]
+        paths: Vec<String>,
+        /// Show every file on a single line with its staged and unstaged status
+        #[clap(short, long)]
+        short: bool,
+    },
+    /// Show the commit that last changed each line of a file
+    Blame {
+        #[clap()]
+        file: String,
+        /// Only blame the lines in start,end or start,+count
+        #[clap(short = 'L')]
+        range: Option<String>,
+        /// Show the commit information in a format meant for scripts
+        #[clap(long)]
+        porcelain: bool,
+        /// Ignore whitespace when comparing a file with its parents
+        #[clap(short = 'w')]
+        ignore_whitespace: bool,
+        /// Follow lines moved within the file
+        #[clap(short = 'M')]
+        find_moves: bool,
+    },
+    /// Print the lines matching a pattern in the tracked files, the index or trees
+    Grep {
+        #[clap()]
+        pattern: String,
+        /// Search these trees, commits or tags instead of the workspace
+        #[clap()]
+        trees: Vec<String>,
+        /// Only search the files matching these pathspecs
+        #[clap(last = true)]
+        paths: Vec<String>,
+        /// Search the blobs of the index instead of the workspace
+        #[clap(long)]
+        cached: bool,
+        /// Read the pattern as a POSIX basic regex, the default
+        #[clap(short = 'G', long)]
+        basic_regexp: bool,
+        /// Read the pattern as a POSIX extended regex
+        #[clap(short = 'E', long)]
+        extended_regexp: bool,
+        /// Read the pattern as a fixed string
+        #[clap(short = 'F', long)]
+        fixed_strings: bool,
+        /// Read the pattern as a Perl compatible regex
+        #[clap(short = 'P', long)]
+        perl_regexp: bool,
+        /// Prefix the lines with their number
+        #[clap(short = 'n', long)]
+        line_number: bool,
+        #[clap(short, long)]
+        ignore_case: bool,
+        /// Only match whole words
+        #[clap(short, long)]
+        word_regexp: bool,
+        /// Only show the names of the matching files
+        #[clap(short = 'l', long)]
+        files_with_matches: bool,
+        /// Show the number of matching lines of every file
+        #[clap(short, long)]
+        count: bool,
+        /// Show this many lines after every match
+        #[clap(short = 'A', long)]
+        after_context: Option<usize>,
+        /// Show this many lines before every match
+        #[clap(short = 'B', long)]
+        before_context: Option<usize>,
+        /// Show this many lines around every match
+        #[clap(short = 'C', long)]
+        context: Option<usize>,
+        /// Number of threads searching files, defaults to GIT_JOBS, grep.threads, core.jobs or
+        /// one per cpu
+        #[clap(long)]
+        threads: Option<usize>,
+    },
+    /// Clone a repository into a new directory
+    Clone {
+        /// Path or url of the repository to clone
+        #[clap()]
+        repository: String,
+        /// Defaults to the name of the repository
+        #[clap(parse(from_os_str))]
+        directory: Option<PathBuf>,
+        /// Only clone this many commits of history, making a shallow repository
+        #[clap(long)]
+        depth: Option<usize>,
+        /// Make a partial clone leaving out objects, like blob:none, blob:limit=<n> or tree:0.
+        /// They're fetched when needed.
+        #[clap(long)]
+        filter: Option<Filter>,
+    },
+    /// Download the objects and refs of another repository
+    Fetch {
+        /// Name of the remote, defaults to origin
+        #[clap()]
+        remote: Option<String>,
+        /// Fetch this many more commits behind the shallow commits
+        #[clap(long)]
+        deepen: Option<usize>,
+        /// Fetch the whole history of a shallow repository
+        #[clap(long)]
+        unshallow: bool,
+    },
+    /// Fetch the upstream of the current branch and integrate it
+    Pull {
+        /// Name or path of the remote, defaults to the upstream of the branch
+        #[clap()]
+        remote: Option<String>,
+        /// Refuse to merge unless it's a fast-forward
+        #[clap(long)]
+        ff_only: bool,
+        /// Fast-forward when possible, overrides pull.ff
+        #[clap(long)]
+        ff: bool,
+        /// Create a merge commit even when it could be a fast-forward
+        #[clap(long)]
+        no_ff: bool,
+        /// Replay the local commits on top of the upstream instead of merging
+        #[clap(short, long)]
+        rebase: bool,
+        /// Merge even when pull.rebase is set
+        #[clap(long)]
+        no_rebase: bool,
+    },
+    /// Update the refs of another repository and send the objects they need
+    Push {
+        /// Name or path of the remote, defaults to origin
+        #[clap()]
+        remote: Option<String>,
+        /// Like <src>:<dst>, defaults to the current branch and its upstream
+        #[clap()]
+        refspecs: Vec<String>,
+        /// Update the remote refs even when it's not a fast-forward
+        #[clap(short, long)]
+        force: bool,
+        /// Only overwrite a remote ref that is where we expect, like <ref>:<expect>
+        #[clap(long, require_equals = true)]
+        force_with_lease: Option<Option<String>>,
+    },
+    /// Send objects to a git client fetching from the repository, over stdin and stdout
+    UploadPack {
+        #[clap(parse(from_os_str))]
+        directory: PathBuf,
+        /// Only advertise the refs, for the first request of smart HTTP
+        #[clap(long)]
+        advertise_refs: bool,
+        /// Serve a single request without advertising the refs, for smart HTTP
+        #[clap(long)]
+        stateless_rpc: bool,
+    },
+    /// Receive what a git client pushes to the repository, over stdin and stdout
+    ReceivePack {
+        #[clap(parse(from_os_str))]
+        directory: PathBuf,
+        /// Only advertise the refs, for the first request of smart HTTP
+        #[clap(long)]
+        advertise_refs: bool,
+        /// Serve a single request without advertising the refs, for smart HTTP
+        #[clap(long)]
+        stateless_rpc: bool,
+    },
+    /// Serve the repositories under a directory with the git:// protocol, read-only
+    Daemon {
+        /// Directory the requested paths are relative to, defaults to the current one
+        #[clap(long, parse(from_os_str))]
+        base_path: Option<PathBuf>,
+        /// Serve the repositories without a git-daemon-export-ok file too
+        #[clap(long)]
+        export_all: bool,
+        /// Address to listen on
+        #[clap(long, default_value = "0.0.0.0")]
+        listen: String,
+        #[clap(long, default_value = "9418")]
+        port: u16,
+    },
+    /// Get and set repository options
+    Config {
+        /// Name of the variable, like diff.algorithm
+        #[clap(required_unless_present = "list")]
+        name: Option<String>,
+        /// Value to set, the current value is shown without it
+        #[clap()]
+        value: Option<String>,
+        /// Add a value without replacing the existing ones
+        #[clap(long)]
+        add: bool,
+        /// Show every value of a multi-valued variable
+        #[clap(long)]
+        get_all: bool,
+        /// Remove every value of the variable
+        #[clap(long)]
+        unset: bool,
+        /// Show every variable
+        #[clap(short, long)]
+        list: bool,
+    },
+    /// Find the commit that introduced a bug with a binary search
+    Bisect {
+        #[clap(subcommand)]
+        command: BisectCommand,
+    },
+    /// Save local changes away and restore them later
+    Stash {
+        #[clap(subcommand)]
+        command: Option<StashCommand>,
+    },
+    /// List the files in the index
+    LsFiles {
+        /// Pathspecs of the files to list
+        #[clap()]
+        paths: Vec<String>,
+        /// Show the mode, object id and stage of each entry
+        #[clap(short, long)]
+        stage: bool,
+    },
+    /// Print the object id a revision points to
+    RevParse {
+        #[clap()]
+        revision: String,
+    },
+}
+
+#[derive(Clap)]
+enum ReflogCommand {
+    /// Show the entries of a reflog, defaults to HEAD
+    Show {
+        #[clap()]
+        reference: Option<String>,
+    },
+    /// Remove the entries older than the expire date
+    Expire {
+        /// Defaults to 90.days.ago, `never` keeps every entry
+        #[clap(long)]
+        expire: Option<String>,
+        /// Expire the reflog of every ref
+        #[clap(long)]
+        all: bool,
+        #[clap()]
+        references: Vec<String>,
+    },
+    /// Remove single entries like HEAD@{2}
+    Delete {
+        #[clap(required = true)]
+        entries: Vec<String>,
+    },
+}
+
+#[derive(Clap)]
+enum BisectCommand {
+    /// Start bisecting, optionally with a bad commit and some good ones
+    Start {
+        #[clap()]
+        bad: Option<String>,
+        #[clap()]
+        good: Vec<String>,
+    },
+    /// Mark commits as good, HEAD by default
+    #[clap(alias = "old")]
+    Good {
+        #[clap()]
+        revisions: Vec<String>,
+    },
+    /// Mark a commit as bad, HEAD by default
+    #[clap(alias = "new")]
+    Bad {
+        #[clap()]
+        revision: Option<String>,
+    },
+    /// Mark commits that can't be tested, HEAD by default
+    Skip {
+        #[clap()]
+        revisions: Vec<String>,
+    },
+    /// Stop bisecting and go back to the original branch or to the given commit
+    Reset {
+        #[clap()]
+        commit: Option<String>,
+    },
+    /// Show the commands of the bisection so far
+    Log,
+    /// Bisect again by running the commands of a log
+    Replay {
+        #[clap()]
+        file: PathBuf,
+    },
+    /// Mark every commit with the result of a command until the first bad commit is found
+    #[clap(setting = AppSettings::TrailingVarArg)]
+    Run {
+        #[clap(required = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+}
+
+#[derive(Clap)]
+enum StashCommand {
+    /// Save the local changes then revert them to HEAD
+    Push {
+        /// Describe the stash instead of using the subject of HEAD
+        #[clap(short, long)]
+        message: Option<String>,
+        /// Also stash the untracked files and remove them
+        #[clap(short = 'u', long)]
+        include_untracked: bool,
+        /// Only stash the changes of the paths matching these pathspecs
+        #[clap(last = true)]
+        paths: Vec<String>,
+    },
+    /// Apply a stash then drop it, defaults to stash@{0}
+    Pop {
+        #[clap()]
+        stash: Option<String>,
+    },
+    /// Apply a stash on top of the workspace, defaults to stash@{0}
+    Apply {
+        #[clap()]
+        stash: Option<String>,
+        /// Also restore the changes that were staged
+        #[clap(long)]
+        index: bool,
+    },
+    /// List the stashes, the latest one first
+    List,
+    /// Show the changes recorded in a stash, defaults to stash@{0}
+    Show {
+        #[clap()]
+        stash: Option<String>,
+        /// Show the changes as a patch instead of a diffstat
+        #[clap(short, long)]
+        patch: bool,
+    },
+    /// Remove a stash, defaults to stash@{0}
+    Drop {
+        #[clap()]
+        stash: Option<String>,
+    },
+    /// Remove every stash
+    Clear,
 }
 
 fn main() -> Result<()> {
@@ -57,10 +662,18 @@ fn main() -> Result<()> {
     let commands: Commands = Commands::parse();
 
     match commands {
-        Commands::Init { path } => {
-            let git_path = path.unwrap_or(std::env::current_dir()?).join(GIT_FOLDER);
+        Commands::Init { path, bare } => {
+            let path = path.unwrap_or(std::env::current_dir()?);
+            let git_path = if bare { path } else { path.join(GIT_FOLDER) };
             fs::create_dir_all(git_path.join("objects"))?;
             fs::create_dir_all(git_path.join("refs"))?;
+            if bare {
+                let mut config = Config::load(git_path.join("config"))?;
+                config.set("core.bare", "true")?;
+                config.save()?;
+                // Nothing is ever checked out to create it, so clients know which branch to use
+                fs::write(git_path.join(HEAD), "ref: refs/heads/main\n")?;
+            }
             log::info!("Initialized git_rs repository in {}", git_path.display());
         }
         Commands::Commit { message } => {
@@ -68,37 +681,27 @@ fn main() -> Result<()> {
             let root_path = std::env::current_dir()?;
             let git_path = root_path.join(GIT_FOLDER);
 
-            let workspace = Workspace::new(root_path.clone());
             let db = Database::new(git_path.join("objects"));
-            let refs = Refs::new(git_path);
+            let mut index = Index::new(git_path.join("index"));
+            let refs = Refs::new(git_path.clone());
 
-            let entries: Vec<Entry> = workspace
-                .list_files(None)?
-                .iter()
-                .map(|path| {
-                    let data =
-                        fs::read(&path).expect(&format!("Failed to read {}", &path.display()));
-                    let blob = Blob::new(data);
-                    let object_id = db.store(&blob).expect("Failed to store object in database");
-
-                    // Make sure the entry path is relative to root and not the full path
-                    let rel_path = pathdiff::diff_paths(path, &root_path).unwrap();
-                    log::trace!("{} {}", rel_path.display(), object_id);
-                    Entry::new(rel_path, object_id)
-                })
-                .collect();
+            // The blobs were already stored by add
+            index.load()?;
+            if index.has_conflicts() {
+                bail!("Committing is not possible because you have unmerged files.");
+            }
+            let tree_id = db.store_tree(&index.tree_items())?;
 
-            let tree = git_rs::database::tree::build(&entries);
-            let tree_id = tree.traverse(&|tree| db.store(tree).expect("Failed while saving tree"));
+            let author = Author::from_env();
 
-            let author = Author {
-                name: std::env::var("GIT_AUTHOR_NAME").expect("GIT_AUTHOR_NAME is undefined"),
-                email: std::env::var("GIT_AUTHOR_EMAIL").expect("GIT_AUTHOR_EMAIL is undefined"),
-                time: Utc::now(),
-            };
+            // Concluding a merge that stopped on conflicts
+            let merge_head = refs.read_ref(MERGE_HEAD);
+            let merge_msg = git_path.join(MERGE_MSG);
 
             let message = if let Some(value) = message {
                 value
+            } else if merge_head.is_some() && merge_msg.is_file() {
+                fs::read_to_string(&merge_msg)?
             } else {
                 let mut message_buf = String::new();
                 std::io::stdin()
@@ -108,11 +711,37 @@ fn main() -> Result<()> {
             };
 
             let parent = refs.read_head();
-            let is_root = parent.is_some();
+            let is_root = parent.is_none();
 
-            let commit = Commit::new(parent, tree_id, author, message.clone());
+            let commit = Commit::new(
+                parent.into_iter().chain(merge_head.clone()).collect(),
+                tree_id,
+                author.clone(),
+                message.clone(),
+            );
             let commit_id = db.store(&commit)?;
-            refs.update_head(commit_id.clone())?;
+            let subject = message.lines().next().expect("Failed to read message");
+            refs.update_head(
+                commit_id.clone(),
+                &author,
+                &format!(
+                    "commit{}: {}",
+                    if is_root {
+                        " (initial)"
+                    } else if merge_head.is_some() {
+                        " (merge)"
+                    } else {
+                        ""
+                    },
+                    subject
+                ),
+            )?;
+            if merge_head.is_some() {
+                refs.delete_ref(MERGE_HEAD)?;
+                if merge_msg.is_file() {
+                    fs::remove_file(merge_msg)?;
+                }
+            }
 
             log::info!(
                 "[{}{}]  {}",
@@ -121,7 +750,7 @@ fn main() -> Result<()> {
                 message.lines().next().expect("Failed to read message")
             );
         }
-        Commands::Add { paths } => {
+        Commands::Add { paths, jobs } => {
             // FIXME this assumes we are at root of repo
             let root_path = std::env::current_dir()?;
             let git_path = root_path.join(GIT_FOLDER);
@@ -129,27 +758,63 @@ fn main() -> Result<()> {
             let workspace = Workspace::new(root_path);
             let db = Database::new(git_path.join("objects"));
             let mut index = Index::new(git_path.join("index"));
+            index.load_for_update()?;
 
-            for path in paths {
-                let path_buf = PathBuf::from(path.clone()).canonicalize()?;
-                for file in workspace.list_files(Some(&path_buf))? {
-                    log::debug!("adding {} to index", file.display());
-
-                    let data = workspace.read_file(&file)?;
-                    let metadata = workspace.file_metadata(&file)?;
+            if paths.is_empty() {
+                bail!("Nothing specified, nothing added.");
+            }
+            let pathspec = Pathspec::parse(&paths, "")?;
 
-                    let blob = Blob::new(data);
-                    let object_id = db.store(&blob)?;
+            let mut files = vec![];
+            let mut matched = BTreeSet::new();
+            for file in workspace.list_files(None)? {
+                let path = file
+                    .to_str()
+                    .expect("Failed to convert path to a valid string");
+                if !pathspec.matches(path) {
+                    continue;
+                }
+                matched.insert(path.to_string());
 
-                    // Using to_string_lossy here isn't nice
-                    index.add(
-                        file.to_str()
-                            .expect("Failed to convert path to a valid string")
-                            .into(),
-                        object_id,
-                        &metadata,
-                    )?;
+                let metadata = workspace.file_metadata(&file)?;
+                if index.is_stat_clean(path, &metadata) {
+                    log::trace!("{} is unchanged", path);
+                    continue;
                 }
+                files.push(file);
+            }
+
+            // Tracked files matching the pathspec that are gone from the workspace are removed
+            let deleted: BTreeSet<String> = index
+                .entries()
+                .map(|entry| entry.path().to_string())
+                .filter(|path| pathspec.matches(path) && !matched.contains(path))
+                .collect();
+            let all_paths = matched.iter().chain(&deleted).map(String::as_str);
+            if let Some(unmatched) = pathspec.unmatched(all_paths).first() {
+                bail!("pathspec '{}' did not match any files", unmatched);
+            }
+            for path in &deleted {
+                log::debug!("removing {} from index", path);
+                index.remove(path);
+            }
+
+            let config = Config::load(git_path.join("config"))?;
+            let jobs = staging::jobs(jobs, &config, "add.jobs")?;
+            let staged_files = staging::stage_files(&workspace, &db, &files, jobs)?;
+            for staged in staged_files {
+                log::debug!("adding {} to index", staged.path.display());
+
+                // Using to_string_lossy here isn't nice
+                index.add(
+                    staged
+                        .path
+                        .to_str()
+                        .expect("Failed to convert path to a valid string")
+                        .into(),
+                    staged.object_id,
+                    &staged.metadata,
+                )?;
             }
 
             index.write_updates()?;
@@ -184,9 +849,1732 @@ fn main() -> Result<()> {
             // WARN this is just for debug purposes
             std::fs::remove_dir_all(GIT_FOLDER)?;
         }
-    }
+        Commands::Reflog { command } => {
+            let refs = Refs::new(std::env::current_dir()?.join(GIT_FOLDER));
 
-    Ok(())
+            match command.unwrap_or(ReflogCommand::Show { reference: None }) {
+                ReflogCommand::Show { reference } => {
+                    let reference = reference.unwrap_or_else(|| HEAD.into());
+                    let name = Revision::parse(&reference)?.reflog_name(&refs)?;
+                    for (i, entry) in refs.reflog(&name).entries()?.iter().rev().enumerate() {
+                        println!(
+                            "{} {}@{{{}}}: {}",
+                            &entry.new_id[..7],
+                            reference,
+                            i,
+                            entry.message
+                        );
+                    }
+                }
+                ReflogCommand::Expire {
+                    expire,
+                    all,
+                    references,
+                } => {
+                    let expire = expire.unwrap_or_else(|| "90.days.ago".into());
+                    if expire == "never" {
+                        return Ok(());
+                    }
+                    let before = parse_date(&expire, Utc::now())
+                        .with_context(|| format!("Invalid date: {}", expire))?;
+
+                    let names = if all {
+                        refs.reflog_names()?
+                    } else {
+                        references
+                            .iter()
+                            .map(|reference| Revision::parse(reference)?.reflog_name(&refs))
+                            .collect::<Result<_>>()?
+                    };
+                    for name in names {
+                        let removed = refs.reflog(&name).expire(before)?;
+                        log::info!("Removed {} entries from {}", removed, name);
+                    }
+                }
+                ReflogCommand::Delete { entries } => {
+                    // Delete the highest indices first so the other entries don't move
+                    let mut selected = vec![];
+                    for entry in &entries {
+                        let revision = Revision::parse(entry)?;
+                        match revision {
+                            Revision::Reflog(_, ReflogSelector::Index(n)) => {
+                                selected.push((revision.reflog_name(&refs)?, n))
+                            }
+                            _ => bail!("Not a reflog entry: {}", entry),
+                        }
+                    }
+                    selected.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+                    for (name, n) in selected {
+                        refs.reflog(&name).delete(n)?;
+                    }
+                }
+            }
+        }
+        Commands::PackRefs { all, no_prune } => {
+            let git_path = std::env::current_dir()?.join(GIT_FOLDER);
+            let db = Database::new(git_path.join("objects"));
+            let refs = Refs::new(git_path);
+
+            refs.pack_refs(all, !no_prune, &|object_id| db.peel(object_id).ok())?;
+        }
+        Commands::Tag {
+            names,
+            annotate,
+            message,
+            force,
+            delete,
+            list,
+            sort,
+        } => {
+            let git_path = std::env::current_dir()?.join(GIT_FOLDER);
+            let db = Database::new(git_path.join("objects"));
+            let refs = Refs::new(git_path);
+
+            if delete {
+                for name in names {
+                    let ref_name = format!("refs/tags/{}", name);
+                    let object_id = refs
+                        .read_ref(&ref_name)
+                        .with_context(|| format!("tag '{}' not found.", name))?;
+                    refs.delete_ref(&ref_name)?;
+                    println!("Deleted tag '{}' (was {})", name, &object_id[..7]);
+                }
+            } else if list || names.is_empty() {
+                let mut tags: Vec<String> = refs
+                    .list_refs("refs/tags/")?
+                    .into_iter()
+                    .map(|(name, _)| name["refs/tags/".len()..].to_string())
+                    .filter(|name| {
+                        names.is_empty() || names.iter().any(|pattern| wildmatch(pattern, name, 0))
+                    })
+                    .collect();
+
+                let sort = sort.unwrap_or_else(|| "refname".into());
+                let (reverse, key) = match sort.strip_prefix('-') {
+                    Some(key) => (true, key),
+                    None => (false, sort.as_str()),
+                };
+                match key {
+                    "refname" => tags.sort(),
+                    "version:refname" | "v:refname" => tags.sort_by(|a, b| version_cmp(a, b)),
+                    _ => bail!("unsupported sort specification '{}'", sort),
+                }
+                if reverse {
+                    tags.reverse();
+                }
+
+                for tag in tags {
+                    println!("{}", tag);
+                }
+            } else {
+                let name = &names[0];
+                let ref_name = format!("refs/tags/{}", name);
+                if refs.read_ref(&ref_name).is_some() && !force {
+                    bail!("tag '{}' already exists", name);
+                }
+
+                let target = names.get(1).map_or(HEAD, |target| target.as_str());
+                let object_id = Revision::parse(target)?.resolve(&refs, &db)?;
+                let tagger = Author::from_env();
+
+                let object_id = if annotate || message.is_some() {
+                    let message = match message {
+                        Some(message) => message,
+                        None => {
+                            let mut message = String::new();
+                            std::io::stdin().read_to_string(&mut message)?;
+                            message
+                        }
+                    };
+                    let (kind, _) = db.load(&object_id)?;
+                    let tag = Tag::new(object_id, kind, name.clone(), tagger.clone(), message);
+                    db.store(&tag)?
+                } else {
+                    object_id
+                };
+
+                refs.update_ref(&ref_name, object_id, &tagger, &format!("tag: {}", name))?;
+            }
+        }
+        Commands::Reset {
+            soft,
+            mixed,
+            hard,
+            args,
+            mut paths,
+        } => {
+            if [soft, mixed, hard].iter().filter(|mode| **mode).count() > 1 {
+                bail!("Only one of --soft, --mixed and --hard can be used");
+            }
+
+            // FIXME this assumes we are at root of repo
+            let root_path = std::env::current_dir()?;
+            let git_path = root_path.join(GIT_FOLDER);
+
+            let workspace = Workspace::new(root_path);
+            let db = Database::new(git_path.join("objects"));
+            let mut index = Index::new(git_path.join("index"));
+            let refs = Refs::new(git_path);
+
+            // The first argument is a revision only if it can be resolved, otherwise it's a path
+            let mut args = args.into_iter().peekable();
+            let revision = match args.peek() {
+                Some(arg) if Revision::parse(arg)?.resolve(&refs, &db).is_ok() => {
+                    args.next().expect("Failed to read revision")
+                }
+                _ => HEAD.to_string(),
+            };
+            let mut all_paths: Vec<String> = args.collect();
+            all_paths.append(&mut paths);
+            let pathspec = Pathspec::parse(&all_paths, "")?;
+
+            // Resetting to HEAD before the first commit empties the index
+            let commit_id = match Revision::parse(&revision)?.resolve(&refs, &db) {
+                Ok(commit_id) => Some(db.peel(&commit_id)?),
+                Err(_) if revision == HEAD && refs.read_head().is_none() => None,
+                Err(err) => return Err(err),
+            };
+            let entries = match &commit_id {
+                Some(commit_id) => db.load_tree_entries(&db.load_tree_id(commit_id)?)?,
+                None => Default::default(),
+            };
+
+            if !pathspec.is_empty() {
+                if soft || hard {
+                    bail!(
+                        "Cannot do {} reset with paths.",
+                        if soft { "soft" } else { "hard" }
+                    );
+                }
+                index.load_for_update()?;
+                index.reset_paths(&pathspec, &entries)?;
+                index.write_updates()?;
+                return Ok(());
+            }
+
+            if !soft {
+                index.load_for_update()?;
+                if hard {
+                    migration::checkout_entries(&db, &workspace, &mut index, &entries)?;
+                } else {
+                    index.reset_to(&entries)?;
+                }
+                index.write_updates()?;
+            }
+
+            if let Some(commit_id) = commit_id {
+                let identity = Author::from_env();
+                let message = format!("reset: moving to {}", revision);
+                if let Some(orig_head) = refs.read_head() {
+                    refs.update_ref(ORIG_HEAD, orig_head, &identity, &message)?;
+                }
+                refs.update_head(commit_id.clone(), &identity, &message)?;
+
+                if hard {
+                    let (_, data) = db.load(&commit_id)?;
+                    let commit = Commit::parse(&data)?;
+                    println!(
+                        "HEAD is now at {} {}",
+                        &commit_id[..7],
+                        commit.message().lines().next().unwrap_or("")
+                    );
+                }
+            }
+        }
+        Commands::Rm {
+            paths,
+            cached,
+            recursive,
+            force,
+        } => {
+            // FIXME this assumes we are at root of repo
+            let root_path = std::env::current_dir()?;
+            let git_path = root_path.join(GIT_FOLDER);
+
+            let workspace = Workspace::new(root_path.clone());
+            let db = Database::new(git_path.join("objects"));
+            let mut index = Index::new(git_path.join("index"));
+            let refs = Refs::new(git_path);
+            index.load_for_update()?;
+
+            let head_entries = match refs.read_head() {
+                Some(head_id) => db.load_tree_entries(&db.load_tree_id(&head_id)?)?,
+                None => Default::default(),
+            };
+
+            // Everything is checked before removing anything so a failure leaves the index as is
+            let pathspec = Pathspec::parse(&paths, "")?;
+            let mut removed = BTreeSet::new();
+            for entry in index.entries() {
+                match pathspec.match_path(entry.path()) {
+                    Some(Match::Directory(directory)) if !recursive => {
+                        bail!("not removing '{}' recursively without -r", directory);
+                    }
+                    Some(_) => {
+                        removed.insert(entry.path().to_string());
+                    }
+                    None => (),
+                }
+            }
+            if let Some(unmatched) = pathspec
+                .unmatched(removed.iter().map(String::as_str))
+                .first()
+            {
+                bail!("pathspec '{}' did not match any files", unmatched);
+            }
+
+            if !force {
+                for path in &removed {
+                    // Unmerged paths have no staged content to lose
+                    let entry = match index.entry(path) {
+                        Some(entry) => entry,
+                        None => continue,
+                    };
+                    let staged = head_entries.get(path).is_none_or(|item| {
+                        &item.object_id != entry.object_id() || item.mode != entry.mode()
+                    });
+                    // A file that is already gone from the workspace has nothing to lose
+                    let modified = root_path.join(path).exists()
+                        && staging::is_modified(&workspace, &index, path)?;
+
+                    if staged && modified {
+                        bail!(
+                            "'{}' has staged content different from both the file and the HEAD",
+                            path
+                        );
+                    } else if staged && !cached {
+                        bail!("'{}' has changes staged in the index", path);
+                    } else if modified && !cached {
+                        bail!("'{}' has local modifications", path);
+                    }
+                }
+            }
+
+            for path in removed {
+                index.remove(&path);
+                if !cached {
+                    workspace.remove_file(Path::new(&path))?;
+                }
+                println!("rm '{}'", path);
+            }
+            index.write_updates()?;
+        }
+        Commands::Mv { mut paths, force } => {
+            // FIXME this assumes we are at root of repo
+            let root_path = std::env::current_dir()?;
+            let git_path = root_path.join(GIT_FOLDER);
+
+            let workspace = Workspace::new(root_path.clone());
+            let mut index = Index::new(git_path.join("index"));
+            index.load_for_update()?;
+
+            let destination = paths.pop().expect("Failed to read destination");
+            let destination = destination.trim_start_matches("./").trim_end_matches('/');
+            let into_directory = root_path.join(destination).is_dir();
+            if paths.len() > 1 && !into_directory {
+                bail!("destination '{}' is not a directory", destination);
+            }
+
+            let mut moves = vec![];
+            for source in &paths {
+                let source = source.trim_start_matches("./").trim_end_matches('/');
+                let target = if into_directory {
+                    let name = Path::new(source)
+                        .file_name()
+                        .with_context(|| format!("bad source, source={}", source))?;
+                    format!("{}/{}", destination, name.to_string_lossy())
+                } else {
+                    destination.to_string()
+                };
+
+                if !index.is_tracked(source) {
+                    bail!(
+                        "not under version control, source={}, destination={}",
+                        source,
+                        target
+                    );
+                }
+                if target.starts_with(&format!("{}/", source)) {
+                    bail!(
+                        "can not move directory into itself, source={}, destination={}",
+                        source,
+                        target
+                    );
+                }
+                let target_path = root_path.join(&target);
+                if target_path.is_dir() || (target_path.exists() && !force) {
+                    bail!(
+                        "destination exists, source={}, destination={}",
+                        source,
+                        target
+                    );
+                }
+                moves.push((source.to_string(), target));
+            }
+
+            for (source, target) in moves {
+                let target_path = root_path.join(&target);
+                if let Some(parent) = target_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::rename(root_path.join(&source), &target_path)?;
+
+                for entry in index.remove(&source) {
+                    let path = format!("{}{}", target, &entry.path()[source.len()..]);
+                    let metadata = workspace.file_metadata(Path::new(&path))?;
+                    index.add(path, entry.object_id().clone(), &metadata)?;
+                }
+            }
+            index.write_updates()?;
+        }
+        Commands::Restore {
+            paths,
+            source,
+            staged,
+            worktree,
+            ours,
+            theirs,
+            merge,
+        } => {
+            // FIXME this assumes we are at root of repo
+            let root_path = std::env::current_dir()?;
+            let git_path = root_path.join(GIT_FOLDER);
+
+            let workspace = Workspace::new(root_path);
+            let db = Database::new(git_path.join("objects"));
+            let mut index = Index::new(git_path.join("index"));
+            let refs = Refs::new(git_path);
+
+            let worktree = worktree || !staged;
+            let conflict_side = [ours, theirs, merge].iter().filter(|flag| **flag).count();
+            if conflict_side > 1 {
+                bail!("--ours, --theirs and --merge are incompatible");
+            }
+            if conflict_side > 0 && (staged || source.is_some()) {
+                bail!("--ours, --theirs and --merge only restore the workspace from the index");
+            }
+            let pathspec = Pathspec::parse(&paths, "")?;
+
+            // The index is the source of the workspace unless a revision is given
+            let source = source.or_else(|| if staged { Some(HEAD.to_string()) } else { None });
+            let source_entries = match &source {
+                Some(revision) => match Revision::parse(revision)?.resolve(&refs, &db) {
+                    Ok(object_id) => Some(db.load_tree_entries(&db.load_tree_id(&object_id)?)?),
+                    Err(_) if revision == HEAD && refs.read_head().is_none() => {
+                        Some(Default::default())
+                    }
+                    Err(err) => return Err(err),
+                },
+                None => None,
+            };
+
+            index.load_for_update()?;
+            let index_paths = index.entries().map(|entry| entry.path());
+            let source_paths = source_entries.iter().flat_map(|entries| entries.keys());
+            let known_paths = index_paths.chain(source_paths.map(String::as_str));
+            if let Some(unmatched) = pathspec.unmatched(known_paths).first() {
+                bail!(
+                    "pathspec '{}' did not match any file(s) known to git",
+                    unmatched
+                );
+            }
+            if worktree && source_entries.is_none() && conflict_side == 0 {
+                if let Some(entry) = index
+                    .entries()
+                    .find(|entry| entry.stage() > 0 && pathspec.matches(entry.path()))
+                {
+                    bail!("path '{}' is unmerged", entry.path());
+                }
+            }
+
+            if worktree {
+                match &source_entries {
+                    Some(entries) => {
+                        let removed: BTreeSet<String> = index
+                            .entries()
+                            .map(|entry| entry.path().to_string())
+                            .filter(|path| pathspec.matches(path) && !entries.contains_key(path))
+                            .collect();
+                        for path in removed {
+                            workspace.remove_file(Path::new(&path))?;
+                        }
+                        for (path, item) in
+                            entries.iter().filter(|(path, _)| pathspec.matches(path))
+                        {
+                            migration::write_blob(&db, &workspace, path, item)?;
+                        }
+                    }
+                    None => {
+                        let entries: Vec<(u16, String, TreeItem)> = index
+                            .entries()
+                            .filter(|entry| pathspec.matches(entry.path()))
+                            .map(|entry| {
+                                let item = TreeItem {
+                                    mode: entry.mode(),
+                                    object_id: entry.object_id().clone(),
+                                };
+                                (entry.stage(), entry.path().to_string(), item)
+                            })
+                            .collect();
+                        let conflicted: BTreeSet<&String> = entries
+                            .iter()
+                            .filter(|(stage, ..)| *stage > 0)
+                            .map(|(_, path, _)| path)
+                            .collect();
+
+                        for (stage, path, item) in &entries {
+                            if *stage == 0 {
+                                migration::write_blob(&db, &workspace, path, item)?;
+                                // The file now matches its entry so its stat can be refreshed
+                                let metadata = workspace.file_metadata(Path::new(path))?;
+                                index.add(path.clone(), item.object_id.clone(), &metadata)?;
+                            }
+                        }
+
+                        for path in conflicted {
+                            let side = |stage| index.conflict_entry(path, stage);
+                            if merge {
+                                let read_side = |stage| -> Result<(Vec<u8>, bool)> {
+                                    match side(stage) {
+                                        Some(entry) => Ok((
+                                            db.load(entry.object_id())?.1,
+                                            entry.mode() == MODE_EXECUTABLE as u32,
+                                        )),
+                                        None => Ok((vec![], false)),
+                                    }
+                                };
+                                let (base, _) = read_side(STAGE_BASE)?;
+                                let (ours, executable) = read_side(STAGE_OURS)?;
+                                let (theirs, _) = read_side(STAGE_THEIRS)?;
+                                let data =
+                                    diff3::merge(&base, &ours, &theirs).to_bytes("ours", "theirs");
+                                workspace.write_file(Path::new(path), &data, executable)?;
+                                continue;
+                            }
+
+                            let (stage, name) = if ours {
+                                (STAGE_OURS, "our")
+                            } else {
+                                (STAGE_THEIRS, "their")
+                            };
+                            let entry = side(stage).with_context(|| {
+                                format!("path '{}' does not have {} version", path, name)
+                            })?;
+                            let item = TreeItem {
+                                mode: entry.mode(),
+                                object_id: entry.object_id().clone(),
+                            };
+                            migration::write_blob(&db, &workspace, path, &item)?;
+                        }
+                    }
+                }
+            }
+
+            if staged {
+                let entries = source_entries.expect("Failed to read source entries");
+                index.reset_paths(&pathspec, &entries)?;
+            }
+            index.write_updates()?;
+        }
+        Commands::CherryPick {
+            commits,
+            record_origin,
+            resume,
+            skip,
+            abort,
+            quit,
+        } => {
+            let options = Options { record_origin };
+            let control = Control::new(resume, skip, abort, quit);
+            run_sequencer(Action::Pick, &commits, &options, control)?;
+        }
+        Commands::Revert {
+            commits,
+            resume,
+            skip,
+            abort,
+            quit,
+        } => {
+            let control = Control::new(resume, skip, abort, quit);
+            run_sequencer(Action::Revert, &commits, &Options::default(), control)?;
+        }
+        Commands::Rebase {
+            upstream,
+            onto,
+            interactive,
+            resume,
+            skip,
+            abort,
+            quit,
+        } => {
+            // FIXME this assumes we are at root of repo
+            let root_path = std::env::current_dir()?;
+            let git_path = root_path.join(GIT_FOLDER);
+
+            let workspace = Workspace::new(root_path);
+            let db = Database::new(git_path.join("objects"));
+            let mut index = Index::new(git_path.join("index"));
+            let refs = Refs::new(git_path.clone());
+            let mut sequencer =
+                Sequencer::for_rebase(&git_path, &db, &workspace, &mut index, &refs)
+                    .with_output(Box::new(std::io::stdout()));
+
+            let control = Control::new(resume, skip, abort, quit);
+            if !matches!(control, Control::Start) {
+                if upstream.is_some() || onto.is_some() {
+                    bail!("cannot combine an upstream with --continue, --skip, --abort or --quit");
+                }
+                return match control {
+                    Control::Continue => sequencer.continue_sequence(),
+                    Control::Skip => sequencer.skip(),
+                    Control::Abort => sequencer.abort(),
+                    _ => sequencer.quit(),
+                };
+            }
+
+            let upstream = upstream.context("no upstream given to rebase onto")?;
+            let resolve = |name: &str| -> Result<String> {
+                db.peel(&Revision::parse(name)?.resolve(&refs, &db)?)
+            };
+            let upstream_id = resolve(&upstream)?;
+            let onto_name = onto.unwrap_or(upstream);
+            let onto_id = resolve(&onto_name)?;
+            rebase_onto(
+                &mut sequencer,
+                &db,
+                &refs,
+                &upstream_id,
+                &onto_id,
+                &onto_name,
+                interactive,
+            )?;
+        }
+        Commands::Diff {
+            commits,
+            cached,
+            find_renames,
+            find_copies,
+            stat,
+            name_status,
+            patience,
+            histogram,
+            diff_algorithm,
+            ignore_all_space,
+            ignore_space_change,
+            ignore_blank_lines,
+            word_diff,
+            paths,
+        } => {
+            // FIXME this assumes we are at root of repo
+            let root_path = std::env::current_dir()?;
+            let git_path = root_path.join(GIT_FOLDER);
+
+            let workspace = Workspace::new(root_path);
+            let db = Database::new(git_path.join("objects"));
+            let mut index = Index::new(git_path.join("index"));
+            let refs = Refs::new(git_path.clone());
+            if cached || commits.len() > 1 {
+                index.load()?;
+            } else {
+                // Files compared to the index are refreshed when no other process holds it
+                let locked = index.load_for_refresh()?;
+                staging::refresh_index(&workspace, &mut index)?;
+                if locked {
+                    index.write_updates()?;
+                }
+            }
+
+            let mut options = diff_options(&git_path)?;
+            if let Some(algorithm) = diff_algorithm {
+                options.algorithm = algorithm.parse()?;
+            }
+            if patience {
+                options.algorithm = DiffAlgorithm::Patience;
+            } else if histogram {
+                options.algorithm = DiffAlgorithm::Histogram;
+            }
+            options.ignore_all_space = ignore_all_space;
+            options.ignore_space_change = ignore_space_change;
+            options.ignore_blank_lines = ignore_blank_lines;
+            options.word_diff = word_diff
+                .map(|mode| mode.as_deref().unwrap_or("plain").parse())
+                .transpose()?;
+
+            let commit_items = |revision: &str| -> Result<BTreeMap<String, TreeItem>> {
+                let commit_id = Revision::parse(revision)?.resolve(&refs, &db)?;
+                db.load_tree_entries(&db.load_tree_id(&commit_id)?)
+            };
+            let (old, new) = match (commits.as_slice(), cached) {
+                ([], false) => (
+                    index.tree_items(),
+                    staging::workspace_items(&workspace, &db, &index)?,
+                ),
+                ([], true) => (commit_items(HEAD)?, index.tree_items()),
+                ([commit], false) => (
+                    commit_items(commit)?,
+                    staging::workspace_items(&workspace, &db, &index)?,
+                ),
+                ([commit], true) => (commit_items(commit)?, index.tree_items()),
+                ([old, new], false) => (commit_items(old)?, commit_items(new)?),
+                _ => bail!("usage: diff [--cached] [<commit> [<commit>]] [-- <path>...]"),
+            };
+            let pathspec = Pathspec::parse(&paths, "")?;
+            let limit = |items: BTreeMap<String, TreeItem>| -> BTreeMap<String, TreeItem> {
+                items
+                    .into_iter()
+                    .filter(|(path, _)| pathspec.matches(path))
+                    .collect()
+            };
+            let (old, new) = (limit(old), limit(new));
+
+            let score = |score: Option<String>| rename::parse_score(&score.unwrap_or_default());
+            let detection = Detection {
+                rename_score: find_renames.map(score).transpose()?,
+                copy_score: find_copies.map(score).transpose()?,
+            };
+            let changes = rename::detect(&db, patch::tree_changes(&old, &new), &detection)?;
+
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            if name_status {
+                patch::write_name_status(&mut out, &changes)?;
+            } else if stat {
+                if !changes.is_empty() {
+                    patch::write_stat(&mut out, &db, &changes, &options)?;
+                }
+            } else {
+                for change in &changes {
+                    patch::write_patch(&mut out, &db, change, &options)?;
+                }
+            }
+        }
+        Commands::Log {
+            revisions,
+            paths,
+            follow,
+            oneline,
+            name_status,
+        } => {
+            let git_path = std::env::current_dir()?.join(GIT_FOLDER);
+            let db = Database::new(git_path.join("objects"));
+            let refs = Refs::new(git_path.clone());
+
+            let revisions = if revisions.is_empty() {
+                vec![HEAD.to_string()]
+            } else {
+                revisions
+            };
+            let tips = revisions
+                .iter()
+                .map(|revision| db.peel(&Revision::parse(revision)?.resolve(&refs, &db)?))
+                .collect::<Result<Vec<_>>>()?;
+            let mut log = match (follow, paths.as_slice()) {
+                (true, [path]) => Log::follow(&db, path)?,
+                (true, _) => bail!("--follow requires exactly one pathspec"),
+                (false, _) => Log::new(&db, Pathspec::parse(&paths, "")?),
+            };
+            if name_status {
+                log = log.with_changes();
+            }
+
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            for (k, entry) in log.walk(&tips)?.iter().enumerate() {
+                let commit = &entry.commit;
+                if oneline {
+                    let summary = commit.message().lines().next().unwrap_or_default();
+                    writeln!(out, "{} {}", entry.commit_id, summary)?;
+                } else {
+                    if k > 0 {
+                        writeln!(out)?;
+                    }
+                    writeln!(out, "commit {}", entry.commit_id)?;
+                    if commit.parents().len() > 1 {
+                        let parents: Vec<&str> =
+                            commit.parents().iter().map(|parent| &parent[..7]).collect();
+                        writeln!(out, "Merge: {}", parents.join(" "))?;
+                    }
+                    let author = commit.author();
+                    writeln!(out, "Author: {} <{}>", author.name, author.email)?;
+                    writeln!(
+                        out,
+                        "Date:   {}",
+                        author.time.format("%a %b %-d %H:%M:%S %Y %z")
+                    )?;
+                    writeln!(out)?;
+                    for line in commit.message().lines() {
+                        writeln!(out, "    {}", line)?;
+                    }
+                }
+                if name_status && !entry.changes.is_empty() {
+                    if !oneline {
+                        writeln!(out)?;
+                    }
+                    patch::write_name_status(&mut out, &entry.changes)?;
+                }
+            }
+        }
+        Commands::Status { paths, short } => {
+            // FIXME this assumes we are at root of repo
+            let root_path = std::env::current_dir()?;
+            let git_path = root_path.join(GIT_FOLDER);
+
+            let workspace = Workspace::new(root_path);
+            let db = Database::new(git_path.join("objects"));
+            let mut index = Index::new(git_path.join("index"));
+            let refs = Refs::new(git_path.clone());
+            // Unchanged files get their stat information back like git status does, the index
+            // is only written when no other process holds it
+            let locked = index.load_for_refresh()?;
+            staging::refresh_index(&workspace, &mut index)?;
+            if locked {
+                index.write_updates()?;
+            }
+
+            let pathspec = Pathspec::parse(&paths, "")?;
+            let limit = |items: BTreeMap<String, TreeItem>| -> BTreeMap<String, TreeItem> {
+                items
+                    .into_iter()
+                    .filter(|(path, _)| pathspec.matches(path))
+                    .collect()
+            };
+            let head_items = match refs.read_head() {
+                Some(head) => db.load_tree_entries(&db.load_tree_id(&head)?)?,
+                None => BTreeMap::new(),
+            };
+            // Conflicted paths only have entries in stages 1 to 3, with the stages they have
+            let mut unmerged: BTreeMap<String, [bool; 3]> = BTreeMap::new();
+            for entry in index.entries() {
+                if entry.stage() > 0 && pathspec.matches(entry.path()) {
+                    let stages = unmerged.entry(entry.path().to_string()).or_default();
+                    stages[(entry.stage() - STAGE_BASE) as usize] = true;
+                }
+            }
+            let is_merged = |change: &patch::FileChange| !unmerged.contains_key(&change.path);
+
+            let index_items = limit(index.tree_items());
+            let staged: Vec<patch::FileChange> = rename::detect(
+                &db,
+                patch::tree_changes(&limit(head_items), &index_items),
+                &Detection::renames(),
+            )?
+            .into_iter()
+            .filter(is_merged)
+            .collect();
+            let worktree_items = limit(staging::workspace_items(&workspace, &db, &index)?);
+            let unstaged: Vec<patch::FileChange> =
+                patch::tree_changes(&index_items, &worktree_items)
+                    .into_iter()
+                    .filter(is_merged)
+                    .collect();
+            let untracked = staging::untracked_files(&workspace, &index, &pathspec)?;
+
+            if short {
+                // The staged status goes in the first column and the unstaged one in the second
+                let mut lines: BTreeMap<&str, (char, char, Option<&str>)> = BTreeMap::new();
+                for change in &staged {
+                    let origin = change.origin.as_ref().map(|origin| origin.path.as_str());
+                    lines.insert(&change.path, (change.status(), ' ', origin));
+                }
+                for change in &unstaged {
+                    lines.entry(&change.path).or_insert((' ', ' ', None)).1 = change.status();
+                }
+                for (path, stages) in &unmerged {
+                    let ((x, y), _) = unmerged_status(*stages);
+                    lines.insert(path, (x, y, None));
+                }
+                for (path, (x, y, origin)) in lines {
+                    match origin {
+                        Some(origin) => println!("{}{} {} -> {}", x, y, origin, path),
+                        None => println!("{}{} {}", x, y, path),
+                    }
+                }
+                for path in untracked {
+                    println!("?? {}", path);
+                }
+                return Ok(());
+            }
+
+            match (refs.read_symref(HEAD), refs.read_head()) {
+                (Some(name), _) => println!("On branch {}", name.trim_start_matches("refs/heads/")),
+                (None, Some(head)) => println!("HEAD detached at {}", &head[..7]),
+                (None, None) => println!("No commits yet"),
+            }
+            let print_changes = |title: &str, changes: &[patch::FileChange]| {
+                if changes.is_empty() {
+                    return;
+                }
+                println!("\n{}", title);
+                for change in changes {
+                    let label = match change.status() {
+                        'R' => "renamed:",
+                        'C' => "copied:",
+                        'A' => "new file:",
+                        'D' => "deleted:",
+                        _ => "modified:",
+                    };
+                    match &change.origin {
+                        Some(origin) => {
+                            println!("\t{:<12}{} -> {}", label, origin.path, change.path)
+                        }
+                        None => println!("\t{:<12}{}", label, change.path),
+                    }
+                }
+            };
+            print_changes("Changes to be committed:", &staged);
+            if !unmerged.is_empty() {
+                println!("\nUnmerged paths:");
+                for (path, stages) in &unmerged {
+                    println!("\t{:<17}{}", unmerged_status(*stages).1, path);
+                }
+            }
+            print_changes("Changes not staged for commit:", &unstaged);
+            if !untracked.is_empty() {
+                println!("\nUntracked files:");
+                for path in &untracked {
+                    println!("\t{}", path);
+                }
+            }
+            if staged.is_empty()
+                && unmerged.is_empty()
+                && unstaged.is_empty()
+                && untracked.is_empty()
+            {
+                println!("nothing to commit, working tree clean");
+            }
+        }
+        Commands::Blame {
+            file,
+            range,
+            porcelain,
+            ignore_whitespace,
+            find_moves,
+        } => {
+            let git_path = std::env::current_dir()?.join(GIT_FOLDER);
+            let db = Database::new(git_path.join("objects"));
+            let refs = Refs::new(git_path.clone());
+
+            let head = Revision::parse(HEAD)?.resolve(&refs, &db)?;
+            let mut options = diff_options(&git_path)?;
+            options.ignore_all_space = ignore_whitespace;
+            let mut blame = Blame::new(&db, options, find_moves);
+            let lines = blame.blame(&head, &file, range.as_deref())?;
+
+            let mut stdout = std::io::stdout();
+            if porcelain {
+                let mut shown = BTreeSet::new();
+                for (k, line) in lines.iter().enumerate() {
+                    let previous = k.checked_sub(1).map(|k| &lines[k]);
+                    let starts_group = previous.is_none_or(|previous| {
+                        previous.commit_id != line.commit_id
+                            || previous.original_line + 1 != line.original_line
+                    });
+                    if !starts_group {
+                        writeln!(
+                            stdout,
+                            "{} {} {}",
+                            line.commit_id, line.original_line, line.final_line
+                        )?;
+                    } else {
+                        let group_len = lines[k..]
+                            .iter()
+                            .enumerate()
+                            .take_while(|(n, other)| {
+                                other.commit_id == line.commit_id
+                                    && other.original_line == line.original_line + n
+                            })
+                            .count();
+                        writeln!(
+                            stdout,
+                            "{} {} {} {}",
+                            line.commit_id, line.original_line, line.final_line, group_len
+                        )?;
+                        if shown.insert(line.commit_id.clone()) {
+                            let commit = blame.commit(&line.commit_id)?;
+                            for (role, identity) in [
+                                ("author", commit.author()),
+                                ("committer", commit.committer()),
+                            ] {
+                                writeln!(stdout, "{} {}", role, identity.name)?;
+                                writeln!(stdout, "{}-mail <{}>", role, identity.email)?;
+                                writeln!(stdout, "{}-time {}", role, identity.time.timestamp())?;
+                                writeln!(stdout, "{}-tz {}", role, identity.time.format("%z"))?;
+                            }
+                            let summary = commit.message().lines().next().unwrap_or_default();
+                            writeln!(stdout, "summary {}", summary)?;
+                            if commit.parents().is_empty() || db.is_shallow(&line.commit_id) {
+                                writeln!(stdout, "boundary")?;
+                            }
+                        }
+                        writeln!(stdout, "filename {}", line.path)?;
+                    }
+                    write!(stdout, "\t")?;
+                    stdout.write_all(&line.content)?;
+                }
+            } else {
+                let show_path = lines.iter().any(|line| line.path != file);
+                let path_width = lines.iter().map(|line| line.path.len()).max().unwrap_or(0);
+                let number_width = lines
+                    .last()
+                    .map_or(1, |line| line.final_line.to_string().len());
+                let mut authors = BTreeMap::new();
+                for line in &lines {
+                    let commit = blame.commit(&line.commit_id)?;
+                    let abbrev = if commit.parents().is_empty() || db.is_shallow(&line.commit_id) {
+                        format!("^{}", &line.commit_id[..7])
+                    } else {
+                        line.commit_id[..8].to_string()
+                    };
+                    let author = commit.author();
+                    let date = author.time.format("%Y-%m-%d %H:%M:%S %z");
+                    authors.insert(line.commit_id.clone(), (abbrev, author.name.clone(), date));
+                }
+                let author_width = authors
+                    .values()
+                    .map(|(_, name, _)| name.chars().count())
+                    .max()
+                    .unwrap_or(0);
+                for line in &lines {
+                    let (abbrev, name, date) = &authors[&line.commit_id];
+                    write!(stdout, "{}", abbrev)?;
+                    if show_path {
+                        write!(stdout, " {:width$}", line.path, width = path_width)?;
+                    }
+                    write!(
+                        stdout,
+                        " ({:author_width$} {} {:>number_width$}) ",
+                        name,
+                        date,
+                        line.final_line,
+                        author_width = author_width,
+                        number_width = number_width
+                    )?;
+                    stdout.write_all(&line.content)?;
+                }
+            }
+        }
+        Commands::Grep {
+            pattern,
+            trees,
+            paths,
+            cached,
+            basic_regexp,
+            extended_regexp,
+            fixed_strings,
+            perl_regexp,
+            line_number,
+            ignore_case,
+            word_regexp,
+            files_with_matches,
+            count,
+            after_context,
+            before_context,
+            context,
+            threads,
+        } => {
+            // FIXME this assumes we are at root of repo
+            let root_path = std::env::current_dir()?;
+            let git_path = root_path.join(GIT_FOLDER);
+
+            let workspace = Workspace::new(root_path);
+            let db = Database::new(git_path.join("objects"));
+            let mut index = Index::new(git_path.join("index"));
+            let refs = Refs::new(git_path.clone());
+            index.load()?;
+
+            if cached && !trees.is_empty() {
+                bail!("--cached cannot be used with a tree");
+            }
+            // The last flag wins like in git
+            let pattern_type = [
+                (basic_regexp, PatternType::Basic),
+                (extended_regexp, PatternType::Extended),
+                (fixed_strings, PatternType::Fixed),
+                (perl_regexp, PatternType::Perl),
+            ]
+            .iter()
+            .rev()
+            .find(|(set, _)| *set)
+            .map_or(PatternType::Basic, |(_, pattern_type)| *pattern_type);
+            let options = GrepOptions {
+                pattern_type,
+                ignore_case,
+                word_regexp,
+                line_number,
+                files_with_matches,
+                count,
+                before_context: before_context.or(context).unwrap_or(0),
+                after_context: after_context.or(context).unwrap_or(0),
+            };
+
+            let pathspec = Pathspec::parse(&paths, "")?;
+            let mut files = vec![];
+            if trees.is_empty() {
+                let mut last_path = None;
+                for entry in index.entries() {
+                    let path = entry.path();
+                    if last_path == Some(path) || !pathspec.matches(path) {
+                        continue;
+                    }
+                    last_path = Some(path);
+                    let content = if cached {
+                        Content::Blob(entry.object_id().clone())
+                    } else if workspace.file_metadata(Path::new(path)).is_ok() {
+                        Content::Workspace(path.to_string())
+                    } else {
+                        continue;
+                    };
+                    files.push(GrepFile {
+                        name: path.to_string(),
+                        content,
+                    });
+                }
+            }
+            for tree in &trees {
+                let object_id = Revision::parse(tree)?.resolve(&refs, &db)?;
+                for (path, item) in db.load_tree_entries(&db.load_tree_id(&object_id)?)? {
+                    if pathspec.matches(&path) {
+                        files.push(GrepFile {
+                            name: format!("{}:{}", tree, path),
+                            content: Content::Blob(item.object_id),
+                        });
+                    }
+                }
+            }
+
+            let grep = Grep::new(&db, &workspace, &pattern, options)?;
+            let mut stdout = std::io::stdout();
+            let config = Config::load(git_path.join("config"))?;
+            let threads = staging::jobs(threads, &config, "grep.threads")?;
+            if !grep.run(&mut stdout, &files, threads)? {
+                std::process::exit(1);
+            }
+        }
+        Commands::Clone {
+            repository,
+            directory,
+            depth,
+            filter,
+        } => {
+            if depth == Some(0) {
+                bail!("depth 0 is not a positive number");
+            }
+            let directory = match directory {
+                Some(directory) => directory,
+                None => {
+                    let name = repository
+                        .trim_end_matches('/')
+                        .rsplit('/')
+                        .next()
+                        .unwrap_or_default();
+                    let name = name.strip_suffix(".git").unwrap_or(name);
+                    if name.is_empty() {
+                        bail!("cannot guess a directory name from {}", repository);
+                    }
+                    PathBuf::from(name)
+                }
+            };
+            println!("Cloning into '{}'...", directory.display());
+            let options = FetchOptions {
+                deepen: depth.map(Deepen::Depth),
+                filter,
+            };
+            remote::clone(&repository, &directory, &options, &Author::from_env())?;
+            if Refs::new(directory.join(GIT_FOLDER)).read_head().is_none() {
+                println!("warning: You appear to have cloned an empty repository.");
+            }
+        }
+        Commands::Fetch {
+            remote: name,
+            deepen,
+            unshallow,
+        } => {
+            let git_path = std::env::current_dir()?.join(GIT_FOLDER);
+            let db = Database::new(git_path.join("objects"));
+            let refs = Refs::new(git_path.clone());
+            let config = Config::load(git_path.join("config"))?;
+
+            let deepen = match (deepen, unshallow) {
+                (Some(_), true) => bail!("--deepen and --unshallow can't be used together"),
+                (_, true) if db.shallow().is_empty() => {
+                    bail!("--unshallow on a complete repository does not make sense")
+                }
+                (_, true) => Some(Deepen::Depth(INFINITE_DEPTH)),
+                (Some(0), _) => bail!("depth 0 is not a positive number"),
+                (Some(depth), _) => Some(Deepen::Relative(depth)),
+                (None, false) => None,
+            };
+            let options = FetchOptions {
+                deepen,
+                ..FetchOptions::default()
+            };
+
+            let remote = Remote::find(&config, name.as_deref().unwrap_or(remote::ORIGIN))?;
+            let mut transport = remote.connect()?;
+            let (_, updates) = remote::fetch(
+                &git_path,
+                &db,
+                &refs,
+                &remote,
+                transport.as_mut(),
+                &options,
+                &Author::from_env(),
+            )?;
+            report_fetch(&remote, &updates)?;
+        }
+        Commands::Pull {
+            remote: name,
+            ff_only,
+            ff,
+            no_ff,
+            rebase,
+            no_rebase,
+        } => {
+            if [ff_only, ff, no_ff].iter().filter(|flag| **flag).count() > 1 {
+                bail!("Only one of --ff, --no-ff and --ff-only can be used");
+            }
+            if rebase && no_rebase {
+                bail!("--rebase and --no-rebase can't be used together");
+            }
+
+            // FIXME this assumes we are at root of repo
+            let root_path = std::env::current_dir()?;
+            let git_path = root_path.join(GIT_FOLDER);
+
+            let workspace = Workspace::new(root_path);
+            let db = Database::new(git_path.join("objects"));
+            let mut index = Index::new(git_path.join("index"));
+            let refs = Refs::new(git_path.clone());
+            let config = Config::load(git_path.join("config"))?;
+
+            let branch = match refs.read_symref(HEAD) {
+                Some(branch) => branch.trim_start_matches("refs/heads/").to_string(),
+                None => bail!("You are not currently on a branch."),
+            };
+            let upstream_remote = config.get(&format!("branch.{}.remote", branch));
+            let name = name
+                .or_else(|| upstream_remote.clone())
+                .unwrap_or_else(|| remote::ORIGIN.to_string());
+            let merge_ref = match config.get(&format!("branch.{}.merge", branch)) {
+                Some(merge_ref) if upstream_remote.as_deref() == Some(name.as_str()) => merge_ref,
+                _ => bail!("There is no tracking information for the current branch.\nPlease specify which branch you want to merge with."),
+            };
+
+            let remote = Remote::find(&config, &name)?;
+            let mut transport = remote.connect()?;
+            let options = FetchOptions::default();
+            let (remote_refs, updates) = remote::fetch(
+                &git_path,
+                &db,
+                &refs,
+                &remote,
+                transport.as_mut(),
+                &options,
+                &Author::from_env(),
+            )?;
+            report_fetch(&remote, &updates)?;
+            let upstream_id = remote_refs.get(&merge_ref).cloned().with_context(|| {
+                format!(
+                    "Your configuration specifies to merge with the ref '{}' from the remote, but no such ref was fetched.",
+                    remote::short_name(&merge_ref)
+                )
+            })?;
+            // Without a fetch refspec for it, the upstream itself wasn't fetched
+            if !db.exists(&upstream_id) {
+                let haves: Vec<String> = refs.read_head().into_iter().collect();
+                let wants = std::slice::from_ref(&upstream_id);
+                transport.fetch_objects(&db, wants, &haves, &options)?;
+            }
+
+            let upstream_name = remote
+                .fetch
+                .iter()
+                .find_map(|refspec| refspec.map(&merge_ref))
+                .unwrap_or_else(|| merge_ref.clone());
+            let upstream_name = remote::short_name(&upstream_name);
+            let rebase = if rebase || no_rebase {
+                rebase
+            } else {
+                pull::rebase_setting(&config, &branch)?
+            };
+            let fast_forward = if ff_only {
+                FastForward::Only
+            } else if no_ff {
+                FastForward::Never
+            } else if ff {
+                FastForward::Allow
+            } else {
+                FastForward::from_config(&config)?
+            };
+
+            // An unborn branch has nothing to rebase and is simply fast-forwarded
+            if rebase && refs.read_head().is_some() {
+                let mut sequencer =
+                    Sequencer::for_rebase(&git_path, &db, &workspace, &mut index, &refs)
+                        .with_output(Box::new(std::io::stdout()));
+                rebase_onto(
+                    &mut sequencer,
+                    &db,
+                    &refs,
+                    &upstream_id,
+                    &upstream_id,
+                    upstream_name,
+                    false,
+                )?;
+            } else {
+                let message = format!(
+                    "Merge branch '{}' of {}",
+                    remote::short_name(&merge_ref),
+                    remote.url
+                );
+                let mut merge = Merge::new(&git_path, &db, &workspace, &mut index, &refs)
+                    .with_output(Box::new(std::io::stdout()));
+                let outcome =
+                    merge.run(&upstream_id, upstream_name, &message, fast_forward, "pull")?;
+                if outcome == Outcome::UpToDate {
+                    println!("Already up to date.");
+                }
+            }
+        }
+        Commands::Push {
+            remote: name,
+            refspecs,
+            force,
+            force_with_lease,
+        } => {
+            let git_path = std::env::current_dir()?.join(GIT_FOLDER);
+            let db = Database::new(git_path.join("objects"));
+            let refs = Refs::new(git_path.clone());
+            let config = Config::load(git_path.join("config"))?;
+
+            let remote = Remote::find(&config, name.as_deref().unwrap_or(remote::ORIGIN))?;
+            let refspecs: Vec<Refspec> = if refspecs.is_empty() {
+                let branch = match refs.read_symref(HEAD) {
+                    Some(branch) => branch,
+                    None => bail!("You are not currently on a branch."),
+                };
+                let name = branch.trim_start_matches("refs/heads/");
+                let is_upstream = config.get(&format!("branch.{}.remote", name)).as_deref()
+                    == Some(remote.name.as_str());
+                let target = match config.get(&format!("branch.{}.merge", name)) {
+                    Some(merge) if is_upstream => merge,
+                    _ => branch.clone(),
+                };
+                vec![format!("{}:{}", branch, target).parse()?]
+            } else {
+                refspecs
+                    .iter()
+                    .map(|refspec| refspec.parse())
+                    .collect::<Result<_>>()?
+            };
+            let options = remote::PushOptions {
+                force,
+                lease: force_with_lease
+                    .map(|lease| lease.unwrap_or_default().parse())
+                    .transpose()?,
+            };
+
+            let mut transport = remote.connect()?;
+            let updates = remote::push(
+                &db,
+                &refs,
+                &remote,
+                transport.as_mut(),
+                &refspecs,
+                &options,
+                &Author::from_env(),
+            )?;
+            let changed: Vec<_> = updates
+                .iter()
+                .filter(|update| update.status != UpdateStatus::UpToDate)
+                .collect();
+            if changed.is_empty() {
+                println!("Everything up-to-date");
+                return Ok(());
+            }
+            println!("To {}", remote.url);
+            for update in &changed {
+                println!("{}", update);
+            }
+            let rejected = changed.iter().any(|update| {
+                matches!(
+                    update.status,
+                    UpdateStatus::Rejected | UpdateStatus::RemoteRejected
+                )
+            });
+            if rejected {
+                bail!("failed to push some refs to '{}'", remote.url);
+            }
+        }
+        Commands::UploadPack {
+            directory,
+            advertise_refs,
+            stateless_rpc,
+        } => {
+            let version = server::protocol_version(std::env::var("GIT_PROTOCOL").ok().as_deref());
+            let options = server::ServeOptions {
+                advertise_refs,
+                stateless_rpc,
+            };
+            let mut output = BufWriter::new(std::io::stdout());
+            server::upload_pack(
+                &directory,
+                version,
+                options,
+                &mut std::io::stdin(),
+                &mut output,
+            )?;
+        }
+        Commands::ReceivePack {
+            directory,
+            advertise_refs,
+            stateless_rpc,
+        } => {
+            let options = server::ServeOptions {
+                advertise_refs,
+                stateless_rpc,
+            };
+            let mut output = BufWriter::new(std::io::stdout());
+            server::receive_pack(&directory, options, &mut std::io::stdin(), &mut output)?;
+        }
+        Commands::Daemon {
+            base_path,
+            export_all,
+            listen,
+            port,
+        } => {
+            let options = DaemonOptions {
+                base_path: base_path.unwrap_or(std::env::current_dir()?),
+                export_all,
+            };
+            let listener = TcpListener::bind((listen.as_str(), port))
+                .with_context(|| format!("Failed to listen on {}:{}", listen, port))?;
+            log::info!("Ready to serve on {}", listener.local_addr()?);
+            daemon::serve(listener, options)?;
+        }
+        Commands::Config {
+            name,
+            value,
+            add,
+            get_all,
+            unset,
+            list,
+        } => {
+            let git_path = std::env::current_dir()?.join(GIT_FOLDER);
+            let mut config = Config::load(git_path.join("config"))?;
+
+            if list {
+                for (name, value) in config.list() {
+                    println!("{}={}", name, value);
+                }
+                return Ok(());
+            }
+            let name = name.expect("Failed to get name");
+            match value {
+                _ if unset => {
+                    if !config.unset(&name)? {
+                        bail!("no such key: {}", name);
+                    }
+                    config.save()?;
+                }
+                Some(value) if add => {
+                    config.add(&name, &value)?;
+                    config.save()?;
+                }
+                Some(value) => {
+                    config.set(&name, &value)?;
+                    config.save()?;
+                }
+                None if get_all => {
+                    for value in config.get_all(&name) {
+                        println!("{}", value);
+                    }
+                }
+                None => match config.get(&name) {
+                    Some(value) => println!("{}", value),
+                    None => std::process::exit(1),
+                },
+            }
+        }
+        Commands::Bisect { command } => {
+            // FIXME this assumes we are at root of repo
+            let root_path = std::env::current_dir()?;
+            let git_path = root_path.join(GIT_FOLDER);
+
+            let workspace = Workspace::new(root_path);
+            let db = Database::new(git_path.join("objects"));
+            let mut index = Index::new(git_path.join("index"));
+            let refs = Refs::new(git_path.clone());
+            let mut bisect = Bisect::new(&git_path, &db, &workspace, &mut index, &refs)
+                .with_output(Box::new(std::io::stdout()));
+
+            match command {
+                BisectCommand::Start { bad, good } => bisect.start(bad.as_deref(), &good)?,
+                BisectCommand::Good { revisions } => {
+                    bisect.mark(Term::Good, &revisions)?;
+                }
+                BisectCommand::Bad { revision } => {
+                    let revisions: Vec<String> = revision.into_iter().collect();
+                    bisect.mark(Term::Bad, &revisions)?;
+                }
+                BisectCommand::Skip { revisions } => {
+                    bisect.mark(Term::Skip, &revisions)?;
+                }
+                BisectCommand::Reset { commit } => bisect.reset(commit.as_deref())?,
+                BisectCommand::Log => print!("{}", bisect.read_log()?),
+                BisectCommand::Replay { file } => bisect.replay(&file)?,
+                BisectCommand::Run { command } => {
+                    bisect.run(&command)?;
+                }
+            }
+        }
+        Commands::Stash { command } => {
+            // FIXME this assumes we are at root of repo
+            let root_path = std::env::current_dir()?;
+            let git_path = root_path.join(GIT_FOLDER);
+
+            let workspace = Workspace::new(root_path);
+            let db = Database::new(git_path.join("objects"));
+            let mut index = Index::new(git_path.join("index"));
+            let refs = Refs::new(git_path.clone());
+            let mut stash = Stash::new(&db, &workspace, &mut index, &refs)
+                .with_output(Box::new(std::io::stdout()));
+
+            let command = command.unwrap_or(StashCommand::Push {
+                message: None,
+                include_untracked: false,
+                paths: vec![],
+            });
+            match command {
+                StashCommand::Push {
+                    message,
+                    include_untracked,
+                    paths,
+                } => {
+                    let pathspec = Pathspec::parse(&paths, "")?;
+                    stash.push(message.as_deref(), &pathspec, include_untracked)?;
+                }
+                StashCommand::Pop { stash: selector } => {
+                    stash.pop(stash::parse_selector(selector.as_deref())?)?
+                }
+                StashCommand::Apply {
+                    stash: selector,
+                    index,
+                } => stash.apply(stash::parse_selector(selector.as_deref())?, index)?,
+                StashCommand::List => {
+                    for (i, message) in stash.list()?.iter().enumerate() {
+                        println!("stash@{{{}}}: {}", i, message);
+                    }
+                }
+                StashCommand::Show {
+                    stash: selector,
+                    patch,
+                } => {
+                    let changes = stash.changes(stash::parse_selector(selector.as_deref())?)?;
+                    let options = diff_options(&git_path)?;
+                    let stdout = std::io::stdout();
+                    let mut out = stdout.lock();
+                    if patch {
+                        for change in &changes {
+                            patch::write_patch(&mut out, &db, change, &options)?;
+                        }
+                    } else {
+                        patch::write_stat(&mut out, &db, &changes, &options)?;
+                    }
+                }
+                StashCommand::Drop { stash: selector } => {
+                    stash.drop(stash::parse_selector(selector.as_deref())?)?
+                }
+                StashCommand::Clear => stash.clear()?,
+            }
+        }
+        Commands::LsFiles { paths, stage } => {
+            let git_path = std::env::current_dir()?.join(GIT_FOLDER);
+            let mut index = Index::new(git_path.join("index"));
+            index.load()?;
+
+            let pathspec = Pathspec::parse(&paths, "")?;
+            let mut last_path = None;
+            for entry in index
+                .entries()
+                .filter(|entry| pathspec.matches(entry.path()))
+            {
+                if stage {
+                    println!(
+                        "{:06o} {} {}\t{}",
+                        entry.mode(),
+                        entry.object_id(),
+                        entry.stage(),
+                        entry.path()
+                    );
+                } else if last_path != Some(entry.path()) {
+                    // Every stage of an unmerged path is listed only once
+                    println!("{}", entry.path());
+                }
+                last_path = Some(entry.path());
+            }
+        }
+        Commands::RevParse { revision } => {
+            let git_path = std::env::current_dir()?.join(GIT_FOLDER);
+            let db = Database::new(git_path.join("objects"));
+            let refs = Refs::new(git_path);
+            println!("{}", Revision::parse(&revision)?.resolve(&refs, &db)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// The short status and the description of a conflicted path, from the stages it has in
+/// the index: the base, ours and theirs
+fn unmerged_status(stages: [bool; 3]) -> ((char, char), &'static str) {
+    match stages {
+        [true, true, true] => (('U', 'U'), "both modified:"),
+        [false, true, true] => (('A', 'A'), "both added:"),
+        [true, false, true] => (('D', 'U'), "deleted by us:"),
+        [true, true, false] => (('U', 'D'), "deleted by them:"),
+        [false, true, false] => (('A', 'U'), "added by us:"),
+        [false, false, true] => (('U', 'A'), "added by them:"),
+        _ => (('D', 'D'), "both deleted:"),
+    }
+}
+
+/// Reads the diff options set in the config, like diff.algorithm
+fn diff_options(git_path: &Path) -> Result<DiffOptions> {
+    let config = Config::load(git_path.join("config"))?;
+    let mut options = DiffOptions::default();
+    if let Some(algorithm) = config.get("diff.algorithm") {
+        options.algorithm = algorithm.parse()?;
+    }
+    Ok(options)
+}
+
+/// What to do with the commits given to cherry-pick and revert, or with the sequence in progress
+enum Control {
+    Start,
+    Continue,
+    Skip,
+    Abort,
+    Quit,
+}
+
+impl Control {
+    fn new(resume: bool, skip: bool, abort: bool, quit: bool) -> Self {
+        if resume {
+            Control::Continue
+        } else if skip {
+            Control::Skip
+        } else if abort {
+            Control::Abort
+        } else if quit {
+            Control::Quit
+        } else {
+            Control::Start
+        }
+    }
+}
+
+/// Prints the refs a fetch changed, like the summary of `git fetch`
+fn report_fetch(remote: &Remote, updates: &[remote::RefUpdate]) -> Result<()> {
+    let changed: Vec<_> = updates
+        .iter()
+        .filter(|update| update.status != UpdateStatus::UpToDate)
+        .collect();
+    if !changed.is_empty() {
+        println!("From {}", remote.url);
+        for update in &changed {
+            println!("{}", update);
+        }
+    }
+    if changed
+        .iter()
+        .any(|update| update.status == UpdateStatus::Rejected)
+    {
+        bail!("some refs were not updated");
+    }
+    Ok(())
+}
+
+/// Replays the commits of HEAD that aren't reachable from `upstream_id` on top of `onto_id`
+fn rebase_onto(
+    sequencer: &mut Sequencer,
+    db: &Database,
+    refs: &Refs,
+    upstream_id: &str,
+    onto_id: &str,
+    onto_name: &str,
+    interactive: bool,
+) -> Result<()> {
+    let head_id = refs.read_head().context("Cannot rebase without a HEAD")?;
+
+    // Merge commits are dropped, their changes are in the replayed commits
+    let commands = rev_list::commits_between(db, &[upstream_id.to_string()], &head_id)?
+        .into_iter()
+        .map(|commit_id| {
+            let (_, data) = db.load(&commit_id)?;
+            Ok((commit_id, Commit::parse(&data)?.parents().len() > 1))
+        })
+        .filter(|result| !matches!(result, Ok((_, true))))
+        .map(|result| {
+            result.map(|(commit_id, _)| Command::Commit {
+                action: Action::Pick,
+                commit_id,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let head_name = refs.read_symref(HEAD);
+    let short_name = head_name
+        .as_deref()
+        .map(|name| name.trim_start_matches("refs/heads/"))
+        .unwrap_or("HEAD");
+    if !interactive && rev_list::is_ancestor(db, onto_id, &head_id)? {
+        let onto_base = rev_list::commits_between(db, &[onto_id.to_string()], &head_id)?;
+        if onto_base.len() == commands.len() {
+            println!("Current branch {} is up to date.", short_name);
+            return Ok(());
+        }
+    }
+
+    sequencer.start_rebase(onto_id, onto_name, &commands, interactive)
+}
+
+fn run_sequencer(
+    action: Action,
+    commits: &[String],
+    options: &Options,
+    control: Control,
+) -> Result<()> {
+    // FIXME this assumes we are at root of repo
+    let root_path = std::env::current_dir()?;
+    let git_path = root_path.join(GIT_FOLDER);
+
+    let workspace = Workspace::new(root_path);
+    let db = Database::new(git_path.join("objects"));
+    let mut index = Index::new(git_path.join("index"));
+    let refs = Refs::new(git_path.clone());
+
+    let commands = commits
+        .iter()
+        .map(|commit| {
+            let commit_id = db.peel(&Revision::parse(commit)?.resolve(&refs, &db)?)?;
+            Ok(Command::Commit { action, commit_id })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if !commands.is_empty() && !matches!(control, Control::Start) {
+        bail!("cannot combine commits with --continue, --skip, --abort or --quit");
+    }
+
+    let mut sequencer = Sequencer::new(&git_path, &db, &workspace, &mut index, &refs)
+        .with_output(Box::new(std::io::stdout()));
+    match control {
+        Control::Start if commands.is_empty() => bail!("empty commit set passed"),
+        Control::Start => sequencer.start(&commands, options),
+        Control::Continue => sequencer.continue_sequence(),
+        Control::Skip => sequencer.skip(),
+        Control::Abort => sequencer.abort(),
+        Control::Quit => sequencer.quit(),
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,181 @@
+use crate::diff::{self, Edit};
+
+#[derive(Debug, PartialEq)]
+pub enum Chunk<'a> {
+    Clean(Vec<&'a [u8]>),
+    Conflict {
+        base: Vec<&'a [u8]>,
+        ours: Vec<&'a [u8]>,
+        theirs: Vec<&'a [u8]>,
+    },
+}
+
+/// Result of merging two versions of a file that both descend from a common base
+#[derive(Debug)]
+pub struct Merge<'a> {
+    chunks: Vec<Chunk<'a>>,
+}
+
+impl<'a> Merge<'a> {
+    pub fn is_clean(&self) -> bool {
+        self.chunks
+            .iter()
+            .all(|chunk| matches!(chunk, Chunk::Clean(_)))
+    }
+
+    pub fn chunks(&self) -> &[Chunk<'a>] {
+        &self.chunks
+    }
+
+    /// Joins the chunks back, marking the conflicts with the name of each side
+    pub fn to_bytes(&self, ours_name: &str, theirs_name: &str) -> Vec<u8> {
+        fn push_lines(data: &mut Vec<u8>, lines: &[&[u8]]) {
+            for line in lines {
+                data.extend_from_slice(line);
+            }
+            if data.last().is_some_and(|byte| *byte != b'\n') {
+                data.push(b'\n');
+            }
+        }
+
+        let mut data = vec![];
+        for chunk in &self.chunks {
+            match chunk {
+                Chunk::Clean(lines) => lines.iter().for_each(|line| data.extend_from_slice(line)),
+                Chunk::Conflict { ours, theirs, .. } => {
+                    push_lines(&mut data, &[]);
+                    data.extend_from_slice(format!("<<<<<<< {}\n", ours_name).as_bytes());
+                    push_lines(&mut data, ours);
+                    data.extend_from_slice(b"=======\n");
+                    push_lines(&mut data, theirs);
+                    data.extend_from_slice(format!(">>>>>>> {}\n", theirs_name).as_bytes());
+                }
+            }
+        }
+        data
+    }
+}
+
+/// Three-way merge of the lines of a file. Lines matched in both diffs from the base split the
+/// files in chunks, a chunk changed on one side only takes that change, otherwise it conflicts.
+pub fn merge<'a>(base: &'a [u8], ours: &'a [u8], theirs: &'a [u8]) -> Merge<'a> {
+    let base = diff::lines(base);
+    let ours = diff::lines(ours);
+    let theirs = diff::lines(theirs);
+
+    let match_ours = match_set(&base, &ours);
+    let match_theirs = match_set(&base, &theirs);
+
+    let mut chunks = vec![];
+    // Number of lines of each file already emitted
+    let (mut line_o, mut line_a, mut line_b) = (0, 0, 0);
+
+    loop {
+        // Length of the common run starting at the current lines, plus one
+        let mut i = 1;
+        let in_bounds = |i: usize, line_o: usize, line_a: usize, line_b: usize| {
+            line_o + i <= base.len() || line_a + i <= ours.len() || line_b + i <= theirs.len()
+        };
+        while in_bounds(i, line_o, line_a, line_b)
+            && match_ours.get(line_o + i) == Some(&Some(line_a + i))
+            && match_theirs.get(line_o + i) == Some(&Some(line_b + i))
+        {
+            i += 1;
+        }
+
+        if !in_bounds(i, line_o, line_a, line_b) {
+            break;
+        }
+
+        if i > 1 {
+            chunks.push(Chunk::Clean(ours[line_a..line_a + i - 1].to_vec()));
+            line_o += i - 1;
+            line_a += i - 1;
+            line_b += i - 1;
+            continue;
+        }
+
+        // Find the next base line matched in both files
+        let next = (line_o + 1..=base.len()).find_map(|o| match (match_ours[o], match_theirs[o]) {
+            (Some(a), Some(b)) => Some((o, a, b)),
+            _ => None,
+        });
+        let (o, a, b) = match next {
+            Some(next) => next,
+            None => break,
+        };
+        chunks.push(chunk(
+            &base[line_o..o - 1],
+            &ours[line_a..a - 1],
+            &theirs[line_b..b - 1],
+        ));
+        line_o = o - 1;
+        line_a = a - 1;
+        line_b = b - 1;
+    }
+
+    let last = chunk(&base[line_o..], &ours[line_a..], &theirs[line_b..]);
+    if last != Chunk::Clean(vec![]) {
+        chunks.push(last);
+    }
+    Merge { chunks }
+}
+
+/// Maps each line of the base, numbered from 1, to the matching line of the other file
+fn match_set(base: &[&[u8]], other: &[&[u8]]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base.len() + 1];
+    for edit in diff::diff(base, other) {
+        if let Edit::Equal(a, b) = edit {
+            matches[a + 1] = Some(b + 1);
+        }
+    }
+    matches
+}
+
+fn chunk<'a>(base: &[&'a [u8]], ours: &[&'a [u8]], theirs: &[&'a [u8]]) -> Chunk<'a> {
+    if ours == base || ours == theirs {
+        Chunk::Clean(theirs.to_vec())
+    } else if theirs == base {
+        Chunk::Clean(ours.to_vec())
+    } else {
+        Chunk::Conflict {
+            base: base.to_vec(),
+            ours: ours.to_vec(),
+            theirs: theirs.to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clean_merge() {
+        let clean = merge(b"a\nb\nc\n", b"a\nB\nc\n", b"a\nb\nc\nd\n");
+        assert!(clean.is_clean());
+        assert_eq!(clean.to_bytes("ours", "theirs"), b"a\nB\nc\nd\n");
+
+        let base = b"celery\ngarlic\nonions\nsalmon\ntomatoes\nwine\n";
+        let ours = b"celery\nsalmon\ntomatoes\ngarlic\nonions\nwine\n";
+        let theirs = b"celery\nsalmon\ngarlic\nonions\ntomatoes\nwine\n";
+        assert!(!merge(base, ours, theirs).is_clean());
+    }
+
+    #[test]
+    fn conflicting_merge() {
+        let conflict = merge(b"a\nb\nc\n", b"a\nours\nc\n", b"a\ntheirs\nc\n");
+        assert!(!conflict.is_clean());
+        assert_eq!(
+            String::from_utf8(conflict.to_bytes("HEAD", "topic")).unwrap(),
+            "a\n<<<<<<< HEAD\nours\n=======\ntheirs\n>>>>>>> topic\nc\n"
+        );
+
+        // Missing final newlines don't glue the markers to the content
+        let conflict = merge(b"", b"ours", b"theirs");
+        assert_eq!(
+            String::from_utf8(conflict.to_bytes("a", "b")).unwrap(),
+            "<<<<<<< a\nours\n=======\ntheirs\n>>>>>>> b\n"
+        );
+    }
+}
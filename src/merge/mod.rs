@@ -0,0 +1,2 @@
+pub mod diff3;
+pub mod resolve;
@@ -0,0 +1,331 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    database::{blob::Blob, tree::TreeItem, Database, MODE_EXECUTABLE},
+    diff::{
+        patch,
+        rename::{self, Detection},
+    },
+    index::{Index, STAGE_BASE, STAGE_OURS, STAGE_THEIRS},
+    merge::diff3,
+    migration, staging,
+    workspace::Workspace,
+};
+
+/// A path changed differently on both sides of a merge
+#[derive(Debug)]
+pub struct Conflict {
+    pub base: Option<TreeItem>,
+    pub ours: Option<TreeItem>,
+    pub theirs: Option<TreeItem>,
+    /// Content of the file with conflict markers when both sides modified it
+    pub content: Option<Vec<u8>>,
+}
+
+impl Conflict {
+    /// Describes the conflict like git does in its CONFLICT messages
+    pub fn kind(&self) -> &str {
+        match (&self.base, &self.ours, &self.theirs) {
+            (None, Some(_), Some(_)) => "add/add",
+            (Some(_), None, Some(_)) | (Some(_), Some(_), None) => "modify/delete",
+            _ => "content",
+        }
+    }
+}
+
+/// The trees of both sides merged against their base
+#[derive(Debug, Default)]
+pub struct TreeMerge {
+    pub clean: BTreeMap<String, TreeItem>,
+    pub conflicts: BTreeMap<String, Conflict>,
+}
+
+impl TreeMerge {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// Merges the changes from `base` to `theirs` into `ours`, path by path. Files modified on
+/// both sides are merged line by line and the merged blobs are stored in the database.
+/// A file renamed on one side takes the changes made to its old path on the other side.
+pub fn merge_trees(
+    database: &Database,
+    base: &BTreeMap<String, TreeItem>,
+    ours: &BTreeMap<String, TreeItem>,
+    theirs: &BTreeMap<String, TreeItem>,
+    ours_name: &str,
+    theirs_name: &str,
+) -> Result<TreeMerge> {
+    let (mut base, mut ours, mut theirs) = (base.clone(), ours.clone(), theirs.clone());
+    follow_renames(database, &mut base, &theirs, &mut ours)?;
+    follow_renames(database, &mut base, &ours, &mut theirs)?;
+
+    let mut paths: Vec<&String> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+    paths.sort();
+    paths.dedup();
+
+    let mut result = TreeMerge::default();
+    for path in paths {
+        let (b, o, t) = (base.get(path), ours.get(path), theirs.get(path));
+        let merged = if o == t || b == t {
+            o.cloned()
+        } else if b == o {
+            t.cloned()
+        } else if let (Some(o), Some(t)) = (o, t) {
+            let base_data = match b {
+                Some(b) => database.load(&b.object_id)?.1,
+                None => vec![],
+            };
+            let ours_data = database.load(&o.object_id)?.1;
+            let theirs_data = database.load(&t.object_id)?.1;
+            let merge = diff3::merge(&base_data, &ours_data, &theirs_data);
+            let data = merge.to_bytes(ours_name, theirs_name);
+
+            // A mode change on one side only is kept
+            let mode = match b {
+                Some(b) if o.mode == b.mode => t.mode,
+                _ => o.mode,
+            };
+            if merge.is_clean() {
+                let object_id = database.store(&Blob::new(data))?;
+                Some(TreeItem { mode, object_id })
+            } else {
+                result.conflicts.insert(
+                    path.clone(),
+                    Conflict {
+                        base: b.cloned(),
+                        ours: Some(o.clone()),
+                        theirs: Some(t.clone()),
+                        content: Some(data),
+                    },
+                );
+                continue;
+            }
+        } else {
+            // Modified on one side and deleted on the other
+            result.conflicts.insert(
+                path.clone(),
+                Conflict {
+                    base: b.cloned(),
+                    ours: o.cloned(),
+                    theirs: t.cloned(),
+                    content: None,
+                },
+            );
+            continue;
+        };
+
+        if let Some(item) = merged {
+            result.clean.insert(path.clone(), item);
+        }
+    }
+    Ok(result)
+}
+
+/// Moves the files that `renamed` renamed from the base to their new path, in the base and in
+/// `other` when it still has them at their old path
+fn follow_renames(
+    database: &Database,
+    base: &mut BTreeMap<String, TreeItem>,
+    renamed: &BTreeMap<String, TreeItem>,
+    other: &mut BTreeMap<String, TreeItem>,
+) -> Result<()> {
+    let changes = rename::detect(
+        database,
+        patch::tree_changes(base, renamed),
+        &Detection::renames(),
+    )?;
+    for change in changes {
+        let origin = match change.origin {
+            Some(origin) if !origin.is_copy => origin,
+            _ => continue,
+        };
+        if other.contains_key(&change.path) || !other.contains_key(&origin.path) {
+            continue;
+        }
+        log::debug!("following rename of {} to {}", origin.path, change.path);
+        if let Some(item) = other.remove(&origin.path) {
+            other.insert(change.path.clone(), item);
+        }
+        if let Some(item) = base.remove(&origin.path) {
+            base.insert(change.path.clone(), item);
+        }
+    }
+    Ok(())
+}
+
+/// Applies a merge on top of `ours`, which must match the index. Conflicts are recorded as
+/// stages in the index and their content with markers is written to the workspace.
+/// Nothing is written if a file that would change has local modifications.
+pub fn apply(
+    database: &Database,
+    workspace: &Workspace,
+    index: &mut Index,
+    ours: &BTreeMap<String, TreeItem>,
+    merge: &TreeMerge,
+) -> Result<()> {
+    let changed: Vec<&String> = merge
+        .clean
+        .iter()
+        .filter(|(path, item)| ours.get(*path) != Some(item))
+        .map(|(path, _)| path)
+        .collect();
+    let removed: Vec<&String> = ours
+        .keys()
+        .filter(|path| !merge.clean.contains_key(*path) && !merge.conflicts.contains_key(*path))
+        .collect();
+
+    let mut dirty = vec![];
+    let mut untracked = vec![];
+    let touched = changed
+        .iter()
+        .chain(&removed)
+        .copied()
+        .chain(merge.conflicts.keys());
+    for path in touched {
+        let exists = workspace.file_metadata(Path::new(path)).is_ok();
+        let staged = |object_id| ours.get(path).map(|item| &item.object_id) != Some(object_id);
+        match index.entry(path) {
+            Some(entry) if staged(entry.object_id()) => dirty.push(path.as_str()),
+            Some(_) if exists && staging::is_modified(workspace, index, path)? => {
+                dirty.push(path.as_str())
+            }
+            None if exists && !ours.contains_key(path) => untracked.push(path.as_str()),
+            _ => (),
+        }
+    }
+    if !dirty.is_empty() {
+        bail!(
+            "Your local changes to the following files would be overwritten by merge:\n\t{}",
+            dirty.join("\n\t")
+        );
+    }
+    if !untracked.is_empty() {
+        bail!(
+            "The following untracked working tree files would be overwritten by merge:\n\t{}",
+            untracked.join("\n\t")
+        );
+    }
+
+    for path in removed {
+        workspace.remove_file(Path::new(path))?;
+        index.remove(path);
+    }
+    for path in changed {
+        let item = &merge.clean[path];
+        migration::write_blob(database, workspace, path, item)?;
+        let metadata = workspace.file_metadata(Path::new(path))?;
+        index.add(path.clone(), item.object_id.clone(), &metadata)?;
+    }
+
+    for (path, conflict) in &merge.conflicts {
+        index.remove(path);
+        let sides = [
+            (STAGE_BASE, &conflict.base),
+            (STAGE_OURS, &conflict.ours),
+            (STAGE_THEIRS, &conflict.theirs),
+        ];
+        for (stage, item) in sides.iter() {
+            if let Some(item) = item {
+                index.add_conflict(path.clone(), *stage, item)?;
+            }
+        }
+
+        match (&conflict.content, &conflict.ours, &conflict.theirs) {
+            (Some(content), Some(ours), _) => {
+                let executable = ours.mode == MODE_EXECUTABLE as u32;
+                workspace.write_file(Path::new(path), content, executable)?;
+            }
+            // The side that still has the file keeps it in the workspace
+            (None, None, Some(theirs)) => migration::write_blob(database, workspace, path, theirs)?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils;
+
+    fn blob(database: &Database, data: &str) -> TreeItem {
+        TreeItem {
+            mode: crate::database::MODE_REGULAR as u32,
+            object_id: database.store(&Blob::new(data.into())).unwrap(),
+        }
+    }
+
+    #[test]
+    fn merge_paths() -> Result<()> {
+        let dir = test_utils::temp_path("merge");
+        let database = Database::new(dir.clone());
+        let tree = |items: &[(&str, &str)]| -> BTreeMap<String, TreeItem> {
+            items
+                .iter()
+                .map(|(path, data)| (path.to_string(), blob(&database, data)))
+                .collect()
+        };
+
+        let base = tree(&[
+            ("same", "1\n"),
+            ("ours", "1\n"),
+            ("both", "a\nb\nc\n"),
+            ("gone", "1\n"),
+            ("conflict", "1\n"),
+            ("modify", "1\n"),
+            ("old_name", "1\n2\n3\n4\n5\n"),
+        ]);
+        let ours = tree(&[
+            ("same", "1\n"),
+            ("ours", "2\n"),
+            ("both", "A\nb\nc\n"),
+            ("conflict", "ours\n"),
+            ("modify", "2\n"),
+            ("old_name", "1\n2\n3\n4\nfive\n"),
+        ]);
+        let theirs = tree(&[
+            ("same", "1\n"),
+            ("ours", "1\n"),
+            ("both", "a\nb\nC\n"),
+            ("conflict", "theirs\n"),
+            ("new", "1\n"),
+            ("new_name", "1\n2\n3\n4\n5\n"),
+        ]);
+
+        let merge = merge_trees(&database, &base, &ours, &theirs, "ours", "theirs")?;
+        let clean: Vec<&str> = merge.clean.keys().map(String::as_str).collect();
+        assert_eq!(clean, vec!["both", "new", "new_name", "ours", "same"]);
+        assert_eq!(
+            database.load(&merge.clean["both"].object_id)?.1,
+            b"A\nb\nC\n"
+        );
+        assert_eq!(merge.clean["ours"], ours["ours"]);
+        // The change made before the rename follows the file
+        assert_eq!(merge.clean["new_name"], ours["old_name"]);
+
+        let conflicts: Vec<(&str, &str)> = merge
+            .conflicts
+            .iter()
+            .map(|(path, conflict)| (path.as_str(), conflict.kind()))
+            .collect();
+        assert_eq!(
+            conflicts,
+            vec![("conflict", "content"), ("modify", "modify/delete")]
+        );
+        assert_eq!(
+            merge.conflicts["conflict"].content.as_deref(),
+            Some(&b"<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\n"[..])
+        );
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
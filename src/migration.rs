@@ -0,0 +1,80 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    database::{tree::TreeItem, Database, MODE_EXECUTABLE},
+    index::Index,
+    workspace::Workspace,
+    ObjectId,
+};
+
+/// Makes the workspace and the index match the given tree entries.
+/// Tracked files missing from the target are deleted, untracked files are left alone.
+pub fn checkout_entries(
+    database: &Database,
+    workspace: &Workspace,
+    index: &mut Index,
+    target: &BTreeMap<String, TreeItem>,
+) -> Result<()> {
+    let removed: BTreeSet<String> = index
+        .entries()
+        .map(|entry| entry.path().to_string())
+        .filter(|path| !target.contains_key(path))
+        .collect();
+    for path in removed {
+        log::debug!("removing {}", path);
+        workspace.remove_file(Path::new(&path))?;
+    }
+
+    let changed: Vec<(&String, &TreeItem)> = target
+        .iter()
+        .filter(|(path, item)| {
+            let is_unchanged = index.entry(path).is_some_and(|entry| {
+                entry.object_id() == &item.object_id && entry.mode() == item.mode
+            }) && workspace
+                .file_metadata(Path::new(path))
+                .is_ok_and(|metadata| index.is_stat_clean(path, &metadata));
+            !is_unchanged
+        })
+        .collect();
+    // A partial clone gets the blobs it's missing all at once
+    let blob_ids: Vec<ObjectId> = changed
+        .iter()
+        .map(|(_, item)| item.object_id.clone())
+        .collect();
+    database.prefetch(&blob_ids)?;
+    for (path, item) in changed {
+        write_blob(database, workspace, path, item)?;
+    }
+
+    index.clear();
+    for (path, item) in target {
+        let metadata = workspace.file_metadata(Path::new(path))?;
+        index.add(path.clone(), item.object_id.clone(), &metadata)?;
+    }
+    Ok(())
+}
+
+/// Writes the blob of a tree entry to the workspace with the mode of the entry
+pub fn write_blob(
+    database: &Database,
+    workspace: &Workspace,
+    path: &str,
+    item: &TreeItem,
+) -> Result<()> {
+    log::debug!("writing {}", path);
+    let (kind, data) = database.load(&item.object_id)?;
+    if kind != "blob" {
+        bail!(
+            "Object {} for {} is a {}, not a blob",
+            item.object_id,
+            path,
+            kind
+        );
+    }
+    workspace.write_file(Path::new(path), &data, item.mode == MODE_EXECUTABLE as u32)
+}
@@ -0,0 +1,422 @@
+use anyhow::{bail, Result};
+
+/// `*` and `?` don't match slashes and `**/` matches any number of directories
+pub const WM_PATHNAME: u8 = 1;
+/// Ignore the case of both the pattern and the text
+pub const WM_CASEFOLD: u8 = 2;
+
+const LITERAL_ENV: &str = "GIT_LITERAL_PATHSPECS";
+const GLOB_ENV: &str = "GIT_GLOB_PATHSPECS";
+const ICASE_ENV: &str = "GIT_ICASE_PATHSPECS";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Magic {
+    pub top: bool,
+    pub literal: bool,
+    pub glob: bool,
+    pub icase: bool,
+    pub exclude: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PathspecItem {
+    /// The pathspec as given on the command line
+    original: String,
+    /// The pattern relative to the root of the repository
+    pattern: String,
+    magic: Magic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Match<'a> {
+    /// The pathspec names the path itself or matches it as a pattern
+    Exact,
+    /// The pathspec names a directory containing the path
+    Directory(&'a str),
+}
+
+/// A list of pathspecs like `src/*.rs`, `:(exclude)target` or `:/README.md`.
+/// A path matches if any positive pathspec matches it and no excluding one does.
+#[derive(Debug, Clone, Default)]
+pub struct Pathspec {
+    items: Vec<PathspecItem>,
+}
+
+impl Pathspec {
+    /// Parses the pathspecs given from the directory `prefix`, relative to the root
+    pub fn parse<S: AsRef<str>>(args: &[S], prefix: &str) -> Result<Self> {
+        let items = args
+            .iter()
+            .map(|arg| PathspecItem::parse(arg.as_ref(), prefix))
+            .collect::<Result<_>>()?;
+        Ok(Self { items })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        self.match_path(path).is_some()
+    }
+
+    /// Tells how the path was matched, a pathspec with only exclusions matches everything else
+    pub fn match_path(&self, path: &str) -> Option<Match<'_>> {
+        if self
+            .items
+            .iter()
+            .any(|item| item.magic.exclude && item.match_path(path).is_some())
+        {
+            return None;
+        }
+
+        let mut positive = self
+            .items
+            .iter()
+            .filter(|item| !item.magic.exclude)
+            .peekable();
+        if positive.peek().is_none() {
+            return Some(Match::Exact);
+        }
+        let mut found = None;
+        for item in positive {
+            match item.match_path(path) {
+                Some(Match::Exact) => return Some(Match::Exact),
+                Some(directory) => found = found.or(Some(directory)),
+                None => (),
+            }
+        }
+        found
+    }
+
+    /// Returns the positive pathspecs that don't match any of the paths
+    pub fn unmatched<'a, I>(&self, paths: I) -> Vec<&str>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut matched = vec![false; self.items.len()];
+        for path in paths {
+            for (i, item) in self.items.iter().enumerate() {
+                if !matched[i] && item.match_path(path).is_some() {
+                    matched[i] = true;
+                }
+            }
+        }
+        self.items
+            .iter()
+            .zip(matched)
+            .filter(|(item, matched)| !item.magic.exclude && !matched)
+            .map(|(item, _)| item.original.as_str())
+            .collect()
+    }
+}
+
+impl PathspecItem {
+    fn parse(arg: &str, prefix: &str) -> Result<Self> {
+        let env_flag = |name| std::env::var(name).is_ok_and(|value| value == "1");
+        let mut magic = Magic {
+            literal: env_flag(LITERAL_ENV),
+            glob: env_flag(GLOB_ENV),
+            icase: env_flag(ICASE_ENV),
+            ..Magic::default()
+        };
+
+        // Magic signatures are ignored entirely with literal pathspecs
+        let mut pattern = arg;
+        if !magic.literal && arg.starts_with(':') {
+            if let Some(long) = arg.strip_prefix(":(") {
+                let end = match long.find(')') {
+                    Some(end) => end,
+                    None => bail!("Missing ')' at the end of pathspec magic in '{}'", arg),
+                };
+                for word in long[..end].split(',').map(str::trim) {
+                    match word {
+                        "top" => magic.top = true,
+                        "literal" => magic.literal = true,
+                        "glob" => magic.glob = true,
+                        "icase" => magic.icase = true,
+                        "exclude" => magic.exclude = true,
+                        "" => (),
+                        _ => bail!("Invalid pathspec magic '{}' in '{}'", word, arg),
+                    }
+                }
+                pattern = &long[end + 1..];
+            } else {
+                let mut rest = &arg[1..];
+                while let Some(c) = rest.chars().next() {
+                    match c {
+                        '/' => magic.top = true,
+                        '!' | '^' => magic.exclude = true,
+                        ':' => {
+                            rest = &rest[1..];
+                            break;
+                        }
+                        _ => break,
+                    }
+                    rest = &rest[1..];
+                }
+                pattern = rest;
+            }
+        }
+
+        if magic.literal && magic.glob {
+            bail!("'literal' and 'glob' are incompatible in '{}'", arg);
+        }
+
+        let pattern = pattern.trim_start_matches("./");
+        let pattern = if pattern == "." { "" } else { pattern };
+        let pattern = if magic.top || prefix.is_empty() {
+            pattern.to_string()
+        } else {
+            format!("{}/{}", prefix.trim_end_matches('/'), pattern)
+        };
+
+        Ok(Self {
+            original: arg.to_string(),
+            pattern,
+            magic,
+        })
+    }
+
+    fn has_wildcards(&self) -> bool {
+        !self.magic.literal && self.pattern.contains(['*', '?', '[', '\\'])
+    }
+
+    fn match_path(&self, path: &str) -> Option<Match<'_>> {
+        let pattern = self.pattern.trim_end_matches('/');
+        if pattern.is_empty() {
+            return Some(Match::Exact);
+        }
+
+        let (pattern, path) = if self.magic.icase {
+            (pattern.to_lowercase(), path.to_lowercase())
+        } else {
+            (pattern.to_string(), path.to_string())
+        };
+
+        // A trailing slash only matches directories
+        let is_directory = self.pattern.ends_with('/');
+        if path == pattern && !is_directory {
+            return Some(Match::Exact);
+        }
+        if path.starts_with(&format!("{}/", pattern)) {
+            return Some(Match::Directory(&self.original));
+        }
+
+        if self.has_wildcards() {
+            let flags = if self.magic.glob { WM_PATHNAME } else { 0 };
+            if wildmatch(&pattern, &path, flags) {
+                return Some(Match::Exact);
+            }
+        }
+        None
+    }
+}
+
+/// Matches `text` against a pattern using git's wildmatch rules: `*`, `?`, `[...]` with
+/// ranges and `[:class:]`, backslash escapes, and `**` when matching pathnames
+pub fn wildmatch(pattern: &str, text: &str, flags: u8) -> bool {
+    let (pattern, text) = if flags & WM_CASEFOLD != 0 {
+        (pattern.to_lowercase(), text.to_lowercase())
+    } else {
+        (pattern.to_string(), text.to_string())
+    };
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_chars(&pattern, &text, 0, flags & WM_PATHNAME != 0)
+}
+
+fn match_chars(pattern: &[char], text: &[char], pattern_start: usize, pathname: bool) -> bool {
+    let p = &pattern[pattern_start..];
+    match p.first() {
+        None => text.is_empty(),
+        Some('\\') if p.len() > 1 => {
+            text.first() == Some(&p[1])
+                && match_chars(pattern, &text[1..], pattern_start + 2, pathname)
+        }
+        Some('?') => match text.first() {
+            Some('/') if pathname => false,
+            Some(_) => match_chars(pattern, &text[1..], pattern_start + 1, pathname),
+            None => false,
+        },
+        Some('*') => {
+            let stars = p.iter().take_while(|c| **c == '*').count();
+            let rest_start = pattern_start + stars;
+            let rest = &pattern[rest_start..];
+
+            let at_segment_start = pattern_start == 0 || pattern[pattern_start - 1] == '/';
+            if pathname && stars >= 2 && at_segment_start {
+                match rest.first() {
+                    None => return true,
+                    // `**/` matches zero or more leading directories
+                    Some('/') => {
+                        return match_chars(pattern, text, rest_start + 1, pathname)
+                            || text.iter().enumerate().any(|(i, c)| {
+                                *c == '/'
+                                    && match_chars(
+                                        pattern,
+                                        &text[i + 1..],
+                                        rest_start + 1,
+                                        pathname,
+                                    )
+                            })
+                    }
+                    _ => (),
+                }
+            }
+
+            for i in 0..=text.len() {
+                if match_chars(pattern, &text[i..], rest_start, pathname) {
+                    return true;
+                }
+                if pathname && text.get(i) == Some(&'/') {
+                    return false;
+                }
+            }
+            false
+        }
+        Some('[') => {
+            let c = match text.first() {
+                Some(c) => *c,
+                None => return false,
+            };
+            match match_class(&p[1..], c) {
+                Some((found, length)) => {
+                    found
+                        && !(pathname && c == '/')
+                        && match_chars(pattern, &text[1..], pattern_start + 1 + length, pathname)
+                }
+                // Without a closing bracket the bracket is matched literally
+                None => c == '[' && match_chars(pattern, &text[1..], pattern_start + 1, pathname),
+            }
+        }
+        Some(p) => {
+            text.first() == Some(p) && match_chars(pattern, &text[1..], pattern_start + 1, pathname)
+        }
+    }
+}
+
+/// Matches a character against the class following an opening bracket.
+/// Returns whether it matched and the length of the class including the closing bracket.
+fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = matches!(class.first(), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut found = false;
+    let mut first = true;
+    loop {
+        let current = *class.get(i)?;
+        if current == ']' && !first {
+            return Some((found != negated, i + 1));
+        }
+        first = false;
+
+        if current == '[' && class.get(i + 1) == Some(&':') {
+            let name_end = class[i + 2..]
+                .windows(2)
+                .position(|window| window == [':', ']'])?;
+            let name: String = class[i + 2..i + 2 + name_end].iter().collect();
+            found |= match name.as_str() {
+                "alnum" => c.is_ascii_alphanumeric(),
+                "alpha" => c.is_ascii_alphabetic(),
+                "blank" => c == ' ' || c == '\t',
+                "cntrl" => c.is_ascii_control(),
+                "digit" => c.is_ascii_digit(),
+                "graph" => c.is_ascii_graphic(),
+                "lower" => c.is_ascii_lowercase(),
+                "print" => c.is_ascii_graphic() || c == ' ',
+                "punct" => c.is_ascii_punctuation(),
+                "space" => c.is_ascii_whitespace(),
+                "upper" => c.is_ascii_uppercase(),
+                "xdigit" => c.is_ascii_hexdigit(),
+                _ => return None,
+            };
+            i += name_end + 4;
+            continue;
+        }
+
+        let (start, next) = match current {
+            '\\' => (*class.get(i + 1)?, i + 2),
+            _ => (current, i + 1),
+        };
+        if class.get(next) == Some(&'-') && class.get(next + 1).is_some_and(|end| *end != ']') {
+            let (end, next) = match class[next + 1] {
+                '\\' => (*class.get(next + 2)?, next + 3),
+                end => (end, next + 2),
+            };
+            found |= start <= c && c <= end;
+            i = next;
+        } else {
+            found |= start == c;
+            i = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wildmatch_patterns() {
+        assert!(wildmatch("v1.*", "v1.0", 0));
+        assert!(wildmatch("v?.[0-9]", "v2.3", 0));
+        assert!(!wildmatch("v?.[!0-9]", "v2.3", 0));
+        assert!(!wildmatch("v1.*", "v2.0", 0));
+        assert!(wildmatch("*", "", 0));
+
+        assert!(wildmatch("*.rs", "src/main.rs", 0));
+        assert!(!wildmatch("*.rs", "src/main.rs", WM_PATHNAME));
+        assert!(wildmatch("**/*.rs", "src/main.rs", WM_PATHNAME));
+        assert!(wildmatch("**/*.rs", "main.rs", WM_PATHNAME));
+        assert!(wildmatch("src/**", "src/a/b", WM_PATHNAME));
+        assert!(wildmatch("a/**/b", "a/b", WM_PATHNAME));
+        assert!(wildmatch("a/**/b", "a/x/y/b", WM_PATHNAME));
+        assert!(!wildmatch("a?b", "a/b", WM_PATHNAME));
+
+        assert!(wildmatch("[[:upper:]]*", "Readme", 0));
+        assert!(wildmatch("[]a]", "]", 0));
+        assert!(wildmatch("\\*", "*", 0));
+        assert!(!wildmatch("\\*", "a", 0));
+        assert!(wildmatch("[", "[", 0));
+        assert!(wildmatch("README", "readme", WM_CASEFOLD));
+    }
+
+    #[test]
+    fn pathspec_magic() -> Result<()> {
+        let pathspec = Pathspec::parse(&["src", ":(exclude)src/main.rs"], "")?;
+        assert_eq!(
+            pathspec.match_path("src/lib.rs"),
+            Some(Match::Directory("src"))
+        );
+        assert!(!pathspec.matches("src/main.rs"));
+        assert!(!pathspec.matches("srcs"));
+
+        let pathspec = Pathspec::parse(&[":!*.md"], "")?;
+        assert!(pathspec.matches("src/lib.rs"));
+        assert!(!pathspec.matches("docs/README.md"));
+
+        let pathspec = Pathspec::parse(&["*.rs", ":(glob)src/*", ":(icase)CARGO.toml"], "")?;
+        assert!(pathspec.matches("src/database/mod.rs"));
+        assert!(pathspec.matches("src/x"));
+        assert!(pathspec.matches("Cargo.toml"));
+        assert!(!pathspec.matches("Cargo.lock"));
+
+        let pathspec = Pathspec::parse(&[":(literal)*.rs", ":/top", "sub"], "dir")?;
+        assert!(pathspec.matches("dir/*.rs"));
+        assert!(!pathspec.matches("dir/a.rs"));
+        assert!(pathspec.matches("top"));
+        assert!(pathspec.matches("dir/sub"));
+        assert_eq!(
+            pathspec.unmatched(vec!["top"]),
+            vec![":(literal)*.rs", "sub"]
+        );
+
+        assert!(Pathspec::parse(&[":(unknown)a"], "").is_err());
+        assert!(Pathspec::parse(&["."], "")?.matches("anything"));
+        Ok(())
+    }
+}
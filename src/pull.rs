@@ -0,0 +1,241 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    config::Config,
+    database::{commit::Commit, tree::TreeItem, Database},
+    index::Index,
+    merge::resolve,
+    refs::{Refs, MERGE_HEAD, ORIG_HEAD},
+    rev_list,
+    sequencer::MERGE_MSG,
+    workspace::Workspace,
+    Author, ObjectId,
+};
+
+/// When a merge can be a fast-forward, from `--ff`, `--no-ff`, `--ff-only` or `pull.ff`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FastForward {
+    /// Fast-forward when possible, otherwise create a merge commit
+    Allow,
+    /// Always create a merge commit
+    Never,
+    /// Refuse to merge when it isn't a fast-forward
+    Only,
+}
+
+impl FromStr for FastForward {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "only" => FastForward::Only,
+            "true" | "yes" | "on" | "1" => FastForward::Allow,
+            "false" | "no" | "off" | "0" => FastForward::Never,
+            _ => bail!("Invalid value for pull.ff: {}", s),
+        })
+    }
+}
+
+impl FastForward {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        config
+            .get("pull.ff")
+            .map_or(Ok(FastForward::Allow), |value| value.parse())
+    }
+}
+
+/// Whether pulling into the branch rebases, `branch.<name>.rebase` wins over `pull.rebase`
+pub fn rebase_setting(config: &Config, branch: &str) -> Result<bool> {
+    let branch_setting = config.get_bool(&format!("branch.{}.rebase", branch))?;
+    match branch_setting {
+        Some(rebase) => Ok(rebase),
+        None => Ok(config.get_bool("pull.rebase")?.unwrap_or(false)),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    UpToDate,
+    FastForward,
+    Merged(ObjectId),
+}
+
+/// Integrates another commit into HEAD like `merge`. A merge that conflicts stops with the
+/// conflicts in the index and the workspace, `MERGE_HEAD` and `MERGE_MSG` are kept so
+/// committing the resolution creates the merge commit.
+pub struct Merge<'a> {
+    git_path: &'a Path,
+    database: &'a Database,
+    workspace: &'a Workspace,
+    index: &'a mut Index,
+    refs: &'a Refs,
+    /// Where the merge and its conflicts are reported, nothing is shown by default
+    out: Box<dyn Write + 'a>,
+}
+
+impl<'a> Merge<'a> {
+    pub fn new(
+        git_path: &'a Path,
+        database: &'a Database,
+        workspace: &'a Workspace,
+        index: &'a mut Index,
+        refs: &'a Refs,
+    ) -> Self {
+        Self {
+            git_path,
+            database,
+            workspace,
+            index,
+            refs,
+            out: Box::new(io::sink()),
+        }
+    }
+
+    /// Reports the merge and its conflicts to `out`
+    pub fn with_output(mut self, out: Box<dyn Write + 'a>) -> Self {
+        self.out = out;
+        self
+    }
+
+    /// Merges `their_id` into HEAD, `their_name` labels its side of the conflicts and
+    /// `action` prefixes the reflog messages
+    pub fn run(
+        &mut self,
+        their_id: &str,
+        their_name: &str,
+        message: &str,
+        fast_forward: FastForward,
+        action: &str,
+    ) -> Result<Outcome> {
+        if self.refs.read_ref(MERGE_HEAD).is_some() {
+            bail!("You have not concluded your merge (MERGE_HEAD exists).\nhint: Please, commit your changes before merging.");
+        }
+        self.index.load_for_update()?;
+        if self.index.has_conflicts() {
+            bail!("Merging is not possible because you have unmerged files.");
+        }
+        let identity = Author::from_env();
+
+        // An unborn branch simply starts at their commit
+        let head_id = match self.refs.read_head() {
+            Some(head_id) => head_id,
+            None => {
+                self.apply(&BTreeMap::new(), &BTreeMap::new(), their_id, their_name)?;
+                let message = format!("{}: Fast-forward", action);
+                self.refs
+                    .update_head(their_id.to_string(), &identity, &message)?;
+                return Ok(Outcome::FastForward);
+            }
+        };
+
+        if rev_list::is_ancestor(self.database, their_id, &head_id)? {
+            return Ok(Outcome::UpToDate);
+        }
+        let is_fast_forward = rev_list::is_ancestor(self.database, &head_id, their_id)?;
+        if !is_fast_forward && fast_forward == FastForward::Only {
+            bail!("Not possible to fast-forward, aborting.");
+        }
+        let base_id = rev_list::merge_base(self.database, &head_id, their_id)?
+            .context("refusing to merge unrelated histories")?;
+
+        let ours = self.tree_items(&head_id)?;
+        let base = self.tree_items(&base_id)?;
+        let merge = self.apply(&base, &ours, their_id, their_name)?;
+        self.refs
+            .update_ref(ORIG_HEAD, head_id.clone(), &identity, action)?;
+
+        if is_fast_forward && fast_forward != FastForward::Never {
+            writeln!(
+                self.out,
+                "Updating {}..{}\nFast-forward",
+                &head_id[..7],
+                &their_id[..7]
+            )?;
+            let message = format!("{}: Fast-forward", action);
+            self.refs
+                .update_head(their_id.to_string(), &identity, &message)?;
+            return Ok(Outcome::FastForward);
+        }
+
+        if !merge.is_clean() {
+            for (path, conflict) in &merge.conflicts {
+                writeln!(
+                    self.out,
+                    "CONFLICT ({}): Merge conflict in {}",
+                    conflict.kind(),
+                    path
+                )?;
+            }
+            fs::write(self.git_path.join(MERGE_MSG), format!("{}\n", message))?;
+            self.refs
+                .update_ref(MERGE_HEAD, their_id.to_string(), &identity, "")?;
+            bail!("Automatic merge failed; fix conflicts and then commit the result.");
+        }
+
+        let tree_id = self.database.store_tree(&self.index.tree_items())?;
+        let parents = vec![head_id, their_id.to_string()];
+        let commit = Commit::new(parents, tree_id, identity.clone(), format!("{}\n", message));
+        let commit_id = self.database.store(&commit)?;
+        let reflog_message = format!("{}: Merge made by the 'recursive' strategy.", action);
+        self.refs
+            .update_head(commit_id.clone(), &identity, &reflog_message)?;
+        writeln!(self.out, "Merge made by the 'recursive' strategy.")?;
+        Ok(Outcome::Merged(commit_id))
+    }
+
+    /// Updates the index and the workspace with the merged trees, local changes to the
+    /// files that would change make it fail before anything is written
+    fn apply(
+        &mut self,
+        base: &BTreeMap<String, TreeItem>,
+        ours: &BTreeMap<String, TreeItem>,
+        their_id: &str,
+        their_name: &str,
+    ) -> Result<resolve::TreeMerge> {
+        let theirs = self.tree_items(their_id)?;
+        let merge = resolve::merge_trees(self.database, base, ours, &theirs, "HEAD", their_name)?;
+        resolve::apply(self.database, self.workspace, self.index, ours, &merge)?;
+        self.index.write_updates()?;
+        Ok(merge)
+    }
+
+    fn tree_items(&self, commit_id: &str) -> Result<BTreeMap<String, TreeItem>> {
+        self.database
+            .load_tree_entries(&self.database.load_tree_id(commit_id)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn pull_settings() -> Result<()> {
+        let path = test_utils::temp_path("pull");
+        let mut config = Config::load(path.clone())?;
+        assert_eq!(FastForward::from_config(&config)?, FastForward::Allow);
+        assert!(!rebase_setting(&config, "main")?);
+
+        config.set("pull.ff", "only")?;
+        config.set("pull.rebase", "true")?;
+        config.set("branch.topic.rebase", "false")?;
+        assert_eq!(FastForward::from_config(&config)?, FastForward::Only);
+        assert!(rebase_setting(&config, "main")?);
+        assert!(!rebase_setting(&config, "topic")?);
+
+        config.set("pull.ff", "false")?;
+        assert_eq!(FastForward::from_config(&config)?, FastForward::Never);
+        config.set("pull.ff", "sometimes")?;
+        assert!(FastForward::from_config(&config).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+
+use crate::{lockfile::Lockfile, Author, ObjectId};
+
+/// A single line of a reflog: `old new identity timestamp tz\tmessage`
+#[derive(Clone)]
+pub struct ReflogEntry {
+    pub old_id: ObjectId,
+    pub new_id: ObjectId,
+    pub identity: Author,
+    pub message: String,
+}
+
+impl Display for ReflogEntry {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        // The message has to stay on a single line
+        let message = self.message.lines().next().unwrap_or("");
+        write!(
+            f,
+            "{} {} {}\t{}",
+            self.old_id, self.new_id, self.identity, message
+        )
+    }
+}
+
+impl FromStr for ReflogEntry {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let (header, message) = line.split_once('\t').unwrap_or((line, ""));
+        let mut parts = header.splitn(3, ' ');
+        let (old_id, new_id, identity) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(old_id), Some(new_id), Some(identity)) => (old_id, new_id, identity),
+            _ => bail!("Invalid reflog entry: {}", line),
+        };
+
+        Ok(Self {
+            old_id: old_id.into(),
+            new_id: new_id.into(),
+            identity: identity.parse()?,
+            message: message.into(),
+        })
+    }
+}
+
+pub struct Reflog {
+    path: PathBuf,
+}
+
+impl Reflog {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.is_file()
+    }
+
+    pub fn append(&self, entry: &ReflogEntry) -> Result<()> {
+        fs::create_dir_all(self.path.parent().expect("Failed to get parent"))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open reflog {}", self.path.display()))?;
+        writeln!(file, "{}", entry)?;
+        Ok(())
+    }
+
+    /// Returns every entry from the oldest to the most recent one
+    pub fn entries(&self) -> Result<Vec<ReflogEntry>> {
+        if !self.exists() {
+            return Ok(vec![]);
+        }
+        fs::read_to_string(&self.path)?
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| line.parse())
+            .collect()
+    }
+
+    /// Returns the entry selected by `@{n}`, 0 being the most recent one
+    pub fn nth(&self, n: usize) -> Result<Option<ReflogEntry>> {
+        Ok(self.entries()?.into_iter().rev().nth(n))
+    }
+
+    /// Removes every entry older than the given date and returns how many were removed
+    pub fn expire(&self, before: DateTime<Utc>) -> Result<usize> {
+        self.rewrite(|entries| {
+            entries.retain(|entry| entry.identity.time >= before);
+            Ok(())
+        })
+    }
+
+    /// Removes the entry selected by `@{n}`
+    pub fn delete(&self, n: usize) -> Result<()> {
+        self.rewrite(|entries| {
+            if n >= entries.len() {
+                bail!("reflog only has {} entries", entries.len());
+            }
+            entries.remove(entries.len() - 1 - n);
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Reads the entries under the lock so a concurrent rewrite isn't lost, lets `update` edit
+    /// them and writes them back. Returns how many entries were removed
+    fn rewrite<F>(&self, update: F) -> Result<usize>
+    where
+        F: FnOnce(&mut Vec<ReflogEntry>) -> Result<()>,
+    {
+        if !self.exists() {
+            update(&mut vec![])?;
+            return Ok(0);
+        }
+        let mut lockfile = Lockfile::new(&self.path);
+        lockfile
+            .hold_for_update()
+            .with_context(|| format!("Could not acquire lock on file: {}", self.path.display()))?;
+        let mut entries = self.entries()?;
+        let count = entries.len();
+        update(&mut entries)?;
+        if entries.len() == count {
+            lockfile.rollback()?;
+            return Ok(0);
+        }
+        for entry in &entries {
+            writeln!(lockfile, "{}", entry)?;
+        }
+        lockfile.commit()?;
+        Ok(count - entries.len())
+    }
+}
@@ -0,0 +1,807 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    lockfile::Lockfile,
+    reflog::{Reflog, ReflogEntry},
+    Author, ObjectId,
+};
+
+pub const HEAD: &str = "HEAD";
+pub const ORIG_HEAD: &str = "ORIG_HEAD";
+pub const MERGE_HEAD: &str = "MERGE_HEAD";
+pub const STASH: &str = "refs/stash";
+pub const NULL_ID: &str = "0000000000000000000000000000000000000000";
+
+const SYMREF_PREFIX: &str = "ref: ";
+const PACKED_REFS: &str = "packed-refs";
+const PACKED_REFS_HEADER: &str = "# pack-refs with: peeled fully-peeled sorted \n";
+
+/// Prefixes tried in order when looking up a short ref name
+const SEARCH_PATHS: &[&str] = &["", "refs/", "refs/tags/", "refs/heads/", "refs/remotes/"];
+
+pub struct Refs {
+    path: PathBuf,
+    /// The last packed-refs read, reused while the file keeps the same mtime and size
+    packed_refs: RwLock<Option<PackedRefsCache>>,
+}
+
+struct PackedRefsCache {
+    modified: SystemTime,
+    len: u64,
+    refs: Arc<BTreeMap<String, PackedRef>>,
+}
+
+/// A line of the packed-refs file, `peeled` is the object an annotated tag points to
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackedRef {
+    pub object_id: ObjectId,
+    pub peeled: Option<ObjectId>,
+}
+
+impl Refs {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            packed_refs: RwLock::new(None),
+        }
+    }
+
+    pub fn head_path(&self) -> PathBuf {
+        self.path.join(HEAD)
+    }
+
+    /// Updates what HEAD points to, or the branch it points to if it's a symbolic ref
+    pub fn update_head(&self, object_id: ObjectId, identity: &Author, message: &str) -> Result<()> {
+        let old_id = self.read_head();
+
+        if let Some(target) = self.read_symref(HEAD) {
+            self.write_ref(&target, &object_id)?;
+            self.append_reflog(&target, old_id.as_deref(), &object_id, identity, message)?;
+        } else {
+            self.write_ref(HEAD, &object_id)?;
+        }
+
+        self.append_reflog(HEAD, old_id.as_deref(), &object_id, identity, message)
+    }
+
+    pub fn read_head(&self) -> Option<ObjectId> {
+        self.read_ref(HEAD)
+    }
+
+    /// Updates the ref with the given full name and records it in its reflog. Commands moving
+    /// refs go through this, `update_head` or `update_symref` so every move is logged
+    pub fn update_ref(
+        &self,
+        name: &str,
+        object_id: ObjectId,
+        identity: &Author,
+        message: &str,
+    ) -> Result<()> {
+        check_name(name)?;
+        let old_id = self.read_ref(name);
+        self.write_ref(name, &object_id)?;
+        if should_log(name) {
+            self.append_reflog(name, old_id.as_deref(), &object_id, identity, message)?;
+        }
+
+        // HEAD moves along with the branch it points to
+        if self.read_symref(HEAD).as_deref() == Some(name) {
+            self.append_reflog(HEAD, old_id.as_deref(), &object_id, identity, message)?;
+        }
+        Ok(())
+    }
+
+    /// Points a ref at `new_id`, or deletes it when `None`, only if it still points at
+    /// `expected`, `None` meaning that it must not exist. The ref is checked and changed while
+    /// holding its lock so a concurrent update can't be overwritten. Symbolic refs are refused,
+    /// writing them would replace the link instead of updating its target.
+    pub fn compare_and_swap(
+        &self,
+        name: &str,
+        expected: Option<&str>,
+        new_id: Option<&str>,
+        identity: &Author,
+        message: &str,
+    ) -> Result<()> {
+        check_name(name)?;
+        let path = self.path.join(name);
+        fs::create_dir_all(path.parent().expect("Failed to get parent"))?;
+        let mut lockfile = Lockfile::new(&path);
+        lockfile
+            .hold_for_update()
+            .with_context(|| format!("Could not acquire lock on file: {}", path.display()))?;
+
+        if self.read_symref(name).is_some() {
+            lockfile.rollback()?;
+            bail!("cannot update symbolic ref '{}'", name);
+        }
+        let current = self.read_ref(name);
+        if current.as_deref() != expected {
+            lockfile.rollback()?;
+            bail!(
+                "cannot lock ref '{}': is at {} but expected {}",
+                name,
+                current.as_deref().unwrap_or(NULL_ID),
+                expected.unwrap_or(NULL_ID)
+            );
+        }
+        match new_id {
+            Some(new_id) => {
+                writeln!(lockfile, "{}", new_id)?;
+                lockfile.commit()?;
+                if should_log(name) {
+                    self.append_reflog(name, expected, new_id, identity, message)?;
+                }
+                if self.read_symref(HEAD).as_deref() == Some(name) {
+                    self.append_reflog(HEAD, expected, new_id, identity, message)?;
+                }
+                Ok(())
+            }
+            None => self.delete_locked(name, lockfile),
+        }
+    }
+
+    /// Reads the object id of the ref with the given full name, following symbolic refs.
+    /// Loose refs take precedence over the ones in packed-refs.
+    pub fn read_ref(&self, name: &str) -> Option<ObjectId> {
+        let content = match fs::read_to_string(self.path.join(name)) {
+            Ok(content) => content,
+            Err(_) => return self.read_packed_ref(name).map(|packed| packed.object_id),
+        };
+        let content = content.trim();
+        match content.strip_prefix(SYMREF_PREFIX) {
+            Some(target) => self.read_ref(target),
+            None => Some(content.to_string()),
+        }
+    }
+
+    pub fn read_packed_ref(&self, name: &str) -> Option<PackedRef> {
+        self.packed_refs().ok()?.get(name).cloned()
+    }
+
+    /// Deletes a ref from both the loose refs and packed-refs, along with its reflog
+    pub fn delete_ref(&self, name: &str) -> Result<()> {
+        check_name(name)?;
+        let path = self.path.join(name);
+        fs::create_dir_all(path.parent().expect("Failed to get parent"))?;
+        let mut lockfile = Lockfile::new(&path);
+        lockfile
+            .hold_for_update()
+            .with_context(|| format!("Could not acquire lock on file: {}", path.display()))?;
+        self.delete_locked(name, lockfile)
+    }
+
+    /// Deletes a ref whose lock is held by the caller, the lock is released once it's gone
+    fn delete_locked(&self, name: &str, mut lockfile: Lockfile) -> Result<()> {
+        let removed = self.remove_ref_files(name);
+        lockfile.rollback()?;
+        self.remove_empty_parents(name)?;
+        if !removed? {
+            bail!("No such ref: {}", name);
+        }
+
+        let reflog_path = self.path.join("logs").join(name);
+        if reflog_path.is_file() {
+            fs::remove_file(reflog_path)?;
+        }
+        Ok(())
+    }
+
+    /// Returns every ref under `refs/` starting with the given prefix, sorted by name
+    pub fn list_refs(&self, prefix: &str) -> Result<Vec<(String, ObjectId)>> {
+        let mut refs: BTreeMap<String, ObjectId> = self
+            .packed_refs()?
+            .iter()
+            .map(|(name, packed)| (name.clone(), packed.object_id.clone()))
+            .collect();
+
+        for name in self.loose_ref_names()? {
+            if let Some(object_id) = self.read_ref(&name) {
+                refs.insert(name, object_id);
+            }
+        }
+
+        Ok(refs
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .collect())
+    }
+
+    /// Moves loose refs into packed-refs. Without `all` only tags are packed.
+    /// `peel` returns the object an annotated tag points to so it can be stored in the file.
+    /// With `prune`, a loose ref is only removed while holding its lock and if it wasn't
+    /// updated since it was packed.
+    pub fn pack_refs(
+        &self,
+        all: bool,
+        prune: bool,
+        peel: &dyn Fn(&str) -> Option<ObjectId>,
+    ) -> Result<()> {
+        let packed_path = self.path.join(PACKED_REFS);
+        let mut lockfile = Lockfile::new(&packed_path);
+        lockfile.hold_for_update().with_context(|| {
+            format!("Could not acquire lock on file: {}", packed_path.display())
+        })?;
+
+        let mut packed_refs = self.read_packed_refs()?;
+        let mut packed_names = vec![];
+        for name in self.loose_ref_names()? {
+            if !all && !name.starts_with("refs/tags/") {
+                continue;
+            }
+            // Symbolic refs are never packed
+            if self.read_symref(&name).is_some() {
+                continue;
+            }
+            if let Some(object_id) = self.read_ref(&name) {
+                let peeled = peel(&object_id).filter(|peeled| peeled != &object_id);
+                packed_refs.insert(name.clone(), PackedRef { object_id, peeled });
+                packed_names.push(name);
+            }
+        }
+
+        write_packed_refs(&mut lockfile, &packed_refs)?;
+        lockfile.commit()?;
+
+        if prune {
+            for name in packed_names {
+                self.prune_loose_ref(&name, &packed_refs[&name].object_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a loose ref that was packed, unless another process updates or holds it
+    fn prune_loose_ref(&self, name: &str, packed_id: &str) -> Result<()> {
+        let path = self.path.join(name);
+        let mut lockfile = Lockfile::new(&path);
+        if lockfile.hold_for_update().is_err() {
+            log::warn!("{} is locked, keeping its loose ref", name);
+            return Ok(());
+        }
+        let is_unchanged = fs::read_to_string(&path)
+            .map(|content| content.trim() == packed_id)
+            .unwrap_or(false);
+        if is_unchanged {
+            fs::remove_file(&path)?;
+        } else {
+            log::warn!("{} changed while packing, keeping its loose ref", name);
+        }
+        lockfile.rollback()?;
+        if is_unchanged {
+            self.remove_empty_parents(name)?;
+        }
+        Ok(())
+    }
+
+    fn read_packed_refs(&self) -> Result<BTreeMap<String, PackedRef>> {
+        Ok(self.packed_refs()?.as_ref().clone())
+    }
+
+    /// The parsed packed-refs, only read again when its mtime or size changed
+    fn packed_refs(&self) -> Result<Arc<BTreeMap<String, PackedRef>>> {
+        let path = self.path.join(PACKED_REFS);
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                *self
+                    .packed_refs
+                    .write()
+                    .expect("Failed to write packed refs") = None;
+                return Ok(Arc::new(BTreeMap::new()));
+            }
+        };
+        let (modified, len) = (metadata.modified()?, metadata.len());
+        if let Some(cache) = self
+            .packed_refs
+            .read()
+            .expect("Failed to read packed refs")
+            .as_ref()
+        {
+            if cache.modified == modified && cache.len == len {
+                return Ok(cache.refs.clone());
+            }
+        }
+
+        let refs = Arc::new(parse_packed_refs(&fs::read_to_string(&path)?)?);
+        *self
+            .packed_refs
+            .write()
+            .expect("Failed to write packed refs") = Some(PackedRefsCache {
+            modified,
+            len,
+            refs: refs.clone(),
+        });
+        Ok(refs)
+    }
+
+    /// Removes the loose ref and its entry in packed-refs, the caller holds the lock of the
+    /// loose ref. packed-refs is read and written under its own lock so a concurrent change to
+    /// it isn't lost. Returns false when the ref didn't exist.
+    fn remove_ref_files(&self, name: &str) -> Result<bool> {
+        let packed_path = self.path.join(PACKED_REFS);
+        let mut packed_lockfile = Lockfile::new(&packed_path);
+        packed_lockfile.hold_for_update().with_context(|| {
+            format!("Could not acquire lock on file: {}", packed_path.display())
+        })?;
+        let mut packed_refs = self.read_packed_refs()?;
+        let is_packed = packed_refs.remove(name).is_some();
+        if is_packed {
+            write_packed_refs(&mut packed_lockfile, &packed_refs)?;
+            packed_lockfile.commit()?;
+        } else {
+            packed_lockfile.rollback()?;
+        }
+
+        let path = self.path.join(name);
+        let is_loose = path.is_file();
+        if is_loose {
+            fs::remove_file(&path)?;
+        }
+        Ok(is_packed || is_loose)
+    }
+
+    /// Names of every loose ref under `refs/`
+    fn loose_ref_names(&self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        let mut dirs = vec![self.path.join("refs")];
+        while let Some(dir) = dirs.pop() {
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension().is_some_and(|ext| ext == "lock") {
+                    continue;
+                } else if let Ok(name) = path.strip_prefix(&self.path) {
+                    names.push(name.to_string_lossy().replace('\\', "/"));
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Removes the directories left empty after deleting a ref, stopping at `refs/`
+    fn remove_empty_parents(&self, name: &str) -> Result<()> {
+        let refs_path = self.path.join("refs");
+        let mut dir = self.path.join(name);
+        while let Some(parent) = dir.parent() {
+            if parent == refs_path || !parent.starts_with(&refs_path) {
+                break;
+            }
+            if fs::read_dir(parent)?.next().is_some() {
+                break;
+            }
+            fs::remove_dir(parent)?;
+            dir = parent.to_path_buf();
+        }
+        Ok(())
+    }
+
+    /// Returns the name of the ref the given ref points to if it's a symbolic ref
+    pub fn read_symref(&self, name: &str) -> Option<String> {
+        let content = fs::read_to_string(self.path.join(name)).ok()?;
+        content
+            .trim()
+            .strip_prefix(SYMREF_PREFIX)
+            .map(|target| target.to_string())
+    }
+
+    /// Expands a short ref name like `master` to the full name of an existing ref
+    pub fn expand_name(&self, name: &str) -> Option<String> {
+        let packed_refs = self.packed_refs().unwrap_or_default();
+        SEARCH_PATHS
+            .iter()
+            .map(|prefix| format!("{}{}", prefix, name))
+            .find(|full_name| {
+                self.path.join(full_name).is_file() || packed_refs.contains_key(full_name)
+            })
+    }
+
+    pub fn reflog(&self, name: &str) -> Reflog {
+        Reflog::new(self.path.join("logs").join(name))
+    }
+
+    /// Returns the full name of every ref that has a reflog
+    pub fn reflog_names(&self) -> Result<Vec<String>> {
+        let logs_path = self.path.join("logs");
+        let mut names = vec![];
+        let mut dirs = vec![logs_path.clone()];
+        while let Some(dir) = dirs.pop() {
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if let Ok(name) = path.strip_prefix(&logs_path) {
+                    names.push(name.to_string_lossy().replace('\\', "/"));
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Removes the reflog entry selected by `@{n}` and points the ref at the most recent entry
+    /// left. The ref is deleted once its reflog is empty.
+    pub fn drop_reflog_entry(&self, name: &str, n: usize) -> Result<()> {
+        let reflog = self.reflog(name);
+        reflog.delete(n)?;
+        match reflog.nth(0)? {
+            Some(entry) => self.write_ref(name, &entry.new_id),
+            None => self.delete_ref(name),
+        }
+    }
+
+    /// Makes a symbolic ref point to another ref, like HEAD to the branch being checked out
+    pub fn update_symref(
+        &self,
+        name: &str,
+        target: &str,
+        identity: &Author,
+        message: &str,
+    ) -> Result<()> {
+        let old_id = self.read_ref(name);
+        self.write_file(name, &format!("{}{}", SYMREF_PREFIX, target))?;
+
+        if let (true, Some(new_id)) = (should_log(name), self.read_ref(target)) {
+            self.append_reflog(name, old_id.as_deref(), &new_id, identity, message)?;
+        }
+        Ok(())
+    }
+
+    fn write_ref(&self, name: &str, object_id: &str) -> Result<()> {
+        self.write_file(name, object_id)
+    }
+
+    fn write_file(&self, name: &str, content: &str) -> Result<()> {
+        let path = self.path.join(name);
+        fs::create_dir_all(path.parent().expect("Failed to get parent"))?;
+
+        let mut lockfile = Lockfile::new(&path);
+        lockfile
+            .hold_for_update()
+            .with_context(|| format!("Could not acquire lock on file: {}", path.display()))?;
+
+        lockfile.write_all(content.as_bytes())?;
+        lockfile.write_all(b"\n")?;
+        lockfile.commit()
+    }
+
+    fn append_reflog(
+        &self,
+        name: &str,
+        old_id: Option<&str>,
+        new_id: &str,
+        identity: &Author,
+        message: &str,
+    ) -> Result<()> {
+        self.reflog(name).append(&ReflogEntry {
+            old_id: old_id.unwrap_or(NULL_ID).into(),
+            new_id: new_id.into(),
+            identity: identity.clone(),
+            message: message.into(),
+        })
+    }
+}
+
+/// Checks a full ref name like `git check-ref-format`. It must be under `refs/` and can't
+/// leave that directory, nor hold what has a meaning in revisions or in the refs directory.
+pub fn check_ref_format(name: &str) -> Result<()> {
+    let is_valid = name.starts_with("refs/")
+        && !name.ends_with('/')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+        && name.split('/').all(|component| {
+            !component.is_empty() && !component.starts_with('.') && !component.ends_with(".lock")
+        });
+    if !is_valid {
+        bail!("'{}' is not a valid ref name", name);
+    }
+    Ok(())
+}
+
+/// Pseudo refs like `HEAD` or `ORIG_HEAD` are at the top of the git directory, the other
+/// refs must have a valid name
+fn check_name(name: &str) -> Result<()> {
+    let is_pseudo_ref =
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_uppercase() || c == '_');
+    if is_pseudo_ref {
+        Ok(())
+    } else {
+        check_ref_format(name)
+    }
+}
+
+/// Like git, only HEAD, branches and remote-tracking branches get a reflog by default
+fn should_log(name: &str) -> bool {
+    name == HEAD
+        || name == STASH
+        || name.starts_with("refs/heads/")
+        || name.starts_with("refs/remotes/")
+}
+
+fn parse_packed_refs(content: &str) -> Result<BTreeMap<String, PackedRef>> {
+    let mut packed_refs: BTreeMap<String, PackedRef> = BTreeMap::new();
+    let mut last_name: Option<String> = None;
+    for line in content.lines() {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if let Some(peeled) = line.strip_prefix('^') {
+            let name = last_name
+                .as_ref()
+                .context("packed-refs has a peeled line without a ref")?;
+            if let Some(packed) = packed_refs.get_mut(name) {
+                packed.peeled = Some(peeled.to_string());
+            }
+            continue;
+        }
+        let (object_id, name) = line
+            .split_once(' ')
+            .with_context(|| format!("Invalid packed-refs line: {}", line))?;
+        packed_refs.insert(
+            name.to_string(),
+            PackedRef {
+                object_id: object_id.to_string(),
+                peeled: None,
+            },
+        );
+        last_name = Some(name.to_string());
+    }
+    Ok(packed_refs)
+}
+
+fn write_packed_refs(
+    lockfile: &mut Lockfile,
+    packed_refs: &BTreeMap<String, PackedRef>,
+) -> Result<()> {
+    lockfile.write_all(PACKED_REFS_HEADER.as_bytes())?;
+    for (name, packed) in packed_refs {
+        writeln!(lockfile, "{} {}", packed.object_id, name)?;
+        if let Some(peeled) = &packed.peeled {
+            writeln!(lockfile, "^{}", peeled)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::test_utils;
+
+    fn temp_refs() -> Refs {
+        let path = test_utils::temp_path("refs");
+        fs::create_dir_all(path.join("refs")).unwrap();
+        Refs::new(path)
+    }
+
+    #[test]
+    fn packed_refs() -> Result<()> {
+        let refs = temp_refs();
+        let identity = test_utils::test_author();
+        let commit_id = "a".repeat(40);
+        let tag_id = "b".repeat(40);
+        refs.update_ref("refs/heads/master", commit_id.clone(), &identity, "branch")?;
+        refs.update_ref("refs/tags/v1.0", tag_id.clone(), &identity, "tag")?;
+
+        refs.pack_refs(true, true, &|object_id| {
+            if object_id == tag_id {
+                Some(commit_id.clone())
+            } else {
+                None
+            }
+        })?;
+
+        assert!(!refs.path.join("refs/tags/v1.0").exists());
+        assert_eq!(refs.read_ref("refs/tags/v1.0"), Some(tag_id.clone()));
+        assert_eq!(
+            refs.read_packed_ref("refs/tags/v1.0"),
+            Some(PackedRef {
+                object_id: tag_id.clone(),
+                peeled: Some(commit_id.clone()),
+            })
+        );
+        assert_eq!(
+            refs.expand_name("master").as_deref(),
+            Some("refs/heads/master")
+        );
+
+        // Loose refs take precedence over packed ones
+        refs.update_ref("refs/heads/master", tag_id.clone(), &identity, "move")?;
+        assert_eq!(refs.read_ref("refs/heads/master"), Some(tag_id.clone()));
+
+        // packed-refs is only changed under its lock, deleting fails while another process has it
+        let packed_lock = refs.path.join("packed-refs.lock");
+        fs::write(&packed_lock, "")?;
+        assert!(refs.delete_ref("refs/heads/master").is_err());
+        assert_eq!(refs.read_ref("refs/heads/master"), Some(tag_id.clone()));
+        fs::remove_file(packed_lock)?;
+
+        refs.delete_ref("refs/heads/master")?;
+        assert_eq!(refs.read_ref("refs/heads/master"), None);
+        assert_eq!(
+            refs.list_refs("refs/")?,
+            vec![("refs/tags/v1.0".to_string(), tag_id)]
+        );
+        assert!(refs.delete_ref("refs/heads/master").is_err());
+
+        fs::remove_dir_all(&refs.path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn rewrite_reflog_under_lock() -> Result<()> {
+        let refs = temp_refs();
+        let identity = test_utils::test_author();
+        let name = "refs/heads/master";
+        for object_id in ["a", "b", "c"] {
+            refs.update_ref(name, object_id.repeat(40), &identity, "commit")?;
+        }
+        let reflog = refs.reflog(name);
+
+        // Another process rewriting the log holds its lock, nothing is removed
+        let mut lockfile = Lockfile::new(&refs.path.join("logs").join(name));
+        lockfile.hold_for_update()?;
+        assert!(reflog.delete(1).is_err());
+        assert!(reflog.expire(Utc::now()).is_err());
+        lockfile.rollback()?;
+        assert_eq!(reflog.entries()?.len(), 3);
+
+        reflog.delete(1)?;
+        let ids: Vec<_> = reflog
+            .entries()?
+            .into_iter()
+            .map(|entry| entry.new_id)
+            .collect();
+        assert_eq!(ids, vec!["a".repeat(40), "c".repeat(40)]);
+        assert!(reflog.delete(2).is_err());
+        assert_eq!(reflog.expire(Utc::now() + chrono::Duration::days(1))?, 2);
+        assert!(reflog.entries()?.is_empty());
+        assert_eq!(refs.reflog("refs/heads/missing").expire(Utc::now())?, 0);
+
+        fs::remove_dir_all(&refs.path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn prune_only_unchanged_refs() -> Result<()> {
+        let refs = temp_refs();
+        let identity = test_utils::test_author();
+        let (a, b, c) = ("a".repeat(40), "b".repeat(40), "c".repeat(40));
+        for name in ["refs/tags/a", "refs/tags/b", "refs/tags/c"] {
+            refs.update_ref(name, a.clone(), &identity, "tag")?;
+        }
+        let mut lockfile = Lockfile::new(&refs.path.join("refs/tags/b"));
+        lockfile.hold_for_update()?;
+
+        // refs/tags/c is updated by another process after it was packed, it's peeled last
+        let peeled = std::cell::Cell::new(0);
+        refs.pack_refs(true, true, &|_| {
+            peeled.set(peeled.get() + 1);
+            if peeled.get() == 3 {
+                fs::write(refs.path.join("refs/tags/c"), format!("{}\n", c)).unwrap();
+            }
+            None
+        })?;
+        lockfile.rollback()?;
+
+        assert!(!refs.path.join("refs/tags/a").exists());
+        assert!(refs.path.join("refs/tags/b").exists());
+        assert_eq!(refs.read_ref("refs/tags/c"), Some(c.clone()));
+        assert_eq!(refs.read_packed_ref("refs/tags/c").unwrap().object_id, a);
+
+        // packed-refs written by another process is read again
+        fs::write(
+            refs.path.join(PACKED_REFS),
+            format!("{}{} refs/tags/a\n", PACKED_REFS_HEADER, b),
+        )?;
+        assert_eq!(refs.read_ref("refs/tags/a"), Some(b));
+        assert_eq!(refs.read_packed_ref("refs/tags/c"), None);
+
+        fs::remove_dir_all(&refs.path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn ref_format() {
+        for name in &[
+            "refs/heads/main",
+            "refs/tags/v1.0",
+            "refs/heads/feature/a-b_c",
+        ] {
+            assert!(check_ref_format(name).is_ok(), "{}", name);
+        }
+        let invalid = [
+            "main",
+            "refs/heads/../../x",
+            "refs//heads",
+            "/refs/heads/main",
+            "refs/heads/main/",
+            "refs/heads/main.lock",
+            "refs/heads/.hidden",
+            "refs/heads/a@{1}",
+            "refs/heads/a b",
+            "refs/heads/a\tb",
+            "refs/heads/a~1",
+            "refs/heads/a^",
+            "refs/heads/a:b",
+            "refs/heads/a?",
+            "refs/heads/a*",
+            "refs/heads/a[",
+            "refs/heads/a\\b",
+        ];
+        for name in &invalid {
+            assert!(check_ref_format(name).is_err(), "{}", name);
+        }
+        assert!(check_name("ORIG_HEAD").is_ok());
+    }
+
+    #[test]
+    fn compare_and_swap_refs() -> Result<()> {
+        let refs = temp_refs();
+        let identity = test_utils::test_author();
+        let (a, b) = ("a".repeat(40), "b".repeat(40));
+        let name = "refs/heads/main";
+        refs.compare_and_swap(name, None, Some(&a), &identity, "create")?;
+        assert!(refs
+            .compare_and_swap(name, None, Some(&b), &identity, "create")
+            .is_err());
+        refs.compare_and_swap(name, Some(&a), Some(&b), &identity, "update")?;
+        assert_eq!(refs.read_ref(name), Some(b.clone()));
+
+        // A held lock makes the update fail instead of waiting for it
+        let mut lockfile = Lockfile::new(&refs.path.join(name));
+        lockfile.hold_for_update()?;
+        assert!(refs
+            .compare_and_swap(name, Some(&b), None, &identity, "delete")
+            .is_err());
+        lockfile.rollback()?;
+        // The packed ref goes along with the loose one
+        refs.pack_refs(true, false, &|_| None)?;
+        refs.compare_and_swap(name, Some(&b), None, &identity, "delete")?;
+        assert_eq!(refs.read_ref(name), None);
+        assert_eq!(refs.read_packed_ref(name), None);
+
+        // Writing a symbolic ref would replace it instead of moving its target
+        refs.compare_and_swap(name, None, Some(&a), &identity, "create")?;
+        refs.update_symref("refs/heads/link", name, &identity, "link")?;
+        assert!(refs
+            .compare_and_swap("refs/heads/link", Some(&a), Some(&b), &identity, "update")
+            .is_err());
+        assert_eq!(refs.read_symref("refs/heads/link").as_deref(), Some(name));
+
+        let escaping = "refs/../../../escaping";
+        assert!(refs
+            .compare_and_swap(escaping, None, Some(&a), &identity, "create")
+            .is_err());
+        assert!(refs
+            .update_ref(escaping, a.clone(), &identity, "create")
+            .is_err());
+        assert!(!refs.path.join(escaping).exists());
+
+        fs::remove_dir_all(&refs.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use std::io::Read;
+
+use anyhow::{bail, Context, Result};
+
+use super::{
+    agent,
+    pkt_line::{self, Packet},
+    FetchOptions, PushCommand, RemoteRefs,
+};
+use crate::{
+    database::{pack, Database},
+    refs::{HEAD, NULL_ID},
+    rev_list::{self, Deepen},
+    ObjectId,
+};
+
+/// Reads the refs a protocol v0 server advertises and the capabilities following the first
+/// one. A shallow server lists its shallow commits after the refs.
+pub fn read_advertisement(input: &mut dyn Read) -> Result<(RemoteRefs, Vec<String>)> {
+    let mut remote_refs = RemoteRefs::default();
+    let mut capabilities = vec![];
+    loop {
+        let packet = match pkt_line::read_packet(input)? {
+            None => bail!("The remote end hung up unexpectedly"),
+            Some(Packet::Flush) => break,
+            Some(packet) => packet,
+        };
+        let line = packet.text().context("Invalid ref advertisement")?;
+        if let Some(error) = line.strip_prefix("ERR ") {
+            bail!("remote error: {}", error);
+        }
+        let line = match line.split_once('\0') {
+            Some((line, advertised)) => {
+                capabilities = advertised.split(' ').map(String::from).collect();
+                line
+            }
+            None => line,
+        };
+        match line.split_once(' ') {
+            // An empty repository only sends its capabilities
+            Some((_, "capabilities^{}")) => (),
+            Some(("shallow", object_id)) => remote_refs.shallow.push(object_id.to_string()),
+            Some((object_id, name)) => remote_refs
+                .refs
+                .push((name.to_string(), object_id.to_string())),
+            None => bail!("Invalid ref advertisement '{}'", line),
+        }
+    }
+    remote_refs.head_symref = capabilities.iter().find_map(|capability| {
+        capability
+            .strip_prefix(&format!("symref={}:", HEAD))
+            .map(String::from)
+    });
+    Ok((remote_refs, capabilities))
+}
+
+/// Asks for the wants and tells the haves in a single request that ends with `done`. The
+/// pack is asked in side-band packets, the server must support `side-band-64k`, and without
+/// progress messages when possible. A shallow repository tells its `shallow` commits. The
+/// filter of a partial clone is ignored by a server that doesn't support it.
+pub fn upload_request(
+    capabilities: &[String],
+    wants: &[ObjectId],
+    haves: &[ObjectId],
+    shallow: &[ObjectId],
+    options: &FetchOptions,
+) -> Result<Vec<u8>> {
+    let has = |name: &str| capabilities.iter().any(|capability| capability == name);
+    if !has("side-band-64k") {
+        bail!("The remote doesn't support side-band-64k");
+    }
+    if (!shallow.is_empty() || options.deepen.is_some()) && !has("shallow") {
+        bail!("Server does not support shallow clients");
+    }
+    let mut extra = String::new();
+    if has("no-progress") {
+        extra.push_str(" no-progress");
+    }
+    if !shallow.is_empty() || options.deepen.is_some() {
+        extra.push_str(" shallow");
+    }
+    let filter = match options.filter {
+        Some(filter) if has("filter") => {
+            extra.push_str(" filter");
+            Some(filter)
+        }
+        Some(_) => {
+            log::warn!("filtering not recognized by server, ignoring");
+            None
+        }
+        None => None,
+    };
+    let depth = match options.deepen {
+        Some(Deepen::Depth(depth)) => Some(depth),
+        Some(Deepen::Relative(depth)) if has("deepen-relative") => {
+            extra.push_str(" deepen-relative");
+            Some(depth)
+        }
+        Some(Deepen::Relative(_)) => bail!("Server does not support --deepen"),
+        None => None,
+    };
+
+    let mut request = vec![];
+    for (i, object_id) in wants.iter().enumerate() {
+        if i == 0 {
+            let line = format!("want {} side-band-64k{} {}", object_id, extra, agent());
+            pkt_line::write_line(&mut request, &line)?;
+        } else {
+            pkt_line::write_line(&mut request, &format!("want {}", object_id))?;
+        }
+    }
+    for object_id in shallow {
+        pkt_line::write_line(&mut request, &format!("shallow {}", object_id))?;
+    }
+    if let Some(depth) = depth {
+        pkt_line::write_line(&mut request, &format!("deepen {}", depth))?;
+    }
+    if let Some(filter) = filter {
+        pkt_line::write_line(&mut request, &format!("filter {}", filter))?;
+    }
+    pkt_line::write_flush(&mut request)?;
+    for object_id in haves {
+        pkt_line::write_line(&mut request, &format!("have {}", object_id))?;
+    }
+    pkt_line::write_line(&mut request, "done")?;
+    Ok(request)
+}
+
+/// Skips the acknowledgments of the server and stores the pack that follows. The shallow
+/// commits a deepening request changed are updated. Returns the number of objects received.
+pub fn read_upload_response(database: &Database, input: &mut dyn Read) -> Result<usize> {
+    let mut pack = vec![];
+    let mut shallow = vec![];
+    let mut unshallow = vec![];
+    loop {
+        let data = match pkt_line::read_packet(input)? {
+            Some(Packet::Data(data)) => data,
+            // The shallow commits end with a flush before the acknowledgments
+            Some(Packet::Flush) if pack.is_empty() => continue,
+            _ => break,
+        };
+        if data.starts_with(b"NAK") || data.starts_with(b"ACK ") {
+            continue;
+        }
+        let line = String::from_utf8_lossy(&data);
+        if let Some(object_id) = line.trim_end().strip_prefix("shallow ") {
+            shallow.push(object_id.to_string());
+            continue;
+        }
+        if let Some(object_id) = line.trim_end().strip_prefix("unshallow ") {
+            unshallow.push(object_id.to_string());
+            continue;
+        }
+        match data.first() {
+            Some(1) => pack.extend_from_slice(&data[1..]),
+            Some(2) => {
+                let progress = String::from_utf8_lossy(&data[1..]);
+                for line in progress.split(['\r', '\n']) {
+                    if !line.is_empty() {
+                        log::info!("remote: {}", line);
+                    }
+                }
+            }
+            Some(3) => bail!("remote error: {}", String::from_utf8_lossy(&data[1..])),
+            _ => bail!("Invalid side-band packet"),
+        }
+    }
+    if pack.is_empty() {
+        bail!("The remote end hung up without sending a pack");
+    }
+    let count = pack::read_pack(database, &mut &pack[..])?.len();
+    database.update_shallow(&shallow, &unshallow)?;
+    Ok(count)
+}
+
+/// The ref updates followed by a pack of the objects the remote doesn't have, it isn't sent
+/// when the updates only delete refs
+pub fn push_request(
+    database: &Database,
+    remote_refs: &RemoteRefs,
+    commands: &[PushCommand],
+) -> Result<Vec<u8>> {
+    let mut request = vec![];
+    for (i, command) in commands.iter().enumerate() {
+        let mut line = format!(
+            "{} {} {}",
+            command.old_id.as_deref().unwrap_or(NULL_ID),
+            command.new_id.as_deref().unwrap_or(NULL_ID),
+            command.name
+        );
+        if i == 0 {
+            line.push_str(&format!("\0report-status delete-refs {}", agent()));
+        }
+        pkt_line::write_line(&mut request, &line)?;
+    }
+    pkt_line::write_flush(&mut request)?;
+
+    let wants: Vec<ObjectId> = commands
+        .iter()
+        .filter_map(|command| command.new_id.clone())
+        .collect();
+    if !wants.is_empty() {
+        let haves: Vec<ObjectId> = remote_refs
+            .refs
+            .iter()
+            .map(|(_, object_id)| object_id.clone())
+            .filter(|object_id| database.exists(object_id))
+            .collect();
+        let objects = rev_list::objects_between(database, &haves, &wants)?;
+        pack::write_pack(database, &objects, &mut request)?;
+    }
+    Ok(request)
+}
+
+/// Reads why the remote refused each command from its `report-status`
+pub fn read_report_status(
+    input: &mut dyn Read,
+    commands: &[PushCommand],
+) -> Result<Vec<Option<String>>> {
+    let mut results = vec![None; commands.len()];
+    let mut unpack = None;
+    while let Some(Packet::Data(data)) = pkt_line::read_packet(input)? {
+        let line = String::from_utf8_lossy(&data);
+        let line = line.trim_end();
+        if let Some(status) = line.strip_prefix("unpack ") {
+            unpack = Some(status.to_string());
+        } else if let Some(rest) = line.strip_prefix("ng ") {
+            let (name, reason) = rest.split_once(' ').unwrap_or((rest, "failed"));
+            if let Some(i) = commands.iter().position(|command| command.name == name) {
+                results[i] = Some(reason.to_string());
+            }
+        }
+    }
+    match unpack.as_deref() {
+        Some("ok") => Ok(results),
+        Some(error) => bail!("remote unpack failed: {}", error),
+        None => bail!("The remote end hung up without reporting the status"),
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    io::{BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use anyhow::{bail, Context, Result};
+
+use super::{
+    client, local,
+    pkt_line::{self, Packet},
+    server::{self, ServeOptions},
+    FetchOptions, PushCommand, RemoteRefs, Transport,
+};
+use crate::{database::Database, ObjectId};
+
+pub const DEFAULT_PORT: u16 = 9418;
+
+/// A repository is only served when its git directory has this file, unless all are exported
+pub const EXPORT_OK: &str = "git-daemon-export-ok";
+
+#[derive(Debug, Clone)]
+pub struct DaemonOptions {
+    /// The requested paths are relative to it
+    pub base_path: PathBuf,
+    /// Serve every repository, even without the `git-daemon-export-ok` file
+    pub export_all: bool,
+}
+
+impl DaemonOptions {
+    /// The git directory of a requested path, `None` when it isn't a repository that can be
+    /// served. The path can't leave the base path and `.git` can be omitted.
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let relative = Path::new(path.trim_start_matches('/'));
+        let is_normal = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !is_normal {
+            return None;
+        }
+        let path = self.base_path.join(relative);
+        let mut with_suffix = path.clone().into_os_string();
+        with_suffix.push(".git");
+        let git_path = local::git_dir(&path).or_else(|| local::git_dir(Path::new(&with_suffix)))?;
+        if self.export_all || git_path.join(EXPORT_OK).is_file() {
+            Some(git_path)
+        } else {
+            None
+        }
+    }
+}
+
+/// Serves the repositories with the `git://` protocol, like `git daemon`. Every connection
+/// is handled in its own thread and only upload-pack is available, so it's read-only.
+pub fn serve(listener: TcpListener, options: DaemonOptions) -> Result<()> {
+    let options = Arc::new(options);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let options = options.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle(&options, stream) {
+                log::warn!("{:#}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Reads the request, like `git-upload-pack /project.git\0host=example.com\0`, and serves
+/// the repository. Extra parameters like `version=2` come after a second `\0`.
+fn handle(options: &DaemonOptions, mut stream: TcpStream) -> Result<()> {
+    let request = match pkt_line::read_packet(&mut stream)? {
+        Some(Packet::Data(data)) => String::from_utf8(data)?,
+        _ => bail!("Invalid request"),
+    };
+    let mut fields = request.trim_end_matches('\n').split('\0');
+    let command = fields.next().unwrap_or_default();
+    let extra: Vec<&str> = fields.skip_while(|field| !field.is_empty()).collect();
+    let version = server::protocol_version(Some(&extra.join(":")));
+
+    let (service, path) = command.split_once(' ').context("Invalid request")?;
+    log::info!("{} {}", service, path);
+    if service != "git-upload-pack" {
+        pkt_line::write_line(&mut stream, "ERR service not enabled")?;
+        return Ok(());
+    }
+    let git_path = match options.resolve(path) {
+        Some(git_path) => git_path,
+        None => {
+            let error = format!("ERR access denied or repository not exported: {}", path);
+            pkt_line::write_line(&mut stream, &error)?;
+            return Ok(());
+        }
+    };
+    let mut input = stream.try_clone()?;
+    let mut output = BufWriter::new(stream);
+    server::upload_pack(
+        &git_path,
+        version,
+        ServeOptions::default(),
+        &mut input,
+        &mut output,
+    )
+}
+
+/// A repository served by a daemon, with a url like `git://host[:port]/path`. The server
+/// stops after sending a pack so every fetch is a new connection.
+pub struct DaemonTransport {
+    url: String,
+    /// A connection that already read the refs and capabilities
+    connection: Option<(TcpStream, RemoteRefs, Vec<String>)>,
+}
+
+impl DaemonTransport {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            connection: None,
+        }
+    }
+
+    fn connect(&self) -> Result<(TcpStream, RemoteRefs, Vec<String>)> {
+        let rest = match self.url.strip_prefix("git://") {
+            Some(rest) => rest,
+            None => bail!("Invalid git url {}", self.url),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => bail!("No repository path in {}", self.url),
+        };
+        let address = if authority.contains(':') {
+            authority.to_string()
+        } else {
+            format!("{}:{}", authority, DEFAULT_PORT)
+        };
+        let mut stream = TcpStream::connect(&address)
+            .with_context(|| format!("Failed to connect to {}", authority))?;
+        let request = format!("git-upload-pack {}\0host={}\0", path, authority);
+        pkt_line::write_data(&mut stream, request.as_bytes())?;
+        let (remote_refs, capabilities) = client::read_advertisement(&mut stream)?;
+        Ok((stream, remote_refs, capabilities))
+    }
+}
+
+impl Transport for DaemonTransport {
+    fn list_refs(&mut self) -> Result<RemoteRefs> {
+        let connection = self.connect()?;
+        let remote_refs = connection.1.clone();
+        self.connection = Some(connection);
+        Ok(remote_refs)
+    }
+
+    fn fetch_objects(
+        &mut self,
+        database: &Database,
+        wants: &[ObjectId],
+        haves: &[ObjectId],
+        options: &FetchOptions,
+    ) -> Result<usize> {
+        let (mut stream, _, capabilities) = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.connect()?,
+        };
+        let request =
+            client::upload_request(&capabilities, wants, haves, &database.shallow(), options)?;
+        stream.write_all(&request)?;
+        client::read_upload_response(database, &mut stream)
+    }
+
+    fn push(&mut self, _: &Database, _: &[PushCommand]) -> Result<Vec<Option<String>>> {
+        bail!("{} is read-only, git:// only supports fetching", self.url)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn serve_exported_repositories() -> Result<()> {
+        let dir = test_utils::temp_path("daemon");
+        let git_path = dir.join("base").join("project.git");
+        let (commit_id, _) = test_utils::served_repo(&git_path)?;
+
+        let options = DaemonOptions {
+            base_path: dir.join("base"),
+            export_all: false,
+        };
+        assert_eq!(options.resolve("/project"), None);
+        std::fs::write(git_path.join(EXPORT_OK), "")?;
+        assert_eq!(options.resolve("/project"), Some(git_path.clone()));
+        assert_eq!(options.resolve("/../base/project.git"), None);
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("git://{}/project", listener.local_addr()?);
+        thread::spawn(move || serve(listener, options));
+
+        let mut transport = DaemonTransport::new(&url);
+        let remote_refs = transport.list_refs()?;
+        assert_eq!(remote_refs.get("refs/heads/main"), Some(&commit_id));
+        let clone = Database::new(dir.join("clone"));
+        let wants = std::slice::from_ref(&commit_id);
+        assert_eq!(
+            transport.fetch_objects(&clone, wants, &[], &FetchOptions::default())?,
+            3
+        );
+        assert!(transport.push(&clone, &[]).is_err());
+
+        let mut transport = DaemonTransport::new(&url.replace("project", "missing"));
+        assert!(transport.list_refs().is_err());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
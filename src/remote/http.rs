@@ -0,0 +1,374 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use anyhow::{bail, Context, Result};
+
+use super::{
+    client,
+    pkt_line::{self, Packet},
+    FetchOptions, PushCommand, RemoteRefs, Transport,
+};
+use crate::{database::Database, ObjectId};
+
+/// A repository served with the smart HTTP protocol, like `http://localhost:8080/repo.git`.
+/// Every request is a new connection so the whole negotiation is sent at once.
+pub struct HttpTransport {
+    url: String,
+    /// The capabilities of upload-pack, known once the refs are listed
+    capabilities: Option<Vec<String>>,
+}
+
+impl HttpTransport {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            capabilities: None,
+        }
+    }
+
+    /// Reads the refs and capabilities a service advertises at `info/refs`
+    fn advertisement(&self, service: &str) -> Result<(RemoteRefs, Vec<String>)> {
+        let url = format!("{}/info/refs?service={}", self.url, service);
+        let response = request("GET", &url, &[], &[])?;
+        self.check(
+            &response,
+            &format!("application/x-{}-advertisement", service),
+        )?;
+
+        // The smart protocol starts with the name of the service
+        let mut body = &response.body[..];
+        let first = pkt_line::read_packet(&mut body)?;
+        let is_service = first
+            .as_ref()
+            .and_then(Packet::text)
+            .is_some_and(|line| line.starts_with("# service="));
+        if !is_service {
+            bail!("Invalid smart HTTP response from {}", self.url);
+        }
+        if pkt_line::read_packet(&mut body)? != Some(Packet::Flush) {
+            bail!("Invalid smart HTTP response from {}", self.url);
+        }
+        client::read_advertisement(&mut body)
+    }
+
+    /// Posts a request to a service and returns its result
+    fn rpc(&self, service: &str, body: &[u8]) -> Result<Vec<u8>> {
+        let url = format!("{}/{}", self.url, service);
+        let content_type = format!("application/x-{}-request", service);
+        let accept = format!("application/x-{}-result", service);
+        let headers = [("Content-Type", content_type.as_str()), ("Accept", &accept)];
+        let response = request("POST", &url, &headers, body)?;
+        self.check(&response, &accept)?;
+        Ok(response.body)
+    }
+
+    fn check(&self, response: &Response, content_type: &str) -> Result<()> {
+        match response.status {
+            200 => (),
+            404 => bail!("repository '{}' not found", self.url),
+            status => bail!(
+                "unable to access '{}': The requested URL returned error: {}",
+                self.url,
+                status
+            ),
+        }
+        if response.header("content-type") != Some(content_type) {
+            bail!("{} doesn't speak the smart HTTP protocol", self.url);
+        }
+        Ok(())
+    }
+}
+
+impl Transport for HttpTransport {
+    fn list_refs(&mut self) -> Result<RemoteRefs> {
+        let (remote_refs, capabilities) = self.advertisement("git-upload-pack")?;
+        self.capabilities = Some(capabilities);
+        Ok(remote_refs)
+    }
+
+    fn fetch_objects(
+        &mut self,
+        database: &Database,
+        wants: &[ObjectId],
+        haves: &[ObjectId],
+        options: &FetchOptions,
+    ) -> Result<usize> {
+        let capabilities = match self.capabilities.take() {
+            Some(capabilities) => capabilities,
+            None => self.advertisement("git-upload-pack")?.1,
+        };
+        let request =
+            client::upload_request(&capabilities, wants, haves, &database.shallow(), options)?;
+        self.capabilities = Some(capabilities);
+        let response = self.rpc("git-upload-pack", &request)?;
+        client::read_upload_response(database, &mut &response[..])
+    }
+
+    fn push(
+        &mut self,
+        database: &Database,
+        commands: &[PushCommand],
+    ) -> Result<Vec<Option<String>>> {
+        let (remote_refs, _) = self.advertisement("git-receive-pack")?;
+        let request = client::push_request(database, &remote_refs, commands)?;
+        let response = self.rpc("git-receive-pack", &request)?;
+        client::read_report_status(&mut &response[..], commands)
+    }
+}
+
+/// A response with its body read entirely
+struct Response {
+    status: u16,
+    /// Names are lowercase
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Sends a HTTP/1.1 request over a new connection that the server closes once it responded.
+/// Only `http://` urls are supported.
+fn request(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<Response> {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => bail!("Unsupported url {}, only http:// is supported", url),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let address = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    let mut stream = TcpStream::connect(&address)
+        .with_context(|| format!("Failed to connect to {}", authority))?;
+
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: git_rs/{}\r\nConnection: close\r\n",
+        method,
+        path,
+        authority,
+        env!("CARGO_PKG_VERSION")
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+
+    let mut raw = vec![];
+    stream.read_to_end(&mut raw)?;
+    parse_response(&raw)
+}
+
+fn parse_response(raw: &[u8]) -> Result<Response> {
+    let end = raw
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .context("Invalid HTTP response")?;
+    let head = std::str::from_utf8(&raw[..end])?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .context("Invalid HTTP status line")?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    let mut response = Response {
+        status,
+        headers,
+        body: raw[end + 4..].to_vec(),
+    };
+    if response.header("transfer-encoding") == Some("chunked") {
+        response.body = decode_chunked(&response.body)?;
+    } else if let Some(len) = response.header("content-length") {
+        let len = len.parse().context("Invalid Content-Length")?;
+        response.body.truncate(len);
+    }
+    Ok(response)
+}
+
+/// Joins the chunks of a body, each one is prefixed by its size in hexadecimal
+fn decode_chunked(mut data: &[u8]) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line_end = data
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .context("Truncated chunked body")?;
+        let size = std::str::from_utf8(&data[..line_end])?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).context("Invalid chunk size")?;
+        if size == 0 {
+            return Ok(body);
+        }
+        let chunk = data
+            .get(line_end + 2..line_end + 2 + size)
+            .context("Truncated chunked body")?;
+        body.extend_from_slice(chunk);
+        data = data.get(line_end + size + 4..).unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        database::{blob::Blob, commit::Commit, tree::TreeItem},
+        refs::HEAD,
+        remote::server::{self, ServeOptions},
+        test_utils, Refs,
+    };
+    use std::{
+        collections::BTreeMap,
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        path::{Path, PathBuf},
+    };
+
+    /// Serves the repository at `path` as `/repo.git` like `git http-backend` would
+    fn serve(path: PathBuf) -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/repo.git", listener.local_addr()?);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                handle(&path, stream).expect("Failed to handle request");
+            }
+        });
+        Ok(url)
+    }
+
+    fn handle(path: &Path, mut stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            match line.trim_end().split_once(':') {
+                Some((name, value)) if name.eq_ignore_ascii_case("content-length") => {
+                    content_length = value.trim().parse()?
+                }
+                Some(_) => (),
+                None => break,
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        let target = request_line.split(' ').nth(1).context("Missing target")?;
+        let target = target.strip_prefix("/repo.git/").context("Unknown repo")?;
+        let (service, advertise_refs) = match target.strip_prefix("info/refs?service=") {
+            Some(service) => (service, true),
+            None => (target, false),
+        };
+        let options = ServeOptions {
+            advertise_refs,
+            stateless_rpc: !advertise_refs,
+        };
+        let mut out = vec![];
+        if advertise_refs {
+            pkt_line::write_line(&mut out, &format!("# service={}", service))?;
+            pkt_line::write_flush(&mut out)?;
+        }
+        match service {
+            "git-upload-pack" => server::upload_pack(path, 0, options, &mut &body[..], &mut out)?,
+            "git-receive-pack" => server::receive_pack(path, options, &mut &body[..], &mut out)?,
+            _ => bail!("Unknown service {}", service),
+        }
+        let kind = if advertise_refs {
+            "advertisement"
+        } else {
+            "result"
+        };
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-{}-{}\r\nTransfer-Encoding: chunked\r\n\r\n",
+            service, kind
+        )?;
+        write!(stream, "{:x}\r\n", out.len())?;
+        stream.write_all(&out)?;
+        write!(stream, "\r\n0\r\n\r\n")?;
+        Ok(())
+    }
+
+    fn commit(database: &Database, parents: Vec<ObjectId>, content: &str) -> Result<ObjectId> {
+        let blob_id = database.store(&Blob::new(content.into()))?;
+        let mut items = BTreeMap::new();
+        items.insert(
+            "a.txt".to_string(),
+            TreeItem {
+                mode: 0o100644,
+                object_id: blob_id,
+            },
+        );
+        let tree_id = database.store_tree(&items)?;
+        let author = test_utils::test_author();
+        database.store(&Commit::new(parents, tree_id, author, content.into()))
+    }
+
+    #[test]
+    fn fetch_and_push_over_http() -> Result<()> {
+        let dir = test_utils::temp_path("http");
+        let remote_path = dir.join("remote");
+        let remote_database = Database::new(remote_path.join("objects"));
+        let remote = Refs::new(remote_path.clone());
+        let author = test_utils::test_author();
+        let first = commit(&remote_database, vec![], "one\n")?;
+        remote.update_ref("refs/heads/main", first.clone(), &author, "test")?;
+        remote.update_symref(HEAD, "refs/heads/main", &author, "test")?;
+
+        let mut transport = HttpTransport::new(&serve(remote_path)?);
+        let remote_refs = transport.list_refs()?;
+        assert_eq!(remote_refs.get("refs/heads/main"), Some(&first));
+        assert_eq!(remote_refs.head_symref.as_deref(), Some("refs/heads/main"));
+
+        let database = Database::new(dir.join("local"));
+        assert_eq!(
+            transport.fetch_objects(
+                &database,
+                std::slice::from_ref(&first),
+                &[],
+                &FetchOptions::default()
+            )?,
+            3
+        );
+        assert!(database.exists(&first));
+
+        let second = commit(&database, vec![first.clone()], "two\n")?;
+        let command = PushCommand {
+            name: "refs/heads/main".to_string(),
+            old_id: Some(first),
+            new_id: Some(second.clone()),
+        };
+        assert_eq!(
+            transport.push(&database, std::slice::from_ref(&command))?,
+            vec![None]
+        );
+        assert_eq!(remote.read_ref("refs/heads/main"), Some(second));
+        assert!(transport.push(&database, &[command])?[0].is_some());
+
+        assert_eq!(
+            decode_chunked(b"3\r\nabc\r\n1;x\r\nd\r\n0\r\n\r\n")?,
+            b"abcd"
+        );
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
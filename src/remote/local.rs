@@ -0,0 +1,186 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+
+use super::{receiver_identity, FetchOptions, PushCommand, RemoteRefs, Transport};
+use crate::{
+    config::Config,
+    database::Database,
+    refs::{self, HEAD},
+    rev_list, revision, Author, ObjectId, Refs, GIT_FOLDER,
+};
+
+/// A repository on the same machine, read directly from its directory
+pub struct LocalTransport {
+    git_path: PathBuf,
+    database: Database,
+    refs: Refs,
+}
+
+/// Finds the git directory of a repository, `path` can be a workspace or a bare repository
+pub fn git_dir(path: &Path) -> Option<PathBuf> {
+    if path.join(GIT_FOLDER).join("objects").is_dir() {
+        Some(path.join(GIT_FOLDER))
+    } else if path.join("objects").is_dir() && path.join("refs").is_dir() {
+        Some(path.to_path_buf())
+    } else {
+        None
+    }
+}
+
+impl LocalTransport {
+    pub fn open(path: &Path) -> Result<Self> {
+        let git_path = match git_dir(path) {
+            Some(git_path) => git_path,
+            None => bail!(
+                "'{}' does not appear to be a git repository",
+                path.display()
+            ),
+        };
+        Ok(Self {
+            database: Database::new(git_path.join("objects")),
+            refs: Refs::new(git_path.clone()),
+            git_path,
+        })
+    }
+
+    pub fn git_path(&self) -> &Path {
+        &self.git_path
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    pub fn refs(&self) -> &Refs {
+        &self.refs
+    }
+
+    /// A bare repository has no workspace, so any of its branches can be pushed to
+    pub fn is_bare(&self) -> Result<bool> {
+        let config = Config::load(self.git_path.join("config"))?;
+        Ok(match config.get_bool("core.bare")? {
+            Some(bare) => bare,
+            None => self.git_path.file_name() != Some(GIT_FOLDER.as_ref()),
+        })
+    }
+
+    /// Applies pushed ref updates once their objects are in the database. Returns why each
+    /// update was refused, the checked out branch of a workspace can't be pushed to.
+    pub fn update_refs(
+        &self,
+        commands: &[PushCommand],
+        identity: &Author,
+    ) -> Result<Vec<Option<String>>> {
+        let checked_out = if self.is_bare()? {
+            None
+        } else {
+            self.refs.read_symref(HEAD)
+        };
+        let mut results = vec![];
+        for command in commands {
+            if refs::check_ref_format(&command.name).is_err() {
+                results.push(Some("invalid ref name".to_string()));
+                continue;
+            }
+            if checked_out.as_deref() == Some(command.name.as_str()) {
+                results.push(Some("branch is currently checked out".to_string()));
+                continue;
+            }
+            let is_missing = |object_id: &str| {
+                !revision::is_object_id(object_id) || !self.database.exists(object_id)
+            };
+            if command.new_id.as_deref().is_some_and(is_missing) {
+                results.push(Some("missing necessary objects".to_string()));
+                continue;
+            }
+            let result = self.refs.compare_and_swap(
+                &command.name,
+                command.old_id.as_deref(),
+                command.new_id.as_deref(),
+                identity,
+                "push",
+            );
+            results.push(result.err().map(|_| "failed to update ref".to_string()));
+        }
+        Ok(results)
+    }
+}
+
+impl Transport for LocalTransport {
+    fn list_refs(&mut self) -> Result<RemoteRefs> {
+        let mut remote_refs = RemoteRefs::default();
+        if let Some(head_id) = self.refs.read_head() {
+            remote_refs.refs.push((HEAD.to_string(), head_id));
+        }
+        remote_refs.head_symref = self.refs.read_symref(HEAD);
+        remote_refs.shallow = self.database.shallow();
+        for (name, object_id) in self.refs.list_refs("refs/")? {
+            let peeled = self.database.peel(&object_id)?;
+            remote_refs.refs.push((name.clone(), object_id.clone()));
+            if peeled != object_id {
+                remote_refs.refs.push((format!("{}^{{}}", name), peeled));
+            }
+        }
+        Ok(remote_refs)
+    }
+
+    fn fetch_objects(
+        &mut self,
+        database: &Database,
+        wants: &[ObjectId],
+        haves: &[ObjectId],
+        options: &FetchOptions,
+    ) -> Result<usize> {
+        let shallow: HashSet<ObjectId> = database.shallow().into_iter().collect();
+        let pack = rev_list::shallow_objects(
+            &self.database,
+            haves,
+            wants,
+            &shallow,
+            options.deepen,
+            options.filter,
+        )?;
+        let mut count = 0;
+        for object_id in pack.objects {
+            if database.exists(&object_id) {
+                continue;
+            }
+            let (kind, data) = self.database.load(&object_id)?;
+            database.store_raw(&kind, &data)?;
+            count += 1;
+        }
+        database.update_shallow(&pack.shallow, &pack.unshallow)?;
+        Ok(count)
+    }
+
+    fn push(
+        &mut self,
+        database: &Database,
+        commands: &[PushCommand],
+    ) -> Result<Vec<Option<String>>> {
+        // Every object must be there before a ref can point to it
+        let haves: Vec<ObjectId> = self
+            .refs
+            .list_refs("refs/")?
+            .into_iter()
+            .map(|(_, object_id)| object_id)
+            .filter(|object_id| database.exists(object_id))
+            .collect();
+        let wants: Vec<ObjectId> = commands
+            .iter()
+            .filter_map(|command| command.new_id.clone())
+            .collect();
+        for object_id in rev_list::objects_between(database, &haves, &wants)? {
+            if !self.database.exists(&object_id) {
+                let (kind, data) = database.load(&object_id)?;
+                self.database.store_raw(&kind, &data)?;
+            }
+        }
+
+        self.update_refs(commands, &receiver_identity())
+    }
+}
@@ -0,0 +1,1004 @@
+pub mod client;
+pub mod daemon;
+pub mod http;
+pub mod local;
+pub mod pkt_line;
+pub mod promisor;
+pub mod refspec;
+pub mod server;
+
+use std::{
+    collections::BTreeSet,
+    env,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+
+use crate::{
+    config::Config,
+    database::Database,
+    index::Index,
+    migration,
+    refs::{self, HEAD, NULL_ID},
+    rev_list::{self, Deepen, Filter},
+    revision::{self, Revision},
+    workspace::Workspace,
+    Author, ObjectId, Refs, GIT_FOLDER,
+};
+use daemon::DaemonTransport;
+use http::HttpTransport;
+use local::LocalTransport;
+use refspec::Refspec;
+
+pub const ORIGIN: &str = "origin";
+/// Records what the last fetch got, with the branch to merge for pull
+pub const FETCH_HEAD: &str = "FETCH_HEAD";
+
+/// The refs a remote advertises. Annotated tags are followed by a `name^{}` entry with the
+/// object they point to.
+#[derive(Debug, Clone, Default)]
+pub struct RemoteRefs {
+    pub refs: Vec<(String, ObjectId)>,
+    /// The branch HEAD points to on the remote
+    pub head_symref: Option<String>,
+    /// The shallow commits of a shallow remote
+    pub shallow: Vec<ObjectId>,
+}
+
+impl RemoteRefs {
+    pub fn get(&self, name: &str) -> Option<&ObjectId> {
+        self.refs
+            .iter()
+            .find(|(ref_name, _)| ref_name == name)
+            .map(|(_, object_id)| object_id)
+    }
+
+    /// The object a ref points to once its tags are peeled
+    pub fn peeled(&self, name: &str) -> Option<&ObjectId> {
+        self.get(&format!("{}^{{}}", name))
+            .or_else(|| self.get(name))
+    }
+
+    /// The branch a clone checks out: the one HEAD points to, or else the first branch at
+    /// the same commit as HEAD. Without a HEAD, main or master are picked before the others.
+    pub fn default_branch(&self) -> Option<String> {
+        if let Some(name) = &self.head_symref {
+            return Some(name.clone());
+        }
+        let mut branches = self
+            .refs
+            .iter()
+            .filter(|(name, _)| name.starts_with("refs/heads/"));
+        match self.get(HEAD) {
+            Some(head_id) => branches
+                .find(|(_, object_id)| object_id == head_id)
+                .map(|(name, _)| name.clone()),
+            None => ["refs/heads/main", "refs/heads/master"]
+                .iter()
+                .find(|name| self.get(name).is_some())
+                .map(|name| name.to_string())
+                .or_else(|| branches.next().map(|(name, _)| name.clone())),
+        }
+    }
+}
+
+/// What a fetch asks for besides the wanted objects
+#[derive(Debug, Clone, Default)]
+pub struct FetchOptions {
+    /// Cut the history, or deepen the history of a shallow repository
+    pub deepen: Option<Deepen>,
+    /// Leave out the objects a partial clone fetches when it needs them
+    pub filter: Option<Filter>,
+}
+
+/// A way to talk to another repository
+pub trait Transport {
+    fn list_refs(&mut self) -> Result<RemoteRefs>;
+
+    /// Copies the objects reachable from `wants` into `database`, the `haves` tell what the
+    /// local repository already has. The shallow commits of `database` are updated when the
+    /// history is cut or deepened. Returns the number of objects received.
+    fn fetch_objects(
+        &mut self,
+        database: &Database,
+        wants: &[ObjectId],
+        haves: &[ObjectId],
+        options: &FetchOptions,
+    ) -> Result<usize>;
+
+    /// Sends the objects the commands need then asks the remote to update its refs. Returns
+    /// the reason of every command the remote refused.
+    fn push(
+        &mut self,
+        database: &Database,
+        commands: &[PushCommand],
+    ) -> Result<Vec<Option<String>>>;
+}
+
+/// A ref to update on the remote, it must still be at `old_id`. It's deleted when `new_id`
+/// is `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PushCommand {
+    pub name: String,
+    pub old_id: Option<ObjectId>,
+    pub new_id: Option<ObjectId>,
+}
+
+/// A remote repository configured in `[remote "<name>"]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Remote {
+    pub name: String,
+    pub url: String,
+    pub fetch: Vec<Refspec>,
+    /// The objects fetched from a promisor remote can point to objects a partial clone left
+    /// out, they're fetched from it when needed
+    pub promisor: bool,
+    /// The filter of the partial clone, used again by every fetch
+    pub filter: Option<Filter>,
+}
+
+impl Remote {
+    pub fn load(config: &Config, name: &str) -> Result<Self> {
+        let url = match config.get(&format!("remote.{}.url", name)) {
+            Some(url) => url,
+            None => bail!("'{}' does not appear to be a git repository", name),
+        };
+        let fetch = config
+            .get_all(&format!("remote.{}.fetch", name))
+            .iter()
+            .map(|refspec| refspec.parse())
+            .collect::<Result<_>>()?;
+        let promisor = config
+            .get_bool(&format!("remote.{}.promisor", name))?
+            .unwrap_or(false);
+        let filter = match config.get(&format!("remote.{}.partialclonefilter", name)) {
+            Some(filter) if promisor => Some(filter.parse()?),
+            _ => None,
+        };
+        Ok(Self {
+            name: name.to_string(),
+            url,
+            fetch,
+            promisor,
+            filter,
+        })
+    }
+
+    /// Configures a new remote fetching all its branches
+    pub fn add(config: &mut Config, name: &str, url: &str) -> Result<Self> {
+        if config.get(&format!("remote.{}.url", name)).is_some() {
+            bail!("remote {} already exists.", name);
+        }
+        let remote = Self {
+            name: name.to_string(),
+            url: url.to_string(),
+            fetch: vec![Refspec::default_fetch(name)],
+            promisor: false,
+            filter: None,
+        };
+        config.set(&format!("remote.{}.url", name), url)?;
+        for refspec in &remote.fetch {
+            config.add(&format!("remote.{}.fetch", name), &refspec.to_string())?;
+        }
+        Ok(remote)
+    }
+
+    /// Makes the remote the promisor remote of a partial clone, it fetches the objects the
+    /// filter leaves out when they're needed
+    pub fn set_promisor(&mut self, config: &mut Config, filter: Filter) -> Result<()> {
+        config.set("core.repositoryformatversion", "1")?;
+        config.set(promisor::PARTIAL_CLONE, &self.name)?;
+        config.set(&format!("remote.{}.promisor", self.name), "true")?;
+        let key = format!("remote.{}.partialclonefilter", self.name);
+        config.set(&key, &filter.to_string())?;
+        self.promisor = true;
+        self.filter = Some(filter);
+        Ok(())
+    }
+
+    /// Loads a configured remote, or uses `name` as the url of a repository on this machine
+    pub fn find(config: &Config, name: &str) -> Result<Self> {
+        if config.get(&format!("remote.{}.url", name)).is_some() {
+            return Self::load(config, name);
+        }
+        match local::git_dir(local_path(name)) {
+            Some(_) => Ok(Self {
+                name: name.to_string(),
+                url: name.to_string(),
+                fetch: vec![],
+                promisor: false,
+                filter: None,
+            }),
+            None => Self::load(config, name),
+        }
+    }
+
+    /// Opens a transport to the remote depending on its url
+    pub fn connect(&self) -> Result<Box<dyn Transport>> {
+        if self.url.starts_with("http://") || self.url.starts_with("https://") {
+            return Ok(Box::new(HttpTransport::new(&self.url)));
+        }
+        if self.url.starts_with("git://") {
+            return Ok(Box::new(DaemonTransport::new(&self.url)));
+        }
+        Ok(Box::new(LocalTransport::open(local_path(&self.url))?))
+    }
+}
+
+/// The path of a repository on this machine, its url is the path or a `file://` url
+fn local_path(url: &str) -> &Path {
+    Path::new(url.strip_prefix("file://").unwrap_or(url))
+}
+
+/// What happened to a ref during a fetch or a push
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpdateStatus {
+    New,
+    FastForward,
+    Forced,
+    UpToDate,
+    Deleted,
+    /// Not a fast-forward and the refspec doesn't force it, the ref is left as is
+    Rejected,
+    /// The remote refused to update the ref
+    RemoteRejected,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefUpdate {
+    /// Name of the ref on the remote
+    pub source: String,
+    /// Name of the local ref
+    pub target: String,
+    pub old_id: Option<ObjectId>,
+    /// The null id when the ref is deleted
+    pub new_id: ObjectId,
+    pub status: UpdateStatus,
+    /// Why the update was rejected, when it isn't because it's not a fast-forward
+    pub reason: Option<String>,
+}
+
+/// Names git_rs in the capabilities of the protocol
+fn agent() -> String {
+    format!("agent=git_rs/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Who the reflogs of a repository receiving a push say updated its refs, the pusher isn't
+/// known there
+fn receiver_identity() -> Author {
+    Author {
+        name: env::var("GIT_AUTHOR_NAME").unwrap_or_else(|_| "git_rs".to_string()),
+        email: env::var("GIT_AUTHOR_EMAIL").unwrap_or_else(|_| "git_rs@localhost".to_string()),
+        time: Utc::now().into(),
+    }
+}
+
+/// Shortens a ref name like git does in the fetch and push summaries
+pub fn short_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+impl Display for RefUpdate {
+    /// Formats the update like a line of the summary of `git fetch`
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let kind = if self.target.starts_with("refs/tags/") {
+            "tag"
+        } else {
+            "branch"
+        };
+        let old = self.old_id.as_deref().map_or("", |id| &id[..7]);
+        let reason = self.reason.as_deref().unwrap_or("non-fast-forward");
+        let (flag, summary, note) = match self.status {
+            UpdateStatus::New => ('*', format!("[new {}]", kind), String::new()),
+            UpdateStatus::FastForward => (
+                ' ',
+                format!("{}..{}", old, &self.new_id[..7]),
+                String::new(),
+            ),
+            UpdateStatus::Forced => (
+                '+',
+                format!("{}...{}", old, &self.new_id[..7]),
+                "  (forced update)".to_string(),
+            ),
+            UpdateStatus::UpToDate => ('=', "[up to date]".to_string(), String::new()),
+            UpdateStatus::Deleted => {
+                return write!(f, " - {:<17} {}", "[deleted]", short_name(&self.target));
+            }
+            UpdateStatus::Rejected => ('!', "[rejected]".to_string(), format!("  ({})", reason)),
+            UpdateStatus::RemoteRejected => (
+                '!',
+                "[remote rejected]".to_string(),
+                format!("  ({})", reason),
+            ),
+        };
+        write!(
+            f,
+            " {} {:<17} {:<10} -> {}{}",
+            flag,
+            summary,
+            short_name(&self.source),
+            short_name(&self.target),
+            note
+        )
+    }
+}
+
+/// Fetches the refs of a remote matching its refspecs along with the tags pointing into the
+/// fetched history, then updates the local refs and `FETCH_HEAD`. A promisor remote keeps
+/// filtering like the partial clone did and its fetches are marked.
+pub fn fetch(
+    git_path: &Path,
+    database: &Database,
+    refs: &Refs,
+    remote: &Remote,
+    transport: &mut dyn Transport,
+    options: &FetchOptions,
+    identity: &Author,
+) -> Result<(RemoteRefs, Vec<RefUpdate>)> {
+    let mut remote_refs = transport.list_refs()?;
+    // The names become paths in the git directory, a remote can't make them leave it
+    remote_refs.refs.retain(|(name, object_id)| {
+        let name = name.strip_suffix("^{}").unwrap_or(name);
+        let is_valid = (name == HEAD || refs::check_ref_format(name).is_ok())
+            && revision::is_object_id(object_id);
+        if !is_valid {
+            log::warn!("ignoring ref with broken name {}", name);
+        }
+        is_valid
+    });
+    remote_refs
+        .shallow
+        .retain(|object_id| revision::is_object_id(object_id));
+    let options = &FetchOptions {
+        filter: options.filter.or(remote.filter),
+        ..options.clone()
+    };
+
+    // (source, target, object id, force)
+    let mut mapped: Vec<(String, String, ObjectId, bool)> = vec![];
+    for (name, object_id) in &remote_refs.refs {
+        if name == HEAD || name.ends_with("^{}") {
+            continue;
+        }
+        for refspec in &remote.fetch {
+            match refspec.map(name) {
+                Some(target) if refs::check_ref_format(&target).is_err() => {
+                    log::warn!("ignoring ref with broken name {}", target);
+                }
+                Some(target) => {
+                    mapped.push((name.clone(), target, object_id.clone(), refspec.force))
+                }
+                None => (),
+            }
+        }
+    }
+
+    let mut haves: Vec<ObjectId> = refs
+        .list_refs("refs/")?
+        .into_iter()
+        .map(|(_, object_id)| object_id)
+        .collect();
+    haves.extend(refs.read_head());
+    // Deepening needs the history of the refs even when they didn't move
+    let wants: BTreeSet<ObjectId> = mapped
+        .iter()
+        .map(|(_, _, object_id, _)| object_id.clone())
+        .filter(|object_id| options.deepen.is_some() || !database.exists(object_id))
+        .collect();
+    let mut fetched = !wants.is_empty();
+    if fetched {
+        let wants: Vec<ObjectId> = wants.into_iter().collect();
+        transport.fetch_objects(database, &wants, &haves, options)?;
+    }
+
+    // Tags are followed when what they point to is now in the repository
+    let mut tags = vec![];
+    for (name, object_id) in &remote_refs.refs {
+        let is_new_tag = name.starts_with("refs/tags/")
+            && !name.ends_with("^{}")
+            && refs.read_ref(name).is_none()
+            && !mapped.iter().any(|(_, target, _, _)| target == name);
+        if is_new_tag
+            && remote_refs
+                .peeled(name)
+                .is_some_and(|id| database.exists(id))
+        {
+            tags.push((name.clone(), name.clone(), object_id.clone(), false));
+        }
+    }
+    let tag_wants: Vec<ObjectId> = tags
+        .iter()
+        .map(|(_, _, object_id, _)| object_id.clone())
+        .filter(|object_id| !database.exists(object_id))
+        .collect();
+    if !tag_wants.is_empty() {
+        let options = FetchOptions {
+            filter: options.filter,
+            ..FetchOptions::default()
+        };
+        transport.fetch_objects(database, &tag_wants, &haves, &options)?;
+        fetched = true;
+    }
+    mapped.extend(tags);
+    if fetched && remote.promisor {
+        let content: String = mapped
+            .iter()
+            .map(|(source, _, object_id, _)| format!("{} {}\n", object_id, source))
+            .collect();
+        promisor::write_marker(git_path, &content)?;
+    }
+
+    // The history of a shallow remote stops at its shallow commits, so does the fetched one
+    let cut: Vec<ObjectId> = remote_refs
+        .shallow
+        .iter()
+        .filter(|commit_id| database.exists(commit_id) && !database.is_shallow(commit_id))
+        .filter(|commit_id| {
+            rev_list::parents(database, commit_id)
+                .is_ok_and(|parents| parents.iter().any(|parent| !database.exists(parent)))
+        })
+        .cloned()
+        .collect();
+    database.update_shallow(&cut, &[])?;
+
+    let mut updates = vec![];
+    for (source, target, new_id, force) in mapped {
+        let old_id = refs.read_ref(&target);
+        let status = match &old_id {
+            None => UpdateStatus::New,
+            Some(old_id) if *old_id == new_id => UpdateStatus::UpToDate,
+            Some(old_id) if rev_list::is_ancestor(database, old_id, &new_id)? => {
+                UpdateStatus::FastForward
+            }
+            Some(_) if force => UpdateStatus::Forced,
+            Some(_) => UpdateStatus::Rejected,
+        };
+        let message = match status {
+            UpdateStatus::New => Some("storing head"),
+            UpdateStatus::FastForward => Some("fast-forward"),
+            UpdateStatus::Forced => Some("forced-update"),
+            _ => None,
+        };
+        if let Some(message) = message {
+            let message = format!("fetch {}: {}", remote.name, message);
+            refs.update_ref(&target, new_id.clone(), identity, &message)?;
+        }
+        updates.push(RefUpdate {
+            source,
+            target,
+            old_id,
+            new_id,
+            status,
+            reason: None,
+        });
+    }
+
+    write_fetch_head(git_path, refs, remote, &updates)?;
+    Ok((remote_refs, updates))
+}
+
+/// Lists the fetched refs in `FETCH_HEAD`, the upstream of the current branch first and
+/// the others marked `not-for-merge`
+fn write_fetch_head(
+    git_path: &Path,
+    refs: &Refs,
+    remote: &Remote,
+    updates: &[RefUpdate],
+) -> Result<()> {
+    let config = Config::load(git_path.join("config"))?;
+    let upstream = refs
+        .read_symref(HEAD)
+        .and_then(|name| name.strip_prefix("refs/heads/").map(String::from))
+        .filter(|branch| {
+            config.get(&format!("branch.{}.remote", branch)).as_deref() == Some(&remote.name)
+        })
+        .and_then(|branch| config.get(&format!("branch.{}.merge", branch)));
+
+    let mut lines = vec![];
+    for update in updates {
+        let (kind, name) = match update.source.strip_prefix("refs/heads/") {
+            Some(name) => ("branch", name),
+            None => ("tag", short_name(&update.source)),
+        };
+        let for_merge = upstream.as_deref() == Some(update.source.as_str());
+        let flag = if for_merge { "" } else { "not-for-merge" };
+        let line = format!(
+            "{}\t{}\t{} '{}' of {}\n",
+            update.new_id, flag, kind, name, remote.url
+        );
+        lines.push((!for_merge, line));
+    }
+    lines.sort_by_key(|(not_for_merge, _)| *not_for_merge);
+    let content: String = lines.into_iter().map(|(_, line)| line).collect();
+    fs::write(git_path.join(FETCH_HEAD), content)?;
+    Ok(())
+}
+
+/// What `--force-with-lease` expects the remote refs to be at before overwriting them
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lease {
+    /// Every pushed ref must be where its remote-tracking ref is
+    All,
+    /// Only this ref is checked, against the given revision or else its remote-tracking ref
+    Ref {
+        name: String,
+        expected: Option<String>,
+    },
+}
+
+impl FromStr for Lease {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.split_once(':') {
+            _ if s.is_empty() => Lease::All,
+            Some((name, expected)) => Lease::Ref {
+                name: name.to_string(),
+                expected: Some(expected.to_string()),
+            },
+            None => Lease::Ref {
+                name: s.to_string(),
+                expected: None,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PushOptions {
+    /// Overwrite the remote refs even when it's not a fast-forward
+    pub force: bool,
+    pub lease: Option<Lease>,
+}
+
+/// Updates the refs of the remote with local refs, like `git push <remote> <refspec>...`.
+/// The remote-tracking refs of the updated refs are moved to where the remote now is.
+pub fn push(
+    database: &Database,
+    refs: &Refs,
+    remote: &Remote,
+    transport: &mut dyn Transport,
+    refspecs: &[Refspec],
+    options: &PushOptions,
+    identity: &Author,
+) -> Result<Vec<RefUpdate>> {
+    let remote_refs = transport.list_refs()?;
+    let mut updates = vec![];
+    let mut commands = vec![];
+    for refspec in refspecs {
+        for (source, target, new_id) in expand_push_refspec(database, refs, &remote_refs, refspec)?
+        {
+            let old_id = remote_refs.get(&target).cloned();
+            let lease = lease_expectation(options.lease.as_ref(), remote, refs, database, &target)?;
+            let (status, reason) = match (&old_id, &new_id) {
+                (None, None) => (UpdateStatus::Rejected, Some("remote ref does not exist")),
+                (Some(old_id), Some(new_id)) if old_id == new_id => (UpdateStatus::UpToDate, None),
+                _ if lease.as_ref().is_some_and(|expected| *expected != old_id) => {
+                    (UpdateStatus::Rejected, Some("stale info"))
+                }
+                (_, None) => (UpdateStatus::Deleted, None),
+                (None, Some(_)) => (UpdateStatus::New, None),
+                (Some(old_id), Some(new_id))
+                    if database.exists(old_id)
+                        && rev_list::is_ancestor(database, old_id, new_id)? =>
+                {
+                    (UpdateStatus::FastForward, None)
+                }
+                _ if refspec.force || options.force || lease.is_some() => {
+                    (UpdateStatus::Forced, None)
+                }
+                (Some(old_id), _) if !database.exists(old_id) => {
+                    (UpdateStatus::Rejected, Some("fetch first"))
+                }
+                _ => (UpdateStatus::Rejected, None),
+            };
+            if !matches!(status, UpdateStatus::UpToDate | UpdateStatus::Rejected) {
+                commands.push((
+                    updates.len(),
+                    PushCommand {
+                        name: target.clone(),
+                        old_id: old_id.clone(),
+                        new_id: new_id.clone(),
+                    },
+                ));
+            }
+            updates.push(RefUpdate {
+                source,
+                target,
+                old_id,
+                new_id: new_id.unwrap_or_else(|| NULL_ID.to_string()),
+                status,
+                reason: reason.map(String::from),
+            });
+        }
+    }
+    if commands.is_empty() {
+        return Ok(updates);
+    }
+
+    let (indices, commands): (Vec<usize>, Vec<PushCommand>) = commands.into_iter().unzip();
+    let results = transport.push(database, &commands)?;
+    for (index, result) in indices.into_iter().zip(results) {
+        let update = &mut updates[index];
+        if let Some(reason) = result {
+            update.status = UpdateStatus::RemoteRejected;
+            update.reason = Some(reason);
+            continue;
+        }
+        let tracking = remote
+            .fetch
+            .iter()
+            .find_map(|refspec| refspec.map(&update.target));
+        match tracking {
+            Some(tracking)
+                if update.status == UpdateStatus::Deleted && refs.read_ref(&tracking).is_some() =>
+            {
+                refs.delete_ref(&tracking)?
+            }
+            Some(_) if update.status == UpdateStatus::Deleted => (),
+            Some(tracking) => {
+                refs.update_ref(&tracking, update.new_id.clone(), identity, "update by push")?
+            }
+            None => (),
+        }
+    }
+    Ok(updates)
+}
+
+/// Resolves a push refspec to the local ref, the remote ref and the id to set it to, `None`
+/// deleting the remote ref
+fn expand_push_refspec(
+    database: &Database,
+    refs: &Refs,
+    remote_refs: &RemoteRefs,
+    refspec: &Refspec,
+) -> Result<Vec<(String, String, Option<ObjectId>)>> {
+    if refspec.source.is_empty() {
+        let target = remote_target(remote_refs, &refspec.target, "");
+        return Ok(vec![(String::new(), target, None)]);
+    }
+    if refspec.source.contains('*') {
+        let mut expanded = vec![];
+        for (name, object_id) in refs.list_refs("refs/")? {
+            if let Some(target) = refspec.map(&name) {
+                expanded.push((name, target, Some(object_id)));
+            }
+        }
+        return Ok(expanded);
+    }
+
+    let new_id = Revision::parse(&refspec.source)?.resolve(refs, database)?;
+    let source = if refspec.source == HEAD {
+        refs.read_symref(HEAD).unwrap_or_else(|| HEAD.to_string())
+    } else {
+        refs.expand_name(&refspec.source)
+            .unwrap_or_else(|| refspec.source.clone())
+    };
+    let target = if !refspec.target.is_empty() {
+        remote_target(remote_refs, &refspec.target, &source)
+    } else if source.starts_with("refs/") {
+        source.clone()
+    } else {
+        bail!(
+            "The destination you provided is not a full refname: {}",
+            refspec.source
+        );
+    };
+    Ok(vec![(source, target, Some(new_id))])
+}
+
+/// Completes a short remote ref name with an existing remote ref, or else the kind of the
+/// local ref pushed to it
+fn remote_target(remote_refs: &RemoteRefs, name: &str, source: &str) -> String {
+    if name.starts_with("refs/") {
+        return name.to_string();
+    }
+    ["refs/heads/", "refs/tags/"]
+        .iter()
+        .map(|prefix| format!("{}{}", prefix, name))
+        .find(|full_name| remote_refs.get(full_name).is_some())
+        .unwrap_or_else(|| {
+            if source.starts_with("refs/tags/") {
+                format!("refs/tags/{}", name)
+            } else {
+                format!("refs/heads/{}", name)
+            }
+        })
+}
+
+/// Where the lease expects the remote ref to be: the given revision or else the
+/// remote-tracking ref. `None` when no lease applies to this ref.
+fn lease_expectation(
+    lease: Option<&Lease>,
+    remote: &Remote,
+    refs: &Refs,
+    database: &Database,
+    target: &str,
+) -> Result<Option<Option<ObjectId>>> {
+    let expected = match lease {
+        None => return Ok(None),
+        Some(Lease::All) => None,
+        Some(Lease::Ref { name, expected }) => {
+            let applies = ["", "refs/heads/", "refs/tags/"]
+                .iter()
+                .any(|prefix| format!("{}{}", prefix, name) == target);
+            if !applies {
+                return Ok(None);
+            }
+            expected.clone()
+        }
+    };
+    Ok(Some(match expected {
+        Some(revision) => Some(Revision::parse(&revision)?.resolve(refs, database)?),
+        None => remote
+            .fetch
+            .iter()
+            .find_map(|refspec| refspec.map(target))
+            .and_then(|tracking| refs.read_ref(&tracking)),
+    }))
+}
+
+/// Clones the repository at `url` into `directory`, with `origin` fetching all its branches
+/// and the default branch checked out and tracking its remote branch. The history is cut
+/// when the options deepen it, and a filter makes a partial clone with `origin` as its
+/// promisor remote.
+pub fn clone(url: &str, directory: &Path, options: &FetchOptions, identity: &Author) -> Result<()> {
+    let is_empty = fs::read_dir(directory).map_or(true, |mut entries| entries.next().is_none());
+    if !is_empty {
+        bail!(
+            "destination path '{}' already exists and is not an empty directory.",
+            directory.display()
+        );
+    }
+    let existed = directory.exists();
+    let result = clone_into(url, directory, options, identity);
+    if result.is_err() {
+        // A failed clone leaves nothing behind, an existing directory is emptied
+        let cleanup = if existed {
+            fs::read_dir(directory).and_then(|mut entries| {
+                entries.try_for_each(|entry| {
+                    let path = entry?.path();
+                    if path.is_dir() {
+                        fs::remove_dir_all(path)
+                    } else {
+                        fs::remove_file(path)
+                    }
+                })
+            })
+        } else {
+            fs::remove_dir_all(directory)
+        };
+        if let Err(err) = cleanup {
+            log::warn!("failed to clean up {}: {}", directory.display(), err);
+        }
+    }
+    result
+}
+
+fn clone_into(
+    url: &str,
+    directory: &Path,
+    options: &FetchOptions,
+    identity: &Author,
+) -> Result<()> {
+    let url = match fs::canonicalize(url) {
+        Ok(path) => path.to_string_lossy().to_string(),
+        Err(_) => url.to_string(),
+    };
+
+    let git_path = directory.join(GIT_FOLDER);
+    fs::create_dir_all(git_path.join("objects"))?;
+    fs::create_dir_all(git_path.join("refs").join("heads"))?;
+    let database = Database::new(git_path.join("objects"));
+    let refs = Refs::new(git_path.clone());
+    let mut config = Config::load(git_path.join("config"))?;
+    let mut remote = Remote::add(&mut config, ORIGIN, &url)?;
+    if let Some(filter) = options.filter {
+        remote.set_promisor(&mut config, filter)?;
+    }
+    config.save()?;
+
+    let mut transport = remote.connect()?;
+    let (remote_refs, _) = fetch(
+        &git_path,
+        &database,
+        &refs,
+        &remote,
+        transport.as_mut(),
+        options,
+        identity,
+    )?;
+    // The fetch only follows the branches, a detached HEAD can be on another commit
+    if let Some(head_id) = remote_refs.get(HEAD).filter(|id| !database.exists(id)) {
+        let haves: Vec<ObjectId> = refs
+            .list_refs("refs/")?
+            .into_iter()
+            .map(|(_, object_id)| object_id)
+            .collect();
+        let wants = std::slice::from_ref(head_id);
+        transport.fetch_objects(&database, wants, &haves, options)?;
+        if remote.promisor {
+            promisor::write_marker(&git_path, &format!("{} {}\n", head_id, HEAD))?;
+        }
+    }
+
+    let message = format!("clone: from {}", url);
+    let branch = remote_refs.default_branch();
+    let head_id = remote_refs.get(HEAD).cloned();
+    let head_id = match (branch, head_id) {
+        (Some(branch), _) if remote_refs.get(&branch).is_some() => {
+            let commit_id = remote_refs
+                .get(&branch)
+                .cloned()
+                .context("Missing branch")?;
+            let name = branch.trim_start_matches("refs/heads/");
+            let tracking = Refspec::default_fetch(ORIGIN)
+                .map(&branch)
+                .context("Invalid branch name")?;
+            let remote_head = format!("refs/remotes/{}/{}", ORIGIN, HEAD);
+            refs.update_symref(&remote_head, &tracking, identity, &message)?;
+            refs.update_ref(&branch, commit_id.clone(), identity, &message)?;
+            refs.update_symref(HEAD, &branch, identity, &message)?;
+            config.set(&format!("branch.{}.remote", name), ORIGIN)?;
+            config.set(&format!("branch.{}.merge", name), &branch)?;
+            config.save()?;
+            commit_id
+        }
+        (_, Some(head_id)) => {
+            // A detached HEAD on the remote stays detached
+            refs.update_ref(HEAD, head_id.clone(), identity, &message)?;
+            head_id
+        }
+        (branch, None) => {
+            let branch = branch.unwrap_or_else(|| "refs/heads/main".to_string());
+            fs::write(refs.head_path(), format!("ref: {}\n", branch))?;
+            return Ok(());
+        }
+    };
+
+    let workspace = Workspace::new(directory.to_path_buf());
+    let mut index = Index::new(git_path.join("index"));
+    index.load_for_update()?;
+    let items = database.load_tree_entries(&database.load_tree_id(&head_id)?)?;
+    migration::checkout_entries(&database, &workspace, &mut index, &items)?;
+    index.write_updates()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{
+        blob::Blob,
+        commit::Commit,
+        tree::{Tree, TreeItem},
+    };
+    use crate::test_utils;
+    use std::collections::BTreeMap;
+
+    /// A remote advertising whatever refs it's given, its objects are already local
+    struct FakeTransport(RemoteRefs);
+
+    impl Transport for FakeTransport {
+        fn list_refs(&mut self) -> Result<RemoteRefs> {
+            Ok(self.0.clone())
+        }
+
+        fn fetch_objects(
+            &mut self,
+            _: &Database,
+            _: &[ObjectId],
+            _: &[ObjectId],
+            _: &FetchOptions,
+        ) -> Result<usize> {
+            Ok(0)
+        }
+
+        fn push(&mut self, _: &Database, _: &[PushCommand]) -> Result<Vec<Option<String>>> {
+            Ok(vec![])
+        }
+    }
+
+    #[test]
+    fn ignore_broken_ref_names() -> Result<()> {
+        let git_path = test_utils::temp_path("fetch");
+        let database = Database::new(git_path.join("objects"));
+        let refs = Refs::new(git_path.clone());
+        let author = test_utils::test_author();
+        let tree_id = database.store(&Tree::new())?;
+        let commit_id =
+            database.store(&Commit::new(vec![], tree_id, author.clone(), "one".into()))?;
+
+        let mut remote_refs = RemoteRefs::default();
+        for name in &[
+            "refs/heads/main",
+            "refs/heads/../../../../escaping",
+            "refs/tags/../../../escaping",
+        ] {
+            remote_refs.refs.push((name.to_string(), commit_id.clone()));
+        }
+        let remote = Remote {
+            name: ORIGIN.to_string(),
+            url: "fake".to_string(),
+            fetch: vec![Refspec::default_fetch(ORIGIN)],
+            promisor: false,
+            filter: None,
+        };
+        let mut transport = FakeTransport(remote_refs);
+        let options = FetchOptions::default();
+        let (_, updates) = fetch(
+            &git_path,
+            &database,
+            &refs,
+            &remote,
+            &mut transport,
+            &options,
+            &author,
+        )?;
+
+        let targets: Vec<&str> = updates
+            .iter()
+            .map(|update| update.target.as_str())
+            .collect();
+        assert_eq!(targets, vec!["refs/remotes/origin/main"]);
+        assert!(!git_path.join("refs/remotes/escaping").exists());
+        assert!(!git_path.join("refs/escaping").exists());
+        assert!(!git_path.join("../escaping").exists());
+
+        std::fs::remove_dir_all(git_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn clone_detached_head() -> Result<()> {
+        let dir = test_utils::temp_path("clone");
+        let git_path = dir.join("source").join(GIT_FOLDER);
+        let database = Database::new(git_path.join("objects"));
+        let refs = Refs::new(git_path.clone());
+        let author = test_utils::test_author();
+        let blob_id = database.store(&Blob::new(b"hello\n".to_vec()))?;
+        let mut items = BTreeMap::new();
+        items.insert(
+            "a.txt".to_string(),
+            TreeItem {
+                mode: 0o100644,
+                object_id: blob_id,
+            },
+        );
+        let tree_id = database.store_tree(&items)?;
+        let commit_id =
+            database.store(&Commit::new(vec![], tree_id, author.clone(), "one".into()))?;
+        // Like a repository made with init and commit, HEAD is detached and there's no branch
+        refs.update_ref(HEAD, commit_id.clone(), &author, "commit")?;
+        std::fs::create_dir_all(git_path.join("refs"))?;
+
+        let url = format!("file://{}", dir.join("source").display());
+        let target = dir.join("target");
+        clone(&url, &target, &FetchOptions::default(), &author)?;
+        assert_eq!(std::fs::read_to_string(target.join("a.txt"))?, "hello\n");
+        assert_eq!(
+            Refs::new(target.join(GIT_FOLDER)).read_head(),
+            Some(commit_id)
+        );
+
+        let failed = dir.join("failed");
+        assert!(clone(
+            &format!("file://{}", dir.join("missing").display()),
+            &failed,
+            &FetchOptions::default(),
+            &author
+        )
+        .is_err());
+        assert!(!failed.exists());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use std::io::{Read, Write};
+
+use anyhow::{bail, Context, Result};
+
+/// The largest data a single packet can hold, the 4 bytes of the length are part of the limit
+pub const MAX_DATA: usize = 65516;
+
+/// A packet of the git protocol, prefixed by its length in 4 hexadecimal digits. The lengths
+/// 0 to 2 are special packets separating the parts of a message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Data(Vec<u8>),
+    /// Ends a message
+    Flush,
+    /// Separates the sections of a protocol v2 message
+    Delim,
+    /// Ends a protocol v2 response over stateless connections
+    ResponseEnd,
+}
+
+impl Packet {
+    /// The content of a data packet as text, without its trailing newline
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            Packet::Data(data) => {
+                let text = std::str::from_utf8(data).ok()?;
+                Some(text.strip_suffix('\n').unwrap_or(text))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Reads the next packet, `None` when the other side closed the connection
+pub fn read_packet(input: &mut dyn Read) -> Result<Option<Packet>> {
+    let mut len = [0; 4];
+    match input.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = std::str::from_utf8(&len)
+        .ok()
+        .and_then(|len| usize::from_str_radix(len, 16).ok())
+        .with_context(|| format!("Invalid packet length {:?}", String::from_utf8_lossy(&len)))?;
+    Ok(Some(match len {
+        0 => Packet::Flush,
+        1 => Packet::Delim,
+        2 => Packet::ResponseEnd,
+        3 => bail!("Invalid packet length 3"),
+        len => {
+            let mut data = vec![0; len - 4];
+            input.read_exact(&mut data).context("Truncated packet")?;
+            Packet::Data(data)
+        }
+    }))
+}
+
+pub fn write_data(out: &mut dyn Write, data: &[u8]) -> Result<()> {
+    if data.len() > MAX_DATA {
+        bail!("Packet of {} bytes is too large", data.len());
+    }
+    write!(out, "{:04x}", data.len() + 4)?;
+    out.write_all(data)?;
+    Ok(())
+}
+
+pub fn write_line(out: &mut dyn Write, line: &str) -> Result<()> {
+    write_data(out, format!("{}\n", line).as_bytes())
+}
+
+/// Ends a message, the output is flushed since the other side waits for it
+pub fn write_flush(out: &mut dyn Write) -> Result<()> {
+    out.write_all(b"0000")?;
+    out.flush()?;
+    Ok(())
+}
+
+pub fn write_delim(out: &mut dyn Write) -> Result<()> {
+    out.write_all(b"0001")?;
+    Ok(())
+}
+
+/// Sends data in packets prefixed by the band they belong to: 1 for the pack, 2 for progress
+/// messages and 3 for a fatal error
+pub fn write_sideband(out: &mut dyn Write, band: u8, data: &[u8]) -> Result<()> {
+    for chunk in data.chunks(MAX_DATA - 1) {
+        let mut packet = Vec::with_capacity(chunk.len() + 1);
+        packet.push(band);
+        packet.extend_from_slice(chunk);
+        write_data(out, &packet)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+
+use super::{FetchOptions, Remote};
+use crate::{config::Config, database::Database, hash, rev_list::Filter, ObjectId};
+
+/// Names the remote a partial clone fetches its missing objects from
+pub const PARTIAL_CLONE: &str = "extensions.partialclone";
+
+/// The promisor remote of a partial clone, `None` when the repository is complete
+pub fn promisor_remote(git_path: &Path) -> Result<Option<Remote>> {
+    let config = Config::load(git_path.join("config"))?;
+    match config.get(PARTIAL_CLONE) {
+        Some(name) => Ok(Some(Remote::load(&config, &name)?)),
+        None => Ok(None),
+    }
+}
+
+/// Fetches objects a partial clone left out in a single request. Like git, a missing tree
+/// comes with the trees it contains but not with their blobs. Returns `false` when the
+/// repository isn't a partial clone.
+pub fn fetch_missing(
+    git_path: &Path,
+    database: &Database,
+    object_ids: &[ObjectId],
+) -> Result<bool> {
+    let remote = match promisor_remote(git_path)? {
+        Some(remote) => remote,
+        None => return Ok(false),
+    };
+    log::info!(
+        "fetching {} missing objects from {}",
+        object_ids.len(),
+        remote.name
+    );
+    let mut transport = remote.connect()?;
+    let options = FetchOptions {
+        filter: Some(Filter::BlobNone),
+        ..FetchOptions::default()
+    };
+    transport.fetch_objects(database, object_ids, &[], &options)?;
+    let content: String = object_ids
+        .iter()
+        .map(|object_id| format!("{}\n", object_id))
+        .collect();
+    write_marker(git_path, &content)?;
+    Ok(true)
+}
+
+/// Records a fetch from a promisor remote, the objects it brought can point to objects that
+/// are missing on purpose. Like the `.promisor` file git keeps next to such a pack, it lists
+/// the fetched tips.
+pub fn write_marker(git_path: &Path, content: &str) -> Result<()> {
+    let dir = git_path.join("objects").join("pack");
+    fs::create_dir_all(&dir)?;
+    let name = format!("pack-{}.promisor", hash(content.as_bytes()));
+    fs::write(dir.join(name), content)?;
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+
+/// Maps the refs of a remote to local refs, like `+refs/heads/*:refs/remotes/origin/*`. A `*`
+/// in the source matches any part of a ref name and is replaced in the target.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Refspec {
+    /// Update the target even when it isn't a fast-forward
+    pub force: bool,
+    pub source: String,
+    pub target: String,
+}
+
+impl Refspec {
+    /// The refspec `clone` and `remote add` configure for a remote
+    pub fn default_fetch(remote: &str) -> Self {
+        Self {
+            force: true,
+            source: "refs/heads/*".to_string(),
+            target: format!("refs/remotes/{}/*", remote),
+        }
+    }
+
+    /// The target of a ref matching the source, `None` when it doesn't match
+    pub fn map(&self, name: &str) -> Option<String> {
+        map_pattern(&self.source, &self.target, name)
+    }
+
+    /// The source of a ref matching the target, to go from a local ref to the remote one
+    pub fn map_reverse(&self, name: &str) -> Option<String> {
+        map_pattern(&self.target, &self.source, name)
+    }
+}
+
+fn map_pattern(from: &str, to: &str, name: &str) -> Option<String> {
+    match from.split_once('*') {
+        Some((prefix, suffix)) => {
+            let middle = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            Some(to.replacen('*', middle, 1))
+        }
+        None if from == name => Some(to.to_string()),
+        None => None,
+    }
+}
+
+impl FromStr for Refspec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (force, spec) = match s.strip_prefix('+') {
+            Some(spec) => (true, spec),
+            None => (false, s),
+        };
+        let (source, target) = spec.split_once(':').unwrap_or((spec, ""));
+        let bad_target = !target.is_empty() && source.contains('*') != target.contains('*');
+        if source.matches('*').count() > 1 || bad_target {
+            bail!("invalid refspec '{}'", s);
+        }
+        Ok(Self {
+            force,
+            source: source.to_string(),
+            target: target.to_string(),
+        })
+    }
+}
+
+impl Display for Refspec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.force {
+            write!(f, "+")?;
+        }
+        write!(f, "{}:{}", self.source, self.target)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn map_refs() -> Result<()> {
+        let refspec: Refspec = "+refs/heads/*:refs/remotes/origin/*".parse()?;
+        assert_eq!(refspec, Refspec::default_fetch("origin"));
+        assert_eq!(
+            refspec.map("refs/heads/topic/a").as_deref(),
+            Some("refs/remotes/origin/topic/a")
+        );
+        assert_eq!(refspec.map("refs/tags/v1"), None);
+        assert_eq!(
+            refspec.map_reverse("refs/remotes/origin/main").as_deref(),
+            Some("refs/heads/main")
+        );
+
+        let refspec: Refspec = "refs/heads/main:refs/heads/copy".parse()?;
+        assert!(!refspec.force);
+        assert_eq!(
+            refspec.map("refs/heads/main").as_deref(),
+            Some("refs/heads/copy")
+        );
+        assert!("refs/heads/*:refs/heads/main".parse::<Refspec>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,697 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+use super::{
+    agent,
+    local::LocalTransport,
+    pkt_line::{self, Packet},
+    receiver_identity, PushCommand, RemoteRefs, Transport,
+};
+use crate::{
+    database::{pack, Database},
+    refs::{HEAD, NULL_ID},
+    rev_list::{self, Deepen, ShallowPack},
+    revision, ObjectId,
+};
+
+/// The protocol version the client asks for in the `GIT_PROTOCOL` environment variable, 0
+/// unless it's 2
+pub fn protocol_version(git_protocol: Option<&str>) -> u8 {
+    let is_v2 =
+        git_protocol.is_some_and(|value| value.split(':').any(|param| param == "version=2"));
+    if is_v2 {
+        2
+    } else {
+        0
+    }
+}
+
+/// How a request is served, stateless requests are made for HTTP where every request is a
+/// new connection
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServeOptions {
+    /// Only advertise the refs, or the capabilities with protocol v2, then stop
+    pub advertise_refs: bool,
+    /// Handle a single request without advertising the refs first
+    pub stateless_rpc: bool,
+}
+
+/// Serves `git fetch` and `git clone` for the repository at `path`, like `git upload-pack`
+pub fn upload_pack(
+    path: &Path,
+    version: u8,
+    options: ServeOptions,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<()> {
+    let mut transport = LocalTransport::open(path)?;
+    let remote_refs = transport.list_refs()?;
+    if version == 2 {
+        return serve_v2(transport.database(), &remote_refs, options, input, output);
+    }
+
+    let mut capabilities = vec![
+        "side-band-64k".to_string(),
+        "include-tag".to_string(),
+        "no-progress".to_string(),
+        "shallow".to_string(),
+        "deepen-relative".to_string(),
+        "filter".to_string(),
+        // A partial clone fetches the blobs it left out by their ids, and checking that they
+        // are reachable would walk every object of the repository. Any object in the database
+        // can be wanted, unreachable ones included.
+        "allow-any-sha1-in-want".to_string(),
+    ];
+    if let Some(target) = &remote_refs.head_symref {
+        capabilities.push(format!("symref={}:{}", HEAD, target));
+    }
+    capabilities.push(agent());
+    if !options.stateless_rpc {
+        let capabilities = capabilities.join(" ");
+        advertise_refs(
+            output,
+            &remote_refs.refs,
+            &remote_refs.shallow,
+            &capabilities,
+        )?;
+    }
+    if options.advertise_refs {
+        return Ok(());
+    }
+
+    let database = transport.database();
+    let mut wants = vec![];
+    let mut client_capabilities = vec![];
+    let mut shallow = HashSet::new();
+    let mut depth = None;
+    let mut filter = None;
+    loop {
+        let line = match pkt_line::read_packet(input)? {
+            None => return Ok(()),
+            Some(Packet::Flush) => break,
+            Some(packet) => packet.text().context("Invalid want line")?.to_string(),
+        };
+        let mut words = line.split(' ');
+        match (words.next(), words.next()) {
+            (Some("want"), Some(object_id)) => {
+                let object_id = client_object_id(output, object_id)?;
+                if !database.exists(object_id) {
+                    return refuse(output, format!("upload-pack: not our ref {}", object_id));
+                }
+                wants.push(object_id.to_string());
+                client_capabilities.extend(words.map(String::from));
+            }
+            (Some("shallow"), Some(object_id)) => {
+                shallow.insert(client_object_id(output, object_id)?.to_string());
+            }
+            (Some("deepen"), Some(value)) => depth = Some(parse_depth(value)?),
+            (Some("filter"), Some(spec)) => filter = Some(spec.parse()?),
+            _ => bail!("Unexpected line '{}'", line),
+        }
+    }
+    if wants.is_empty() {
+        return Ok(());
+    }
+    let has = |name: &str| {
+        client_capabilities
+            .iter()
+            .any(|capability| capability == name)
+    };
+
+    // The shallow commits are sent before the negotiation, so without knowing the haves
+    let deepen = depth.map(|depth| {
+        if has("deepen-relative") {
+            Deepen::Relative(depth)
+        } else {
+            Deepen::Depth(depth)
+        }
+    });
+    if deepen.is_some() {
+        let pack = rev_list::shallow_objects(database, &[], &wants, &shallow, deepen, filter)?;
+        write_shallow_info(output, &pack)?;
+        pkt_line::write_flush(output)?;
+    }
+
+    // Without multi_ack only the first common object is acknowledged, the client then stops
+    // sending haves. A stateless request ends at the first flush without a done.
+    let mut common = vec![];
+    loop {
+        let line = match pkt_line::read_packet(input)? {
+            None => return Ok(()),
+            Some(Packet::Flush) => {
+                if common.is_empty() {
+                    pkt_line::write_line(output, "NAK")?;
+                    output.flush()?;
+                }
+                continue;
+            }
+            Some(packet) => packet.text().context("Invalid have line")?.to_string(),
+        };
+        if line == "done" {
+            if common.is_empty() {
+                pkt_line::write_line(output, "NAK")?;
+            }
+            break;
+        }
+        match line.strip_prefix("have ") {
+            Some(object_id) if database.exists(client_object_id(output, object_id)?) => {
+                if common.is_empty() {
+                    pkt_line::write_line(output, &format!("ACK {}", object_id))?;
+                    output.flush()?;
+                }
+                common.push(object_id.to_string());
+            }
+            Some(_) => (),
+            None => bail!("Unexpected line '{}'", line),
+        }
+    }
+
+    let pack = rev_list::shallow_objects(database, &common, &wants, &shallow, deepen, filter)?;
+    send_pack(
+        database,
+        &remote_refs,
+        pack.objects,
+        has("include-tag"),
+        has("side-band-64k"),
+        output,
+    )
+}
+
+/// Tells the client why its request is refused with an `ERR` packet, then fails with the
+/// same message
+fn refuse<T>(output: &mut dyn Write, message: String) -> Result<T> {
+    pkt_line::write_line(output, &format!("ERR {}", message))?;
+    output.flush()?;
+    bail!(message)
+}
+
+/// Checks an object id sent by the client before the database uses it as a path
+fn client_object_id<'i>(output: &mut dyn Write, value: &'i str) -> Result<&'i str> {
+    if !revision::is_object_id(value) {
+        return refuse(
+            output,
+            format!("upload-pack: invalid object id '{}'", value),
+        );
+    }
+    Ok(value)
+}
+
+/// The depth of a `deepen` request, git asks for `INFINITE_DEPTH` to get the whole history
+fn parse_depth(value: &str) -> Result<usize> {
+    match value.parse() {
+        Ok(depth) if depth > 0 => Ok(depth),
+        _ => bail!("invalid deepen: {}", value),
+    }
+}
+
+/// Tells the client which commits become shallow and which aren't anymore
+fn write_shallow_info(output: &mut dyn Write, pack: &ShallowPack) -> Result<()> {
+    for commit_id in &pack.shallow {
+        pkt_line::write_line(output, &format!("shallow {}", commit_id))?;
+    }
+    for commit_id in &pack.unshallow {
+        pkt_line::write_line(output, &format!("unshallow {}", commit_id))?;
+    }
+    Ok(())
+}
+
+/// Lists the refs with the capabilities after the first one, a repository without refs
+/// advertises the capabilities alone. The shallow commits of a shallow repository follow.
+fn advertise_refs(
+    output: &mut dyn Write,
+    refs: &[(String, ObjectId)],
+    shallow: &[ObjectId],
+    capabilities: &str,
+) -> Result<()> {
+    if refs.is_empty() {
+        let line = format!("{} capabilities^{{}}\0{}\n", NULL_ID, capabilities);
+        pkt_line::write_data(output, line.as_bytes())?;
+    }
+    for (i, (name, object_id)) in refs.iter().enumerate() {
+        let line = if i == 0 {
+            format!("{} {}\0{}\n", object_id, name, capabilities)
+        } else {
+            format!("{} {}\n", object_id, name)
+        };
+        pkt_line::write_data(output, line.as_bytes())?;
+    }
+    for commit_id in shallow {
+        pkt_line::write_line(output, &format!("shallow {}", commit_id))?;
+    }
+    pkt_line::write_flush(output)
+}
+
+/// Sends the objects in side-band packets when the client supports them. With
+/// `include_tag` the annotated tags of the sent commits come along.
+fn send_pack(
+    database: &Database,
+    remote_refs: &RemoteRefs,
+    mut objects: Vec<ObjectId>,
+    include_tag: bool,
+    sideband: bool,
+    output: &mut dyn Write,
+) -> Result<()> {
+    if include_tag {
+        let sent: BTreeSet<ObjectId> = objects.iter().cloned().collect();
+        for (name, object_id) in &remote_refs.refs {
+            let is_tag = name.starts_with("refs/tags/") && !name.ends_with("^{}");
+            if is_tag
+                && !sent.contains(object_id)
+                && remote_refs
+                    .peeled(name)
+                    .is_some_and(|peeled| sent.contains(peeled))
+            {
+                objects.push(object_id.clone());
+            }
+        }
+    }
+
+    let mut pack = vec![];
+    pack::write_pack(database, &objects, &mut pack)?;
+    if sideband {
+        pkt_line::write_sideband(output, 1, &pack)?;
+        pkt_line::write_flush(output)
+    } else {
+        output.write_all(&pack)?;
+        output.flush()?;
+        Ok(())
+    }
+}
+
+/// Protocol v2 starts with the capabilities of the server, then the client sends commands
+/// until it closes the connection
+fn serve_v2(
+    database: &Database,
+    remote_refs: &RemoteRefs,
+    options: ServeOptions,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<()> {
+    if !options.stateless_rpc {
+        pkt_line::write_line(output, "version 2")?;
+        pkt_line::write_line(output, &agent())?;
+        pkt_line::write_line(output, "ls-refs=unborn")?;
+        pkt_line::write_line(output, "fetch=shallow filter")?;
+        pkt_line::write_line(output, "object-format=sha1")?;
+        pkt_line::write_flush(output)?;
+    }
+    if options.advertise_refs {
+        return Ok(());
+    }
+
+    loop {
+        let (command, arguments) = match read_command(input)? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        match command.as_str() {
+            "ls-refs" => ls_refs(remote_refs, &arguments, output)?,
+            "fetch" => fetch(database, remote_refs, &arguments, output)?,
+            _ => bail!("unknown command '{}'", command),
+        }
+    }
+}
+
+/// Reads a v2 request: the command and its capabilities, then its arguments after a
+/// delimiter. `None` when the client is done.
+fn read_command(input: &mut dyn Read) -> Result<Option<(String, Vec<String>)>> {
+    let mut command = None;
+    let mut arguments = vec![];
+    let mut in_arguments = false;
+    loop {
+        match pkt_line::read_packet(input)? {
+            None | Some(Packet::Flush) => break,
+            Some(Packet::Delim) => in_arguments = true,
+            Some(packet) => {
+                let line = packet.text().context("Invalid request line")?;
+                if in_arguments {
+                    arguments.push(line.to_string());
+                } else if let Some(name) = line.strip_prefix("command=") {
+                    command = Some(name.to_string());
+                }
+            }
+        }
+    }
+    Ok(command.map(|command| (command, arguments)))
+}
+
+fn ls_refs(remote_refs: &RemoteRefs, arguments: &[String], output: &mut dyn Write) -> Result<()> {
+    let has = |name: &str| arguments.iter().any(|argument| argument == name);
+    let prefixes: Vec<&str> = arguments
+        .iter()
+        .filter_map(|argument| argument.strip_prefix("ref-prefix "))
+        .collect();
+    let wanted = |name: &str| prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p));
+
+    if let Some(target) = &remote_refs.head_symref {
+        if remote_refs.get(HEAD).is_none() && has("unborn") && wanted(HEAD) {
+            pkt_line::write_line(output, &format!("unborn {} symref-target:{}", HEAD, target))?;
+        }
+    }
+    for (name, object_id) in &remote_refs.refs {
+        if name.ends_with("^{}") || !wanted(name) {
+            continue;
+        }
+        let mut line = format!("{} {}", object_id, name);
+        if let Some(target) = remote_refs.head_symref.as_ref().filter(|_| name == HEAD) {
+            if has("symrefs") {
+                line.push_str(&format!(" symref-target:{}", target));
+            }
+        }
+        if let Some(peeled) = remote_refs.get(&format!("{}^{{}}", name)) {
+            if has("peel") {
+                line.push_str(&format!(" peeled:{}", peeled));
+            }
+        }
+        pkt_line::write_line(output, &line)?;
+    }
+    pkt_line::write_flush(output)
+}
+
+/// Answers a v2 fetch: the acknowledgments of the haves until the client says it's done or
+/// a common commit is found, then the shallow commits when the history is cut and the pack
+fn fetch(
+    database: &Database,
+    remote_refs: &RemoteRefs,
+    arguments: &[String],
+    output: &mut dyn Write,
+) -> Result<()> {
+    let mut wants = vec![];
+    let mut common = vec![];
+    let mut done = false;
+    let mut include_tag = false;
+    let mut shallow = HashSet::new();
+    let mut depth = None;
+    let mut relative = false;
+    let mut filter = None;
+    for argument in arguments {
+        let (name, value) = argument.split_once(' ').unwrap_or((argument, ""));
+        match name {
+            "want" => {
+                let object_id = client_object_id(output, value)?;
+                if !database.exists(object_id) {
+                    return refuse(output, format!("upload-pack: not our ref {}", object_id));
+                }
+                wants.push(object_id.to_string());
+            }
+            "have" if database.exists(client_object_id(output, value)?) => {
+                common.push(value.to_string())
+            }
+            "done" => done = true,
+            "include-tag" => include_tag = true,
+            "shallow" => {
+                shallow.insert(client_object_id(output, value)?.to_string());
+            }
+            "deepen" => depth = Some(parse_depth(value)?),
+            "deepen-relative" => relative = true,
+            "filter" => filter = Some(value.parse()?),
+            _ => (),
+        }
+    }
+    let deepen = depth.map(|depth| {
+        if relative {
+            Deepen::Relative(depth)
+        } else {
+            Deepen::Depth(depth)
+        }
+    });
+
+    if !done {
+        pkt_line::write_line(output, "acknowledgments")?;
+        if common.is_empty() {
+            pkt_line::write_line(output, "NAK")?;
+            return pkt_line::write_flush(output);
+        }
+        for object_id in &common {
+            pkt_line::write_line(output, &format!("ACK {}", object_id))?;
+        }
+        pkt_line::write_line(output, "ready")?;
+        pkt_line::write_delim(output)?;
+    }
+    let pack = rev_list::shallow_objects(database, &common, &wants, &shallow, deepen, filter)?;
+    if deepen.is_some() || !shallow.is_empty() || !remote_refs.shallow.is_empty() {
+        pkt_line::write_line(output, "shallow-info")?;
+        write_shallow_info(output, &pack)?;
+        pkt_line::write_delim(output)?;
+    }
+    pkt_line::write_line(output, "packfile")?;
+    send_pack(
+        database,
+        remote_refs,
+        pack.objects,
+        include_tag,
+        true,
+        output,
+    )
+}
+
+/// Receives the objects and the ref updates of `git push` for the repository at `path`, like
+/// `git receive-pack`
+pub fn receive_pack(
+    path: &Path,
+    options: ServeOptions,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<()> {
+    let transport = LocalTransport::open(path)?;
+    let refs = transport.refs().list_refs("refs/")?;
+    let capabilities = format!("report-status delete-refs ofs-delta {}", agent());
+    if !options.stateless_rpc {
+        advertise_refs(output, &refs, &[], &capabilities)?;
+    }
+    if options.advertise_refs {
+        return Ok(());
+    }
+
+    let mut commands = vec![];
+    let mut client_capabilities = vec![];
+    loop {
+        let line = match pkt_line::read_packet(input)? {
+            None => return Ok(()),
+            Some(Packet::Flush) => break,
+            Some(packet) => packet.text().context("Invalid command line")?.to_string(),
+        };
+        let (line, capabilities) = line.split_once('\0').unwrap_or((&line, ""));
+        client_capabilities.extend(capabilities.split(' ').map(String::from));
+        let parse_id = |id: &str| Some(id.to_string()).filter(|id| id != NULL_ID);
+        match line.split(' ').collect::<Vec<_>>()[..] {
+            [old_id, new_id, name] => commands.push(PushCommand {
+                name: name.to_string(),
+                old_id: parse_id(old_id),
+                new_id: parse_id(new_id),
+            }),
+            _ => bail!("Invalid push command '{}'", line),
+        }
+    }
+    if commands.is_empty() {
+        return Ok(());
+    }
+
+    // Only deletions come without a pack
+    let unpacked = if commands.iter().any(|command| command.new_id.is_some()) {
+        pack::read_pack(transport.database(), input).map(|_| ())
+    } else {
+        Ok(())
+    };
+    let results = match &unpacked {
+        Ok(()) => transport.update_refs(&commands, &receiver_identity())?,
+        Err(_) => vec![Some("unpacker error".to_string()); commands.len()],
+    };
+
+    if client_capabilities
+        .iter()
+        .any(|name| name == "report-status")
+    {
+        match &unpacked {
+            Ok(()) => pkt_line::write_line(output, "unpack ok")?,
+            Err(err) => pkt_line::write_line(output, &format!("unpack {}", err))?,
+        }
+        for (command, result) in commands.iter().zip(results) {
+            match result {
+                None => pkt_line::write_line(output, &format!("ok {}", command.name))?,
+                Some(reason) => {
+                    pkt_line::write_line(output, &format!("ng {} {}", command.name, reason))?
+                }
+            }
+        }
+        pkt_line::write_flush(output)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::remote::client;
+    use crate::{database::commit::Commit, test_utils, Refs};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn serve_fetch() -> Result<()> {
+        let dir = test_utils::temp_path("server");
+        let (commit_id, blob_id) = test_utils::served_repo(&dir)?;
+
+        let mut input = vec![];
+        pkt_line::write_line(&mut input, &format!("want {} include-tag", commit_id))?;
+        pkt_line::write_flush(&mut input)?;
+        pkt_line::write_line(&mut input, "done")?;
+        let mut output = vec![];
+        upload_pack(
+            &dir,
+            0,
+            ServeOptions::default(),
+            &mut &input[..],
+            &mut output,
+        )?;
+
+        let mut output = &output[..];
+        let first = pkt_line::read_packet(&mut output)?.context("Missing advertisement")?;
+        let first = first.text().context("Invalid advertisement")?.to_string();
+        assert!(first.starts_with(&format!("{} HEAD\0", commit_id)));
+        assert!(first.contains("symref=HEAD:refs/heads/main"));
+        let second = pkt_line::read_packet(&mut output)?.context("Missing ref")?;
+        assert_eq!(
+            second.text(),
+            Some(format!("{} refs/heads/main", commit_id).as_str())
+        );
+        assert_eq!(pkt_line::read_packet(&mut output)?, Some(Packet::Flush));
+        let nak = pkt_line::read_packet(&mut output)?.context("Missing NAK")?;
+        assert_eq!(nak.text(), Some("NAK"));
+        let clone = Database::new(dir.join("clone"));
+        assert_eq!(pack::read_pack(&clone, &mut output)?.len(), 3);
+        assert!(clone.exists(&blob_id));
+
+        let mut input = vec![];
+        pkt_line::write_line(&mut input, "command=ls-refs")?;
+        pkt_line::write_delim(&mut input)?;
+        pkt_line::write_line(&mut input, "symrefs")?;
+        pkt_line::write_line(&mut input, "ref-prefix HEAD")?;
+        pkt_line::write_flush(&mut input)?;
+        let mut output = vec![];
+        upload_pack(
+            &dir,
+            2,
+            ServeOptions::default(),
+            &mut &input[..],
+            &mut output,
+        )?;
+        let mut output = &output[..];
+        while pkt_line::read_packet(&mut output)? != Some(Packet::Flush) {}
+        let head = pkt_line::read_packet(&mut output)?.context("Missing HEAD")?;
+        assert_eq!(
+            head.text(),
+            Some(format!("{} HEAD symref-target:refs/heads/main", commit_id).as_str())
+        );
+        assert_eq!(pkt_line::read_packet(&mut output)?, Some(Packet::Flush));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_invalid_object_ids() -> Result<()> {
+        let dir = test_utils::temp_path("server");
+        test_utils::served_repo(&dir)?;
+        let options = ServeOptions {
+            advertise_refs: false,
+            stateless_rpc: true,
+        };
+        let refused = |version: u8, lines: &[String]| -> Result<bool> {
+            let mut input = vec![];
+            for line in lines {
+                match line.as_str() {
+                    "" => pkt_line::write_delim(&mut input)?,
+                    line => pkt_line::write_line(&mut input, line)?,
+                }
+            }
+            pkt_line::write_flush(&mut input)?;
+            let mut output = vec![];
+            let result = upload_pack(&dir, version, options, &mut &input[..], &mut output);
+            let error = pkt_line::read_packet(&mut &output[..])?.context("Missing error")?;
+            Ok(result.is_err() && error.text().is_some_and(|line| line.starts_with("ERR ")))
+        };
+
+        for id in ["x", "ab/../../../../etc/passwd"] {
+            assert!(refused(0, &[format!("want {}", id)])?);
+            assert!(refused(
+                2,
+                &["command=fetch".into(), "".into(), format!("have {}", id)]
+            )?);
+        }
+        assert!(refused(0, &[format!("want {}", "0".repeat(40))])?);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_invalid_ref_names() -> Result<()> {
+        let dir = test_utils::temp_path("receive");
+        let remote_path = dir.join("remote.git");
+        std::fs::create_dir_all(remote_path.join("objects"))?;
+        std::fs::create_dir_all(remote_path.join("refs"))?;
+        let database = Database::new(dir.join("objects"));
+        let author = test_utils::test_author();
+        let tree_id = database.store_tree(&BTreeMap::new())?;
+        let commit_id = database.store(&Commit::new(vec![], tree_id, author, "one".into()))?;
+
+        let escaping = "refs/../../escaping";
+        let commands = [
+            PushCommand {
+                name: escaping.to_string(),
+                old_id: None,
+                new_id: Some(commit_id.clone()),
+            },
+            PushCommand {
+                name: "refs/heads/main".to_string(),
+                old_id: None,
+                new_id: Some(commit_id.clone()),
+            },
+        ];
+        let input = client::push_request(&database, &RemoteRefs::default(), &commands)?;
+        let options = ServeOptions {
+            advertise_refs: false,
+            stateless_rpc: true,
+        };
+        let mut output = vec![];
+        receive_pack(&remote_path, options, &mut &input[..], &mut output)?;
+
+        let results = client::read_report_status(&mut &output[..], &commands)?;
+        assert_eq!(results, vec![Some("invalid ref name".to_string()), None]);
+        assert!(!remote_path.join(escaping).exists());
+        assert_eq!(
+            Refs::new(remote_path.clone()).read_ref("refs/heads/main"),
+            Some(commit_id)
+        );
+
+        // A ref can't point to an object the pack didn't bring
+        let missing = PushCommand {
+            name: "refs/heads/missing".to_string(),
+            old_id: None,
+            new_id: Some("1".repeat(40)),
+        };
+        let mut input = vec![];
+        let line = format!(
+            "{} {} {}\0report-status",
+            NULL_ID,
+            "1".repeat(40),
+            missing.name
+        );
+        pkt_line::write_line(&mut input, &line)?;
+        pkt_line::write_flush(&mut input)?;
+        pack::write_pack(&database, &[], &mut input)?;
+        let mut output = vec![];
+        receive_pack(&remote_path, options, &mut &input[..], &mut output)?;
+        let results = client::read_report_status(&mut &output[..], &[missing])?;
+        assert_eq!(results, vec![Some("missing necessary objects".to_string())]);
+        assert_eq!(Refs::new(remote_path).read_ref("refs/heads/missing"), None);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
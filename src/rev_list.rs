@@ -0,0 +1,535 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    database::{commit::Commit, tag::Tag, tree, Database, MODE_DIRECTORY},
+    ObjectId,
+};
+
+/// Mode of the submodule entries of a tree, their commits are in another repository
+const MODE_GITLINK: u32 = 0o160000;
+
+/// Depth git asks for to fetch the whole history of a shallow repository
+pub const INFINITE_DEPTH: usize = 0x7fff_ffff;
+
+/// How far back the history of a shallow fetch goes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Deepen {
+    /// At most this many commits from the wanted ones
+    Depth(usize),
+    /// This many more commits behind the current shallow commits
+    Relative(usize),
+}
+
+/// The objects to send to a shallow repository and how its shallow commits change
+#[derive(Debug, Default)]
+pub struct ShallowPack {
+    pub objects: Vec<ObjectId>,
+    /// Commits whose parents aren't sent
+    pub shallow: Vec<ObjectId>,
+    /// Shallow commits of the other side whose parents are sent
+    pub unshallow: Vec<ObjectId>,
+}
+
+/// Objects a partial clone leaves out, the other side fetches them when it needs them.
+/// The objects asked for by name are always sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    /// `blob:none`, no blobs at all
+    BlobNone,
+    /// `blob:limit=<n>`, the blobs of at least this many bytes are left out
+    BlobLimit(u64),
+    /// `tree:<depth>`, the trees and blobs at least this deep from the root tree are left out
+    TreeDepth(usize),
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || format!("invalid filter-spec '{}'", s);
+        if s == "blob:none" {
+            return Ok(Filter::BlobNone);
+        }
+        if let Some(limit) = s.strip_prefix("blob:limit=") {
+            let (number, unit) = match limit.char_indices().last() {
+                Some((i, 'k')) | Some((i, 'K')) => (&limit[..i], 1 << 10),
+                Some((i, 'm')) | Some((i, 'M')) => (&limit[..i], 1 << 20),
+                Some((i, 'g')) | Some((i, 'G')) => (&limit[..i], 1 << 30),
+                _ => (limit, 1),
+            };
+            let number: u64 = number.parse().with_context(invalid)?;
+            return Ok(Filter::BlobLimit(number * unit));
+        }
+        if let Some(depth) = s.strip_prefix("tree:") {
+            return Ok(Filter::TreeDepth(depth.parse().with_context(invalid)?));
+        }
+        bail!(invalid())
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Filter::BlobNone => write!(f, "blob:none"),
+            Filter::BlobLimit(limit) => write!(f, "blob:limit={}", limit),
+            Filter::TreeDepth(depth) => write!(f, "tree:{}", depth),
+        }
+    }
+}
+
+impl Filter {
+    /// Whether a tree, or a blob, `depth` trees below the root tree of a commit is sent
+    fn includes(
+        &self,
+        database: &Database,
+        object_id: &str,
+        is_tree: bool,
+        depth: usize,
+    ) -> Result<bool> {
+        Ok(match self {
+            Filter::BlobNone => is_tree,
+            Filter::BlobLimit(limit) => {
+                is_tree || (database.load(object_id)?.1.len() as u64) < *limit
+            }
+            Filter::TreeDepth(max_depth) => depth < *max_depth,
+        })
+    }
+}
+
+fn load_commit(database: &Database, commit_id: &str) -> Result<Commit> {
+    let (_, data) = database.load(commit_id)?;
+    Commit::parse(&data)
+}
+
+/// The parents of a commit, a shallow commit is a root since its parents are missing
+pub fn parents(database: &Database, commit_id: &str) -> Result<Vec<ObjectId>> {
+    if database.is_shallow(commit_id) {
+        return Ok(vec![]);
+    }
+    Ok(load_commit(database, commit_id)?.parents().to_vec())
+}
+
+/// Returns every commit reachable from `commit_id`, including itself
+pub fn ancestors(database: &Database, commit_id: &str) -> Result<HashSet<ObjectId>> {
+    reachable(database, &[commit_id.to_string()], &HashSet::new())
+}
+
+/// Every commit reachable from `tips`, the walk stops at the `roots`
+fn reachable(
+    database: &Database,
+    tips: &[ObjectId],
+    roots: &HashSet<ObjectId>,
+) -> Result<HashSet<ObjectId>> {
+    let mut seen = HashSet::new();
+    let mut queue = tips.to_vec();
+    while let Some(commit_id) = queue.pop() {
+        if !seen.insert(commit_id.clone()) || roots.contains(&commit_id) {
+            continue;
+        }
+        queue.extend(parents(database, &commit_id)?);
+    }
+    Ok(seen)
+}
+
+pub fn is_ancestor(database: &Database, ancestor: &str, commit_id: &str) -> Result<bool> {
+    Ok(ancestors(database, commit_id)?.contains(ancestor))
+}
+
+/// Returns the commits reachable from `head` but not from any of `excluded`, parents first
+pub fn commits_between(
+    database: &Database,
+    excluded: &[ObjectId],
+    head: &str,
+) -> Result<Vec<ObjectId>> {
+    let mut hidden = HashSet::new();
+    for commit_id in excluded {
+        hidden.extend(ancestors(database, commit_id)?);
+    }
+
+    // Depth first walk emitting each commit after its parents
+    let mut commits = vec![];
+    let mut visited = BTreeSet::new();
+    let mut stack = vec![(head.to_string(), false)];
+    while let Some((commit_id, parents_done)) = stack.pop() {
+        if parents_done {
+            commits.push(commit_id);
+            continue;
+        }
+        if hidden.contains(&commit_id) || !visited.insert(commit_id.clone()) {
+            continue;
+        }
+        stack.push((commit_id.clone(), true));
+        for parent in parents(database, &commit_id)?.into_iter().rev() {
+            stack.push((parent, false));
+        }
+    }
+    Ok(commits)
+}
+
+/// Finds a best common ancestor of two commits, one that isn't an ancestor of another one
+pub fn merge_base(database: &Database, a: &str, b: &str) -> Result<Option<ObjectId>> {
+    let a_ancestors = ancestors(database, a)?;
+    let common: Vec<ObjectId> = commits_between(database, &[], b)?
+        .into_iter()
+        .filter(|commit_id| a_ancestors.contains(commit_id))
+        .collect();
+    // Parents come first, so the last common commit can't be the ancestor of another one
+    Ok(common.last().cloned())
+}
+
+/// Lists the objects reachable from `wants` but not from `haves`, like `rev-list --objects`.
+/// Only the trees of the `haves` themselves are excluded, the rest of their history isn't
+/// walked, so a few objects the other side already has can be listed. It fails when the
+/// history stops at a shallow commit since the other side would miss its parents.
+pub fn objects_between(
+    database: &Database,
+    haves: &[ObjectId],
+    wants: &[ObjectId],
+) -> Result<Vec<ObjectId>> {
+    let pack = shallow_objects(database, haves, wants, &HashSet::new(), None, None)?;
+    if let Some(commit_id) = pack.shallow.first() {
+        bail!(
+            "shallow update not allowed, the parents of {} are missing",
+            commit_id
+        );
+    }
+    Ok(pack.objects)
+}
+
+/// Lists the objects like `objects_between` for another repository whose history stops at
+/// its `shallow` commits. With `deepen` the history is cut, the commits whose parents aren't
+/// sent become shallow and the shallow commits of the other side whose parents are sent
+/// aren't anymore. The shallow commits of the database are always where the history stops.
+/// The `filter` of a partial clone leaves out the trees and blobs it doesn't want.
+pub fn shallow_objects(
+    database: &Database,
+    haves: &[ObjectId],
+    wants: &[ObjectId],
+    shallow: &HashSet<ObjectId>,
+    deepen: Option<Deepen>,
+    filter: Option<Filter>,
+) -> Result<ShallowPack> {
+    let mut excluded = vec![];
+    let mut known = HashSet::new();
+    for have in haves.iter().filter(|have| database.exists(have)) {
+        let commit_id = database.peel(have)?;
+        if database.load(&commit_id)?.0 == "commit" {
+            let tree_id = database.load_tree_id(&commit_id)?;
+            tree_objects(database, &tree_id, None, 0, &mut known)?;
+            excluded.push(commit_id);
+        }
+    }
+    // The other side doesn't have the history behind its shallow commits
+    let hidden = reachable(database, &excluded, shallow)?;
+
+    let mut objects = vec![];
+    let mut seen = known;
+    let mut tips = vec![];
+    for want in wants {
+        let mut object_id = want.clone();
+        loop {
+            let (kind, data) = database.load(&object_id)?;
+            match kind.as_str() {
+                "commit" => {
+                    tips.push(object_id);
+                    break;
+                }
+                "tag" => {
+                    if seen.insert(object_id.clone()) {
+                        objects.push(object_id);
+                    }
+                    object_id = Tag::parse(&data)?.object;
+                }
+                _ => {
+                    let mut found = HashSet::new();
+                    tree_objects(database, &object_id, filter, 0, &mut found)?;
+                    objects.extend(found.into_iter().filter(|id| seen.insert(id.clone())));
+                    break;
+                }
+            }
+        }
+    }
+
+    // Breadth first walk where each commit has a number of commits left to send, `None`
+    // when it's unlimited. A commit is visited again when it's reached with more left.
+    let start = match deepen {
+        Some(Deepen::Depth(depth)) => Some(depth),
+        _ => None,
+    };
+    let mut left: HashMap<ObjectId, Option<usize>> = HashMap::new();
+    let mut queue: VecDeque<(ObjectId, Option<usize>)> =
+        tips.into_iter().map(|tip| (tip, start)).collect();
+    let mut boundary = BTreeSet::new();
+    let mut expanded = BTreeSet::new();
+    while let Some((commit_id, mut depth)) = queue.pop_front() {
+        let is_deeper = match (left.get(&commit_id), depth) {
+            (None, _) => true,
+            (Some(Some(seen)), Some(depth)) => depth > *seen,
+            (Some(Some(_)), None) => true,
+            (Some(None), _) => false,
+        };
+        if !is_deeper {
+            continue;
+        }
+        left.insert(commit_id.clone(), depth);
+
+        // Deepening goes through the history the other side has to reach its shallow commits
+        let is_hidden = hidden.contains(&commit_id);
+        if is_hidden && deepen.is_none() {
+            continue;
+        }
+        let is_shallow = shallow.contains(&commit_id);
+        let commit = load_commit(database, &commit_id)?;
+        if !is_hidden && seen.insert(commit_id.clone()) {
+            objects.push(commit_id.clone());
+            let tree_id = commit.tree_id();
+            if filter.map_or(Ok(true), |filter| {
+                filter.includes(database, tree_id, true, 0)
+            })? {
+                let mut found = HashSet::new();
+                tree_objects(database, tree_id, filter, 0, &mut found)?;
+                objects.extend(found.into_iter().filter(|id| seen.insert(id.clone())));
+            }
+        }
+
+        if let (Some(Deepen::Relative(relative)), true) = (deepen, is_shallow) {
+            depth = Some(relative + 1);
+        }
+        if commit.parents().is_empty() {
+            continue;
+        }
+        let is_boundary =
+            database.is_shallow(&commit_id) || depth == Some(1) || (is_shallow && deepen.is_none());
+        if is_boundary {
+            boundary.insert(commit_id);
+            continue;
+        }
+        expanded.insert(commit_id.clone());
+        for parent in commit.parents() {
+            queue.push_back((parent.clone(), depth.map(|depth| depth - 1)));
+        }
+    }
+
+    Ok(ShallowPack {
+        objects,
+        shallow: boundary
+            .into_iter()
+            .filter(|commit_id| !expanded.contains(commit_id) && !shallow.contains(commit_id))
+            .collect(),
+        unshallow: expanded
+            .into_iter()
+            .filter(|commit_id| shallow.contains(commit_id))
+            .collect(),
+    })
+}
+
+/// Adds a tree, or a blob, and what it contains to `objects`, except what `filter` leaves
+/// out. The tree is `depth` trees below the root tree of a commit.
+fn tree_objects(
+    database: &Database,
+    object_id: &str,
+    filter: Option<Filter>,
+    depth: usize,
+    objects: &mut HashSet<ObjectId>,
+) -> Result<()> {
+    if !objects.insert(object_id.to_string()) {
+        return Ok(());
+    }
+    let (kind, data) = database.load(object_id)?;
+    if kind != "tree" {
+        return Ok(());
+    }
+    for (_, item) in tree::parse(&data)? {
+        if item.mode == MODE_GITLINK {
+            continue;
+        }
+        let is_tree = item.mode == MODE_DIRECTORY as u32;
+        let is_included = filter.map_or(Ok(true), |filter| {
+            filter.includes(database, &item.object_id, is_tree, depth + 1)
+        })?;
+        if !is_included {
+            continue;
+        }
+        if is_tree {
+            tree_objects(database, &item.object_id, filter, depth + 1, objects)?;
+        } else {
+            objects.insert(item.object_id);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        database::{blob::Blob, tree::Tree, tree::TreeItem},
+        test_utils,
+    };
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn walk_history() -> Result<()> {
+        let dir = test_utils::temp_path("rev_list");
+        let database = Database::new(dir.clone());
+        let tree_id = database.store(&Tree::new())?;
+        let author = test_utils::test_author();
+        let commit = |parents: Vec<&ObjectId>, message: &str| {
+            let parents = parents.into_iter().cloned().collect();
+            let commit = Commit::new(parents, tree_id.clone(), author.clone(), message.into());
+            database.store(&commit).unwrap()
+        };
+
+        //   a - b - c
+        //        \
+        //         d - e
+        let a = commit(vec![], "a");
+        let b = commit(vec![&a], "b");
+        let c = commit(vec![&b], "c");
+        let d = commit(vec![&b], "d");
+        let e = commit(vec![&d], "e");
+
+        assert_eq!(
+            commits_between(&database, std::slice::from_ref(&c), &e)?,
+            vec![d.clone(), e.clone()]
+        );
+        assert_eq!(
+            commits_between(&database, &[], &c)?,
+            vec![a.clone(), b.clone(), c.clone()]
+        );
+        assert_eq!(merge_base(&database, &c, &e)?, Some(b.clone()));
+        assert!(is_ancestor(&database, &a, &e)?);
+        assert!(!is_ancestor(&database, &c, &e)?);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn cut_history() -> Result<()> {
+        let dir = test_utils::temp_path("shallow");
+        let database = Database::new(dir.join("objects"));
+        let tree_id = database.store(&Tree::new())?;
+        let author = test_utils::test_author();
+        let commit = |parents: Vec<&ObjectId>, message: &str| {
+            let parents = parents.into_iter().cloned().collect();
+            let commit = Commit::new(parents, tree_id.clone(), author.clone(), message.into());
+            database.store(&commit).unwrap()
+        };
+
+        // a - b - c - d
+        let a = commit(vec![], "a");
+        let b = commit(vec![&a], "b");
+        let c = commit(vec![&b], "c");
+        let d = commit(vec![&c], "d");
+        let wants = std::slice::from_ref(&d);
+
+        let pack = shallow_objects(
+            &database,
+            &[],
+            wants,
+            &HashSet::new(),
+            Some(Deepen::Depth(2)),
+            None,
+        )?;
+        assert_eq!(pack.shallow, vec![c.clone()]);
+        assert!(pack.objects.contains(&c) && !pack.objects.contains(&b));
+
+        let shallow = std::iter::once(c.clone()).collect();
+        let pack = shallow_objects(
+            &database,
+            wants,
+            wants,
+            &shallow,
+            Some(Deepen::Relative(1)),
+            None,
+        )?;
+        assert_eq!(pack.shallow, vec![b.clone()]);
+        assert_eq!(pack.unshallow, vec![c.clone()]);
+        assert_eq!(pack.objects, vec![b.clone()]);
+
+        let shallow = std::iter::once(b.clone()).collect();
+        let deepen = Some(Deepen::Depth(INFINITE_DEPTH));
+        let pack = shallow_objects(&database, wants, wants, &shallow, deepen, None)?;
+        assert!(pack.shallow.is_empty());
+        assert_eq!(pack.unshallow, vec![b.clone()]);
+        assert_eq!(pack.objects, vec![a.clone()]);
+
+        database.update_shallow(std::slice::from_ref(&c), &[])?;
+        assert_eq!(
+            Database::new(dir.join("objects")).shallow(),
+            vec![c.clone()]
+        );
+        assert_eq!(
+            commits_between(&database, &[], &d)?,
+            vec![c.clone(), d.clone()]
+        );
+        assert!(objects_between(&database, &[], wants).is_err());
+        database.update_shallow(&[], std::slice::from_ref(&c))?;
+        assert!(!dir.join("shallow").exists());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn filter_objects() -> Result<()> {
+        let dir = test_utils::temp_path("filter");
+        let database = Database::new(dir.join("objects"));
+        let item = |object_id: &ObjectId, mode| TreeItem {
+            mode,
+            object_id: object_id.clone(),
+        };
+        let small = database.store(&Blob::new(b"small\n".to_vec()))?;
+        let large = database.store(&Blob::new(vec![b'x'; 2048]))?;
+        let mut items = BTreeMap::new();
+        items.insert("small.txt".to_string(), item(&small, 0o100644));
+        items.insert("dir/large.txt".to_string(), item(&large, 0o100644));
+        let tree_id = database.store_tree(&items)?;
+        let author = test_utils::test_author();
+        let commit = Commit::new(vec![], tree_id.clone(), author, "one".into());
+        let commit_id = database.store(&commit)?;
+        let wants = std::slice::from_ref(&commit_id);
+        let objects = |filter: &str| -> Result<Vec<ObjectId>> {
+            let filter = Some(filter.parse()?);
+            let pack = shallow_objects(&database, &[], wants, &HashSet::new(), None, filter)?;
+            Ok(pack.objects)
+        };
+
+        assert_eq!(objects("tree:0")?, vec![commit_id.clone()]);
+        assert_eq!(objects("tree:1")?.len(), 2);
+        let objects_without_blobs = objects("blob:none")?;
+        assert_eq!(objects_without_blobs.len(), 3);
+        assert!(!objects_without_blobs.contains(&small));
+        let objects_without_large = objects("blob:limit=1k")?;
+        assert!(objects_without_large.contains(&small) && !objects_without_large.contains(&large));
+        assert_eq!(objects("blob:limit=4k")?.len(), 5);
+
+        let wants = std::slice::from_ref(&large);
+        let pack = shallow_objects(
+            &database,
+            &[],
+            wants,
+            &HashSet::new(),
+            None,
+            Some(Filter::BlobNone),
+        )?;
+        assert_eq!(pack.objects, vec![large.clone()]);
+
+        assert_eq!(
+            "blob:limit=2m".parse::<Filter>()?,
+            Filter::BlobLimit(2 << 20)
+        );
+        assert_eq!(Filter::TreeDepth(0).to_string(), "tree:0");
+        assert!("blob:some".parse::<Filter>().is_err());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+use std::fmt::{self, Display, Formatter};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+
+use crate::{
+    database::{commit::Commit, tag::Tag, Database},
+    refs::{HEAD, NULL_ID},
+    utils::parse_date,
+    ObjectId, Refs,
+};
+
+/// Selects an entry of a reflog with `@{<n>}` or `@{<date>}`
+#[derive(Debug, PartialEq)]
+pub enum ReflogSelector {
+    Index(usize),
+    Date(DateTime<Utc>),
+}
+
+/// A parsed revision like `HEAD`, `master@{2}` or `@{yesterday}`
+#[derive(Debug, PartialEq)]
+pub enum Revision {
+    Ref(String),
+    /// `None` refers to the current branch
+    Reflog(Option<String>, ReflogSelector),
+    /// `<rev>^{}` follows tags, `<rev>^{<type>}` peels until an object of that type
+    Peel(Box<Revision>, Option<String>),
+    /// `<rev>^<n>` selects the nth parent of a commit, `^0` is the commit itself
+    Parent(Box<Revision>, usize),
+    /// `<rev>~<n>` follows the first parent n times
+    Ancestor(Box<Revision>, usize),
+}
+
+impl Revision {
+    pub fn parse(revision: &str) -> Result<Self> {
+        if let Some(rest) = revision.strip_suffix('}') {
+            if let Some((inner, kind)) = rest.rsplit_once("^{") {
+                let kind = if kind.is_empty() {
+                    None
+                } else {
+                    Some(kind.to_string())
+                };
+                return Ok(Revision::Peel(Box::new(Revision::parse(inner)?), kind));
+            }
+        }
+
+        if let Some(position) = revision.rfind(['^', '~']) {
+            let (inner, suffix) = revision.split_at(position);
+            let count = &suffix[1..];
+            if !inner.is_empty() && count.chars().all(|c| c.is_ascii_digit()) {
+                let n = if count.is_empty() { 1 } else { count.parse()? };
+                let inner = Box::new(Revision::parse(inner)?);
+                return Ok(if suffix.starts_with('^') {
+                    Revision::Parent(inner, n)
+                } else {
+                    Revision::Ancestor(inner, n)
+                });
+            }
+        }
+
+        if revision == "@" {
+            return Ok(Revision::Ref(HEAD.into()));
+        }
+
+        if let Some((name, selector)) = revision.split_once("@{") {
+            let selector = selector
+                .strip_suffix('}')
+                .with_context(|| format!("Invalid revision: {}", revision))?;
+            let selector = match selector.parse() {
+                Ok(n) => ReflogSelector::Index(n),
+                Err(_) => ReflogSelector::Date(
+                    parse_date(selector, Utc::now())
+                        .with_context(|| format!("Invalid date: {}", selector))?,
+                ),
+            };
+            let name = if name.is_empty() {
+                None
+            } else {
+                Some(name.to_string())
+            };
+            return Ok(Revision::Reflog(name, selector));
+        }
+
+        Ok(Revision::Ref(revision.into()))
+    }
+
+    pub fn resolve(&self, refs: &Refs, database: &Database) -> Result<ObjectId> {
+        match self {
+            Revision::Parent(revision, n) => {
+                let object_id = database.peel(&revision.resolve(refs, database)?)?;
+                if *n == 0 {
+                    return Ok(object_id);
+                }
+                self.ensure_not_shallow(database, &object_id)?;
+                load_commit(database, &object_id)?
+                    .parents()
+                    .get(n - 1)
+                    .cloned()
+                    .with_context(|| format!("Revision '{}' does not exist", self))
+            }
+            Revision::Ancestor(revision, n) => {
+                let mut object_id = database.peel(&revision.resolve(refs, database)?)?;
+                for _ in 0..*n {
+                    self.ensure_not_shallow(database, &object_id)?;
+                    object_id = load_commit(database, &object_id)?
+                        .parent()
+                        .cloned()
+                        .with_context(|| format!("Revision '{}' does not exist", self))?;
+                }
+                Ok(object_id)
+            }
+            Revision::Peel(revision, None) => database.peel(&revision.resolve(refs, database)?),
+            Revision::Peel(revision, Some(kind)) => {
+                let mut object_id = revision.resolve(refs, database)?;
+                loop {
+                    let (object_kind, data) = database.load(&object_id)?;
+                    if &object_kind == kind {
+                        return Ok(object_id);
+                    }
+                    object_id = match (object_kind.as_str(), kind.as_str()) {
+                        ("tag", _) => Tag::parse(&data)?.object,
+                        ("commit", "tree") => Commit::parse(&data)?.tree_id().clone(),
+                        _ => bail!("'{}' could not be peeled to a {}", self, kind),
+                    };
+                }
+            }
+            Revision::Ref(name) => {
+                if is_object_id(name) {
+                    return Ok(name.clone());
+                }
+                if let Some(object_id) =
+                    refs.expand_name(name).and_then(|name| refs.read_ref(&name))
+                {
+                    return Ok(object_id);
+                }
+
+                let mut matches = database.prefix_match(name)?;
+                match matches.len() {
+                    0 => bail!("Not a valid object name: '{}'", name),
+                    1 => Ok(matches.remove(0)),
+                    _ => bail!("Short object id {} is ambiguous", name),
+                }
+            }
+            Revision::Reflog(_, selector) => {
+                let name = self.reflog_name(refs)?;
+                let entries = refs.reflog(&name).entries()?;
+                if entries.is_empty() {
+                    bail!("Log for '{}' is empty", name);
+                }
+
+                match selector {
+                    ReflogSelector::Index(n) => {
+                        if *n < entries.len() {
+                            Ok(entries[entries.len() - 1 - n].new_id.clone())
+                        } else if *n == entries.len() && entries[0].old_id != NULL_ID {
+                            Ok(entries[0].old_id.clone())
+                        } else {
+                            bail!("Log for '{}' only has {} entries", name, entries.len())
+                        }
+                    }
+                    ReflogSelector::Date(date) => {
+                        match entries
+                            .iter()
+                            .rev()
+                            .find(|entry| entry.identity.time <= *date)
+                        {
+                            Some(entry) => Ok(entry.new_id.clone()),
+                            None => {
+                                log::warn!(
+                                    "Log for '{}' only goes back to {}",
+                                    name,
+                                    entries[0].identity.time
+                                );
+                                if entries[0].old_id != NULL_ID {
+                                    Ok(entries[0].old_id.clone())
+                                } else {
+                                    Ok(entries[0].new_id.clone())
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the full name of the ref whose reflog is used by the revision
+    pub fn reflog_name(&self, refs: &Refs) -> Result<String> {
+        let name = match self {
+            Revision::Ref(name) | Revision::Reflog(Some(name), _) => name,
+            Revision::Peel(revision, _)
+            | Revision::Parent(revision, _)
+            | Revision::Ancestor(revision, _) => return revision.reflog_name(refs),
+            // @{n} without a name uses the branch HEAD points to
+            Revision::Reflog(None, _) => {
+                return Ok(refs.read_symref(HEAD).unwrap_or_else(|| HEAD.into()))
+            }
+        };
+        refs.expand_name(name)
+            .with_context(|| format!("Not a valid ref: '{}'", name))
+    }
+
+    /// The parents of a shallow commit are missing, they can't be resolved
+    fn ensure_not_shallow(&self, database: &Database, commit_id: &str) -> Result<()> {
+        if database.is_shallow(commit_id) {
+            bail!(
+                "Revision '{}' does not exist, the history of this shallow repository stops at {}",
+                self,
+                commit_id
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Display for Revision {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Revision::Ref(name) => write!(f, "{}", name),
+            Revision::Reflog(name, selector) => {
+                write!(f, "{}@{{", name.as_deref().unwrap_or(""))?;
+                match selector {
+                    ReflogSelector::Index(n) => write!(f, "{}}}", n),
+                    ReflogSelector::Date(date) => write!(f, "{}}}", date),
+                }
+            }
+            Revision::Peel(revision, kind) => {
+                write!(f, "{}^{{{}}}", revision, kind.as_deref().unwrap_or(""))
+            }
+            Revision::Parent(revision, n) => write!(f, "{}^{}", revision, n),
+            Revision::Ancestor(revision, n) => write!(f, "{}~{}", revision, n),
+        }
+    }
+}
+
+fn load_commit(database: &Database, object_id: &str) -> Result<Commit> {
+    let (kind, data) = database.load(object_id)?;
+    if kind != "commit" {
+        bail!("Object {} is a {}, not a commit", object_id, kind);
+    }
+    Commit::parse(&data)
+}
+
+/// Checks if the string is a full 40 characters hexadecimal object id
+pub fn is_object_id(s: &str) -> bool {
+    s.len() == 40 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() -> Result<()> {
+        assert_eq!(Revision::parse("@")?, Revision::Ref("HEAD".into()));
+        assert_eq!(Revision::parse("master")?, Revision::Ref("master".into()));
+        assert_eq!(
+            Revision::parse("HEAD@{2}")?,
+            Revision::Reflog(Some("HEAD".into()), ReflogSelector::Index(2))
+        );
+        assert_eq!(
+            Revision::parse("@{0}")?,
+            Revision::Reflog(None, ReflogSelector::Index(0))
+        );
+        assert!(matches!(
+            Revision::parse("@{yesterday}")?,
+            Revision::Reflog(None, ReflogSelector::Date(_))
+        ));
+        assert_eq!(
+            Revision::parse("v1.0^{}")?,
+            Revision::Peel(Box::new(Revision::Ref("v1.0".into())), None)
+        );
+        assert_eq!(
+            Revision::parse("@{1}^{tree}")?,
+            Revision::Peel(
+                Box::new(Revision::Reflog(None, ReflogSelector::Index(1))),
+                Some("tree".into())
+            )
+        );
+        assert_eq!(
+            Revision::parse("HEAD~2^")?,
+            Revision::Parent(
+                Box::new(Revision::Ancestor(
+                    Box::new(Revision::Ref("HEAD".into())),
+                    2
+                )),
+                1
+            )
+        );
+        assert_eq!(
+            Revision::parse("master^2")?,
+            Revision::Parent(Box::new(Revision::Ref("master".into())), 2)
+        );
+        assert!(Revision::parse("HEAD@{2").is_err());
+        assert!(Revision::parse("HEAD@{someday}").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,963 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    database::{commit::Commit, tree::TreeItem, Database},
+    index::Index,
+    merge::resolve,
+    migration,
+    refs::{Refs, HEAD, ORIG_HEAD},
+    staging,
+    workspace::Workspace,
+    Author, ObjectId,
+};
+
+pub const CHERRY_PICK_HEAD: &str = "CHERRY_PICK_HEAD";
+pub const REVERT_HEAD: &str = "REVERT_HEAD";
+pub const REBASE_HEAD: &str = "REBASE_HEAD";
+pub const MERGE_MSG: &str = "MERGE_MSG";
+const COMMIT_EDITMSG: &str = "COMMIT_EDITMSG";
+const SEQUENCER_DIR: &str = "sequencer";
+const REBASE_DIR: &str = "rebase-merge";
+const EDITOR_ENV: &str = "GIT_EDITOR";
+const SEQUENCE_EDITOR_ENV: &str = "GIT_SEQUENCE_EDITOR";
+
+const TODO_HELP: &str = "
+# Commands:
+# p, pick <commit> = use commit
+# r, reword <commit> = use commit, but edit the commit message
+# e, edit <commit> = use commit, but stop for amending
+# s, squash <commit> = use commit, but meld into previous commit
+# f, fixup <commit> = like \"squash\", but discard this commit's log message
+# x, exec <command> = run command (the rest of the line) using shell
+# d, drop <commit> = remove commit
+#
+# These lines can be re-ordered; they are executed from top to bottom.
+# If you remove a line here THAT COMMIT WILL BE LOST.
+# However, if you remove everything, the rebase will be aborted.
+";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Pick,
+    Revert,
+    Reword,
+    Edit,
+    Squash,
+    Fixup,
+    Drop,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match self {
+            Action::Pick => "pick",
+            Action::Revert => "revert",
+            Action::Reword => "reword",
+            Action::Edit => "edit",
+            Action::Squash => "squash",
+            Action::Fixup => "fixup",
+            Action::Drop => "drop",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pick" | "p" => Ok(Action::Pick),
+            "revert" => Ok(Action::Revert),
+            "reword" | "r" => Ok(Action::Reword),
+            "edit" | "e" => Ok(Action::Edit),
+            "squash" | "s" => Ok(Action::Squash),
+            "fixup" | "f" => Ok(Action::Fixup),
+            "drop" | "d" => Ok(Action::Drop),
+            _ => bail!("Invalid sequencer command: {}", s),
+        }
+    }
+}
+
+/// A line of the todo list, like `pick 1a2b3c4 Subject of the commit` or `exec make test`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Commit { action: Action, commit_id: ObjectId },
+    Exec(String),
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (name, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        if name == "exec" || name == "x" {
+            if rest.trim().is_empty() {
+                bail!("Missing command in sequencer command: {}", s);
+            }
+            return Ok(Command::Exec(rest.trim().to_string()));
+        }
+
+        let action = name.parse()?;
+        let commit_id = rest
+            .split_whitespace()
+            .next()
+            .with_context(|| format!("Missing commit in sequencer command: {}", s))?
+            .to_string();
+        Ok(Command::Commit { action, commit_id })
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Append `(cherry picked from commit ...)` to the messages of picked commits
+    pub record_origin: bool,
+}
+
+/// What to do after a command was executed
+enum Flow {
+    Next,
+    /// Give control back to the user, the command is done
+    Stop,
+    /// The command is done but failed
+    Fail(String),
+}
+
+/// Replays a list of commits onto HEAD, one commit at a time. Its state is kept on disk so the
+/// user can resolve a conflict and `--continue`, `--skip` the commit or `--abort` everything.
+/// cherry-pick and revert keep it in `sequencer`, rebase in `rebase-merge` with a detached HEAD.
+pub struct Sequencer<'a> {
+    git_path: PathBuf,
+    path: PathBuf,
+    rebase: bool,
+    database: &'a Database,
+    workspace: &'a Workspace,
+    index: &'a mut Index,
+    refs: &'a Refs,
+    /// Where the progress and the conflicts are reported, nothing is shown by default
+    out: Box<dyn Write + 'a>,
+}
+
+impl<'a> Sequencer<'a> {
+    pub fn new(
+        git_path: &Path,
+        database: &'a Database,
+        workspace: &'a Workspace,
+        index: &'a mut Index,
+        refs: &'a Refs,
+    ) -> Self {
+        Self {
+            git_path: git_path.to_path_buf(),
+            path: git_path.join(SEQUENCER_DIR),
+            rebase: false,
+            database,
+            workspace,
+            index,
+            refs,
+            out: Box::new(io::sink()),
+        }
+    }
+
+    pub fn for_rebase(
+        git_path: &Path,
+        database: &'a Database,
+        workspace: &'a Workspace,
+        index: &'a mut Index,
+        refs: &'a Refs,
+    ) -> Self {
+        Self {
+            path: git_path.join(REBASE_DIR),
+            rebase: true,
+            ..Self::new(git_path, database, workspace, index, refs)
+        }
+    }
+
+    /// Reports the progress and the conflicts to `out`
+    pub fn with_output(mut self, out: Box<dyn Write + 'a>) -> Self {
+        self.out = out;
+        self
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.path.is_dir()
+    }
+
+    /// Records the commands and the current HEAD then starts applying the commands
+    pub fn start(&mut self, commands: &[Command], options: &Options) -> Result<()> {
+        if self.in_progress() {
+            bail!("a cherry-pick or revert is already in progress\nhint: try \"cherry-pick (--continue | --skip | --abort | --quit)\"");
+        }
+        let head_id = self
+            .refs
+            .read_head()
+            .context("Cannot replay commits without a HEAD")?;
+        let name = match commands.first() {
+            Some(Command::Commit {
+                action: Action::Revert,
+                ..
+            }) => "revert",
+            _ => "cherry-pick",
+        };
+        self.ensure_clean(&head_id, name)?;
+
+        fs::create_dir_all(&self.path)?;
+        fs::write(self.path.join("head"), format!("{}\n", head_id))?;
+        let mut opts = String::from("[options]\n");
+        if options.record_origin {
+            opts.push_str("\trecord-origin = true\n");
+        }
+        fs::write(self.path.join("opts"), opts)?;
+        self.write_todo(commands)?;
+
+        let result = self.resume();
+        // Nothing was done when the first command failed before it was shifted from the todo
+        // list, there is then nothing to continue or abort
+        if result.is_err()
+            && self.todo()?.len() == commands.len()
+            && self.refs.read_head().as_deref() == Some(head_id.as_str())
+        {
+            self.quit()?;
+        }
+        result
+    }
+
+    /// Detaches HEAD at `onto` then replays the commands on top of it. With `interactive` the
+    /// todo list goes through the sequence editor first.
+    pub fn start_rebase(
+        &mut self,
+        onto: &str,
+        onto_name: &str,
+        commands: &[Command],
+        interactive: bool,
+    ) -> Result<()> {
+        if self.in_progress() {
+            bail!("It seems that there is already a rebase-merge directory\nhint: try \"rebase (--continue | --skip | --abort | --quit)\"");
+        }
+        let head_id = self
+            .refs
+            .read_head()
+            .context("Cannot rebase without a HEAD")?;
+        self.ensure_clean(&head_id, "rebase")?;
+
+        let head_name = self
+            .refs
+            .read_symref(HEAD)
+            .unwrap_or_else(|| "detached HEAD".to_string());
+        fs::create_dir_all(&self.path)?;
+        fs::write(self.path.join("head-name"), format!("{}\n", head_name))?;
+        fs::write(self.path.join("onto"), format!("{}\n", onto))?;
+        fs::write(self.path.join("orig-head"), format!("{}\n", head_id))?;
+        self.write_todo(commands)?;
+
+        if interactive {
+            let todo_path = self.todo_path();
+            let mut todo = OpenOptions::new().append(true).open(&todo_path)?;
+            write!(
+                todo,
+                "\n# Rebase {}..{} onto {} ({} commands)\n#{}",
+                &onto[..7],
+                &head_id[..7],
+                &onto[..7],
+                commands.len(),
+                TODO_HELP
+            )?;
+            let editor = [SEQUENCE_EDITOR_ENV, EDITOR_ENV, "EDITOR"]
+                .iter()
+                .find_map(|name| std::env::var(name).ok())
+                .unwrap_or_else(|| "vi".to_string());
+            if let Err(err) = run_editor(&editor, &todo_path) {
+                self.quit()?;
+                return Err(err);
+            }
+            if self.todo()?.is_empty() {
+                self.quit()?;
+                writeln!(self.out, "Nothing to do")?;
+                return Ok(());
+            }
+        }
+
+        let identity = Author::from_env();
+        let message = format!("rebase (start): checkout {}", onto_name);
+        self.refs
+            .update_ref(ORIG_HEAD, head_id, &identity, &message)?;
+        self.reset_hard(onto)?;
+        self.refs
+            .update_ref(HEAD, onto.to_string(), &identity, &message)?;
+
+        self.resume()
+    }
+
+    /// Applies the remaining commands, stopping at the first one that conflicts
+    pub fn resume(&mut self) -> Result<()> {
+        let options = self.options()?;
+        loop {
+            let todo = self.todo()?;
+            let command = match todo.first() {
+                Some(command) => command,
+                None => break,
+            };
+            match self.run(command, &options)? {
+                Flow::Next => self.shift()?,
+                Flow::Stop => return self.shift(),
+                Flow::Fail(message) => {
+                    self.shift()?;
+                    bail!(message);
+                }
+            }
+        }
+        self.finish()
+    }
+
+    /// Commits the resolved conflict of the current command then applies the remaining ones
+    pub fn continue_sequence(&mut self) -> Result<()> {
+        if !self.in_progress() {
+            bail!("no {} in progress", self.name());
+        }
+        self.index.load_for_update()?;
+        if self.index.has_conflicts() {
+            bail!("Committing is not possible because you have unmerged files.");
+        }
+
+        if let Some(Command::Commit { action, commit_id }) = self.todo()?.first() {
+            if self.refs.read_ref(self.head_ref(*action)).is_some() {
+                let message = fs::read_to_string(self.git_path.join(MERGE_MSG))?;
+                self.commit(*action, commit_id, message)?;
+                self.clear_pick_head(*action)?;
+                self.shift()?;
+            }
+        }
+
+        // Changes staged after stopping at an `edit` command are added to the stopped commit
+        let amend_path = self.path.join("amend");
+        if amend_path.is_file() {
+            let head_id = self.refs.read_head().context("Failed to read HEAD")?;
+            if self.index.tree_items() != self.tree_items(&head_id)? {
+                let head = self.load_commit(&head_id)?;
+                let tree_id = self.database.store_tree(&self.index.tree_items())?;
+                let committer = Author::from_env();
+                let subject = head.message().lines().next().unwrap_or("").to_string();
+                let commit = Commit::new(
+                    head.parents().to_vec(),
+                    tree_id,
+                    head.author().clone(),
+                    head.message().to_string(),
+                )
+                .with_committer(committer.clone());
+                let commit_id = self.database.store(&commit)?;
+                self.refs.update_head(
+                    commit_id,
+                    &committer,
+                    &format!("{} (amend): {}", self.name(), subject),
+                )?;
+            }
+            fs::remove_file(amend_path)?;
+        }
+        self.index.write_updates()?;
+
+        self.resume()
+    }
+
+    /// Drops the changes of the current command and applies the remaining ones
+    pub fn skip(&mut self) -> Result<()> {
+        if !self.in_progress() {
+            bail!("no {} in progress", self.name());
+        }
+        let head_id = self.refs.read_head().context("Failed to read HEAD")?;
+        self.reset_hard(&head_id)?;
+
+        if let Some(Command::Commit { action, .. }) = self.todo()?.first() {
+            if self.refs.read_ref(self.head_ref(*action)).is_some() {
+                self.clear_pick_head(*action)?;
+                self.shift()?;
+            }
+        }
+        self.resume()
+    }
+
+    /// Goes back to the HEAD from before the sequence started
+    pub fn abort(&mut self) -> Result<()> {
+        if !self.in_progress() {
+            bail!("no {} in progress", self.name());
+        }
+        let head_file = if self.rebase { "orig-head" } else { "head" };
+        let head_id = self.read_state(head_file)?;
+        self.reset_hard(&head_id)?;
+        if let Some(Command::Commit { action, .. }) = self.todo()?.first() {
+            self.clear_pick_head(*action)?;
+        }
+
+        let identity = Author::from_env();
+        if self.rebase {
+            let head_name = self.read_state("head-name")?;
+            let message = format!("rebase (abort): returning to {}", head_name);
+            if head_name.starts_with("refs/") {
+                self.refs
+                    .update_symref(HEAD, &head_name, &identity, &message)?;
+            } else {
+                self.refs.update_ref(HEAD, head_id, &identity, &message)?;
+            }
+        } else {
+            let message = format!("{}: aborting", self.name());
+            self.refs.update_head(head_id, &identity, &message)?;
+        }
+        self.quit()
+    }
+
+    /// Forgets about the sequence in progress without touching HEAD or the workspace
+    pub fn quit(&mut self) -> Result<()> {
+        if self.in_progress() {
+            fs::remove_dir_all(&self.path)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.rebase {
+            let head_id = self.refs.read_head().context("Failed to read HEAD")?;
+            let head_name = self.read_state("head-name")?;
+            let onto = self.read_state("onto")?;
+            if head_name.starts_with("refs/") {
+                let identity = Author::from_env();
+                self.refs.update_ref(
+                    &head_name,
+                    head_id,
+                    &identity,
+                    &format!("rebase (finish): {} onto {}", head_name, onto),
+                )?;
+                self.refs.update_symref(
+                    HEAD,
+                    &head_name,
+                    &identity,
+                    &format!("rebase (finish): returning to {}", head_name),
+                )?;
+            }
+            writeln!(self.out, "Successfully rebased and updated {}.", head_name)?;
+        }
+        self.quit()
+    }
+
+    fn run(&mut self, command: &Command, options: &Options) -> Result<Flow> {
+        let (action, commit_id) = match command {
+            Command::Exec(command_line) => {
+                writeln!(self.out, "Executing: {}", command_line)?;
+                self.out.flush()?;
+                let status = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(command_line)
+                    .status()?;
+                if !status.success() {
+                    return Ok(Flow::Fail(format!(
+                        "Execution failed: {}\nYou can fix the problem, and then run\n\n  rebase --continue",
+                        command_line
+                    )));
+                }
+                return Ok(Flow::Next);
+            }
+            Command::Commit { action, commit_id } => (*action, commit_id),
+        };
+        if action == Action::Drop {
+            return Ok(Flow::Next);
+        }
+
+        self.pick(action, commit_id, options)?;
+
+        if action == Action::Edit {
+            let head_id = self.refs.read_head().context("Failed to read HEAD")?;
+            fs::write(self.path.join("amend"), format!("{}\n", head_id))?;
+            let commit = self.load_commit(commit_id)?;
+            writeln!(
+                self.out,
+                "Stopped at {}... {}\nYou can amend the commit now, then continue with\n\n  rebase --continue",
+                &commit_id[..7],
+                commit.message().lines().next().unwrap_or("")
+            )?;
+            return Ok(Flow::Stop);
+        }
+        Ok(Flow::Next)
+    }
+
+    fn pick(&mut self, action: Action, commit_id: &str, options: &Options) -> Result<()> {
+        let head_id = self.refs.read_head().context("Failed to read HEAD")?;
+        let commit = self.load_commit(commit_id)?;
+        let short_id = &commit_id[..7];
+        let subject = commit.message().lines().next().unwrap_or("").to_string();
+
+        if commit.parents().len() > 1 {
+            bail!(
+                "commit {} is a merge but no -m option was given.",
+                commit_id
+            );
+        }
+        if self.database.is_shallow(commit_id) {
+            bail!(
+                "cannot {} {}: its parent is missing from this shallow repository",
+                self.name(),
+                short_id
+            );
+        }
+        let parent_items = match commit.parent() {
+            Some(parent_id) => self.tree_items(parent_id)?,
+            None => BTreeMap::new(),
+        };
+        let commit_items = self.tree_items(commit_id)?;
+        let (base, theirs) = match action {
+            Action::Revert => (commit_items, parent_items),
+            _ => (parent_items, commit_items),
+        };
+        let ours = self.tree_items(&head_id)?;
+
+        self.index.load_for_update()?;
+        if self.index.has_conflicts() {
+            bail!(
+                "{} is not possible because you have unmerged files.",
+                self.name()
+            );
+        }
+        if self.index.tree_items() != ours {
+            bail!(
+                "your local changes would be overwritten by {}.\nhint: commit your changes or stash them to proceed.",
+                self.name()
+            );
+        }
+
+        let theirs_name = match action {
+            Action::Revert => format!("parent of {} ({})", short_id, subject),
+            _ => format!("{} ({})", short_id, subject),
+        };
+        let merge =
+            resolve::merge_trees(self.database, &base, &ours, &theirs, "HEAD", &theirs_name)?;
+        resolve::apply(self.database, self.workspace, self.index, &ours, &merge)?;
+        self.index.write_updates()?;
+
+        let message = match action {
+            Action::Revert => format!(
+                "Revert \"{}\"\n\nThis reverts commit {}.\n",
+                subject, commit_id
+            ),
+            Action::Squash => {
+                let head = self.load_commit(&head_id)?;
+                format!(
+                    "# This is a combination of 2 commits.\n# This is the 1st commit message:\n\n{}\n\n# This is the commit message #2:\n\n{}",
+                    head.message().trim_end(),
+                    commit.message()
+                )
+            }
+            Action::Fixup => self.load_commit(&head_id)?.message().to_string(),
+            _ => {
+                let mut message = commit.message().to_string();
+                if options.record_origin {
+                    if !message.ends_with('\n') {
+                        message.push('\n');
+                    }
+                    message.push_str(&format!("\n(cherry picked from commit {})\n", commit_id));
+                }
+                message
+            }
+        };
+
+        if !merge.is_clean() {
+            for (path, conflict) in &merge.conflicts {
+                writeln!(
+                    self.out,
+                    "CONFLICT ({}): Merge conflict in {}",
+                    conflict.kind(),
+                    path
+                )?;
+            }
+            fs::write(self.git_path.join(MERGE_MSG), &message)?;
+            self.refs.update_ref(
+                self.head_ref(action),
+                commit_id.to_string(),
+                &Author::from_env(),
+                "",
+            )?;
+            bail!(
+                "could not {} {}... {}\nhint: after resolving the conflicts, mark the corrected paths with 'add <paths>' or 'rm <paths>'\nhint: and run '{} --continue'",
+                if action == Action::Revert { "revert" } else { "apply" },
+                short_id,
+                subject,
+                self.name()
+            );
+        }
+
+        self.commit(action, commit_id, message)
+    }
+
+    /// Commits the index for the given command. Squashes and fixups replace HEAD instead of
+    /// adding a commit on top of it.
+    fn commit(&mut self, action: Action, commit_id: &str, message: String) -> Result<()> {
+        let head_id = self.refs.read_head().context("Failed to read HEAD")?;
+        let commit = self.load_commit(commit_id)?;
+        let (parents, author) = match action {
+            Action::Squash | Action::Fixup => {
+                let head = self.load_commit(&head_id)?;
+                (head.parents().to_vec(), head.author().clone())
+            }
+            Action::Revert => (vec![head_id.clone()], Author::from_env()),
+            _ => (vec![head_id.clone()], commit.author().clone()),
+        };
+        let message = match action {
+            Action::Reword | Action::Squash => self.edit_message(&message)?,
+            _ => strip_comments(&message),
+        };
+
+        let items = self.index.tree_items();
+        let is_amend = matches!(action, Action::Squash | Action::Fixup);
+        if !is_amend && items == self.tree_items(&head_id)? {
+            if self.rebase {
+                log::info!(
+                    "dropping {} {} -- patch contents already upstream",
+                    commit_id,
+                    message.lines().next().unwrap_or("")
+                );
+                return Ok(());
+            }
+            bail!(
+                "The previous {} is now empty, possibly due to conflict resolution.\nhint: use '{} --skip' to skip this commit",
+                self.name(),
+                self.name()
+            );
+        }
+
+        let tree_id = self.database.store_tree(&items)?;
+        let committer = Author::from_env();
+        let subject = message.lines().next().unwrap_or("").to_string();
+        let commit =
+            Commit::new(parents, tree_id, author, message).with_committer(committer.clone());
+        let new_id = self.database.store(&commit)?;
+
+        let reflog_message = if self.rebase {
+            format!("rebase ({}): {}", action, subject)
+        } else {
+            format!("{}: {}", self.name(), subject)
+        };
+        self.refs
+            .update_head(new_id.clone(), &committer, &reflog_message)?;
+        if !self.rebase {
+            writeln!(self.out, "[{}] {}", &new_id[..7], subject)?;
+        }
+        Ok(())
+    }
+
+    /// Lets the user edit a message with GIT_EDITOR when it's set, comments are stripped
+    fn edit_message(&self, message: &str) -> Result<String> {
+        let editor = match std::env::var(EDITOR_ENV) {
+            Ok(editor) => editor,
+            Err(_) => return Ok(strip_comments(message)),
+        };
+        let path = self.git_path.join(COMMIT_EDITMSG);
+        fs::write(&path, message)?;
+        run_editor(&editor, &path)?;
+        let message = strip_comments(&fs::read_to_string(&path)?);
+        if message.trim().is_empty() {
+            bail!("Aborting commit due to empty commit message.");
+        }
+        Ok(message)
+    }
+
+    /// Makes sure nothing would be lost by resetting the workspace and the index
+    fn ensure_clean(&mut self, head_id: &str, name: &str) -> Result<()> {
+        self.index.load()?;
+        if self.index.tree_items() != self.tree_items(head_id)? {
+            bail!("cannot {}: Your index contains uncommitted changes.", name);
+        }
+        for entry in self.index.entries() {
+            if staging::is_modified(self.workspace, self.index, entry.path())? {
+                bail!("cannot {}: You have unstaged changes.", name);
+            }
+        }
+        Ok(())
+    }
+
+    fn reset_hard(&mut self, commit_id: &str) -> Result<()> {
+        let items = self.tree_items(commit_id)?;
+        self.index.load_for_update()?;
+        migration::checkout_entries(self.database, self.workspace, self.index, &items)?;
+        self.index.write_updates()
+    }
+
+    /// Name of the command running the sequence, for messages
+    fn name(&self) -> &str {
+        if self.rebase {
+            return "rebase";
+        }
+        let first = self.todo().ok().and_then(|todo| todo.into_iter().next());
+        match first {
+            Some(Command::Commit {
+                action: Action::Revert,
+                ..
+            }) => "revert",
+            _ => "cherry-pick",
+        }
+    }
+
+    fn head_ref(&self, action: Action) -> &str {
+        if self.rebase {
+            REBASE_HEAD
+        } else if action == Action::Revert {
+            REVERT_HEAD
+        } else {
+            CHERRY_PICK_HEAD
+        }
+    }
+
+    fn clear_pick_head(&self, action: Action) -> Result<()> {
+        let head_ref = self.head_ref(action);
+        if self.refs.read_ref(head_ref).is_some() {
+            self.refs.delete_ref(head_ref)?;
+        }
+        let merge_msg = self.git_path.join(MERGE_MSG);
+        if merge_msg.is_file() {
+            fs::remove_file(merge_msg)?;
+        }
+        Ok(())
+    }
+
+    fn load_commit(&self, commit_id: &str) -> Result<Commit> {
+        let (kind, data) = self.database.load(commit_id)?;
+        if kind != "commit" {
+            bail!("{} is a {}, not a commit", commit_id, kind);
+        }
+        Commit::parse(&data)
+    }
+
+    fn tree_items(&self, commit_id: &str) -> Result<BTreeMap<String, TreeItem>> {
+        self.database
+            .load_tree_entries(&self.database.load_tree_id(commit_id)?)
+    }
+
+    fn read_state(&self, name: &str) -> Result<String> {
+        Ok(fs::read_to_string(self.path.join(name))
+            .with_context(|| format!("Failed to read {} state: {}", self.name(), name))?
+            .trim()
+            .to_string())
+    }
+
+    fn todo_path(&self) -> PathBuf {
+        self.path.join(if self.rebase {
+            "git-rebase-todo"
+        } else {
+            "todo"
+        })
+    }
+
+    /// Reads the remaining commands, abbreviated commit ids are expanded
+    fn todo(&self) -> Result<Vec<Command>> {
+        fs::read_to_string(self.todo_path())?
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|line| match line.parse()? {
+                Command::Commit { action, commit_id } => {
+                    match self.database.prefix_match(&commit_id)?.as_slice() {
+                        [commit_id] => Ok(Command::Commit {
+                            action,
+                            commit_id: commit_id.clone(),
+                        }),
+                        [] => bail!("invalid commit in todo list: {}", line),
+                        _ => bail!("ambiguous commit in todo list: {}", line),
+                    }
+                }
+                command => Ok(command),
+            })
+            .collect()
+    }
+
+    fn write_todo(&self, commands: &[Command]) -> Result<()> {
+        let mut todo = String::new();
+        for command in commands {
+            match command {
+                Command::Commit { action, commit_id } => {
+                    let subject = self
+                        .load_commit(commit_id)
+                        .map(|commit| commit.message().lines().next().unwrap_or("").to_string())
+                        .unwrap_or_default();
+                    todo.push_str(&format!("{} {} {}\n", action, &commit_id[..7], subject));
+                }
+                Command::Exec(command_line) => todo.push_str(&format!("exec {}\n", command_line)),
+            }
+        }
+        fs::write(self.todo_path(), todo)?;
+        Ok(())
+    }
+
+    /// Removes the first command of the todo list, rebase keeps it in its done list
+    fn shift(&self) -> Result<()> {
+        let todo_path = self.todo_path();
+        let content = fs::read_to_string(&todo_path)?;
+        let mut lines = content
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'));
+        let done = lines.next();
+        let rest: Vec<&str> = lines.collect();
+
+        if let (true, Some(done)) = (self.rebase, done) {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path.join("done"))?;
+            writeln!(file, "{}", done)?;
+        }
+        let mut todo = rest.join("\n");
+        if !todo.is_empty() {
+            todo.push('\n');
+        }
+        fs::write(todo_path, todo)?;
+        Ok(())
+    }
+
+    fn options(&self) -> Result<Options> {
+        let opts = fs::read_to_string(self.path.join("opts")).unwrap_or_default();
+        Ok(Options {
+            record_origin: opts
+                .lines()
+                .any(|line| line.trim() == "record-origin = true"),
+        })
+    }
+}
+
+/// Runs an editor command through the shell like git does, so it can have arguments
+fn run_editor(editor: &str, path: &Path) -> Result<()> {
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(editor)
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to run editor: {}", editor))?;
+    if !status.success() {
+        bail!("There was a problem with the editor '{}'.", editor);
+    }
+    Ok(())
+}
+
+/// Removes comment lines, consecutive blank lines and surrounding blank lines from a message
+fn strip_comments(message: &str) -> String {
+    let mut lines: Vec<&str> = vec![];
+    for line in message.lines().filter(|line| !line.starts_with('#')) {
+        let line = line.trim_end();
+        if !(line.is_empty() && lines.last().is_some_and(|last| last.is_empty())) {
+            lines.push(line);
+        }
+    }
+    let message = lines.join("\n");
+    let message = message.trim_matches('\n');
+    if message.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{database::blob::Blob, test_utils};
+
+    fn commit_file(
+        database: &Database,
+        parents: Vec<ObjectId>,
+        content: &str,
+    ) -> Result<(ObjectId, BTreeMap<String, TreeItem>)> {
+        let blob_id = database.store(&Blob::new(content.as_bytes().to_vec()))?;
+        let mut items = BTreeMap::new();
+        items.insert(
+            "f".to_string(),
+            TreeItem {
+                mode: 0o100644,
+                object_id: blob_id,
+            },
+        );
+        let tree_id = database.store_tree(&items)?;
+        let commit = Commit::new(parents, tree_id, test_utils::test_author(), content.into());
+        Ok((database.store(&commit)?, items))
+    }
+
+    #[test]
+    fn refused_pick_leaves_no_state() -> Result<()> {
+        let dir = test_utils::temp_path("sequencer");
+        let git_path = dir.join("git");
+        let database = Database::new(git_path.join("objects"));
+        let workspace = Workspace::new(dir.clone());
+        let refs = Refs::new(git_path.clone());
+        let mut index = Index::new(git_path.join("index"));
+        let author = test_utils::test_author();
+
+        let (base_id, items) = commit_file(&database, vec![], "one\n")?;
+        let (pick_id, _) = commit_file(&database, vec![base_id.clone()], "two\n")?;
+        let (merge_id, _) = commit_file(&database, vec![base_id.clone(), pick_id.clone()], "m\n")?;
+        refs.update_ref("refs/heads/main", base_id, &author, "test")?;
+        refs.update_symref(HEAD, "refs/heads/main", &author, "test")?;
+        index.load_for_update()?;
+        migration::checkout_entries(&database, &workspace, &mut index, &items)?;
+        index.write_updates()?;
+
+        let pick = |commit_id: &ObjectId| {
+            vec![Command::Commit {
+                action: Action::Pick,
+                commit_id: commit_id.clone(),
+            }]
+        };
+        let mut sequencer = Sequencer::new(&git_path, &database, &workspace, &mut index, &refs);
+        // A merge can't be picked, the sequence never started
+        assert!(sequencer
+            .start(&pick(&merge_id), &Options::default())
+            .is_err());
+        assert!(!sequencer.in_progress());
+
+        // Local changes are refused before any state is written, so aborting can't lose them
+        workspace.write_file(Path::new("f"), b"local\n", false)?;
+        let err = sequencer
+            .start(&pick(&pick_id), &Options::default())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot cherry-pick: You have unstaged changes."
+        );
+        assert!(!sequencer.in_progress());
+        assert!(sequencer.abort().is_err());
+        assert_eq!(fs::read_to_string(dir.join("f"))?, "local\n");
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn parse_todo_commands() -> Result<()> {
+        assert_eq!(
+            "pick 1a2b3c4 Subject of the commit".parse::<Command>()?,
+            Command::Commit {
+                action: Action::Pick,
+                commit_id: "1a2b3c4".into()
+            }
+        );
+        assert_eq!(
+            "f 1a2b3c4".parse::<Command>()?,
+            Command::Commit {
+                action: Action::Fixup,
+                commit_id: "1a2b3c4".into()
+            }
+        );
+        assert_eq!(
+            "exec make test".parse::<Command>()?,
+            Command::Exec("make test".into())
+        );
+        assert!("pick".parse::<Command>().is_err());
+        assert!("unknown 1a2b3c4".parse::<Command>().is_err());
+
+        assert_eq!(
+            strip_comments("# header\n\nsubject\n\n# comment\n\nbody\n# trailer\n"),
+            "subject\n\nbody\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+
+use crate::{
+    config::Config,
+    database::{blob::Blob, tree::TreeItem, Database, Object, MODE_EXECUTABLE, MODE_REGULAR},
+    index::Index,
+    pathspec::Pathspec,
+    workspace::Workspace,
+    Metadata, ObjectId,
+};
+
+/// Environment variable used as the default number of jobs when `-j` isn't given
+pub const JOBS_ENV: &str = "GIT_JOBS";
+
+/// Config variable giving the number of jobs of every command that runs in parallel
+pub const JOBS_CONFIG: &str = "core.jobs";
+
+/// A file that has been read, hashed and stored in the database
+pub struct StagedFile {
+    pub path: PathBuf,
+    pub object_id: ObjectId,
+    pub metadata: Metadata,
+}
+
+/// Returns the number of jobs to use, 0 lets the thread pool pick one per cpu. `-j` comes
+/// first, then GIT_JOBS, then the `name` config variable of the command and `core.jobs`.
+pub fn jobs(jobs: Option<usize>, config: &Config, name: &str) -> Result<usize> {
+    if let Some(jobs) = jobs {
+        return Ok(jobs);
+    }
+    if let Ok(value) = std::env::var(JOBS_ENV) {
+        return parse_jobs(&value, JOBS_ENV);
+    }
+    for name in [name, JOBS_CONFIG] {
+        if let Some(value) = config.get(name) {
+            return parse_jobs(&value, name);
+        }
+    }
+    Ok(0)
+}
+
+fn parse_jobs(value: &str, name: &str) -> Result<usize> {
+    match value.trim().parse() {
+        Ok(jobs) => Ok(jobs),
+        Err(_) => bail!("bad numeric config value '{}' for '{}'", value, name),
+    }
+}
+
+/// Reads, hashes and stores every file as a blob using `jobs` threads.
+/// The results are returned in the same order as `files` so updating the index stays deterministic.
+pub fn stage_files(
+    workspace: &Workspace,
+    database: &Database,
+    files: &[PathBuf],
+    jobs: usize,
+) -> Result<Vec<StagedFile>> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .context("Failed to build staging thread pool")?;
+
+    pool.install(|| {
+        files
+            .par_iter()
+            .map(|path| stage_file(workspace, database, path))
+            .collect()
+    })
+}
+
+/// Checks if the file in the workspace differs from its index entry.
+/// The file is only hashed when its stat information doesn't match.
+pub fn is_modified(workspace: &Workspace, index: &Index, path: &str) -> Result<bool> {
+    let entry = match index.entry(path) {
+        Some(entry) => entry,
+        None => return Ok(true),
+    };
+    let metadata = match workspace.file_metadata(Path::new(path)) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(true),
+    };
+    if index.is_stat_clean(path, &metadata) {
+        return Ok(false);
+    }
+
+    let mode = if metadata.is_executable {
+        MODE_EXECUTABLE
+    } else {
+        MODE_REGULAR
+    };
+    let object_id = Blob::new(workspace.read_file(Path::new(path))?).object_id();
+    Ok(&object_id != entry.object_id() || mode as u32 != entry.mode())
+}
+
+/// Updates the stat information of the entries whose files are unchanged, like
+/// `git update-index --refresh`. Entries read from trees have none, every file would be hashed
+/// again by each command otherwise. Returns how many entries were refreshed.
+pub fn refresh_index(workspace: &Workspace, index: &mut Index) -> Result<usize> {
+    let paths: Vec<String> = index
+        .entries()
+        .filter(|entry| entry.stage() == 0)
+        .map(|entry| entry.path().to_string())
+        .collect();
+    let mut refreshed = 0;
+    for path in paths {
+        let metadata = match workspace.file_metadata(Path::new(&path)) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if index.is_stat_clean(&path, &metadata) || is_modified(workspace, index, &path)? {
+            continue;
+        }
+        index.refresh(&path, &metadata)?;
+        refreshed += 1;
+    }
+    Ok(refreshed)
+}
+
+/// Returns the tracked files as they are in the workspace. Modified files are hashed as blobs
+/// the database only keeps in memory so they can be compared to trees without writing them,
+/// deleted ones are left out.
+pub fn workspace_items(
+    workspace: &Workspace,
+    database: &Database,
+    index: &Index,
+) -> Result<BTreeMap<String, TreeItem>> {
+    let mut items = index.tree_items();
+    let paths: Vec<String> = items.keys().cloned().collect();
+    for path in paths {
+        let metadata = match workspace.file_metadata(Path::new(&path)) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                items.remove(&path);
+                continue;
+            }
+        };
+        if !is_modified(workspace, index, &path)? {
+            continue;
+        }
+        let mode = if metadata.is_executable {
+            MODE_EXECUTABLE
+        } else {
+            MODE_REGULAR
+        };
+        let object_id = database.pretend(&Blob::new(workspace.read_file(Path::new(&path))?));
+        let mode = mode as u32;
+        items.insert(path, TreeItem { mode, object_id });
+    }
+    Ok(items)
+}
+
+/// Lists the files of the workspace matching the pathspec that the index doesn't track
+pub fn untracked_files(
+    workspace: &Workspace,
+    index: &Index,
+    pathspec: &Pathspec,
+) -> Result<Vec<String>> {
+    let mut files = vec![];
+    for file in workspace.list_files(None)? {
+        let path = file
+            .to_str()
+            .expect("Failed to convert path to a valid string");
+        if !index.is_tracked(path) && pathspec.matches(path) {
+            files.push(path.to_string());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn stage_file(workspace: &Workspace, database: &Database, path: &Path) -> Result<StagedFile> {
+    log::debug!("staging {}", path.display());
+
+    let data = workspace.read_file(path)?;
+    let metadata = workspace.file_metadata(path)?;
+    let object_id = database.store(&Blob::new(data))?;
+
+    Ok(StagedFile {
+        path: path.to_path_buf(),
+        object_id,
+        metadata,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn parallel_staging_is_deterministic() -> Result<()> {
+        let dir = test_utils::temp_path("staging");
+        let workspace = Workspace::new(dir.clone());
+        let database = Database::new(dir.join("objects"));
+        // Listing order isn't sorted, staging must keep it whatever thread finishes first
+        let files: Vec<PathBuf> = (0..200)
+            .map(|n| PathBuf::from(format!("dir{}/file{}", n % 7, (n * 37) % 200)))
+            .collect();
+        for (n, file) in files.iter().enumerate() {
+            workspace.write_file(file, format!("content {}\n", n).as_bytes(), false)?;
+        }
+
+        // Every file has its own content, a result out of place has the wrong id
+        let expected: Vec<(&PathBuf, ObjectId)> = files
+            .iter()
+            .enumerate()
+            .map(|(n, file)| {
+                let blob = Blob::new(format!("content {}\n", n).into_bytes());
+                (file, blob.object_id())
+            })
+            .collect();
+        for jobs in [1, 8] {
+            let staged = stage_files(&workspace, &database, &files, jobs)?;
+            let results: Vec<(&PathBuf, ObjectId)> = staged
+                .iter()
+                .map(|staged| (&staged.path, staged.object_id.clone()))
+                .collect();
+            assert_eq!(results, expected);
+        }
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_entries_from_trees() -> Result<()> {
+        let dir = test_utils::temp_path("refresh");
+        let workspace = Workspace::new(dir.clone());
+        let mut items = BTreeMap::new();
+        for (path, content) in [("a.txt", "a\n"), ("b.txt", "b\n"), ("c.txt", "c\n")] {
+            workspace.write_file(Path::new(path), content.as_bytes(), false)?;
+            let object_id = Blob::new(content.as_bytes().to_vec()).object_id();
+            let mode = MODE_REGULAR as u32;
+            items.insert(path.to_string(), TreeItem { mode, object_id });
+        }
+        workspace.write_file(Path::new("b.txt"), b"changed\n", false)?;
+        std::fs::remove_file(dir.join("c.txt"))?;
+
+        let index_path = dir.join("index");
+        let mut index = Index::new(index_path.clone());
+        assert!(index.load_for_refresh()?);
+        index.reset_to(&items)?;
+        let metadata = workspace.file_metadata(Path::new("a.txt"))?;
+        assert!(!index.is_stat_clean("a.txt", &metadata));
+
+        // Only the unchanged file gets its stat information, the others are still compared
+        assert_eq!(refresh_index(&workspace, &mut index)?, 1);
+        assert!(index.is_stat_clean("a.txt", &metadata));
+        assert!(is_modified(&workspace, &index, "b.txt")?);
+        assert!(is_modified(&workspace, &index, "c.txt")?);
+        assert_eq!(refresh_index(&workspace, &mut index)?, 0);
+        index.write_updates()?;
+
+        // Another process holds the lock, the index is still read
+        let mut lockfile = crate::lockfile::Lockfile::new(&index_path);
+        lockfile.hold_for_update()?;
+        let mut index = Index::new(index_path);
+        assert!(!index.load_for_refresh()?);
+        assert_eq!(index.entries().count(), 3);
+        lockfile.rollback()?;
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn jobs_from_config() -> Result<()> {
+        // Only the precedence of the config variables is checked, GIT_JOBS is left alone
+        if std::env::var(JOBS_ENV).is_ok() {
+            return Ok(());
+        }
+        let mut config = Config::load(std::env::temp_dir().join("git_rs_no_config"))?;
+        assert_eq!(jobs(None, &config, "add.jobs")?, 0);
+        config.set(JOBS_CONFIG, "2")?;
+        assert_eq!(jobs(None, &config, "add.jobs")?, 2);
+        config.set("add.jobs", "3")?;
+        assert_eq!(jobs(None, &config, "add.jobs")?, 3);
+        assert_eq!(jobs(Some(4), &config, "add.jobs")?, 4);
+        config.set("add.jobs", "many")?;
+        assert!(jobs(None, &config, "add.jobs").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bad_jobs_values() {
+        // GIT_JOBS and the config variables are parsed and reported the same way
+        assert_eq!(parse_jobs(" 4\n", JOBS_ENV).unwrap(), 4);
+        for value in ["many", "-1", ""] {
+            let error = parse_jobs(value, JOBS_ENV).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("bad numeric config value '{}' for 'GIT_JOBS'", value)
+            );
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    database::{
+        blob::Blob, commit::Commit, tree::TreeItem, Database, MODE_EXECUTABLE, MODE_REGULAR,
+    },
+    diff::patch::{self, FileChange},
+    index::Index,
+    merge::resolve,
+    migration,
+    pathspec::Pathspec,
+    refs::{Refs, HEAD, STASH},
+    staging,
+    workspace::Workspace,
+    Author, ObjectId,
+};
+
+/// A stash is a commit of the workspace whose parents are HEAD, a commit of the index and,
+/// when untracked files were stashed, a parentless commit of those files.
+/// Every stash is an entry in the reflog of `refs/stash`, `stash@{0}` being the latest one.
+pub struct Stash<'a> {
+    database: &'a Database,
+    workspace: &'a Workspace,
+    index: &'a mut Index,
+    refs: &'a Refs,
+    /// Where what was saved, dropped or conflicts is reported, nothing is shown by default
+    out: Box<dyn Write + 'a>,
+}
+
+impl<'a> Stash<'a> {
+    pub fn new(
+        database: &'a Database,
+        workspace: &'a Workspace,
+        index: &'a mut Index,
+        refs: &'a Refs,
+    ) -> Self {
+        Self {
+            database,
+            workspace,
+            index,
+            refs,
+            out: Box::new(io::sink()),
+        }
+    }
+
+    /// Reports what was saved, dropped or conflicts to `out`
+    pub fn with_output(mut self, out: Box<dyn Write + 'a>) -> Self {
+        self.out = out;
+        self
+    }
+
+    /// Saves the changes of the paths matching the pathspec then reverts them to HEAD.
+    /// Returns `None` when there was nothing to save.
+    pub fn push(
+        &mut self,
+        message: Option<&str>,
+        pathspec: &Pathspec,
+        include_untracked: bool,
+    ) -> Result<Option<ObjectId>> {
+        let head_id = self
+            .refs
+            .read_head()
+            .context("You do not have the initial commit yet")?;
+        let head = self.load_commit(&head_id)?;
+        let head_items = self.tree_items(&head_id)?;
+
+        self.index.load_for_update()?;
+        if self.index.has_conflicts() {
+            bail!("Cannot save the current index state: you have unmerged paths");
+        }
+
+        // Only the changes of the matching paths are stashed, staged or not
+        let index_items = self.index.tree_items();
+        let paths: BTreeSet<String> = head_items
+            .keys()
+            .chain(index_items.keys())
+            .filter(|path| pathspec.matches(path))
+            .cloned()
+            .collect();
+        let mut staged = head_items.clone();
+        for path in &paths {
+            match index_items.get(path) {
+                Some(item) => staged.insert(path.clone(), item.clone()),
+                None => staged.remove(path),
+            };
+        }
+        let mut worktree = staged.clone();
+        for path in paths.iter().filter(|path| index_items.contains_key(*path)) {
+            if self.workspace.file_metadata(Path::new(path)).is_err() {
+                worktree.remove(path);
+            } else if staging::is_modified(self.workspace, self.index, path)? {
+                worktree.insert(path.clone(), self.store_file(path)?);
+            }
+        }
+
+        let untracked: Vec<String> = if include_untracked {
+            staging::untracked_files(self.workspace, self.index, pathspec)?
+        } else {
+            vec![]
+        };
+        if worktree == head_items && staged == head_items && untracked.is_empty() {
+            writeln!(self.out, "No local changes to save")?;
+            return Ok(None);
+        }
+
+        let branch = match self.refs.read_symref(HEAD) {
+            Some(name) => name.trim_start_matches("refs/heads/").to_string(),
+            None => "(no branch)".to_string(),
+        };
+        let subject = head.message().lines().next().unwrap_or("");
+        let on_head = format!("{}: {} {}", branch, &head_id[..7], subject);
+        let message = match message {
+            Some(message) => format!("On {}: {}", branch, message),
+            None => format!("WIP on {}", on_head),
+        };
+
+        let identity = Author::from_env();
+        let mut parents = vec![head_id.clone()];
+        parents.push(self.store_commit(
+            vec![head_id.clone()],
+            &staged,
+            &identity,
+            format!("index on {}\n", on_head),
+        )?);
+        if !untracked.is_empty() {
+            let mut items = BTreeMap::new();
+            for path in &untracked {
+                items.insert(path.clone(), self.store_file(path)?);
+            }
+            parents.push(self.store_commit(
+                vec![],
+                &items,
+                &identity,
+                format!("untracked files on {}\n", on_head),
+            )?);
+        }
+        let stash_id =
+            self.store_commit(parents, &worktree, &identity, format!("{}\n", message))?;
+        self.refs
+            .update_ref(STASH, stash_id.clone(), &identity, &message)?;
+
+        // Revert the stashed paths to HEAD without touching the other ones
+        for path in &paths {
+            match head_items.get(path) {
+                Some(item) => {
+                    migration::write_blob(self.database, self.workspace, path, item)?;
+                    let metadata = self.workspace.file_metadata(Path::new(path))?;
+                    self.index
+                        .add(path.clone(), item.object_id.clone(), &metadata)?;
+                }
+                None => {
+                    self.workspace.remove_file(Path::new(path))?;
+                    self.index.remove(path);
+                }
+            }
+        }
+        for path in &untracked {
+            self.workspace.remove_file(Path::new(path))?;
+        }
+        self.index.write_updates()?;
+
+        writeln!(
+            self.out,
+            "Saved working directory and index state {}",
+            message
+        )?;
+        Ok(Some(stash_id))
+    }
+
+    /// Merges the changes of the nth stash in the workspace. With `restore_index`, the changes
+    /// that were staged are staged again.
+    pub fn apply(&mut self, n: usize, restore_index: bool) -> Result<()> {
+        let stash_id = self.stash_id(n)?;
+        let stash = self.load_commit(&stash_id)?;
+        let (base_id, index_id) = match stash.parents() {
+            [base_id, index_id, ..] => (base_id, index_id),
+            _ => bail!("{} is not a stash-like commit", stash_id),
+        };
+        let base = self.tree_items(base_id)?;
+        let theirs = self.tree_items(&stash_id)?;
+        let index_items = self.tree_items(index_id)?;
+
+        self.index.load_for_update()?;
+        if self.index.has_conflicts() {
+            bail!("Cannot apply a stash with unmerged paths in the index");
+        }
+        let ours = self.index.tree_items();
+        let stash_name = format!("stash@{{{}}}", n);
+
+        let index_merge = if restore_index && index_items != base {
+            let merge = resolve::merge_trees(
+                self.database,
+                &base,
+                &ours,
+                &index_items,
+                "Updated upstream",
+                "Stashed changes",
+            )?;
+            if !merge.is_clean() {
+                bail!("Conflicts in index. Try without --index.");
+            }
+            Some(merge)
+        } else {
+            None
+        };
+
+        let untracked = match stash.parents().get(2) {
+            Some(untracked_id) => self.tree_items(untracked_id)?,
+            None => BTreeMap::new(),
+        };
+        let existing: Vec<&str> = untracked
+            .keys()
+            .filter(|path| self.workspace.file_metadata(Path::new(path)).is_ok())
+            .map(String::as_str)
+            .collect();
+        if !existing.is_empty() {
+            bail!(
+                "The following untracked files already exist:\n\t{}\nCould not restore untracked files from stash",
+                existing.join("\n\t")
+            );
+        }
+
+        let merge = resolve::merge_trees(
+            self.database,
+            &base,
+            &ours,
+            &theirs,
+            "Updated upstream",
+            "Stashed changes",
+        )?;
+        resolve::apply(self.database, self.workspace, self.index, &ours, &merge)?;
+        for (path, item) in &untracked {
+            migration::write_blob(self.database, self.workspace, path, item)?;
+        }
+
+        if merge.is_clean() {
+            // Changes end up unstaged, except for new files that stay tracked
+            let staged = match &index_merge {
+                Some(index_merge) => index_merge.clean.clone(),
+                None => {
+                    let mut staged = ours.clone();
+                    for (path, item) in &merge.clean {
+                        if !ours.contains_key(path) && !base.contains_key(path) {
+                            staged.insert(path.clone(), item.clone());
+                        }
+                    }
+                    staged
+                }
+            };
+            for change in patch::tree_changes(&self.index.tree_items(), &staged) {
+                match change.new {
+                    Some(item) => self.index.add_tree_item(change.path, &item)?,
+                    None => {
+                        self.index.remove(&change.path);
+                    }
+                }
+            }
+        }
+        self.index.write_updates()?;
+
+        if !merge.is_clean() {
+            for (path, conflict) in &merge.conflicts {
+                writeln!(
+                    self.out,
+                    "CONFLICT ({}): Merge conflict in {}",
+                    conflict.kind(),
+                    path
+                )?;
+            }
+            bail!("Conflicts while applying {}", stash_name);
+        }
+        Ok(())
+    }
+
+    /// Applies the nth stash then drops it, the stash is kept when the changes conflict
+    pub fn pop(&mut self, n: usize) -> Result<()> {
+        if let Err(err) = self.apply(n, false) {
+            if self.index.has_conflicts() {
+                writeln!(
+                    self.out,
+                    "The stash entry is kept in case you need it again."
+                )?;
+            }
+            return Err(err);
+        }
+        self.drop(n)
+    }
+
+    pub fn drop(&mut self, n: usize) -> Result<()> {
+        let stash_id = self.stash_id(n)?;
+        self.refs.drop_reflog_entry(STASH, n)?;
+        writeln!(self.out, "Dropped refs/stash@{{{}}} ({})", n, stash_id)?;
+        Ok(())
+    }
+
+    /// Drops every stash
+    pub fn clear(&self) -> Result<()> {
+        if self.refs.read_ref(STASH).is_some() {
+            self.refs.delete_ref(STASH)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the message of every stash, the latest one first
+    pub fn list(&self) -> Result<Vec<String>> {
+        Ok(self
+            .refs
+            .reflog(STASH)
+            .entries()?
+            .into_iter()
+            .rev()
+            .map(|entry| entry.message)
+            .collect())
+    }
+
+    /// The changes recorded by the nth stash, compared to the commit it was based on
+    pub fn changes(&self, n: usize) -> Result<Vec<FileChange>> {
+        let stash_id = self.stash_id(n)?;
+        let stash = self.load_commit(&stash_id)?;
+        let base_id = stash
+            .parent()
+            .with_context(|| format!("{} is not a stash-like commit", stash_id))?;
+        Ok(patch::tree_changes(
+            &self.tree_items(base_id)?,
+            &self.tree_items(&stash_id)?,
+        ))
+    }
+
+    fn stash_id(&self, n: usize) -> Result<ObjectId> {
+        match self.refs.reflog(STASH).nth(n)? {
+            Some(entry) => Ok(entry.new_id),
+            None if n == 0 => bail!("No stash entries found."),
+            None => bail!("stash@{{{}}} is not a valid reference", n),
+        }
+    }
+
+    fn store_file(&self, path: &str) -> Result<TreeItem> {
+        let data = self.workspace.read_file(Path::new(path))?;
+        let metadata = self.workspace.file_metadata(Path::new(path))?;
+        let mode = if metadata.is_executable {
+            MODE_EXECUTABLE
+        } else {
+            MODE_REGULAR
+        };
+        Ok(TreeItem {
+            mode: mode as u32,
+            object_id: self.database.store(&Blob::new(data))?,
+        })
+    }
+
+    fn store_commit(
+        &self,
+        parents: Vec<ObjectId>,
+        items: &BTreeMap<String, TreeItem>,
+        identity: &Author,
+        message: String,
+    ) -> Result<ObjectId> {
+        let tree_id = self.database.store_tree(items)?;
+        let commit = Commit::new(parents, tree_id, identity.clone(), message);
+        self.database.store(&commit)
+    }
+
+    fn load_commit(&self, commit_id: &str) -> Result<Commit> {
+        let (kind, data) = self.database.load(commit_id)?;
+        if kind != "commit" {
+            bail!("{} is a {}, not a commit", commit_id, kind);
+        }
+        Commit::parse(&data)
+    }
+
+    fn tree_items(&self, commit_id: &str) -> Result<BTreeMap<String, TreeItem>> {
+        self.database
+            .load_tree_entries(&self.database.load_tree_id(commit_id)?)
+    }
+}
+
+/// Parses `stash@{<n>}` or `<n>` into the index of the stash, defaults to the latest one
+pub fn parse_selector(stash: Option<&str>) -> Result<usize> {
+    let stash = match stash {
+        Some(stash) => stash,
+        None => return Ok(0),
+    };
+    let n = stash
+        .strip_prefix("stash@{")
+        .and_then(|rest| rest.strip_suffix('}'))
+        .unwrap_or(stash);
+    n.parse()
+        .with_context(|| format!("{} is not a valid reference", stash))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stash_selector() -> Result<()> {
+        assert_eq!(parse_selector(None)?, 0);
+        assert_eq!(parse_selector(Some("2"))?, 2);
+        assert_eq!(parse_selector(Some("stash@{1}"))?, 1);
+        assert!(parse_selector(Some("master@{1}")).is_err());
+        Ok(())
+    }
+}
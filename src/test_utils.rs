@@ -0,0 +1,53 @@
+//! Fixtures shared by the tests of several modules
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::{
+    database::{blob::Blob, commit::Commit, tree::TreeItem, Database},
+    refs::HEAD,
+    Author, ObjectId, Refs,
+};
+
+/// A path in the temp directory no other test uses, nothing is created there
+pub fn temp_path(name: &str) -> PathBuf {
+    let suffix: String = std::iter::repeat_with(fastrand::alphanumeric)
+        .take(6)
+        .collect();
+    std::env::temp_dir().join(format!("git_rs_{}_{}", name, suffix))
+}
+
+pub fn test_author() -> Author {
+    Author {
+        name: "A U Thor".into(),
+        email: "author@example.com".into(),
+        time: chrono::Utc::now().into(),
+    }
+}
+
+/// Creates a repository to serve with a single commit adding `a.txt` on `main`, HEAD points
+/// to it. Returns the ids of the commit and of the blob.
+pub fn served_repo(git_path: &Path) -> Result<(ObjectId, ObjectId)> {
+    let database = Database::new(git_path.join("objects"));
+    let refs = Refs::new(git_path.to_path_buf());
+    let author = test_author();
+    let blob_id = database.store(&Blob::new(b"hello\n".to_vec()))?;
+    let mut items = BTreeMap::new();
+    items.insert(
+        "a.txt".to_string(),
+        TreeItem {
+            mode: 0o100644,
+            object_id: blob_id.clone(),
+        },
+    );
+    let tree_id = database.store_tree(&items)?;
+    let commit = Commit::new(vec![], tree_id, author.clone(), "one".into());
+    let commit_id = database.store(&commit)?;
+    refs.update_ref("refs/heads/main", commit_id.clone(), &author, "test")?;
+    refs.update_symref(HEAD, "refs/heads/main", &author, "test")?;
+    Ok((commit_id, blob_id))
+}
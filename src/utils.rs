@@ -1,5 +1,6 @@
 use anyhow::Result;
-use std::fmt::Write;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use std::{cmp::Ordering, fmt::Write};
 
 /// Converts a string of hexadecimal numbers to the equivalent vec of bytes
 pub fn serialize_hash(s: &str) -> Result<Vec<u8>> {
@@ -16,3 +17,116 @@ pub fn deserialize_hash(data: &[u8]) -> String {
     }
     s
 }
+
+/// Parses the dates accepted by options like `--expire` and `@{<date>}`:
+/// `now`, `yesterday`, `<n>.<unit>.ago`, `<n> <unit> ago` and ISO 8601 dates
+pub fn parse_date(s: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    match s {
+        "now" | "all" => return Some(now),
+        "yesterday" => return Some(now - Duration::days(1)),
+        _ => (),
+    }
+
+    if let Ok(date) = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S") {
+        return Some(Utc.from_utc_datetime(&date));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Some(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?));
+    }
+
+    let s = s.replace('.', " ");
+    let parts: Vec<&str> = s.split_whitespace().collect();
+    if let [count, unit, "ago"] = parts[..] {
+        let count: i64 = count.parse().ok()?;
+        let unit = match unit.strip_suffix('s') {
+            Some(unit) => unit,
+            None => unit,
+        };
+        let duration = match unit {
+            "second" => Duration::seconds(count),
+            "minute" => Duration::minutes(count),
+            "hour" => Duration::hours(count),
+            "day" => Duration::days(count),
+            "week" => Duration::weeks(count),
+            "month" => Duration::days(count * 30),
+            "year" => Duration::days(count * 365),
+            _ => return None,
+        };
+        return Some(now - duration);
+    }
+
+    None
+}
+
+/// Compares strings like `sort -V`, runs of digits are compared by their numeric value
+pub fn version_cmp(a: &str, b: &str) -> Ordering {
+    fn chunks(s: &str) -> Vec<(bool, &str)> {
+        let mut chunks = vec![];
+        let mut start = 0;
+        for (i, c) in s.char_indices().skip(1) {
+            let previous = s[..i].chars().last().unwrap_or(c);
+            if previous.is_ascii_digit() != c.is_ascii_digit() {
+                chunks.push((
+                    s[start..i].starts_with(|c: char| c.is_ascii_digit()),
+                    &s[start..i],
+                ));
+                start = i;
+            }
+        }
+        if start < s.len() {
+            chunks.push((
+                s[start..].starts_with(|c: char| c.is_ascii_digit()),
+                &s[start..],
+            ));
+        }
+        chunks
+    }
+
+    for (a, b) in chunks(a).into_iter().zip(chunks(b)) {
+        let ordering = match (a, b) {
+            ((true, a), (true, b)) => {
+                let a = a.trim_start_matches('0');
+                let b = b.trim_start_matches('0');
+                a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+            }
+            ((_, a), (_, b)) => a.cmp(b),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        let now = Utc.with_ymd_and_hms(2021, 3, 10, 12, 0, 0).unwrap();
+        assert_eq!(parse_date("now", now), Some(now));
+        assert_eq!(parse_date("yesterday", now), Some(now - Duration::days(1)));
+        assert_eq!(
+            parse_date("2.weeks.ago", now),
+            Some(now - Duration::weeks(2))
+        );
+        assert_eq!(
+            parse_date("1 hour ago", now),
+            Some(now - Duration::hours(1))
+        );
+        assert_eq!(
+            parse_date("2021-03-01", now),
+            Some(Utc.with_ymd_and_hms(2021, 3, 1, 0, 0, 0).unwrap())
+        );
+        assert_eq!(parse_date("someday", now), None);
+    }
+
+    #[test]
+    fn versions() {
+        let mut tags = vec!["v1.10", "v1.2", "v1.9.1", "v1.9", "v10.0"];
+        tags.sort_by(|a, b| version_cmp(a, b));
+        assert_eq!(tags, vec!["v1.2", "v1.9", "v1.9.1", "v1.10", "v10.0"]);
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     path::{Component, Path, PathBuf, Prefix},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{Context, Result};
@@ -44,23 +44,111 @@ impl Workspace {
     }
 
     pub fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
-        Ok(std::fs::read(&self.path.join(path))?)
+        Ok(std::fs::read(self.path.join(path))?)
+    }
+
+    /// Writes a file relative to the root, replacing whatever was at that path
+    pub fn write_file(&self, path: &Path, data: &[u8], executable: bool) -> Result<()> {
+        let full_path = self.path.join(path);
+        if full_path.is_dir() {
+            std::fs::remove_dir_all(&full_path)?;
+        }
+        if let Some(parent) = full_path.parent() {
+            // A file could be in the way of the parent directory
+            if parent.is_file() {
+                std::fs::remove_file(parent)?;
+            }
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&full_path, data)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        set_executable(&full_path, executable)
+    }
+
+    /// Removes a file and the directories it leaves empty
+    pub fn remove_file(&self, path: &Path) -> Result<()> {
+        let full_path = self.path.join(path);
+        if full_path.is_file() {
+            std::fs::remove_file(&full_path)?;
+        }
+
+        let mut dir = full_path.parent();
+        while let Some(parent) = dir {
+            if parent == self.path || !parent.starts_with(&self.path) {
+                break;
+            }
+            let is_empty =
+                std::fs::read_dir(parent).is_ok_and(|mut entries| entries.next().is_none());
+            if !is_empty {
+                break;
+            }
+            std::fs::remove_dir(parent)?;
+            dir = parent.parent();
+        }
+        Ok(())
     }
 
     pub fn file_metadata(&self, path: &Path) -> Result<Metadata> {
-        let fs_metadata = std::fs::metadata(self.path.join(path)).expect("Failed to read metadata");
+        let full_path = self.path.join(path);
+        let fs_metadata = std::fs::metadata(&full_path)
+            .with_context(|| format!("Failed to read metadata of {}", path.display()))?;
         Ok(Metadata {
-            // On linux this isn't supported so set it to beginning of time
-            created: fs_metadata.created().unwrap_or(SystemTime::UNIX_EPOCH),
+            created: changed_time(&fs_metadata),
             modified: fs_metadata.modified()?,
             accessed: fs_metadata.accessed()?,
-            device_id: 0, // Could use get_drive() but paths are hard
             len: fs_metadata.len() as u32,
-            is_executable: path.is_executable(),
+            is_executable: full_path.is_executable(),
+            ..stat_ids(&fs_metadata)
         })
     }
 }
 
+#[cfg(unix)]
+fn set_executable(path: &Path, executable: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = if executable { 0o755 } else { 0o644 };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path, _executable: bool) -> Result<()> {
+    Ok(())
+}
+
+/// On unix this is the ctime of the file, other platforms use the creation time
+#[cfg(unix)]
+fn changed_time(fs_metadata: &std::fs::Metadata) -> SystemTime {
+    use std::os::unix::fs::MetadataExt;
+    SystemTime::UNIX_EPOCH
+        + Duration::new(fs_metadata.ctime() as u64, fs_metadata.ctime_nsec() as u32)
+}
+
+#[cfg(not(unix))]
+fn changed_time(fs_metadata: &std::fs::Metadata) -> SystemTime {
+    fs_metadata.created().unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+/// Fills the device, inode, uid and gid of the file, the other fields need to be overwritten
+#[cfg(unix)]
+fn stat_ids(fs_metadata: &std::fs::Metadata) -> Metadata {
+    use std::os::unix::fs::MetadataExt;
+    Metadata {
+        device_id: fs_metadata.dev() as u32,
+        inode: fs_metadata.ino() as u32,
+        uid: fs_metadata.uid(),
+        gid: fs_metadata.gid(),
+        ..Metadata::default()
+    }
+}
+
+// ino, uid, gid are set to 0 on windows because they don't have an equivalent
+// device_id could use get_drive() but paths are hard
+#[cfg(not(unix))]
+fn stat_ids(_fs_metadata: &std::fs::Metadata) -> Metadata {
+    Metadata::default()
+}
+
 /// On windows, this returns the drive letter, on linux it will only return 0
 // TODO add a linux compatible version that returns device_id
 fn _get_drive(path: &Path) -> Result<u32> {
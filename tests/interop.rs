@@ -0,0 +1,204 @@
+//! Runs the system git against the servers, every test is skipped when `git` isn't installed
+
+use std::{
+    collections::BTreeMap,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+};
+
+use anyhow::{bail, Result};
+use git_rs::{
+    database::{blob::Blob, commit::Commit, tree::TreeItem, Database},
+    refs::HEAD,
+    remote::daemon::{self, DaemonOptions},
+    Author, Refs,
+};
+
+const UPLOAD_PACK: &str = env!("CARGO_BIN_EXE_git_rs-upload-pack");
+const RECEIVE_PACK: &str = env!("CARGO_BIN_EXE_git_rs-receive-pack");
+
+fn has_git() -> bool {
+    let found = Command::new("which")
+        .arg("git")
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+    if !found {
+        eprintln!("git isn't installed, skipping");
+    }
+    found
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let suffix: String = std::iter::repeat_with(fastrand::alphanumeric)
+        .take(6)
+        .collect();
+    std::env::temp_dir().join(format!("git_rs_interop_{}_{}", name, suffix))
+}
+
+/// Runs the system git in `dir`, isolated from the config of the user
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .env("HOME", dir)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_AUTHOR_NAME", "A U Thor")
+        .env("GIT_AUTHOR_EMAIL", "author@example.com")
+        .env("GIT_COMMITTER_NAME", "A U Thor")
+        .env("GIT_COMMITTER_EMAIL", "author@example.com")
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Commits a single `a.txt` file on `main` in the git directory, HEAD points to `main`
+fn commit(git_path: &Path, content: &str, parents: Vec<String>) -> Result<String> {
+    let database = Database::new(git_path.join("objects"));
+    let refs = Refs::new(git_path.to_path_buf());
+    let author = Author {
+        name: "A U Thor".into(),
+        email: "author@example.com".into(),
+        time: chrono::Utc::now().into(),
+    };
+    let blob_id = database.store(&Blob::new(content.as_bytes().to_vec()))?;
+    let mut items = BTreeMap::new();
+    items.insert(
+        "a.txt".to_string(),
+        TreeItem {
+            mode: 0o100644,
+            object_id: blob_id,
+        },
+    );
+    let tree_id = database.store_tree(&items)?;
+    let commit = Commit::new(parents, tree_id, author.clone(), content.into());
+    let commit_id = database.store(&commit)?;
+    refs.update_ref("refs/heads/main", commit_id.clone(), &author, "test")?;
+    refs.update_symref(HEAD, "refs/heads/main", &author, "test")?;
+    Ok(commit_id)
+}
+
+#[test]
+fn clone_and_fetch_from_upload_pack() -> Result<()> {
+    if !has_git() {
+        return Ok(());
+    }
+    let dir = temp_dir("upload_pack");
+    let git_path = dir.join("remote.git");
+    let first = commit(&git_path, "one\n", vec![])?;
+    let url = format!("file://{}", git_path.display());
+
+    for version in ["0", "2"] {
+        let protocol = format!("protocol.version={}", version);
+        let clone = format!("clone{}", version);
+        let upload_pack = format!("--upload-pack={}", UPLOAD_PACK);
+        git(
+            &dir,
+            &["-c", &protocol, "clone", &upload_pack, &url, &clone],
+        )?;
+        let clone = dir.join(clone);
+        assert_eq!(std::fs::read_to_string(clone.join("a.txt"))?, "one\n");
+        assert_eq!(git(&clone, &["rev-parse", "HEAD"])?, first);
+    }
+
+    let second = commit(&git_path, "two\n", vec![first])?;
+    for version in ["0", "2"] {
+        let protocol = format!("protocol.version={}", version);
+        let clone = dir.join(format!("clone{}", version));
+        git(&clone, &["-c", &protocol, "fetch", "origin"])?;
+        assert_eq!(git(&clone, &["rev-parse", "origin/main"])?, second);
+        git(&clone, &["fsck", "--no-dangling"])?;
+    }
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn push_to_receive_pack() -> Result<()> {
+    if !has_git() {
+        return Ok(());
+    }
+    let dir = temp_dir("receive_pack");
+    let git_path = dir.join("remote.git");
+    let first = commit(&git_path, "one\n", vec![])?;
+    let url = format!("file://{}", git_path.display());
+    let upload_pack = format!("--upload-pack={}", UPLOAD_PACK);
+    git(&dir, &["clone", &upload_pack, &url, "local"])?;
+
+    let local = dir.join("local");
+    std::fs::write(local.join("b.txt"), "pushed\n")?;
+    git(&local, &["add", "b.txt"])?;
+    git(&local, &["commit", "-q", "-m", "two"])?;
+    let pushed = git(&local, &["rev-parse", "HEAD"])?;
+    let receive_pack = format!("--receive-pack={}", RECEIVE_PACK);
+    git(
+        &local,
+        &["push", &receive_pack, "origin", "HEAD:refs/heads/main"],
+    )?;
+    git(
+        &local,
+        &["push", &receive_pack, "origin", "HEAD:refs/heads/topic"],
+    )?;
+
+    let refs = Refs::new(git_path.clone());
+    assert_eq!(refs.read_ref("refs/heads/main"), Some(pushed.clone()));
+    assert_eq!(refs.read_ref("refs/heads/topic"), Some(pushed.clone()));
+    let database = Database::new(git_path.join("objects"));
+    let (_, data) = database.load(&pushed)?;
+    assert_eq!(Commit::parse(&data)?.parents(), &[first]);
+    let tree = database.load_tree_entries(&database.load_tree_id(&pushed)?)?;
+    assert!(tree.contains_key("b.txt"));
+
+    git(
+        &local,
+        &["push", &receive_pack, "origin", ":refs/heads/topic"],
+    )?;
+    assert_eq!(refs.read_ref("refs/heads/topic"), None);
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn clone_from_daemon() -> Result<()> {
+    if !has_git() {
+        return Ok(());
+    }
+    let dir = temp_dir("daemon");
+    let git_path = dir.join("base").join("project.git");
+    let commit_id = commit(&git_path, "one\n", vec![])?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("git://{}/project", listener.local_addr()?);
+    let options = DaemonOptions {
+        base_path: dir.join("base"),
+        export_all: true,
+    };
+    thread::spawn(move || daemon::serve(listener, options));
+
+    for version in ["0", "2"] {
+        let protocol = format!("protocol.version={}", version);
+        let clone = format!("clone{}", version);
+        git(&dir, &["-c", &protocol, "clone", &url, &clone])?;
+        let clone = dir.join(clone);
+        assert_eq!(std::fs::read_to_string(clone.join("a.txt"))?, "one\n");
+        assert_eq!(git(&clone, &["rev-parse", "HEAD"])?, commit_id);
+        git(&clone, &["-c", &protocol, "fetch", "origin"])?;
+    }
+    // The daemon only serves upload-pack
+    let clone = dir.join("clone0");
+    assert!(git(&clone, &["push", "origin", "HEAD:refs/heads/other"]).is_err());
+    assert_eq!(Refs::new(git_path).read_ref("refs/heads/other"), None);
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}